@@ -0,0 +1,197 @@
+//!
+//! Accounts: what a player keeps from one session to the next. They aren't registered, there is no
+//! password nor database: the server creates an account at the first login and answers with its
+//! token, the client sends the token back with its next logins to get the same account. A lost
+//! token is a lost account.
+//!
+//! The friends of a player, its privacy settings, its statistics and rating, its achievements and
+//! its cosmetics are kept with its account. With ACCOUNTS_FILE the accounts are loaded from that JSON
+//! file at startup and written back a few seconds after a change and when the server stops,
+//! otherwise they're lost with a restart. The file only holds the SHA1 of the tokens.
+//!
+//! The bans of the operators are saved with them: a banned account can't log in anymore, and the
+//! addresses banned along with it (see admin.rs) refuse every login, until an operator unbans them.
+//!
+
+use std::{collections::{BTreeSet, HashMap}, env, fs, net::IpAddr, path::{Path, PathBuf}, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::achievements::Achievements;
+use crate::cosmetics::Inventory;
+use crate::player_stats::PlayerStats;
+use crate::protocol::{IdType, PrivacySettings};
+use crate::ratings::Rating;
+
+/// How often the accounts are written, if they changed.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Account {
+    token_hash: String,
+    pub username: String,// Of the last login
+    pub privacy: PrivacySettings,// Of the last login
+    pub banned: bool,// By an operator
+    pub friends: BTreeSet<IdType>,// The accounts this one added
+    pub requests: BTreeSet<IdType>,// The accounts that added this one, not added back yet
+    pub stats: PlayerStats,
+    pub rating: Rating,// See ratings.rs
+    pub achievements: Achievements,
+    pub inventory: Inventory,// The cosmetics unlocked, see cosmetics.rs
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccountsFile {
+    pub accounts: HashMap<IdType, Account>,
+    pub banned_addresses: BTreeSet<IpAddr>,
+}
+
+pub struct Accounts {
+    path: Option<PathBuf>,
+    accounts: HashMap<IdType, Account>,
+    tokens: HashMap<String, IdType>,// SHA1 of the token -> account
+    banned_addresses: BTreeSet<IpAddr>,
+    changed: bool,// Since the last save
+}
+
+fn token_hash(token: &str) -> String {
+    sha1::Sha1::from(token).digest().to_string()
+}
+
+/// The accounts saved in a file, for the startup and the --check mode.
+pub fn read(path: &Path) -> Result<AccountsFile, String> {
+    let data = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&data).map_err(|e| e.to_string())
+}
+
+impl Accounts {
+    pub fn from_env() -> Self {
+        Accounts::load(env::var_os("ACCOUNTS_FILE").map(PathBuf::from))
+    }
+
+    /// A file that doesn't exist yet is created with the first account.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let file = match &path {
+            Some(x) if x.exists() => read(x).unwrap_or_else(|e| {
+                // Starting empty would overwrite the file with the first save.
+                panic!("Cannot read ACCOUNTS_FILE {}: {}", x.display(), e);
+            }),
+            _ => AccountsFile::default(),
+        };
+        if let Some(x) = &path {
+            info!(path = %x.display(), accounts = file.accounts.len(), banned_addresses = file.banned_addresses.len(), "Accounts loaded");
+        }
+        let tokens = file.accounts.iter().map(|(id, x)| (x.token_hash.clone(), *id)).collect();
+        Accounts { path, accounts: file.accounts, tokens, banned_addresses: file.banned_addresses, changed: false }
+    }
+
+    /// The account of a token, a new one (with its token) without.
+    pub fn login(&mut self, token: Option<&str>, rng: &mut impl Rng) -> Result<(IdType, Option<String>), &'static str> {
+        if let Some(token) = token {
+            return self.tokens.get(&token_hash(token)).map(|x| (*x, None)).ok_or("invalid_account");
+        }
+        let id = loop {
+            let id = rng.gen::<IdType>();
+            if !self.accounts.contains_key(&id) {
+                break id;
+            }
+        };
+        let token = base64::encode(rng.gen::<[u8; 24]>());
+        self.tokens.insert(token_hash(&token), id);
+        self.accounts.insert(id, Account { token_hash: token_hash(&token), ..Account::default() });
+        self.changed = true;
+        Ok((id, Some(token)))
+    }
+
+    pub fn get(&self, id: IdType) -> Option<&Account> {
+        self.accounts.get(&id)
+    }
+
+    /// The account is saved with the next save.
+    pub fn get_mut(&mut self, id: IdType) -> Option<&mut Account> {
+        self.changed = true;
+        self.accounts.get_mut(&id)
+    }
+
+    pub fn is_banned_address(&self, ip: IpAddr) -> bool {
+        self.banned_addresses.contains(&ip)
+    }
+
+    pub fn ban_addresses(&mut self, ips: impl Iterator<Item = IpAddr>) {
+        self.banned_addresses.extend(ips);
+        self.changed = true;
+    }
+
+    /// Lifts the bans of the accounts and the addresses.
+    pub fn unban(&mut self, accounts: &[IdType], ips: &[IpAddr]) {
+        for id in accounts {
+            if let Some(x) = self.accounts.get_mut(id) {
+                x.banned = false;
+            }
+        }
+        for ip in ips {
+            self.banned_addresses.remove(ip);
+        }
+        self.changed = true;
+    }
+
+    /// The banned accounts and addresses.
+    pub fn bans(&self) -> (Vec<(IdType, &Account)>, Vec<IpAddr>) {
+        let accounts = self.accounts.iter().filter(|x| x.1.banned).map(|(id, x)| (*id, x)).collect();
+        (accounts, self.banned_addresses.iter().copied().collect())
+    }
+
+    /// Writes the accounts to ACCOUNTS_FILE if they changed since the last save.
+    pub fn save(&mut self) {
+        let path = match &self.path {
+            Some(x) if self.changed => x,
+            _ => return,
+        };
+        // Written aside and renamed, a crash in the middle doesn't leave half a file.
+        let tmp = path.with_extension("tmp");
+        let data = serde_json::json!({ "accounts": &self.accounts, "bannedAddresses": &self.banned_addresses }).to_string();
+        match fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, path)) {
+            Ok(()) => self.changed = false,
+            Err(e) => warn!(path = %path.display(), error = %e, "Cannot save the accounts"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cosmetics;
+    use crate::protocol::{CosmeticItem, PlayerCosmetics};
+
+    #[test]
+    fn accounts_survive_a_restart() {
+        let path = env::temp_dir().join(format!("accounts-{}.json", rand::random::<u64>()));
+        let mut rng = rand::thread_rng();
+        let mut accounts = Accounts::load(Some(path.clone()));
+        let (id, token) = accounts.login(None, &mut rng).unwrap();
+        let token = token.unwrap();
+        accounts.get_mut(id).unwrap().friends.insert(42);
+        accounts.get_mut(id).unwrap().inventory.add(std::iter::once(CosmeticItem::Avatar(cosmetics::EARNED_AVATARS)));
+        accounts.get_mut(id).unwrap().banned = true;
+        accounts.ban_addresses(["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()].iter().copied());
+        accounts.save();
+
+        let mut accounts = Accounts::load(Some(path.clone()));
+        assert_eq!(accounts.login(Some(&token), &mut rng), Ok((id, None)));
+        assert_eq!(accounts.login(Some("forged"), &mut rng), Err("invalid_account"));
+        assert!(accounts.get(id).unwrap().friends.contains(&42));
+        assert!(accounts.get(id).unwrap().inventory.owns(&PlayerCosmetics { avatar: cosmetics::EARNED_AVATARS, color: 0 }));
+        assert!(!fs::read_to_string(&path).unwrap().contains(&token));
+        assert!(accounts.get(id).unwrap().banned && accounts.is_banned_address("10.0.0.1".parse().unwrap()));
+
+        accounts.unban(&[id], &["10.0.0.1".parse().unwrap()]);
+        let (banned, addresses) = accounts.bans();
+        assert!(banned.is_empty());
+        assert_eq!(addresses, ["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//!
+//! Achievements: goals that a player reaches once, like completing a city of 10 tiles or winning a
+//! game with 3 cloisters. Each one is a rule evaluated on the validated events of the games: the
+//! features scored by the authoritative engine, as soon as the room scores them, and the end of
+//! every game with its result. A player receives an event_achievement_unlocked for every new one,
+//! some of them unlock a cosmetic too (see cosmetics.rs).
+//!
+//! The unlocked achievements belong to the account of the player (see accounts.rs) and are listed
+//! in its profile. A suspended session doesn't receive the events, the achievements are unlocked
+//! all the same.
+//!
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::game::scoring::{FeatureKind, ScoredFeature};
+use crate::player_stats::{GameResult, PlayerStats};
+use crate::protocol::Achievement;
+
+pub enum Rule {
+    Feature { kind: FeatureKind, tiles: u32 },// A completed feature of at least `tiles` tiles
+    Win { cloisters: u32 },// A game won with at least `cloisters` completed cloisters
+    Points(u32),// In a single game
+    GamesPlayed(u32),
+}
+
+pub const RULES: &[(Achievement, Rule)] = &[
+    (Achievement::FirstWin, Rule::Win { cloisters: 0 }),
+    (Achievement::RoadBuilder, Rule::Feature { kind: FeatureKind::Road, tiles: 10 }),
+    (Achievement::CityPlanner, Rule::Feature { kind: FeatureKind::City, tiles: 10 }),
+    (Achievement::Veteran, Rule::GamesPlayed(10)),
+    (Achievement::HighScore, Rule::Points(100)),
+    (Achievement::Abbot, Rule::Win { cloisters: 3 }),
+];
+
+pub enum AchievementEvent<'a> {
+    Scored(&'a ScoredFeature),// For each of its players
+    Ended(&'a PlayerStats, &'a GameResult),// The statistics include the game already
+}
+
+impl Rule {
+    fn matches(&self, event: &AchievementEvent) -> bool {
+        match (self, event) {
+            (Rule::Feature { kind, tiles }, AchievementEvent::Scored(feature)) => {
+                feature.complete && feature.feature == *kind && feature.tiles >= *tiles
+            },
+            // A win relayed without the engine has no points, it doesn't count.
+            (Rule::Win { cloisters }, AchievementEvent::Ended(_, result)) => {
+                result.won && result.points.is_some() && result.cloisters >= *cloisters
+            },
+            (Rule::Points(points), AchievementEvent::Ended(_, result)) => result.points.map(|x| x >= *points).unwrap_or(false),
+            (Rule::GamesPlayed(games), AchievementEvent::Ended(stats, _)) => stats.games_played() >= *games,
+            _ => false,
+        }
+    }
+}
+
+/// The achievements reached with an event, unlocked or not.
+pub fn evaluate<'a>(event: &'a AchievementEvent<'a>) -> impl Iterator<Item = Achievement> + 'a {
+    RULES.iter().filter(move |(_, rule)| rule.matches(event)).map(|x| x.0)
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Achievements {
+    unlocked: BTreeSet<Achievement>,
+}
+
+impl Achievements {
+    /// Unlocks the achievements reached, returns the new ones.
+    pub fn unlock(&mut self, reached: impl Iterator<Item = Achievement>) -> Vec<Achievement> {
+        reached.filter(|x| self.unlocked.insert(*x)).collect()
+    }
+
+    pub fn list(&self) -> Vec<Achievement> {
+        self.unlocked.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SerId;
+
+    fn feature(feature: FeatureKind, tiles: u32, complete: bool) -> ScoredFeature {
+        ScoredFeature { feature, points: 0, players: vec![SerId(1)], tiles, complete }
+    }
+
+    fn result(points: Option<u32>, won: bool, cloisters: u32) -> GameResult {
+        GameResult { points, won, longest_road: 0, biggest_city: 0, cloisters }
+    }
+
+    #[test]
+    fn rules_match_their_events() {
+        let mut achievements = Achievements::default();
+        let city = feature(FeatureKind::City, 12, true);
+        assert_eq!(achievements.unlock(evaluate(&AchievementEvent::Scored(&city))), [Achievement::CityPlanner]);
+        assert!(achievements.unlock(evaluate(&AchievementEvent::Scored(&city))).is_empty());
+        // Scored unfinished at the end of the game.
+        let road = feature(FeatureKind::Road, 12, false);
+        assert_eq!(evaluate(&AchievementEvent::Scored(&road)).count(), 0);
+
+        let mut stats = PlayerStats::default();
+        let relayed = result(None, true, 3);
+        stats.record(&relayed);
+        assert_eq!(evaluate(&AchievementEvent::Ended(&stats, &relayed)).count(), 0);
+        let game = result(Some(120), true, 3);
+        stats.record(&game);
+        assert_eq!(achievements.unlock(evaluate(&AchievementEvent::Ended(&stats, &game))), [
+            Achievement::FirstWin, Achievement::HighScore, Achievement::Abbot,
+        ]);
+        assert_eq!(achievements.list().len(), 4);
+    }
+}
@@ -0,0 +1,571 @@
+//!
+//! Admin api for the operators: list the rooms and the players, close a room, kick a player,
+//! announce something to every connected player, change the message of the day, switch the lobby to read only during an incident
+//! and read the relay schemas (see relay_sampling), without restarting the server.
+//!
+//! The bulk operations (close the rooms matching a filter, kick, ban or warn a list of players) are
+//! handled by the lobby in a single message: a list with an unknown id is refused as a whole, and
+//! the rooms and sessions are ended asynchronously, the operation is a job whose progress can be
+//! followed on /admin/jobs/{id}. A ban refuses the logins of the accounts of the players, and of
+//! their addresses if asked (see handshake.rs for the clients behind a proxy), until an operator
+//! lifts it; the bans are saved with the accounts (see accounts.rs).
+//!
+//! A room can be moved to another instance to drain this one for a maintenance, see migration.rs.
+//! Its messages can be written to a file for a while to debug a report, see room_trace.rs.
+//!
+//! It's only enabled when ADMIN_TOKEN is set, every request must carry it as
+//! `Authorization: Bearer <token>`. With ADMIN_BIND_ADDR it's only served on that address. With
+//! ADMIN_SOCKET it's also served without a token (and without ADMIN_TOKEN) on that unix socket.
+//!
+
+use std::{env, net::IpAddr};
+
+use actix::prelude::*;
+use actix_web::{http::header, HttpRequest, HttpResponse, web};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::listeners;
+use crate::lobby_actor::{self, LobbyActor};
+use crate::migration::{self, Imported, RoomData};
+use crate::protocol::{IdType, RoomSettings, SerId};
+use crate::relay_sampling;
+use crate::room_trace;
+use crate::room_actor::RoomState;
+
+#[derive(Clone)]
+pub struct AdminConfig {
+    token: Option<String>,
+}
+
+impl AdminConfig {
+    pub fn from_env() -> Self {
+        AdminConfig {
+            token: env::var("ADMIN_TOKEN").ok().filter(|x| !x.is_empty()),
+        }
+    }
+
+    /// Returns the response to send back if the request isn't allowed.
+    fn check(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        check_bearer(req, self.token.as_deref())
+    }
+}
+
+/// Checks the `Authorization: Bearer <token>` header of a request, not found if there's no token
+/// or if it came in on another address than ADMIN_BIND_ADDR. The requests on the admin socket need
+/// no token.
+pub fn check_bearer(req: &HttpRequest, token: Option<&str>) -> Result<(), HttpResponse> {
+    // Whoever can open the admin socket is trusted, see listeners.
+    if listeners::on_admin_socket(req) {
+        return Ok(());
+    }
+    let token = match token {
+        Some(x) if listeners::admin_allowed(req) => x,
+        _ => return Err(HttpResponse::NotFound().finish()),
+    };
+    let given = req.headers().get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    if !given.map(|x| same_token(x.as_bytes(), token.as_bytes())).unwrap_or(false) {
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+    Ok(())
+}
+
+/// Compares in a time that doesn't depend on where the tokens differ, only on their length.
+fn same_token(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len() && given.iter().zip(token).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminRoom {
+    pub room_id: SerId,
+    pub public: bool,
+    pub state: RoomState,
+    pub locked: bool,
+    pub host: String,
+    pub players: Vec<SerId>,
+    pub settings: RoomSettings,
+    pub age_secs: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminPlayer {
+    pub player_id: SerId,
+    pub account_id: SerId,
+    pub username: String,
+    pub connected: bool,// False while the session waits for a reconnection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<SerId>,
+}
+
+// The ids are in the body since base64 isn't url safe.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseRoom {
+    room_id: SerId,
+}
+
+/// Traces the room for `durationSecs` (at most room_trace::MAX_TRACE_SECS), 0 ends the trace.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceRoom {
+    room_id: SerId,
+    duration_secs: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Traced {
+    room_id: SerId,
+    file: String,// On the host of this instance
+    duration_secs: u64,
+}
+
+/// `url` is the base url of the admin api of the target instance.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrateRoom {
+    room_id: SerId,
+    url: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Migrated {
+    room_id: SerId,
+    url: String,// Where the players reconnect
+}
+
+#[derive(Serialize)]
+struct Refused {
+    reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KickPlayer {
+    player_id: SerId,
+}
+
+/// Only these players (or the players of these rooms) receive the announcement, every connected
+/// player if both are missing.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Announcement {
+    message: String,
+    player_ids: Option<Vec<SerId>>,
+    room_ids: Option<Vec<SerId>>,
+}
+
+/// Sent to every new session after its login, removed if the message is missing or empty.
+#[derive(Deserialize)]
+pub struct Motd {
+    message: Option<String>,
+}
+
+/// Read only lobby during an incident, lighter than a shutdown: the players stay connected and
+/// receive the announcements but can't start new games.
+#[derive(Deserialize)]
+pub struct Incident {
+    active: bool,
+    message: Option<String>,
+}
+
+/// The players of a bulk kick.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KickPlayers {
+    player_ids: Vec<SerId>,
+}
+
+/// `addresses` bans the addresses of the players too.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BanPlayers {
+    player_ids: Vec<SerId>,
+    #[serde(default)]
+    addresses: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Unban {
+    account_ids: Vec<SerId>,
+    addresses: Vec<IpAddr>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BannedAccount {
+    pub account_id: SerId,
+    pub username: String,// Of its last login
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminBans {
+    pub accounts: Vec<BannedAccount>,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// The rooms matching every field given, at least one is needed.
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RoomFilter {
+    pub state: Option<RoomState>,
+    pub public: Option<bool>,
+    pub locked: Option<bool>,
+    pub host: Option<String>,
+    pub min_age_secs: Option<u64>,
+}
+
+impl RoomFilter {
+    fn is_empty(&self) -> bool {
+        self.state.is_none() && self.public.is_none() && self.locked.is_none() && self.host.is_none() && self.min_age_secs.is_none()
+    }
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminJobKind {
+    CloseRooms,
+    KickPlayers,
+    BanPlayers,
+    Announce,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminJobReport {
+    pub job_id: u64,
+    pub kind: AdminJobKind,
+    pub total: usize,
+    pub done: usize,
+    pub pending: Vec<SerId>,// Rooms or players not closed yet
+    pub elapsed_ms: u64,
+}
+
+/// Answer to a list with unknown ids, nothing has been done.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotFound {
+    not_found: Vec<SerId>,
+}
+
+fn ids(list: Option<Vec<SerId>>) -> Option<Vec<IdType>> {
+    list.map(|x| x.into_iter().map(|id| id.0).collect())
+}
+
+fn job(res: Result<Result<AdminJobReport, Vec<SerId>>, MailboxError>) -> HttpResponse {
+    match res {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        Ok(Err(not_found)) => HttpResponse::NotFound().json(NotFound { not_found }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+fn done(res: Result<bool, MailboxError>) -> HttpResponse {
+    match res {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn rooms(req: HttpRequest, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    match lobby.send(lobby_actor::AdminListRooms).await {
+        Ok(rooms) => HttpResponse::Ok().json(rooms),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn players(req: HttpRequest, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    match lobby.send(lobby_actor::AdminListPlayers).await {
+        Ok(players) => HttpResponse::Ok().json(players),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn close_room(req: HttpRequest, body: web::Json<CloseRoom>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    done(lobby.send(lobby_actor::AdminCloseRoom { room_id: body.room_id.0 }).await)
+}
+
+pub async fn kick_player(req: HttpRequest, body: web::Json<KickPlayer>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    done(lobby.send(lobby_actor::AdminKickPlayer { id: body.player_id.0 }).await)
+}
+
+pub async fn announce(req: HttpRequest, body: web::Json<Announcement>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let body = body.into_inner();
+    if body.message.trim().is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let (players, rooms) = (ids(body.player_ids), ids(body.room_ids));
+    if players.is_none() && rooms.is_none() {
+        return match lobby.send(lobby_actor::AdminAnnounce { message: body.message }).await {
+            Ok(()) => HttpResponse::NoContent().finish(),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        };
+    }
+    job(lobby.send(lobby_actor::AdminAnnounceTo {
+        message: body.message,
+        players: players.unwrap_or_default(),
+        rooms: rooms.unwrap_or_default(),
+    }).await)
+}
+
+pub async fn motd(req: HttpRequest, body: web::Json<Motd>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let message = body.into_inner().message.map(|x| x.trim().to_string()).filter(|x| !x.is_empty());
+    match lobby.send(lobby_actor::AdminSetMotd { message }).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn incident(req: HttpRequest, body: web::Json<Incident>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let body = body.into_inner();
+    let message = body.message.filter(|x| !x.trim().is_empty());
+    match lobby.send(lobby_actor::AdminSetIncident { active: body.active, message }).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn close_rooms(req: HttpRequest, body: web::Json<RoomFilter>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let filter = body.into_inner();
+    if filter.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    match lobby.send(lobby_actor::AdminCloseRooms { filter }).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn kick_players(req: HttpRequest, body: web::Json<KickPlayers>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let ids = ids(Some(body.into_inner().player_ids)).unwrap_or_default();
+    job(lobby.send(lobby_actor::AdminKickPlayers { ids }).await)
+}
+
+pub async fn ban_players(req: HttpRequest, body: web::Json<BanPlayers>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let body = body.into_inner();
+    let ids = ids(Some(body.player_ids)).unwrap_or_default();
+    job(lobby.send(lobby_actor::AdminBanPlayers { ids, addresses: body.addresses }).await)
+}
+
+pub async fn unban(req: HttpRequest, body: web::Json<Unban>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let body = body.into_inner();
+    if body.account_ids.is_empty() && body.addresses.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let accounts = ids(Some(body.account_ids)).unwrap_or_default();
+    match lobby.send(lobby_actor::AdminUnban { accounts, addresses: body.addresses }).await {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(not_found)) => HttpResponse::NotFound().json(NotFound { not_found }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn bans(req: HttpRequest, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    match lobby.send(lobby_actor::AdminListBans).await {
+        Ok(bans) => HttpResponse::Ok().json(bans),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn job_progress(req: HttpRequest, path: web::Path<u64>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    match lobby.send(lobby_actor::AdminGetJob { id: path.into_inner() }).await {
+        Ok(Some(report)) => HttpResponse::Ok().json(report),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn relay_schemas(req: HttpRequest, config: web::Data<AdminConfig>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    HttpResponse::Ok().json(relay_sampling::report())
+}
+
+pub async fn trace_room(req: HttpRequest, body: web::Json<TraceRoom>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let body = body.into_inner();
+    match lobby.send(lobby_actor::AdminTraceRoom { room_id: body.room_id.0, secs: body.duration_secs }).await {
+        Ok(Ok(Some(file))) => HttpResponse::Ok().json(Traced {
+            room_id: body.room_id,
+            file: file.display().to_string(),
+            duration_secs: body.duration_secs.min(room_trace::MAX_TRACE_SECS),
+        }),
+        Ok(Ok(None)) if body.duration_secs == 0 => HttpResponse::NoContent().finish(),
+        Ok(Err(())) => HttpResponse::NotFound().finish(),
+        // The file couldn't be created, the reason is in the logs.
+        Ok(Ok(None)) | Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn migrate_room(req: HttpRequest, body: web::Json<MigrateRoom>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    let body = body.into_inner();
+    let room_id = body.room_id.0;
+    let target = body.url.trim().trim_end_matches('/').to_string();
+    if target.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let data = match lobby.send(lobby_actor::MigrateRoom { room_id }).await {
+        Ok(Ok(x)) => x,
+        Ok(Err(())) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // The target shares the token of this instance.
+    let token = config.token.clone().unwrap_or_default();
+    let res = migration::send(target.clone(), token, &data).await;
+    lobby.do_send(lobby_actor::EndMigration { room_id, url: res.as_ref().ok().cloned() });
+    match res {
+        Ok(url) => HttpResponse::Ok().json(Migrated { room_id: body.room_id, url }),
+        Err(e) => {
+            warn!(room = room_id, target = %target, error = %e, "Room migration refused");
+            HttpResponse::BadGateway().json(Refused { reason: e })
+        },
+    }
+}
+
+pub async fn import_room(req: HttpRequest, body: web::Json<RoomData>, config: web::Data<AdminConfig>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    if let Err(res) = config.check(&req) {
+        return res;
+    }
+    match lobby.send(lobby_actor::ImportRoom(body.into_inner())).await {
+        Ok(Ok(url)) => HttpResponse::Ok().json(Imported { url }),
+        Ok(Err(reason)) => HttpResponse::Conflict().json(Refused { reason: reason.to_string() }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_server::{self, TestServer};
+
+    fn server() -> TestServer {
+        test_server::start(AdminConfig { token: Some("t".into()) })
+    }
+
+    async fn post(server: &TestServer, path: &str, body: Value) -> (StatusCode, Value) {
+        let mut res = server.http.post(path).header(header::AUTHORIZATION, "Bearer t").send_json(&body).await.unwrap();
+        (res.status(), res.json().await.unwrap_or(Value::Null))
+    }
+
+    async fn get(server: &TestServer, path: &str) -> (StatusCode, Value) {
+        get_as(server, path, Some("t")).await
+    }
+
+    async fn get_as(server: &TestServer, path: &str, token: Option<&str>) -> (StatusCode, Value) {
+        let mut req = server.http.get(path);
+        if let Some(x) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", x));
+        }
+        let mut res = req.send().await.unwrap();
+        (res.status(), res.json().await.unwrap_or(Value::Null))
+    }
+
+    #[actix_rt::test]
+    async fn requests_need_the_token() {
+        let server = server();
+        assert_eq!(get_as(&server, "/admin/rooms", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_as(&server, "/admin/rooms", Some("wrong")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&server, "/admin/rooms").await.0, StatusCode::OK);
+
+        // Without a token there's no admin api at all.
+        let server = test_server::start(AdminConfig { token: None });
+        assert_eq!(get(&server, "/admin/rooms").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn bulk_jobs_report_their_progress() {
+        let mut server = server();
+        let mut alice = server.login("alice").await;
+        let mut bob = server.login("bob").await;
+
+        let unknown = "AAAAAAAAAAA=";
+        let (status, res) = post(&server, "/admin/players/kick_many", json!({ "playerIds": [alice.player_id, unknown] })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(res["notFound"], json!([unknown]));
+
+        let (status, res) = post(&server, "/admin/players/ban_many", json!({ "playerIds": [alice.player_id, bob.player_id] })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res["total"], 2);
+        let path = format!("/admin/jobs/{}", res["jobId"]);
+        let mut report = res;
+        for _ in 0..50 {
+            if report["done"] == 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            report = get(&server, &path).await.1;
+        }
+        assert_eq!(report["done"], 2, "{}", report);
+        assert_eq!(report["pending"], json!([]));
+        assert_eq!(get(&server, "/admin/jobs/123456").await.0, StatusCode::NOT_FOUND);
+
+        // The banned accounts can't come back.
+        assert_eq!(alice.closed().await.as_deref(), Some("kicked"));
+        assert_eq!(bob.closed().await.as_deref(), Some("kicked"));
+        let mut client = server.connect().await;
+        let res = client.request(json!({
+            "type": "login",
+            "details": { "username": "alice", "avatar": 1, "color": 1, "accountToken": alice.account_token },
+        })).await;
+        assert_eq!(res["result"], "banned");
+    }
+}
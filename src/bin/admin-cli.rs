@@ -0,0 +1,333 @@
+//!
+//! Command line client of the admin api, for the day to day operations and the scripts. It talks
+//! to the unix socket of ADMIN_SOCKET when it's set (no token needed), otherwise to ADMIN_URL
+//! (`http://127.0.0.1:8081` by default, the ADMIN_BIND_ADDR of the server if it has one) with
+//! ADMIN_TOKEN. `--socket`, `--url` and `--token` override them.
+//!
+//! The answers are printed as json, the command exits with 1 if the server refused it. Only plain
+//! http is supported: the admin api is meant for a private address or the socket.
+//!
+
+use std::{env, io::{self, Read, Write}, net::{IpAddr, TcpStream}, process, time::Duration};
+
+use serde_json::{json, Value};
+
+const USAGE: &str = "\
+Usage: admin-cli [--socket <path> | --url <http url>] [--token <token>] <command>
+
+Commands:
+  rooms                                  List the rooms
+  room <roomId>                          Show a room and its players
+  players                                List the sessions
+  close <roomId>                         Close a room, its players go back to the matchmaking
+  trace <roomId> <seconds>               Write the messages of a room to a file for a while, 0 ends it
+  kick <playerId>...                     Disconnect players and end their sessions
+  ban [--addresses] <playerId>...        Kick players and refuse the logins of their accounts, and of
+                                         their addresses with --addresses
+  unban <accountId | address>...         Lift the bans of accounts and addresses
+  bans                                   List the banned accounts and addresses
+  announce <message> [--room <roomId>]... [--player <playerId>]...
+                                         Show a message to every player, or only to these
+  motd [message]                         Set the message of the day of the new sessions, none removes it
+  maintenance on [message] | off         Switch the lobby to read only, or back
+  job <jobId>                            Progress of a bulk operation";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+enum Target {
+    Socket(String),
+    Http { host: String, prefix: String },
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: &'static str,
+    path: String,
+    body: Option<Value>,
+}
+
+enum Command {
+    Send(Request),
+    Room(String),// Filtered from the lists of rooms and players, ids aren't url safe
+}
+
+fn get(path: &str) -> Request {
+    Request { method: "GET", path: path.to_string(), body: None }
+}
+
+fn post(path: &str, body: Value) -> Request {
+    Request { method: "POST", path: path.to_string(), body: Some(body) }
+}
+
+fn parse_url(url: &str) -> Result<Target, String> {
+    let rest = match url.strip_prefix("http://") {
+        Some(x) => x,
+        None if url.starts_with("https://") => return Err("https isn't supported, use the admin address or the socket".to_string()),
+        None => return Err(format!("\"{}\" is not an http url", url)),
+    };
+    let (host, prefix) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    if host.is_empty() {
+        return Err(format!("\"{}\" has no host", url));
+    }
+    // The port of an IPv6 address is after the brackets.
+    let has_port = if host.starts_with('[') { host.contains("]:") } else { host.contains(':') };
+    let host = if has_port { host.to_string() } else { format!("{}:80", host) };
+    Ok(Target::Http { host, prefix: prefix.to_string() })
+}
+
+fn parse_command(args: &[String]) -> Result<Command, String> {
+    let arg = |i: usize| args.get(i).cloned().ok_or_else(|| format!("{} needs more arguments", args[0]));
+    let command = match args.first().map(|x| x.as_str()) {
+        Some("rooms") => Command::Send(get("/admin/rooms")),
+        Some("room") => Command::Room(arg(1)?),
+        Some("players") => Command::Send(get("/admin/players")),
+        Some("close") => Command::Send(post("/admin/rooms/close", json!({ "roomId": arg(1)? }))),
+        Some("trace") => {
+            let secs = arg(2)?;
+            let secs: u64 = secs.parse().map_err(|_| format!("\"{}\" is not a number of seconds", secs))?;
+            Command::Send(post("/admin/rooms/trace", json!({ "roomId": arg(1)?, "durationSecs": secs })))
+        },
+        Some("kick") => match &args[1..] {
+            [] => return Err("kick needs at least a player id".to_string()),
+            [id] => Command::Send(post("/admin/players/kick", json!({ "playerId": id }))),
+            ids => Command::Send(post("/admin/players/kick_many", json!({ "playerIds": ids }))),
+        },
+        Some("ban") => {
+            let addresses = args.get(1).map(|x| x == "--addresses").unwrap_or(false);
+            match &args[if addresses { 2 } else { 1 }..] {
+                [] => return Err("ban needs at least a player id".to_string()),
+                ids => Command::Send(post("/admin/players/ban_many", json!({ "playerIds": ids, "addresses": addresses }))),
+            }
+        },
+        Some("unban") => {
+            if args.len() < 2 {
+                return Err("unban needs at least an account id or an address".to_string());
+            }
+            // The account ids are base64, never an address.
+            let (addresses, accounts): (Vec<&String>, Vec<&String>) = args[1..].iter().partition(|x| x.parse::<IpAddr>().is_ok());
+            Command::Send(post("/admin/players/unban", json!({ "accountIds": accounts, "addresses": addresses })))
+        },
+        Some("bans") => Command::Send(get("/admin/bans")),
+        Some("announce") => {
+            let message = arg(1)?;
+            let (mut rooms, mut players) = (Vec::new(), Vec::new());
+            let mut i = 2;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "--room" => rooms.push(arg(i + 1)?),
+                    "--player" => players.push(arg(i + 1)?),
+                    x => return Err(format!("unknown option {}", x)),
+                }
+                i += 2;
+            }
+            let mut body = json!({ "message": message });
+            if !rooms.is_empty() {
+                body["roomIds"] = json!(rooms);
+            }
+            if !players.is_empty() {
+                body["playerIds"] = json!(players);
+            }
+            Command::Send(post("/admin/announce", body))
+        },
+        Some("motd") => match &args[1..] {
+            [] => Command::Send(post("/admin/motd", json!({ "message": null }))),
+            words => Command::Send(post("/admin/motd", json!({ "message": words.join(" ") }))),
+        },
+        Some("maintenance") => match arg(1)?.as_str() {
+            "on" => {
+                let message = Some(args[2..].join(" ")).filter(|x| !x.is_empty());
+                Command::Send(post("/admin/incident", json!({ "active": true, "message": message })))
+            },
+            "off" => Command::Send(post("/admin/incident", json!({ "active": false }))),
+            x => return Err(format!("maintenance is on or off, not {}", x)),
+        },
+        Some("job") => {
+            let id = arg(1)?;
+            let id: u64 = id.parse().map_err(|_| format!("\"{}\" is not a job id", id))?;
+            Command::Send(get(&format!("/admin/jobs/{}", id)))
+        },
+        Some(x) => return Err(format!("unknown command {}", x)),
+        None => return Err("missing command".to_string()),
+    };
+    Ok(command)
+}
+
+/// Sends the request and returns the status and the body.
+fn send(target: &Target, token: Option<&str>, req: &Request) -> io::Result<(u16, Vec<u8>)> {
+    let body = req.body.as_ref().map(|x| x.to_string()).unwrap_or_default();
+    let (host, prefix) = match target {
+        Target::Socket(_) => ("localhost", ""),
+        Target::Http { host, prefix } => (host.as_str(), prefix.as_str()),
+    };
+    let mut head = format!("{} {}{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n", req.method, prefix, req.path, host, body.len());
+    if req.body.is_some() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    if let Some(token) = token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    let request = format!("{}\r\n{}", head, body);
+
+    let mut response = Vec::new();
+    match target {
+        #[cfg(unix)]
+        Target::Socket(path) => {
+            let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.write_all(request.as_bytes())?;
+            stream.read_to_end(&mut response)?;
+        },
+        #[cfg(not(unix))]
+        Target::Socket(_) => return Err(io::Error::new(io::ErrorKind::Other, "unix sockets need a unix system")),
+        Target::Http { host, .. } => {
+            let mut stream = TcpStream::connect(host)?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.write_all(request.as_bytes())?;
+            stream.read_to_end(&mut response)?;
+        },
+    }
+    parse_response(&response).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid http response"))
+}
+
+fn parse_response(data: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let end = data.windows(4).position(|x| x == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&data[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let chunked = lines.any(|x| x.to_ascii_lowercase().starts_with("transfer-encoding:") && x.to_ascii_lowercase().contains("chunked"));
+    let mut body = &data[end + 4..];
+    if !chunked {
+        return Some((status, body.to_vec()));
+    }
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|x| x == b"\r\n")?;
+        let len = usize::from_str_radix(String::from_utf8_lossy(&body[..line_end]).trim(), 16).ok()?;
+        if len == 0 {
+            return Some((status, decoded));
+        }
+        decoded.extend_from_slice(body.get(line_end + 2..line_end + 2 + len)?);
+        body = body.get(line_end + 4 + len..)?;
+    }
+}
+
+/// The answer as json, an error with the status if the server refused the request.
+fn run(target: &Target, token: Option<&str>, req: &Request) -> Result<Value, String> {
+    let (status, body) = send(target, token, req).map_err(|e| e.to_string())?;
+    let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    match status {
+        200..=299 => Ok(value),
+        401 => Err("401, wrong token".to_string()),
+        404 if body.is_empty() => Err("404, not found (or the admin api isn't enabled on this address)".to_string()),
+        x => Err(format!("{} {}", x, value)),
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut socket = env::var("ADMIN_SOCKET").ok().filter(|x| !x.is_empty());
+    let mut url = env::var("ADMIN_URL").ok().filter(|x| !x.is_empty());
+    let mut token = env::var("ADMIN_TOKEN").ok().filter(|x| !x.is_empty());
+    while args.len() >= 2 && args[0].starts_with("--") {
+        let value = args.remove(1);
+        match args.remove(0).as_str() {
+            "--socket" => (socket, url) = (Some(value), None),
+            "--url" => (url, socket) = (Some(value), None),
+            "--token" => token = Some(value),
+            x => usage(&format!("unknown option {}", x)),
+        }
+    }
+    if args.first().map(|x| x == "help" || x == "--help").unwrap_or(false) {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let target = match (socket, url) {
+        (Some(path), _) => Target::Socket(path),
+        (None, url) => parse_url(url.as_deref().unwrap_or("http://127.0.0.1:8081")).unwrap_or_else(|e| usage(&e)),
+    };
+    let command = parse_command(&args).unwrap_or_else(|e| usage(&e));
+    let result = match command {
+        Command::Send(req) => run(&target, token.as_deref(), &req),
+        Command::Room(id) => run(&target, token.as_deref(), &get("/admin/rooms")).and_then(|rooms| {
+            let room = rooms.as_array().into_iter().flatten()
+                .find(|x| x["roomId"] == id.as_str())
+                .cloned()
+                .ok_or_else(|| format!("no room {}", id))?;
+            let players = run(&target, token.as_deref(), &get("/admin/players"))?;
+            let players: Vec<&Value> = players.as_array().into_iter().flatten().filter(|x| x["roomId"] == id.as_str()).collect();
+            Ok(json!({ "room": room, "players": players }))
+        }),
+    };
+    match result {
+        Ok(Value::Null) => {},
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap_or_default()),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("error: {}\n\n{}", error, USAGE);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn commands_and_responses() {
+        assert_eq!(parse_url("http://10.0.0.5:9000/").unwrap(), Target::Http { host: "10.0.0.5:9000".to_string(), prefix: "".to_string() });
+        assert_eq!(parse_url("http://admin/server").unwrap(), Target::Http { host: "admin:80".to_string(), prefix: "/server".to_string() });
+        assert_eq!(parse_url("http://[::1]").unwrap(), Target::Http { host: "[::1]:80".to_string(), prefix: "".to_string() });
+        assert!(parse_url("https://admin").is_err());
+
+        let request = |line| match parse_command(&args(line)) {
+            Ok(Command::Send(x)) => x,
+            _ => panic!("{} isn't a request", line),
+        };
+        assert_eq!(request("kick AAAAAAAAAAE="), post("/admin/players/kick", json!({ "playerId": "AAAAAAAAAAE=" })));
+        assert_eq!(request("kick a b").path, "/admin/players/kick_many");
+        assert_eq!(request("ban a").body.unwrap(), json!({ "playerIds": ["a"], "addresses": false }));
+        assert_eq!(request("ban --addresses a b").body.unwrap(), json!({ "playerIds": ["a", "b"], "addresses": true }));
+        assert!(parse_command(&args("ban")).is_err());
+        assert!(parse_command(&args("ban --addresses")).is_err());
+        assert_eq!(
+            request("unban AAAAAAAAAAE= 10.0.0.1 ::1").body.unwrap(),
+            json!({ "accountIds": ["AAAAAAAAAAE="], "addresses": ["10.0.0.1", "::1"] })
+        );
+        assert!(parse_command(&args("unban")).is_err());
+        assert_eq!(request("bans").path, "/admin/bans");
+        assert_eq!(
+            request("announce hi --room r1 --player p1").body.unwrap(),
+            json!({ "message": "hi", "roomIds": ["r1"], "playerIds": ["p1"] }),
+        );
+        assert_eq!(request("motd Welcome back").body.unwrap(), json!({ "message": "Welcome back" }));
+        assert_eq!(request("motd").body.unwrap(), json!({ "message": null }));
+        assert_eq!(request("maintenance off").body.unwrap(), json!({ "active": false }));
+        assert_eq!(request("maintenance on Database down").body.unwrap(), json!({ "active": true, "message": "Database down" }));
+        assert_eq!(request("maintenance on").body.unwrap(), json!({ "active": true, "message": null }));
+        assert_eq!(request("job 3").path, "/admin/jobs/3");
+        assert_eq!(request("trace r1 600").body.unwrap(), json!({ "roomId": "r1", "durationSecs": 600 }));
+        assert!(parse_command(&args("trace r1")).is_err());
+        assert!(parse_command(&args("maintenance maybe")).is_err());
+        assert!(parse_command(&args("job x")).is_err());
+
+        assert_eq!(parse_response(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n"), Some((204, Vec::new())));
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n2\r\n[]\r\n0\r\n\r\n"),
+            Some((200, b"[]".to_vec())),
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//!
+//! Bots added by the host to fill a room, they need the authoritative engine. A bot is a player of
+//! the room without connection: the room gives it a copy of the game when its tile is drawn and it
+//! answers with its moves after BOT_THINK_TIME, the room validates and relays them as the moves of
+//! any other player.
+//!
+//! The easy bot places its tile anywhere it fits and rarely a meeple. The normal bot tries every
+//! placement and every meeple on a copy of the game and keeps the one that scores the most for it,
+//! compared to the other players, a meeple on an unfinished feature being worth a few points.
+//!
+
+use std::time::Duration;
+
+use actix::prelude::*;
+use rand::{rngs::ThreadRng, seq::SliceRandom, Rng};
+use tracing::debug;
+
+use crate::isolation::{self, Subsystem};
+use crate::game::{GameMove, GameState, MeeplePosition};
+use crate::protocol::{BotDifficulty, IdType};
+use crate::room_actor::{BotMoves, RoomActor};
+
+const BOT_THINK_TIME: Duration = Duration::from_millis(1500);
+
+const MEEPLE_POSITIONS: [MeeplePosition; 5] = [
+    MeeplePosition::Center,
+    MeeplePosition::North,
+    MeeplePosition::East,
+    MeeplePosition::South,
+    MeeplePosition::West,
+];
+
+pub struct BotActor {
+    id: IdType,
+    room: Addr<RoomActor>,
+    difficulty: BotDifficulty,
+    rng: ThreadRng,
+}
+
+impl BotActor {
+    pub fn new(id: IdType, room: Addr<RoomActor>, difficulty: BotDifficulty) -> Self {
+        BotActor { id, room, difficulty, rng: rand::thread_rng() }
+    }
+}
+
+impl Actor for BotActor {
+    type Context = Context<Self>;
+}
+
+/// Sent by the room when the tile of the bot is drawn.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PlayTurn {
+    pub game: GameState,
+}
+
+impl Handler<PlayTurn> for BotActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayTurn, ctx: &mut Context<Self>) -> Self::Result {
+        let (id, difficulty, rng) = (self.id, self.difficulty, &mut self.rng);
+        // Without moves the bot misses its turn, like a player away from the keyboard.
+        let moves = isolation::isolate(Subsystem::Bots, || choose_moves(&msg.game, id, difficulty, rng))
+            .unwrap_or_default();
+        debug!(bot = self.id, moves = moves.len(), "Bot turn played");
+        let moves = moves.iter()
+            .map(|x| serde_json::to_string(x).expect("Error serializing bot move"))
+            .collect();
+        ctx.run_later(BOT_THINK_TIME, |act, _| {
+            act.room.do_send(BotMoves { id: act.id, moves });
+        });
+    }
+}
+
+/// Difference between the points of the bot and the best of the other players.
+fn margin(game: &GameState, bot: IdType) -> i64 {
+    let mine = game.scores().get(&bot).copied().unwrap_or(0) as i64;
+    let best_other = game.scores().iter().filter(|x| *x.0 != bot).map(|x| *x.1 as i64).max().unwrap_or(0);
+    mine - best_other
+}
+
+/// The tile and meeple of the bot for this turn, nothing if it isn't its turn.
+fn choose_moves(game: &GameState, bot: IdType, difficulty: BotDifficulty, rng: &mut impl Rng) -> Vec<GameMove> {
+    let tile = match game.drawn() {
+        Some((player, tile)) if player == bot => tile,
+        _ => return Vec::new(),
+    };
+    let mut placements = game.placements(tile);
+    placements.shuffle(rng);
+    let place = |(x, y): (i32, i32), rotation| GameMove::PlaceTile { tile: tile.to_string(), x, y, rotation };
+    let meeple = |(x, y): (i32, i32), position| GameMove::PlaceMeeple { x, y, position };
+    let can_place_meeple = game.meeples_left(bot) > 0;
+
+    if difficulty == BotDifficulty::Easy {
+        let (pos, rotation) = match placements.first() {
+            Some(x) => *x,
+            None => return Vec::new(),
+        };
+        let mut moves = vec![place(pos, rotation)];
+        if can_place_meeple && rng.gen_bool(0.25) {
+            let mut after = game.clone();
+            if after.apply(bot, place(pos, rotation)).is_ok() {
+                let position = *MEEPLE_POSITIONS.choose(rng).unwrap();
+                if after.apply(bot, meeple(pos, position)).is_ok() {
+                    moves.push(meeple(pos, position));
+                }
+            }
+        }
+        return moves;
+    }
+
+    let before = margin(game, bot);
+    let mut best: Option<(i64, Vec<GameMove>)> = None;
+    for (pos, rotation) in placements {
+        let mut placed = game.clone();
+        if placed.apply(bot, place(pos, rotation)).is_err() {
+            continue;
+        }
+        let mut value = margin(&placed, bot) - before;
+        let mut moves = vec![place(pos, rotation)];
+
+        if can_place_meeple {
+            let mut best_meeple: Option<(i64, MeeplePosition)> = None;
+            for position in MEEPLE_POSITIONS.iter().copied() {
+                let mut after = placed.clone();
+                if after.apply(bot, meeple(pos, position)).is_err() {
+                    continue;
+                }
+                // A meeple going back right away scored its feature, otherwise it may score later.
+                let kept = after.meeples_of(bot) > placed.meeples_of(bot);
+                let potential = match position {
+                    _ if !kept => 0,
+                    MeeplePosition::Center => 4,
+                    _ => 2,
+                };
+                let gain = margin(&after, bot) - margin(&placed, bot) + potential;
+                if best_meeple.map(|x| gain > x.0).unwrap_or(gain > 0) {
+                    best_meeple = Some((gain, position));
+                }
+            }
+            if let Some((gain, position)) = best_meeple {
+                value += gain;
+                moves.push(meeple(pos, position));
+            }
+        }
+
+        if best.as_ref().map(|x| value > x.0).unwrap_or(true) {
+            best = Some((value, moves));
+        }
+    }
+    best.map(|x| x.1).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::fixture::GameStateBuilder;
+
+    fn drawn_game(bot: IdType) -> GameState {
+        let mut game = GameStateBuilder::new().build();
+        game.start_draws(vec![bot, bot + 1], &mut rand::thread_rng());
+        game
+    }
+
+    #[test]
+    fn bots_play_legal_moves() {
+        for difficulty in [BotDifficulty::Easy, BotDifficulty::Normal].iter().copied() {
+            let mut game = drawn_game(1);
+            let moves = choose_moves(&game, 1, difficulty, &mut rand::thread_rng());
+            assert!(!moves.is_empty());
+            for mv in moves {
+                game.apply(1, mv).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn bots_wait_for_their_turn() {
+        let game = drawn_game(1);
+        assert!(choose_moves(&game, 2, BotDifficulty::Normal, &mut rand::thread_rng()).is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+//!
+//! The periodic sweep of the lobby, for what the normal flow misses. A room waiting in the
+//! matchmaking with no join, leave or state change for ROOM_IDLE_TTL_SECS (1 hour by default, 0
+//! disables it) is closed, its players go back to the matchmaking. The clients already drop the
+//! connections that stop answering the pings (see client_ws), the sweep also forgets the sessions
+//! and the rooms whose actor stopped without telling the lobby. The counts are on /stats/cleanup.
+//!
+
+use std::{env, sync::{atomic::{AtomicU64, Ordering}, OnceLock}, time::Duration};
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_ROOM_IDLE_TTL_SECS: u64 = 3600;
+
+static ROOM_IDLE_TTL: OnceLock<Option<Duration>> = OnceLock::new();
+static SWEEPS: AtomicU64 = AtomicU64::new(0);
+static IDLE_ROOMS: AtomicU64 = AtomicU64::new(0);
+static DEAD_ROOMS: AtomicU64 = AtomicU64::new(0);
+static DEAD_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// How long a room can wait without activity, none if they can wait forever.
+pub fn room_idle_ttl() -> Option<Duration> {
+    *ROOM_IDLE_TTL.get_or_init(|| {
+        let secs = env::var("ROOM_IDLE_TTL_SECS").ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_ROOM_IDLE_TTL_SECS);
+        Some(Duration::from_secs(secs)).filter(|x| *x > Duration::from_secs(0))
+    })
+}
+
+/// What a sweep removed.
+#[derive(Default)]
+pub struct Sweep {
+    pub idle_rooms: u64,
+    pub dead_rooms: u64,
+    pub dead_sessions: u64,
+}
+
+impl Sweep {
+    pub fn is_empty(&self) -> bool {
+        self.idle_rooms == 0 && self.dead_rooms == 0 && self.dead_sessions == 0
+    }
+
+    pub fn record(&self) {
+        SWEEPS.fetch_add(1, Ordering::Relaxed);
+        IDLE_ROOMS.fetch_add(self.idle_rooms, Ordering::Relaxed);
+        DEAD_ROOMS.fetch_add(self.dead_rooms, Ordering::Relaxed);
+        DEAD_SESSIONS.fetch_add(self.dead_sessions, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CleanupStats {
+    sweeps: u64,
+    idle_rooms: u64,
+    dead_rooms: u64,
+    dead_sessions: u64,
+}
+
+pub async fn stats() -> HttpResponse {
+    HttpResponse::Ok().json(CleanupStats {
+        sweeps: SWEEPS.load(Ordering::Relaxed),
+        idle_rooms: IDLE_ROOMS.load(Ordering::Relaxed),
+        dead_rooms: DEAD_ROOMS.load(Ordering::Relaxed),
+        dead_sessions: DEAD_SESSIONS.load(Ordering::Relaxed),
+    })
+}
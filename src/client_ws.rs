@@ -1,20 +1,31 @@
-use std::time::{Duration, Instant};
+use std::{collections::HashSet, mem, net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, Instant}};
 
 use actix::{Actor, Addr, AsyncContext, prelude::*, StreamHandler};
 use actix_web::{Error, HttpRequest, HttpResponse, web};
 use actix_web_actors::ws;
 use serde::Serialize;
+use tracing::{debug_span, info, trace, warn};
 
-use crate::protocol::{IdMessage, IdType, LoginResponse, NoData, OutEvent, OutGameMessage, OutMessage, ReceivedGameMessage, ReceivedMessage, Response, RoomCreateResponse, RoomJoinResponse, RoomFindResponse};
+use crate::clock;
+use crate::codec::{Codec, Frame};
+use crate::cosmetics::Inventory;
+use crate::game;
+use crate::handshake::{self, HandshakeConfig};
+use crate::heartbeat::{self, Backlog, Pressure};
+use crate::idempotency::{self, RecentRequests, Seen};
+use crate::listeners;
+use crate::lobby_actor::{self, FindRoomResult, PlayerRef, LobbyActor};
+use crate::network::NetworkStats;
+use crate::protocol::{Achievement, CatalogEntry, CosmeticsCatalogResponse, CursorPosition, EventClass, IdMessage, OutGameEvent, IdType, InviteId, LoginData, LoginResponse, NoData, OutEvent, OutGameMessage, OutMessage, PlayerObject, RateLimitScope, ReceivedGameMessage, ReceivedMessage, Response, ResumeResponse, ResumedState, RoomCodeResponse, RoomCreateResponse, RoomRedirectResponse, RoomJoinResponse, RoomFindResponse, ReplayResponse, RoomAddBotResponse, RoomSpectateResponse, RoomStartFailures, RoomSettings, TournamentResponse};
 use crate::protocol;
-use crate::server_actor::{self, Event, GameEvent, JoinRoomResult, FindRoomResult, SendRelayMexRaw, ServerActor};
+use crate::rate_limit::{self, TokenBucket};
+use crate::replay::REPLAY_CHUNK_SIZE;
+use crate::status_page;
+use crate::room_trace::{Direction, RoomTrace};
+use crate::room_actor::{self, Event, GameEvent, JoinRoomResult, Member, RoomActor, SendRelayMexRaw, SpectateResult};
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-
-const RELAY_QUEUE_MAX_SIZE: usize = 64usize;
+/// Cursor updates of a client are forwarded at most once per interval, the latest one wins.
+const CURSOR_MIN_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(PartialEq, Eq)]
 pub enum ClientState {
@@ -22,39 +33,132 @@ pub enum ClientState {
     MatchMaking,// Join or Create room (can also re-login to change name)
     Lobby,// You're in a room, prepare for battle (can also change cosmetics).
     PrePlaying(u64),// The game is started but the client hasn't acknowledged it yet.
-    Playing// Playing.
+    Playing,// Playing.
+    Spectating,// Watching a game, uses the in-game protocol but can't send relay messages.
 }
 
 pub struct ClientWs {
     state: ClientState,
     last_hb: Instant,
+    network: NetworkStats,
+    backlog: Backlog,
     session_id: IdType,
+    resume_token: String,
+    account_id: IdType,// See accounts.rs
+    clean_close: bool,// The client said goodbye, its session won't be kept for a reconnection
     next_send_id: u64,
-    db: Addr<ServerActor>,
-    relay_queue: Vec<server_actor::SendRelayMexRaw>,
+    details: LoginData,
+    unsubscribed: HashSet<EventClass>,
+    lobby: Addr<LobbyActor>,
+    room: Option<Addr<RoomActor>>,// Set while the client is in a room
+    cursor_sent_at: Option<Instant>,
+    cursor_pending: Option<CursorPosition>,// Waiting for the end of the interval
+    relay_bucket: Option<TokenBucket>,
+    relay_dropped: u32,// Relay messages dropped in a row by the rate limit
+    codec: Codec,// Format of the messages of the server, negotiated in the handshake
+    local_addr: SocketAddr,// Of the listener the client connected to
+    peer_ip: Option<IpAddr>,// None on a unix socket
+    requests: RecentRequests,// Keys of the last commands, see idempotency.rs
+    trace: Option<(Addr<RoomActor>, Arc<RoomTrace>)>,// Of the room traced by an admin, see room_trace.rs
+    inventory: Inventory,// Copy of the one of the account, see cosmetics.rs
 }
 
 impl ClientWs {
-    pub fn new(db: Addr<ServerActor>) -> Self {
+    pub fn new(lobby: Addr<LobbyActor>, codec: Codec, local_addr: SocketAddr, peer_ip: Option<IpAddr>) -> Self {
         ClientWs {
             state: ClientState::PreLogin,
             last_hb: Instant::now(),
+            network: NetworkStats::default(),
+            backlog: Backlog::default(),
             session_id: 0,
+            resume_token: String::new(),
+            account_id: 0,
+            clean_close: false,
             next_send_id: 0,
-            db,
-            relay_queue: Vec::new(),
+            details: LoginData::default(),
+            unsubscribed: HashSet::new(),
+            lobby,
+            room: None,
+            cursor_sent_at: None,
+            cursor_pending: None,
+            relay_bucket: rate_limit::connection_bucket(),
+            relay_dropped: 0,
+            codec,
+            local_addr,
+            peer_ip,
+            requests: RecentRequests::default(),
+            trace: None,
+            inventory: Inventory::default(),
+        }
+    }
+
+    fn member(&self, ctx: &mut ws::WebsocketContext<Self>) -> Member {
+        Member {
+            id: self.session_id,
+            addr: ctx.address(),
+            details: self.details.clone(),
+            unsubscribed: self.unsubscribed.clone(),
+        }
+    }
+
+    /// Throttles the cursor updates, an update that comes too early is sent at the end of the interval.
+    fn send_cursor(&mut self, ctx: &mut ws::WebsocketContext<Self>, position: CursorPosition) {
+        let scheduled = self.cursor_pending.is_some();
+        self.cursor_pending = Some(position);
+        if scheduled {
+            return;
+        }
+
+        let elapsed = self.cursor_sent_at.map(|x| x.elapsed()).unwrap_or(CURSOR_MIN_INTERVAL);
+        if elapsed >= CURSOR_MIN_INTERVAL {
+            self.flush_cursor();
+        } else {
+            ctx.run_later(CURSOR_MIN_INTERVAL - elapsed, |act, _| act.flush_cursor());
+        }
+    }
+
+    fn flush_cursor(&mut self) {
+        let position = match self.cursor_pending.take() {
+            Some(x) => x,
+            None => return,
+        };
+        if let (Some(room), ClientState::Playing) = (&self.room, &self.state) {
+            room.do_send(room_actor::SendCursor {
+                sender_id: self.session_id,
+                position,
+            });
+        }
+        self.cursor_sent_at = Some(Instant::now());
+    }
+
+    /// Writes a message to the trace of the room the client is in, if an admin traces it.
+    fn trace(&mut self, direction: Direction, data: impl FnOnce() -> String) {
+        match &self.trace {
+            Some((_, trace)) if !trace.active() => self.trace = None,
+            Some((room, trace)) if self.room.as_ref() == Some(room) => trace.record(direction, self.session_id, &data()),
+            _ => {},
         }
     }
 
-    /// helper method that sends ping to client every second.
+    fn leave_room(&mut self) {
+        if let Some(room) = self.room.take() {
+            room.do_send(room_actor::Leave {
+                id: self.session_id,
+            });
+        }
+    }
+
+    /// helper method that sends ping to client every heartbeat interval.
     ///
-    /// also this method checks heartbeats from client
+    /// also this method checks heartbeats and the backlog of the client, see heartbeat.rs
     fn start_heartbeat_checker(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        let config = heartbeat::config();
+        ctx.run_interval(config.interval, move |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.last_hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.last_hb) > config.timeout {
                 // heartbeat timed out
-                println!("Websocket Client heartbeat failed, disconnecting!");
+                info!(player = act.session_id, "Websocket client heartbeat failed, disconnecting");
+                heartbeat::timed_out();
 
                 // stop actor
                 ctx.stop();
@@ -63,79 +167,184 @@ impl ClientWs {
                 return;
             }
 
-            ctx.ping(b"");
+            if act.backlog.check(config) == Pressure::TooSlow {
+                warn!(player = act.session_id, backlog = act.backlog.bytes(), "Websocket client too slow, disconnecting");
+                heartbeat::slow_disconnect();
+                // It would fall behind again, it leaves the room instead of keeping its session.
+                act.clean_close = true;
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("too_slow".to_string()),
+                }));
+                ctx.stop();
+                return;
+            }
+
+            // The room shows the connection quality to the players before the game starts.
+            if act.state == ClientState::Lobby {
+                if let Some(room) = &act.room {
+                    room.do_send(room_actor::UpdateNetworkQuality {
+                        id: act.session_id,
+                        quality: act.network.quality(),
+                    });
+                }
+            }
+
+            let payload = act.network.ping_sent(Instant::now());
+            act.backlog.ping_sent(payload);
+            ctx.ping(&payload);
         });
     }
+
+    /// Sends the hello handshake with the features enabled on this deployment.
+    fn send_hello(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.lobby.send(lobby_actor::GetHello)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(hello) => {
+                        act.send_message(ctx, &hello);
+                    },
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
 }
 
 impl Actor for ClientWs {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.start_heartbeat_checker(ctx)
+        handshake::connection_opened(self.local_addr);
+        self.start_heartbeat_checker(ctx);
+        self.send_hello(ctx);
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        match self.state {
-            ClientState::PreLogin => {},
-            _ => {
-                self.db.do_send(server_actor::Disconnect {
-                    id: self.session_id,
-                });
-            },
+        if self.state == ClientState::PreLogin {
+            return Running::Stop;
         }
+        // Unless the client closed the connection the room is kept so the session can be resumed.
+        let snapshot = if self.clean_close {
+            self.leave_room();
+            None
+        } else {
+            Some(lobby_actor::SessionSnapshot {
+                details: self.details.clone(),
+                unsubscribed: self.unsubscribed.clone(),
+                room: self.room.take(),
+                requests: mem::take(&mut self.requests).suspend(),
+            })
+        };
+        self.lobby.do_send(lobby_actor::Disconnect {
+            id: self.session_id,
+            snapshot,
+        });
         Running::Stop
     }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        handshake::connection_closed(self.local_addr);
+    }
 }
 
 impl ClientWs {
     pub fn allocate_id(&mut self) -> u64 {
         let id = self.next_send_id;
         self.next_send_id += 1;
-        id.into()
+        id
     }
 
     pub fn send_message<T: ?Sized + Serialize> (&mut self, ctx: &mut <Self as Actor>::Context, inner: &T) -> u64 {
+        // The response of a command with a key carries it and is kept for its retries.
+        if self.requests.waiting(Instant::now()) {
+            if let Ok(mut value) = serde_json::to_value(inner) {
+                if self.requests.answer(&mut value) {
+                    return self.send_encoded(ctx, &value);
+                }
+            }
+        }
+        self.send_encoded(ctx, inner)
+    }
+
+    fn send_encoded<T: ?Sized + Serialize> (&mut self, ctx: &mut <Self as Actor>::Context, inner: &T) -> u64 {
         let id = self.allocate_id();
         let mex = OutMessage {
             id, mex: inner
         };
+        self.trace(Direction::Out, || serde_json::to_string(&mex).unwrap_or_default());
 
-        let mut writer = Vec::with_capacity(128);
-        if self.state == ClientState::Playing {
-            // Special message
-            writer.push(b'#');
+        let special = self.state == ClientState::Playing || self.state == ClientState::Spectating;
+        match self.codec.encode(&mex, special) {
+            Frame::Text(x) => {
+                self.backlog.written(x.len());
+                ctx.text(x)
+            },
+            Frame::Binary(x) => {
+                self.backlog.written(x.len());
+                ctx.binary(x)
+            },
         }
-        serde_json::to_writer(&mut writer, &mex).expect("Error serializing message");
-
-        let text = unsafe {// It's safe? well it copies serde's method so I hope it is.
-            String::from_utf8_unchecked(writer)
-        };
-        ctx.text(text);
         id
     }
 
     pub fn handle_message_login(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
-        if let ReceivedMessage::Login { details } = mex {
-            self.db.send(server_actor::RegisterSession {
-                id: None,
+        if let ReceivedMessage::Resume { token } = mex {
+            self.resume_session(ctx, id, token);
+        } else if let ReceivedMessage::Login { mut details } = mex {
+            let mut languages: Vec<String> = Vec::new();
+            for tag in details.languages.iter().filter_map(|x| protocol::normalize_language(x)) {
+                if !languages.contains(&tag) && languages.len() < protocol::MAX_LANGUAGES {
+                    languages.push(tag);
+                }
+            }
+            details.languages = languages;
+            // Only the lobby keeps it.
+            let account_token = details.account_token.take();
+            // A new account owns nothing, no need to create it, the lobby checks the others.
+            if account_token.is_none() && !Inventory::default().owns(&details.cosmetics) {
+                self.send_message(ctx, &Response::from(id, "login_response".into(), Some("cosmetics_not_owned".into()), NoData {}));
+                return;
+            }
+            self.details = details;
+            let register = lobby_actor::RegisterSession {
                 addr: ctx.address(),
-                obj: details,
-            })
+                username: self.details.username.clone(),
+                privacy: self.details.privacy.clone(),
+                cosmetics: self.details.cosmetics.clone(),
+                ip: self.peer_ip,
+                account_token,
+            };
+            self.lobby.send(register)
                 .into_actor(self)
                 .then(move |res, act, ctx| {
-                    match res {
-                        Ok(res) => act.session_id = res,
+                    let account_token = match res {
+                        Ok(Ok(res)) => {
+                            act.session_id = res.id;
+                            act.resume_token = res.token;
+                            act.account_id = res.account;
+                            act.inventory = res.inventory;
+                            res.account_token
+                        },
+                        Ok(Err(e)) => {
+                            act.send_message(ctx, &Response::from(id, "login_response".into(), Some(e.into()), NoData {}));
+                            return fut::ready(());
+                        },
                         _ => {
                             // something is wrong with chat server
                             ctx.stop();
                             return fut::ready(());
                         },
-                    }
+                    };
                     let res = Response::ok(
                         id, "login_response".into(),
                         LoginResponse {
                             player_id: act.session_id.into(),
+                            resume_token: act.resume_token.clone(),
+                            account_id: act.account_id.into(),
+                            account_token,
                         }
                     );
                     act.state = ClientState::MatchMaking;
@@ -148,186 +357,827 @@ impl ClientWs {
         }
     }
 
+    fn resume_session(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, token: String) {
+        self.lobby.send(lobby_actor::ResumeSession {
+            token: token.clone(),
+            addr: ctx.address(),
+        })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let resumed = match res {
+                    Ok(Some(x)) => x,
+                    Ok(None) => {
+                        let pkt = Response::from(
+                            id, "resume_response".into(), Some("invalid_token".into()), NoData {}
+                        );
+                        act.send_message(ctx, &pkt);
+                        return fut::Either::Right(fut::ready(()));
+                    },
+                    _ => {
+                        ctx.stop();
+                        return fut::Either::Right(fut::ready(()));
+                    },
+                };
+                act.session_id = resumed.id;
+                act.resume_token = token;
+                act.details = resumed.snapshot.details;
+                act.unsubscribed = resumed.snapshot.unsubscribed;
+                act.requests = resumed.snapshot.requests;
+                act.inventory = resumed.inventory;
+                act.state = ClientState::MatchMaking;
+
+                let room = match resumed.snapshot.room {
+                    Some(x) => x,
+                    None => {
+                        act.send_resume_response(ctx, id, ResumedState::MatchMaking, None);
+                        return fut::Either::Right(fut::ready(()));
+                    },
+                };
+
+                let rejoin = room.send(room_actor::Rejoin {
+                    id: act.session_id,
+                    addr: ctx.address(),
+                });
+                fut::Either::Left(rejoin.into_actor(act).map(move |res, act, ctx| {
+                    match res {
+                        Ok(Some(res)) => {
+                            act.room = Some(room);
+                            let state = if res.in_game { ResumedState::Playing } else { ResumedState::Lobby };
+                            act.send_resume_response(ctx, id, state, Some(res));
+                        },
+                        // The room has been closed or started without the player.
+                        _ => act.send_resume_response(ctx, id, ResumedState::MatchMaking, None),
+                    }
+                }))
+            })
+            .wait(ctx);
+    }
+
+    /// Asks the room for the relay packets sent since the start of the game. The client is kicked out
+    /// if it took so long to acknowledge the start that some of them are lost, it can still resume.
+    fn relay_ready(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let room = match &self.room {
+            Some(x) => x,
+            None => return,
+        };
+        room.send(room_actor::RelayReady { id: self.session_id })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                if let Ok(lost) = res {
+                    if lost > 0 {
+                        warn!(player = act.session_id, lost, "Client too slow to acknowledge event_room_start, kicking out");
+                        ctx.stop();
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn send_resume_response(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, state: ResumedState, room: Option<room_actor::RejoinResult>) {
+        let (players, board, relay_lost) = match room {
+            Some(x) => (Some(x.players), x.board, x.relay_lost),
+            None => (None, None, 0),
+        };
+        let res = Response::ok(
+            id, "resume_response".into(),
+            ResumeResponse {
+                player_id: self.session_id.into(),
+                state,
+                players,
+                board,
+                relay_lost,
+            }
+        );
+        self.send_message(ctx, &res);
+        self.state = match state {
+            ResumedState::MatchMaking => ClientState::MatchMaking,
+            ResumedState::Lobby => ClientState::Lobby,
+            ResumedState::Playing => ClientState::Playing,
+        };
+    }
+
+    pub fn handle_message_subscriptions(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        let (mut events, subscribe, ptype) = match mex {
+            ReceivedMessage::Subscribe { events } => (events, true, "subscribe_response"),
+            ReceivedMessage::Unsubscribe { events } => (events, false, "unsubscribe_response"),
+            _ => return,
+        };
+        events.retain(|x| *x != EventClass::Unknown);
+        for class in events.iter() {
+            if subscribe {
+                self.unsubscribed.remove(class);
+            } else {
+                self.unsubscribed.insert(*class);
+            }
+        }
+        if let Some(room) = &self.room {
+            room.do_send(room_actor::EditSubscriptions {
+                id: self.session_id,
+                events,
+                subscribe,
+            });
+        }
+        self.send_message(ctx, &Response::ok(id, ptype.into(), NoData {}));
+    }
+
+    fn send_match_history(&mut self, ctx: &mut <Self as Actor>::Context, id: u64) {
+        self.lobby.send(lobby_actor::GetMatchHistory { id: self.session_id })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(res) => {
+                        act.send_message(ctx, &Response::ok(id, "match_history_response".into(), res));
+                    },
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn send_player_stats(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, player: PlayerRef) {
+        self.lobby.send(lobby_actor::GetPlayerStats { player, requester: Some(self.session_id) })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Some(res)) => {
+                        act.send_message(ctx, &Response::ok(id, "player_stats_response".into(), res));
+                    },
+                    Ok(None) => {
+                        act.send_message(ctx, &Response::from(id, "player_stats_response".into(), Some("player_not_found".into()), NoData {}));
+                    },
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Answers with the description of the replay then streams its events.
+    fn send_replay(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, replay_id: IdType) {
+        self.lobby.send(lobby_actor::GetReplay { replay_id })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let replay = match res {
+                    Ok(Some(x)) => x,
+                    Ok(None) => {
+                        act.send_message(ctx, &Response::from(id, "replay_response".into(), Some("replay_not_found".into()), NoData {}));
+                        return fut::ready(());
+                    },
+                    _ => {
+                        ctx.stop();
+                        return fut::ready(());
+                    },
+                };
+                act.send_message(ctx, &Response::ok(id, "replay_response".into(), ReplayResponse {
+                    room_id: replay.room_id.into(),
+                    players: replay.players.clone(),
+                    duration_ms: replay.duration_ms,
+                    event_count: replay.events.len(),
+                }));
+                let chunks = replay.events.len().div_ceil(REPLAY_CHUNK_SIZE);
+                for (i, events) in replay.chunks().enumerate() {
+                    act.send_message(ctx, &OutEvent::EventReplayChunk {
+                        replay_id: replay_id.into(),
+                        events: events.to_vec(),
+                        done: i + 1 == chunks,
+                    });
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// The friends and the invites, available both in the matchmaking and in a room.
+    pub fn handle_message_friends(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        match mex {
+            ReceivedMessage::FriendAdd { player_id, account_id } => {
+                let friend = match PlayerRef::new(player_id, account_id) {
+                    Some(x) => x,
+                    None => {
+                        self.send_message(ctx, &Response::from(id, "friend_add_response".into(), Some("player_not_found".into()), NoData {}));
+                        return;
+                    },
+                };
+                self.lobby.send(lobby_actor::AddFriend { id: self.session_id, friend })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.send_message(ctx, &Response::ok(id, "friend_add_response".into(), NoData {}));
+                            },
+                            Ok(Err(e)) => {
+                                act.send_message(ctx, &Response::from(id, "friend_add_response".into(), Some(e.into()), NoData {}));
+                            },
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::FriendRemove { player_id, account_id } => {
+                if let Some(friend) = PlayerRef::new(player_id, account_id) {
+                    self.lobby.do_send(lobby_actor::RemoveFriend { id: self.session_id, friend });
+                }
+                self.send_message(ctx, &Response::ok(id, "friend_remove_response".into(), NoData {}));
+            },
+            ReceivedMessage::FriendList {} => {
+                self.lobby.send(lobby_actor::GetFriends { id: self.session_id })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(res) => {
+                                act.send_message(ctx, &Response::ok(id, "friend_list_response".into(), res));
+                            },
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomInviteDecline { room_id } => {
+                self.lobby.do_send(lobby_actor::DeclineInvite { id: self.session_id, room_id: room_id.0 });
+                self.send_message(ctx, &Response::ok(id, "room_invite_decline_response".into(), NoData {}));
+            },
+            _ => {},
+        }
+    }
+
+    /// The tournaments, available both in the matchmaking and in a room, see tournament.rs.
+    pub fn handle_message_tournaments(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        match mex {
+            ReceivedMessage::TournamentCreate { name, max_players } => {
+                let create = self.lobby.send(lobby_actor::CreateTournament { member: self.member(ctx), name, max_players });
+                self.send_tournament_result(ctx, id, "tournament_create_response", create);
+            },
+            ReceivedMessage::TournamentRegister { tournament_id } => {
+                let register = self.lobby.send(lobby_actor::RegisterTournament { member: self.member(ctx), tournament_id: tournament_id.0 });
+                self.send_tournament_result(ctx, id, "tournament_register_response", register);
+            },
+            ReceivedMessage::TournamentLeave { tournament_id } => {
+                let leave = self.lobby.send(lobby_actor::LeaveTournament { id: self.session_id, tournament_id: tournament_id.0 });
+                self.send_tournament_result(ctx, id, "tournament_leave_response", leave);
+            },
+            ReceivedMessage::TournamentStart { tournament_id } => {
+                let start = self.lobby.send(lobby_actor::StartTournament { id: self.session_id, tournament_id: tournament_id.0 });
+                self.send_tournament_result(ctx, id, "tournament_start_response", start);
+            },
+            _ => {},
+        }
+    }
+
+    /// Answers a tournament request once the lobby replied, with the tournament as it is now.
+    fn send_tournament_result<M>(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, ptype: &'static str, req: Request<LobbyActor, M>)
+        where M: Message<Result = Result<TournamentResponse, &'static str>> + Send + 'static, LobbyActor: Handler<M> {
+        req.into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(res)) => {
+                        act.send_message(ctx, &Response::ok(id, ptype.into(), res));
+                    },
+                    Ok(Err(e)) => {
+                        act.send_message(ctx, &Response::from(id, ptype.into(), Some(e.into()), NoData {}));
+                    },
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn join_room(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, ptype: &'static str, invite: InviteId, password: Option<String>, ticket: Option<String>) {
+        self.lobby.send(lobby_actor::JoinRoom {
+            member: self.member(ctx),
+            invite,
+            password,
+            ticket,
+        })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(res)) => act.send_join_result(ctx, id, ptype, res),
+                    _ => {
+                        // something is wrong with chat server
+                        ctx.stop();
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Shows the pinned message and the auto-start countdown of the room just joined, after the join response.
+    fn send_room_status(&mut self, ctx: &mut <Self as Actor>::Context, pinned: Option<String>, countdown: Option<u32>) {
+        if pinned.is_some() {
+            self.send_message(ctx, &OutEvent::EventRoomPinnedMessage { message: pinned });
+        }
+        if countdown.is_some() {
+            self.send_message(ctx, &OutEvent::EventRoomStartCountdown { seconds: countdown, canceled_by: None });
+        }
+    }
+
+    fn send_join_result(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, ptype: &'static str, res: JoinRoomResult) {
+        let ptype = ptype.into();
+        match res {
+            JoinRoomResult::Success { room, players, settings, pinned, countdown } => {
+                let pkt = Response::ok(
+                    id, ptype,
+                    RoomJoinResponse { players, settings }
+                );
+                self.send_message(ctx, &pkt);
+                self.send_room_status(ctx, pinned, countdown);
+                self.state = ClientState::Lobby;
+                self.room = Some(room);
+            }
+            JoinRoomResult::RoomNotFound => {
+                let pkt = Response::from(
+                    id, ptype, Some("room_not_found".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::Redirect { url, room_id } => {
+                // The client has to join the room on the other instance.
+                let pkt = Response::from(
+                    id, ptype, Some("redirect".into()),
+                    RoomRedirectResponse { url, room_id: room_id.into() }
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::AlreadyPlaying => {
+                let pkt = Response::from(
+                    id, ptype, Some("already_playing".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::RoomIsFull => {
+                let pkt = Response::from(
+                    id, ptype, Some("room_is_full".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::WrongPassword => {
+                let pkt = Response::from(
+                    id, ptype, Some("wrong_password".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::RoomLocked => {
+                let pkt = Response::from(
+                    id, ptype, Some("room_locked".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::Banned => {
+                let pkt = Response::from(
+                    id, ptype, Some("banned".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            },
+            JoinRoomResult::InvalidTicket => {
+                let pkt = Response::from(
+                    id, ptype, Some("invalid_ticket".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            }
+        }
+    }
+
     pub fn handle_message_matchmaking(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
         match mex {
             ReceivedMessage::Login { details } => {
-                self.db.send(server_actor::RegisterSession {
-                    id: Some(self.session_id),
-                    addr: ctx.address(),
-                    obj: details,
+                if !self.inventory.owns(&details.cosmetics) {
+                    self.send_message(ctx, &Response::from(id, "login_response".into(), Some("cosmetics_not_owned".into()), NoData {}));
+                    return;
+                }
+                self.details = details;
+                self.lobby.do_send(lobby_actor::UpdatePrivacy { id: self.session_id, privacy: self.details.privacy.clone() });
+                let res = Response::ok(
+                    id, "login_response".into(),
+                    LoginResponse {
+                        player_id: self.session_id.into(),
+                        resume_token: self.resume_token.clone(),
+                        account_id: self.account_id.into(),
+                        account_token: None,
+                    }
+                );
+                self.send_message(ctx, &res);
+            },
+            ReceivedMessage::RoomFind { region, queue: true } => {
+                self.lobby.send(lobby_actor::JoinQueue {
+                    member: self.member(ctx),
+                    region,
+                    request_id: id,
                 })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
                         match res {
-                            Ok(_) => {},
+                            // Answered once matched, see QueueResult.
+                            Ok(None) => {},
+                            Ok(Some(res)) => act.send_find_result(ctx, id, res),
+                            Err(_) => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            },
+            ReceivedMessage::RoomFind { region, queue: false } => {
+                self.lobby.send(lobby_actor::FindRoom {
+                    member: self.member(ctx),
+                    region,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(res)) => act.send_find_result(ctx, id, res),
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            },
+            ReceivedMessage::QueueLeave {} => {
+                self.lobby.send(lobby_actor::LeaveQueue { id: self.session_id })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let pkt = match res {
+                            Ok(true) => Response::ok(id, "queue_leave_response".into(), NoData {}),
+                            Ok(false) => Response::from(id, "queue_leave_response".into(), Some("not_queued".into()), NoData {}),
+                            Err(_) => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            },
+            ReceivedMessage::RoomList { page } => {
+                self.lobby.send(lobby_actor::ListRooms { page })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(res) => {
+                                act.send_message(ctx, &Response::ok(id, "room_list_response".into(), res));
+                            },
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomCreate { options } => {
+                let streamer_mode = options.streamer_mode;
+                self.lobby.send(lobby_actor::CreateRoom {
+                    member: self.member(ctx),
+                    options,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let res = match res {
+                            Ok(Ok(res)) => res,
+                            Ok(Err(error)) => {
+                                let pkt = Response::from(id, "room_create_response".into(), Some(error.into()), NoData {});
+                                act.send_message(ctx, &pkt);
+                                return fut::ready(());
+                            },
                             _ => {
                                 // something is wrong with chat server
                                 ctx.stop();
                                 return fut::ready(());
                             },
-                        }
-                        let res = Response::ok(
-                            id, "login_response".into(),
-                            LoginResponse {
-                                player_id: act.session_id.into(),
+                        };
+                        let pkt = Response::ok(
+                            id, "room_create_response".into(),
+                            RoomCreateResponse {
+                                players: [res.player],
+                                // Streamer mode rooms only reveal the code with room_code.
+                                invite_id: if streamer_mode { None } else { Some(res.room_id.into()) },
+                                code: if streamer_mode { None } else { Some(res.code) },
                             }
                         );
-                        act.send_message(ctx, &res);
+                        act.send_message(ctx, &pkt);
+                        act.state = ClientState::Lobby;
+                        act.room = Some(res.room);
 
+                        fut::ready(())
+                    }).wait(ctx);
+            },
+            ReceivedMessage::RoomJoin { invite_id, password, ticket } => {
+                self.join_room(ctx, id, "room_join_response", invite_id, password, ticket);
+            },
+            ReceivedMessage::RoomInviteAccept { room_id } => {
+                self.lobby.send(lobby_actor::AcceptInvite {
+                    member: self.member(ctx),
+                    room_id: room_id.0,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(res)) => act.send_join_result(ctx, id, "room_invite_accept_response", res),
+                            _ => ctx.stop(),
+                        }
                         fut::ready(())
                     })
                     .wait(ctx);
             },
-            ReceivedMessage::RoomFind {} => {
-                self.db.send(server_actor::FindRoom {
-                    id: self.session_id
+            ReceivedMessage::RoomSpectate { invite_id, password } => {
+                self.lobby.send(lobby_actor::SpectateRoom {
+                    member: self.member(ctx),
+                    invite: invite_id,
+                    password,
                 })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
                         let res = match res {
-                            Ok(res) => res,
+                            Ok(Ok(res)) => res,
                             _ => {
                                 ctx.stop();
                                 return fut::ready(());
                             }
                         };
 
-                        let pkt_type = "room_find_response".into();
-                        match res {
-                            FindRoomResult::Success { players, room_id, just_created } => {
+                        let ptype = "room_spectate_response".into();
+                        let error = match res {
+                            SpectateResult::Success { room, players, spectators, board } => {
                                 let pkt = Response::ok(
-                                    id, pkt_type,
-                                    RoomFindResponse {
-                                        players,
-                                        room_id: room_id.into(),
-                                        just_created
-                                    }
+                                    id, ptype,
+                                    RoomSpectateResponse { players, spectators, board }
                                 );
                                 act.send_message(ctx, &pkt);
-                                act.state = ClientState::Lobby;
+                                act.state = ClientState::Spectating;
+                                act.room = Some(room);
+                                return fut::ready(());
                             },
-                            FindRoomResult::GameIsFull => {
-                                // TODO
-                                let pkt = Response::from(
-                                    id, pkt_type,
-                                    Some("game_is_full".into()), NoData {}
-                                );
-                                act.send_message(ctx, &pkt);
-                            }
-                        }
+                            SpectateResult::RoomNotFound => "room_not_found",
+                            SpectateResult::NotPlaying => "not_playing",
+                            SpectateResult::WrongPassword => "wrong_password",
+                            SpectateResult::RoomLocked => "room_locked",
+                            SpectateResult::Banned => "banned",
+                        };
+                        act.send_message(ctx, &Response::from(id, ptype, Some(error.into()), NoData {}));
                         fut::ready(())
                     })
-                    .wait(ctx)
+                    .wait(ctx);
             },
-            ReceivedMessage::RoomCreate {} => {
-                self.db.send(server_actor::CreateRoom {
-                    id: self.session_id
+            ReceivedMessage::Subscribe { .. } | ReceivedMessage::Unsubscribe { .. } => {
+                self.handle_message_subscriptions(ctx, id, mex);
+            },
+            ReceivedMessage::MatchHistory {} => {
+                self.send_match_history(ctx, id);
+            },
+            ReceivedMessage::PlayerStats { player_id, account_id } => {
+                let player = PlayerRef::new(player_id, account_id).unwrap_or(PlayerRef::Player(self.session_id));
+                self.send_player_stats(ctx, id, player);
+            },
+            ReceivedMessage::CosmeticsCatalog {} => {
+                let res = CosmeticsCatalogResponse { catalog: self.inventory.catalog() };
+                self.send_message(ctx, &Response::ok(id, "cosmetics_catalog_response".into(), res));
+            },
+            ReceivedMessage::FriendAdd { .. } | ReceivedMessage::FriendRemove { .. } |
+            ReceivedMessage::FriendList {} | ReceivedMessage::RoomInviteDecline { .. } => {
+                self.handle_message_friends(ctx, id, mex);
+            },
+            ReceivedMessage::TournamentCreate { .. } | ReceivedMessage::TournamentRegister { .. } |
+            ReceivedMessage::TournamentLeave { .. } | ReceivedMessage::TournamentStart { .. } => {
+                self.handle_message_tournaments(ctx, id, mex);
+            },
+            ReceivedMessage::ReplayGet { replay_id } => {
+                self.send_replay(ctx, id, replay_id.0);
+            },
+            _ => {
+                self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid message type".into(), None));
+            }
+        }
+    }
+
+    pub fn handle_message_lobby(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        let room = match &self.room {
+            Some(x) => x.clone(),
+            None => return,
+        };
+
+        match mex {
+            ReceivedMessage::ChangeAvatar { cosmetics } => {
+                if !self.inventory.owns(&cosmetics) {
+                    self.send_message(ctx, &protocol::Error::from_origin(id, "Cosmetics not owned".into(), None));
+                    return;
+                }
+                self.details.cosmetics = cosmetics.clone();
+                room.do_send(room_actor::EditCosmetics {
+                    id: self.session_id,
+                    obj: cosmetics,
+                })
+            },
+            ReceivedMessage::RoomLeave {} => {
+                self.leave_room();
+                self.state = ClientState::MatchMaking;
+                self.send_message(ctx, &Response::ok(id, "room_leave_response".into(), NoData {}));
+            },
+            ReceivedMessage::RoomLock { locked } => {
+                room.send(room_actor::SetLocked {
+                    id: self.session_id,
+                    locked,
                 })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
-                        let res = match res {
-                            Ok(res) => res,
+                        let pkt = match res {
+                            Ok(true) => Response::ok(id, "room_lock_response".into(), NoData {}),
+                            Ok(false) => Response::from(id, "room_lock_response".into(), Some("not_host".into()), NoData {}),
                             _ => {
-                                // something is wrong with chat server
                                 ctx.stop();
                                 return fut::ready(());
                             },
                         };
-                        let pkt = Response::ok(
-                            id, "room_create_response".into(),
-                            RoomCreateResponse {
-                                players: [res.player],
-                                invite_id: res.room_id.into(),
-                            }
-                        );
                         act.send_message(ctx, &pkt);
-                        act.state = ClientState::Lobby;
-
                         fut::ready(())
-                    }).wait(ctx);
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomSettings { settings } => {
+                room.send(room_actor::UpdateRoomSettings {
+                    id: self.session_id,
+                    settings,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let pkt = match res {
+                            Ok(Ok(())) => Response::ok(id, "room_settings_response".into(), NoData {}),
+                            Ok(Err(e)) => Response::from(id, "room_settings_response".into(), Some(e.into()), NoData {}),
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomTeam { team } => {
+                room.send(room_actor::ChangeTeam {
+                    id: self.session_id,
+                    team,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let pkt = match res {
+                            Ok(Ok(())) => Response::ok(id, "room_team_response".into(), NoData {}),
+                            Ok(Err(e)) => Response::from(id, "room_team_response".into(), Some(e.into()), NoData {}),
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomInvite { player_id } => {
+                self.lobby.send(lobby_actor::InviteToRoom { from: self.session_id, to: player_id.0 })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.send_message(ctx, &Response::ok(id, "room_invite_response".into(), NoData {}));
+                            },
+                            Ok(Err(e)) => {
+                                act.send_message(ctx, &Response::from(id, "room_invite_response".into(), Some(e.into()), NoData {}));
+                            },
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomAddBot { difficulty } => {
+                room.send(room_actor::AddBot {
+                    id: self.session_id,
+                    difficulty,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(player)) => act.send_message(ctx, &Response::ok(id, "room_add_bot_response".into(), RoomAddBotResponse { player })),
+                            Ok(Err(e)) => act.send_message(ctx, &Response::from(id, "room_add_bot_response".into(), Some(e.into()), NoData {})),
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        fut::ready(())
+                    })
+                    .wait(ctx);
             },
-            ReceivedMessage::RoomJoin { invite_id } => {
-                self.db.send(server_actor::JoinRoom {
+            ReceivedMessage::RoomPin { message } => {
+                room.send(room_actor::PinMessage {
                     id: self.session_id,
-                    room_id: invite_id.into(),
+                    message,
                 })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
-                        let res = match res {
-                            Ok(res) => res,
+                        let pkt = match res {
+                            Ok(Ok(())) => Response::ok(id, "room_pin_response".into(), NoData {}),
+                            Ok(Err(e)) => Response::from(id, "room_pin_response".into(), Some(e.into()), NoData {}),
                             _ => {
-                                // something is wrong with chat server
                                 ctx.stop();
                                 return fut::ready(());
                             },
                         };
-                        let ptype = "room_join_response".into();
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomKick { player_id } => {
+                let kick = room.send(room_actor::KickPlayer { id: self.session_id, target: player_id.into() });
+                self.send_host_action_result(ctx, id, "room_kick_response", kick);
+            },
+            ReceivedMessage::RoomBan { player_id } => {
+                let ban = room.send(room_actor::BanPlayer { id: self.session_id, target: player_id.into() });
+                self.send_host_action_result(ctx, id, "room_ban_response", ban);
+            },
+            ReceivedMessage::RoomTransferHost { player_id } => {
+                let transfer = room.send(room_actor::TransferHost { id: self.session_id, target: player_id.into() });
+                self.send_host_action_result(ctx, id, "room_transfer_host_response", transfer);
+            },
+            ReceivedMessage::RoomCode {} => {
+                room.send(room_actor::GetRoomCode)
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
                         match res {
-                            JoinRoomResult::Success(players) => {
+                            Ok(room) => {
                                 let pkt = Response::ok(
-                                    id, ptype,
-                                    RoomJoinResponse { players }
-                                );
-                                act.send_message(ctx, &pkt);
-                                act.state = ClientState::Lobby;
-                            }
-                            JoinRoomResult::RoomNotFound => {
-                                let pkt = Response::from(
-                                    id, ptype, Some("room_not_found".into()), NoData {}
+                                    id, "room_code_response".into(),
+                                    RoomCodeResponse { invite_id: room.id.into(), code: room.code }
                                 );
                                 act.send_message(ctx, &pkt);
                             },
-                            JoinRoomResult::AlreadyPlaying => {
-                                let pkt = Response::from(
-                                    id, ptype, Some("already_playing".into()), NoData {}
-                                );
-                                act.send_message(ctx, &pkt);
-                            },
-                            JoinRoomResult::RoomIsFull => {
-                                let pkt = Response::from(
-                                    id, ptype, Some("room_is_full".into()), NoData {}
-                                );
-                                act.send_message(ctx, &pkt);
-                            }
+                            _ => ctx.stop(),
                         }
                         fut::ready(())
                     })
                     .wait(ctx);
             },
-            _ => {
-                self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid message type".into(), None));
-            }
-        }
-    }
-
-    pub fn handle_message_lobby(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
-        match mex {
-            ReceivedMessage::ChangeAvatar { cosmetics } => {
-                self.db.do_send(server_actor::EditCosmetics {
-                    id: self.session_id,
-                    obj: cosmetics,
+            ReceivedMessage::RoomStart { connection_type, snapshot, countdown } => {
+                let game = match snapshot.map(|x| base64::decode(&x).map_err(|_| "Invalid base64").and_then(|x| game::snapshot::decode(&x))) {
+                    Some(Ok(x)) => Some(x),
+                    Some(Err(e)) => {
+                        self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid snapshot".into(), Some(e.into())));
+                        return;
+                    },
+                    None => None,
+                };
+                room.send(room_actor::StartRoom {
+                    by: Some(self.session_id),
+                    conn_type: connection_type,
+                    game,
+                    countdown,
                 })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.send_message(ctx, &Response::ok(id, "room_start_response".into(), NoData {}));
+                            },
+                            Ok(Err(failures)) => {
+                                let pkt = Response::from(id, "room_start_response".into(), Some("start_refused".into()), RoomStartFailures { failures });
+                                act.send_message(ctx, &pkt);
+                            },
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
             },
-            ReceivedMessage::RoomLeave {} => {
-                self.db.do_send(server_actor::LeaveRoom {
-                    id: self.session_id
-                });
-                self.state = ClientState::MatchMaking;
-                self.send_message(ctx, &Response::ok(id, "room_leave_response".into(), NoData {}));
+            ReceivedMessage::RoomStartCancel {} => {
+                let cancel = room.send(room_actor::CancelStart { id: self.session_id });
+                self.send_host_action_result(ctx, id, "room_start_cancel_response", cancel);
             },
-            ReceivedMessage::RoomStart { connection_type } => {
-                self.db.do_send(server_actor::StartRoom {
-                    id: self.session_id,
-                    conn_type: connection_type
-                });
+            ReceivedMessage::RoomMergeAccept {} if self.state == ClientState::Lobby => {
+                room.send(room_actor::GetMergeProposal)
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Some(target)) => {
+                                act.leave_room();
+                                act.state = ClientState::MatchMaking;
+                                act.join_room(ctx, id, "room_merge_accept_response", InviteId::Id(target), None, None);
+                            },
+                            Ok(None) => act.send_join_result(ctx, id, "room_merge_accept_response", JoinRoomResult::RoomNotFound),
+                            _ => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
             },
             ReceivedMessage::EventRoomStartAck { request_id } => {
                 if let ClientState::PrePlaying(res_id) = &self.state {
                     if *res_id == request_id {
                         self.state = ClientState::Playing;
-                        for x in self.relay_queue.drain(..) {
-                            ctx.text(x.data);
-                        }
+                        self.relay_ready(ctx);
                     } else {
                         self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid request_id".into(), None));
                     }
@@ -335,13 +1185,125 @@ impl ClientWs {
                     self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid state".into(), Some("No message to acknowledge".into())));
                 }
             },
+            ReceivedMessage::Subscribe { .. } | ReceivedMessage::Unsubscribe { .. } => {
+                self.handle_message_subscriptions(ctx, id, mex);
+            },
+            ReceivedMessage::MatchHistory {} => {
+                self.send_match_history(ctx, id);
+            },
+            ReceivedMessage::PlayerStats { player_id, account_id } => {
+                let player = PlayerRef::new(player_id, account_id).unwrap_or(PlayerRef::Player(self.session_id));
+                self.send_player_stats(ctx, id, player);
+            },
+            ReceivedMessage::CosmeticsCatalog {} => {
+                let res = CosmeticsCatalogResponse { catalog: self.inventory.catalog() };
+                self.send_message(ctx, &Response::ok(id, "cosmetics_catalog_response".into(), res));
+            },
+            ReceivedMessage::FriendAdd { .. } | ReceivedMessage::FriendRemove { .. } |
+            ReceivedMessage::FriendList {} | ReceivedMessage::RoomInviteDecline { .. } => {
+                self.handle_message_friends(ctx, id, mex);
+            },
+            ReceivedMessage::TournamentCreate { .. } | ReceivedMessage::TournamentRegister { .. } |
+            ReceivedMessage::TournamentLeave { .. } | ReceivedMessage::TournamentStart { .. } => {
+                self.handle_message_tournaments(ctx, id, mex);
+            },
+            ReceivedMessage::ReplayGet { replay_id } => {
+                self.send_replay(ctx, id, replay_id.0);
+            },
             _ => {
                 self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid message type".into(), None));
             }
         }
     }
 
+    /// Answers a room_find request, right away or once matched in the queue.
+    fn send_find_result(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, res: FindRoomResult) {
+        let pkt_type = "room_find_response".into();
+        match res {
+            FindRoomResult::Success { players, room_id, room, just_created, settings, pinned, countdown } => {
+                let pkt = Response::ok(
+                    id, pkt_type,
+                    RoomFindResponse {
+                        players,
+                        room_id: room_id.into(),
+                        just_created,
+                        settings,
+                    }
+                );
+                self.send_message(ctx, &pkt);
+                self.send_room_status(ctx, pinned, countdown);
+                self.state = ClientState::Lobby;
+                self.room = Some(room);
+            },
+            FindRoomResult::Redirect { url, room_id } => {
+                // The client has to join the room on the other instance.
+                let pkt = Response::from(
+                    id, pkt_type,
+                    Some("redirect".into()),
+                    RoomRedirectResponse {
+                        url,
+                        room_id: room_id.into(),
+                    }
+                );
+                self.send_message(ctx, &pkt);
+            },
+            FindRoomResult::GameIsFull => {
+                // TODO
+                let pkt = Response::from(
+                    id, pkt_type,
+                    Some("game_is_full".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            }
+            FindRoomResult::ShuttingDown => {
+                let pkt = Response::from(
+                    id, pkt_type,
+                    Some("server_shutting_down".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            }
+            FindRoomResult::ReadOnly => {
+                let pkt = Response::from(
+                    id, pkt_type,
+                    Some("server_read_only".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            }
+            FindRoomResult::Cancelled => {
+                let pkt = Response::from(
+                    id, pkt_type,
+                    Some("cancelled".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+            }
+        }
+    }
+
+    /// Answers a host only request once the room replied (false if the request was refused).
+    fn send_host_action_result<M>(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, ptype: &'static str, req: Request<RoomActor, M>)
+        where M: Message<Result = bool> + Send + 'static, RoomActor: Handler<M> {
+        req.into_actor(self)
+            .then(move |res, act, ctx| {
+                let pkt = match res {
+                    Ok(true) => Response::ok(id, ptype.into(), NoData {}),
+                    Ok(false) => Response::from(id, ptype.into(), Some("not_allowed".into()), NoData {}),
+                    _ => {
+                        ctx.stop();
+                        return fut::ready(());
+                    },
+                };
+                act.send_message(ctx, &pkt);
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
     pub fn handle_message(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        if let ReceivedMessage::Unknown = mex {
+            // Sent by a newer client, it can tell that this server doesn't support the message.
+            self.send_message(ctx, &protocol::Error::from_origin(id, "Unknown message type".into(), None));
+            return;
+        }
         match &self.state {
             ClientState::PreLogin => {
                 self.handle_message_login(ctx, id, mex);
@@ -352,7 +1314,7 @@ impl ClientWs {
             ClientState::Lobby | ClientState::PrePlaying(..) => {
                 self.handle_message_lobby(ctx, id, mex);
             },
-            ClientState::Playing => {},
+            ClientState::Playing | ClientState::Spectating => {},
         }
     }
 
@@ -360,12 +1322,12 @@ impl ClientWs {
         if text.is_empty() {
             return;
         }
-        if text.chars().next() == Some('#') {
-            if text.len() < 2 {
+        if let Some(special) = text.strip_prefix('#') {
+            if special.is_empty() {
                 return;
             }
             // Special message
-            let mex = match serde_json::from_str::<ReceivedGameMessage>(&text[1..]) {
+            let mex = match serde_json::from_str::<ReceivedGameMessage>(special) {
                 Ok(x) => x,
                 Err(_) => {
                     let err = protocol::Error::from("Invalid special Json".into(), None);
@@ -374,9 +1336,57 @@ impl ClientWs {
                 },
             };
 
+            let room = match &self.room {
+                Some(x) => x,
+                None => return,
+            };
+
             match mex {
+                ReceivedGameMessage::Cursor { position } => {
+                    self.send_cursor(ctx, position);
+                },
+                ReceivedGameMessage::SaveGame {} => {
+                    room.send(room_actor::SaveGame)
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            match res {
+                                Ok(Some(data)) => {
+                                    let mex = OutGameMessage::SaveGameAck {
+                                        snapshot_id: game::snapshot::content_id(&data),
+                                        data: base64::encode(&data),
+                                    };
+                                    act.send_message(ctx, &mex);
+                                },
+                                Ok(None) => {
+                                    let err = protocol::Error::from("Invalid message type".into(), Some("The server doesn't track the game".into()));
+                                    act.send_message(ctx, &err);
+                                },
+                                _ => ctx.stop(),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx);
+                },
+                ReceivedGameMessage::SpectateJoin {} | ReceivedGameMessage::SpectateLeave {} => {
+                    let err = protocol::Error::from("Invalid message type".into(), Some("Only for spectators".into()));
+                    self.send_message(ctx, &err);
+                },
+                ReceivedGameMessage::TimeSync { client_time } => {
+                    self.send_message(ctx, &OutGameMessage::TimeSyncAck { client_time, server_time: clock::now_ms() });
+                },
+                ReceivedGameMessage::VoteStart { proposal } => {
+                    let room = room.clone();
+                    self.send_vote(ctx, room, room_actor::StartVote { id: self.session_id, proposal });
+                },
+                ReceivedGameMessage::Vote { yes } => {
+                    let room = room.clone();
+                    self.send_vote(ctx, room, room_actor::CastVote { id: self.session_id, yes });
+                },
+                ReceivedGameMessage::Unknown => {
+                    self.send_message(ctx, &protocol::Error::from("Unknown message type".into(), None));
+                },
                 ReceivedGameMessage::EndGame {} => {
-                    self.db.send(server_actor::GameEndRequest {
+                    room.send(room_actor::GameEndRequest {
                         id: self.session_id,
                     })
                         .into_actor(self)
@@ -391,7 +1401,8 @@ impl ClientWs {
                                 },
                             };
                             let mex = OutGameMessage::EndGameAck {
-                                players: res.0,
+                                players: res.players,
+                                spectators: res.spectators,
                             };
                             act.send_message(ctx, &mex);
                             act.state = ClientState::Lobby;
@@ -401,11 +1412,131 @@ impl ClientWs {
                         .wait(ctx);
                 },
             };
-        } else {
-            self.db.do_send(server_actor::SendRelayMex {
-                sender_id: self.session_id,
-                data: text
-            });
+        } else if self.relay_allowed(ctx) {
+            if let Some(room) = &self.room {
+                room.do_send(room_actor::SendRelayMex {
+                    sender_id: self.session_id,
+                    data: text
+                });
+            }
+        }
+    }
+
+    /// The votes are answered with their events, only a refused one gets an error.
+    fn send_vote<M>(&mut self, ctx: &mut <Self as Actor>::Context, room: Addr<RoomActor>, msg: M)
+    where
+        M: Message<Result = Result<(), &'static str>> + Send + 'static,
+        RoomActor: Handler<M>,
+    {
+        room.send(msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => {
+                        act.send_message(ctx, &protocol::Error::from(e.into(), None));
+                    },
+                    Err(_) => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Applies the rate limit of the connection to a relay message, the client is warned the first
+    /// time a message is dropped and disconnected if it doesn't slow down.
+    fn relay_allowed(&mut self, ctx: &mut <Self as Actor>::Context) -> bool {
+        let bucket = match &mut self.relay_bucket {
+            Some(x) => x,
+            None => return true,
+        };
+        if bucket.try_take(1.0, Instant::now()) {
+            self.relay_dropped = 0;
+            return true;
+        }
+
+        self.relay_dropped += 1;
+        if self.relay_dropped == 1 {
+            self.send_message(ctx, &OutEvent::RateLimited { scope: RateLimitScope::Connection });
+        } else if self.relay_dropped >= rate_limit::MAX_DROPPED_RELAY {
+            warn!("Client flooding the relay, disconnecting");
+            // Not resumable, the client would start again.
+            self.clean_close = true;
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("rate_limited".to_string()),
+            }));
+            ctx.stop();
+        }
+        false
+    }
+}
+
+impl ClientWs {
+    pub fn handle_message_spectating(&mut self, ctx: &mut <Self as Actor>::Context, text: String) {
+        let special = match text.strip_prefix('#') {
+            Some(x) => x,
+            None => {
+                let err = protocol::Error::from("Spectators can't send relay messages".into(), None);
+                self.send_message(ctx, &err);
+                return;
+            },
+        };
+        let mex = match serde_json::from_str::<ReceivedGameMessage>(special) {
+            Ok(x) => x,
+            Err(_) => {
+                let err = protocol::Error::from("Invalid special Json".into(), None);
+                self.send_message(ctx, &err);
+                return;
+            },
+        };
+
+        let room = match &self.room {
+            Some(x) => x,
+            None => return,
+        };
+
+        match mex {
+            ReceivedGameMessage::SpectateJoin {} => {
+                room.send(room_actor::SpectatorJoin {
+                    id: self.session_id,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(JoinRoomResult::Success { players, .. }) => {
+                                act.send_message(ctx, &OutGameMessage::SpectateJoinAck { players });
+                                act.state = ClientState::Lobby;
+                            },
+                            Ok(JoinRoomResult::AlreadyPlaying) => {
+                                act.send_message(ctx, &protocol::Error::from("already_playing".into(), None));
+                            },
+                            Ok(JoinRoomResult::RoomIsFull) => {
+                                act.send_message(ctx, &protocol::Error::from("room_is_full".into(), None));
+                            },
+                            Ok(_) => {},
+                            Err(_) => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedGameMessage::SpectateLeave {} => {
+                self.leave_room();
+                self.send_message(ctx, &OutGameMessage::SpectateLeaveAck {});
+                self.state = ClientState::MatchMaking;
+            },
+            ReceivedGameMessage::TimeSync { client_time } => {
+                self.send_message(ctx, &OutGameMessage::TimeSyncAck { client_time, server_time: clock::now_ms() });
+            },
+            ReceivedGameMessage::EndGame {} | ReceivedGameMessage::Cursor { .. } | ReceivedGameMessage::SaveGame {}
+                | ReceivedGameMessage::VoteStart { .. } | ReceivedGameMessage::Vote { .. } => {
+                let err = protocol::Error::from("Invalid message type".into(), Some("Spectators are not in game".into()));
+                self.send_message(ctx, &err);
+            },
+            ReceivedGameMessage::Unknown => {
+                self.send_message(ctx, &protocol::Error::from("Unknown message type".into(), None));
+            },
         }
     }
 }
@@ -414,10 +1545,160 @@ impl Handler<Event> for ClientWs {
     type Result = ();
 
     fn handle(&mut self, msg: Event, ctx: &mut <Self as Actor>::Context) -> Self::Result {
-        let id = self.send_message(ctx, &msg.0);
+        if msg.0.class().is_some() && self.backlog.drops_optional() {
+            return;
+        }
+        let id = self.send_message(ctx, &*msg.0);
+
+        match *msg.0 {
+            OutEvent::EventRoomStart { .. } => self.state = ClientState::PrePlaying(id),
+            OutEvent::EventKicked { .. } | OutEvent::EventRoomClosed {} | OutEvent::EventRoomMigrated { .. } => {
+                // The room already forgot about us.
+                self.room = None;
+                self.state = ClientState::MatchMaking;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Sent by the lobby to a player of the matchmaking queue: the answer of its room_find request.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct QueueResult {
+    pub request_id: u64,
+    pub result: FindRoomResult,
+}
+
+impl Handler<QueueResult> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: QueueResult, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        if let FindRoomResult::Success { room, .. } = &msg.result {
+            // Joined another room in the meantime.
+            if self.state != ClientState::MatchMaking || self.room.is_some() {
+                room.do_send(room_actor::Leave { id: self.session_id });
+                return;
+            }
+        }
+        self.send_find_result(ctx, msg.request_id, msg.result);
+    }
+}
+
+/// Sent by the lobby when the player unlocked achievements, with the cosmetics they earned.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AchievementsUnlocked {
+    pub achievements: Vec<Achievement>,
+    pub cosmetics: Vec<CatalogEntry>,
+}
+
+impl Handler<AchievementsUnlocked> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: AchievementsUnlocked, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        for achievement in msg.achievements {
+            self.send_message(ctx, &OutEvent::EventAchievementUnlocked { achievement });
+        }
+        if !msg.cosmetics.is_empty() {
+            self.inventory.add(msg.cosmetics.iter().map(|x| x.item));
+            self.send_message(ctx, &OutEvent::EventCosmeticsUnlocked { unlocked: msg.cosmetics });
+        }
+    }
+}
+
+/// Sent by a room traced by an admin to its members.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TraceRoom {
+    pub room: Addr<RoomActor>,
+    pub trace: Arc<RoomTrace>,
+}
+
+impl Handler<TraceRoom> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: TraceRoom, _: &mut <Self as Actor>::Context) -> Self::Result {
+        self.trace = Some((msg.room, msg.trace));
+    }
+}
+
+/// Sent by the lobby once the player is in the room of its next tournament match.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TournamentMatch {
+    pub tournament_id: IdType,
+    pub round: usize,
+    pub room_id: IdType,
+    pub room: Addr<RoomActor>,
+    pub players: Vec<PlayerObject>,
+    pub settings: RoomSettings,
+    pub pinned: Option<String>,
+    pub countdown: Option<u32>,
+}
+
+impl Handler<TournamentMatch> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: TournamentMatch, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        match self.state {
+            ClientState::MatchMaking => {},
+            // The room of the match replaces the one waiting for a game.
+            ClientState::Lobby => self.leave_room(),
+            // Still in another game (or gone), the player gives up the match.
+            _ => {
+                msg.room.do_send(room_actor::Leave { id: self.session_id });
+                self.lobby.do_send(lobby_actor::LeaveTournament { id: self.session_id, tournament_id: msg.tournament_id });
+                return;
+            },
+        }
+        self.send_message(ctx, &OutEvent::EventTournamentMatch {
+            tournament_id: msg.tournament_id.into(),
+            round: msg.round,
+            room_id: msg.room_id.into(),
+            players: msg.players,
+            settings: msg.settings,
+        });
+        self.send_room_status(ctx, msg.pinned, msg.countdown);
+        self.state = ClientState::Lobby;
+        self.room = Some(msg.room);
+    }
+}
+
+/// Sent by the lobby when an admin kicks the player out of the server.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kicked;
+
+impl Handler<Kicked> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, _: Kicked, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        // Not resumable, the session ends with the connection.
+        self.clean_close = true;
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("kicked".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+/// Sent by the room when a vote ends the game of the player, as if it had sent end_game.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReturnToLobby {
+    pub players: Vec<PlayerObject>,
+    pub spectators: Vec<PlayerObject>,
+}
+
+impl Handler<ReturnToLobby> for ClientWs {
+    type Result = ();
 
-        if let OutEvent::EventRoomStart { .. } = msg.0 {
-            self.state = ClientState::PrePlaying(id);
+    fn handle(&mut self, msg: ReturnToLobby, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        if let ClientState::Playing | ClientState::PrePlaying(_) = self.state {
+            self.send_message(ctx, &OutGameMessage::EndGameAck { players: msg.players, spectators: msg.spectators });
+            self.state = ClientState::Lobby;
         }
     }
 }
@@ -426,11 +1707,21 @@ impl Handler<GameEvent> for ClientWs {
     type Result = ();
 
     fn handle(&mut self, msg: GameEvent, ctx: &mut <Self as Actor>::Context) -> Self::Result {
-        self.send_message(ctx, &msg.0);
+        if let OutGameEvent::Cursor { .. } = *msg.0 {
+            if self.backlog.drops_optional() {
+                return;
+            }
+        }
+        self.send_message(ctx, &*msg.0);
+
+        if let OutGameEvent::RoomClosed {} | OutGameEvent::RoomMigrated { .. } = *msg.0 {
+            self.room = None;
+            self.state = ClientState::MatchMaking;
+        }
     }
 }
 
-impl Handler<server_actor::SendRelayMexRaw> for ClientWs {
+impl Handler<SendRelayMexRaw> for ClientWs {
     type Result = ();
 
     fn handle(&mut self, msg: SendRelayMexRaw, ctx: &mut <Self as Actor>::Context) -> Self::Result {
@@ -438,16 +1729,12 @@ impl Handler<server_actor::SendRelayMexRaw> for ClientWs {
             ClientState::PreLogin => {},
             ClientState::MatchMaking => {},
             ClientState::Lobby => {},
-            ClientState::PrePlaying(_) => {
-                if self.relay_queue.len() >= RELAY_QUEUE_MAX_SIZE {
-                    eprintln!("Client {} not responding to event_room_start, queue full. kicking out", self.session_id);
-                    ctx.stop();
-                    return;
-                }
-                self.relay_queue.push(msg)
-            },
-            ClientState::Playing => {
-                ctx.text(msg.data);
+            ClientState::PrePlaying(_) => {},// The room keeps them until the start is acknowledged
+            ClientState::Playing | ClientState::Spectating => {
+                // The frame needs its own copy, the only one made for this client.
+                self.trace(Direction::Out, || msg.data.to_string());
+                self.backlog.written(msg.data.len());
+                ctx.text(&*msg.data);
             },
         }
     }
@@ -460,6 +1747,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
         msg: Result<ws::Message, ws::ProtocolError>,
         ctx: &mut Self::Context,
     ) {
+        let _span = debug_span!("client_message", player = self.session_id).entered();
         let msg = match msg {
             Ok(x) => x,
             Err(_) => {
@@ -467,8 +1755,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
                 return;
             }
         };
-
-        //eprintln!("REC {:?} => {:?}", self.peer, msg);
+        trace!(frame = ?msg, "Received");
 
         let text = match msg {
             ws::Message::Ping(msg) => {
@@ -476,24 +1763,29 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
                 ctx.pong(&msg);
                 return
             },
-            ws::Message::Pong(_) => {
+            ws::Message::Pong(msg) => {
                 self.last_hb = Instant::now();
+                self.network.pong_received(&msg, self.last_hb);
+                self.backlog.pong_received(&msg);
                 return
             }
             ws::Message::Text(text) => text,
             ws::Message::Close(_) => {
+                self.clean_close = true;
                 ctx.stop();
                 return
             },
             _ => return,
         };
+        self.trace(Direction::In, || text.to_string());
 
-        match self.state {
-            ClientState::Playing => {
-                self.handle_message_playing(ctx, text);
-                return;
-            },
-            _ => {}
+        if self.state == ClientState::Playing {
+            self.handle_message_playing(ctx, text);
+            return;
+        }
+        if self.state == ClientState::Spectating {
+            self.handle_message_spectating(ctx, text);
+            return;
         }
 
         let id_message = match serde_json::from_str::<IdMessage>(&text) {
@@ -514,6 +1806,22 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
             Some(x) => x,
         };
 
+        if let Some(key) = &id_message.request_key {
+            if key.is_empty() || key.len() > idempotency::MAX_KEY_LEN {
+                let err = protocol::Error::from_origin(id, "Invalid requestKey".into(), None);
+                self.send_message(ctx, &err);
+                return;
+            }
+            match self.requests.begin(key, id, Instant::now()) {
+                Seen::New => {},
+                Seen::Pending | Seen::Done => return,
+                Seen::Answered(response) => {
+                    self.send_encoded(ctx, &response);
+                    return;
+                },
+            }
+        }
+
         let mex = match serde_json::from_str::<ReceivedMessage>(&text) {
             Ok(x) => x,
             Err(x) => {
@@ -523,14 +1831,36 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
             }
         };
 
+        let has_response = mex.has_response();
         self.handle_message(ctx, id, mex);
+        if !has_response {
+            self.requests.handled(id);
+        }
     }
 }
 
 pub async fn matchmaking_start(
     req: HttpRequest,
     stream: web::Payload,
-    data: web::Data<Addr<server_actor::ServerActor>>,
+    data: web::Data<Addr<LobbyActor>>,
+    handshake: web::Data<HandshakeConfig>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(ClientWs::new(data.get_ref().clone()), &req, stream)
+    if !status_page::is_upgrade(&req) {
+        return Ok(status_page::page(data.get_ref()).await);
+    }
+    if let Err(res) = handshake.check(&req) {
+        return Ok(res);
+    }
+
+    // Only ask the lobby for the features when the client wants a subprotocol.
+    let binary_allowed = Codec::any_requested(&req) && match data.send(lobby_actor::GetHello).await {
+        Ok(hello) => hello.capabilities.binary_protocol,
+        Err(_) => false,
+    };
+    let codec = Codec::negotiate(&req, binary_allowed);
+    let actor = ClientWs::new(data.get_ref().clone(), codec.unwrap_or(Codec::Json), listeners::local_addr(&req), handshake.client_ip(&req));
+    match codec {
+        Some(x) => ws::start_with_protocols(actor, &[x.protocol()], &req, stream),
+        None => ws::start(actor, &req, stream),
+    }
 }
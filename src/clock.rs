@@ -0,0 +1,21 @@
+//!
+//! Server time for the clients: the events of the turns carry the time they were sent and the
+//! deadline of the turn (with a turn timer) as absolute times, in milliseconds since the Unix epoch,
+//! so that the countdowns of every client end at the same moment whatever their latency.
+//!
+//! A client syncs its clock with the time_sync special message: the server answers right away with
+//! its time and the clientTime it received. The offset of the client is then about
+//! `serverTime - (sent + received) / 2`, with `sent` and `received` read on its own clock.
+//!
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0)
+}
+
+/// The time `after` from now, in milliseconds since the Unix epoch.
+pub fn deadline_ms(after: Duration) -> u64 {
+    now_ms() + after.as_millis() as u64
+}
@@ -0,0 +1,312 @@
+//!
+//! A shared room registry for several instances behind a load balancer: the instances of a cluster
+//! write where their rooms are in Redis, so that a player can join a room by id or code on any of
+//! them. The instance that doesn't host the room redirects the player to the one that does (its
+//! PUBLIC_URL), every player of a room ends up on the same instance.
+//!
+//! It's not a shared state: the sessions, the matchmaking and the games stay on their instance and
+//! nothing is relayed between the instances (no pub/sub), so a room is never split over two of them
+//! and Redis is the only backend.
+//!
+//! It's enabled by CLUSTER_REDIS_URL (`redis://[:password@]host[:port][/db]`) with PUBLIC_URL and
+//! the `clustering` feature flag. Every REFRESH_INTERVAL the lobby writes where its rooms are,
+//! with an expiration so that the rooms of a crashed instance disappear on their own; the closed
+//! rooms are deleted with the next refresh.
+//!
+//! The Redis client is the bare minimum for this (a connection per operation, the commands are
+//! few and rare), not a general purpose one.
+//!
+
+use std::{env, time::Duration};
+
+use actix_rt::{net::TcpStream, time::timeout};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::protocol::{IdType, InviteId, SerId};
+
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// Expiration of the entries, a room is forgotten after missing a few refreshes.
+const ENTRY_TTL_SECS: u64 = 35;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+const KEY_PREFIX: &str = "carcassonne:";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RedisUrl {
+    addr: String,
+    password: Option<String>,
+    db: u32,
+}
+
+impl RedisUrl {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url.strip_prefix("redis://").ok_or_else(|| format!("\"{}\" is not a redis:// url", url))?;
+        let (password, rest) = match rest.rfind('@') {
+            Some(i) => {
+                let auth = &rest[..i];
+                (Some(auth.strip_prefix(':').unwrap_or(auth).to_string()), &rest[i + 1..])
+            },
+            None => (None, rest),
+        };
+        let (host, db) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(format!("\"{}\" has no host", url));
+        }
+        let db = match db {
+            "" => 0,
+            x => x.parse().map_err(|_| format!("\"{}\" is not a database number", x))?,
+        };
+        Ok(RedisUrl {
+            addr: if has_port(host) { host.to_string() } else { format!("{}:6379", host) },
+            password: password.filter(|x| !x.is_empty()),
+            db,
+        })
+    }
+}
+
+/// The port of an IPv6 address is after the brackets, `[::1]` has none.
+fn has_port(host: &str) -> bool {
+    if host.starts_with('[') {
+        host.contains("]:")
+    } else {
+        host.contains(':')
+    }
+}
+
+pub struct ClusterConfig {
+    pub redis: RedisUrl,
+    pub node_url: String,// PUBLIC_URL, where the players of the rooms of this instance connect
+}
+
+impl ClusterConfig {
+    /// None if the cluster isn't configured.
+    pub fn from_env(public_url: Option<&str>) -> Option<Self> {
+        let url = env::var("CLUSTER_REDIS_URL").ok().filter(|x| !x.trim().is_empty())?;
+        let redis = match RedisUrl::parse(url.trim()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(error = %e, "Invalid CLUSTER_REDIS_URL, clustering disabled");
+                return None;
+            },
+        };
+        match public_url {
+            Some(x) => Some(ClusterConfig { redis, node_url: x.to_string() }),
+            None => {
+                warn!("CLUSTER_REDIS_URL is set without PUBLIC_URL, clustering disabled");
+                None
+            },
+        }
+    }
+}
+
+/// Where a room of the cluster is.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+    pub url: String,
+    pub room_id: SerId,
+}
+
+fn room_key(id: IdType) -> String {
+    format!("{}room:{}", KEY_PREFIX, id)
+}
+
+fn code_key(code: &str) -> String {
+    format!("{}code:{}", KEY_PREFIX, code)
+}
+
+/// Writes the rooms of this instance to the registry and deletes the closed ones (id and code).
+pub async fn refresh(redis: RedisUrl, node_url: String, rooms: Vec<(IdType, String)>, closed: Vec<(IdType, String)>) -> Result<(), String> {
+    let ttl = ENTRY_TTL_SECS.to_string();
+    let mut commands: Vec<Vec<Vec<u8>>> = Vec::new();
+    for (id, code) in rooms.iter() {
+        let location = serde_json::to_string(&Location { url: node_url.clone(), room_id: (*id).into() })
+            .map_err(|e| e.to_string())?;
+        for key in [room_key(*id), code_key(code)].iter() {
+            commands.push(vec![b"SET".to_vec(), key.as_bytes().to_vec(), location.as_bytes().to_vec(), b"EX".to_vec(), ttl.as_bytes().to_vec()]);
+        }
+    }
+    for (id, code) in closed.iter() {
+        commands.push(vec![b"DEL".to_vec(), room_key(*id).into_bytes(), code_key(code).into_bytes()]);
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+    for reply in run(&redis, commands).await? {
+        if let Reply::Error(e) = reply {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// The room of an id or a code on another instance of the cluster.
+pub async fn lookup(redis: RedisUrl, invite: InviteId) -> Result<Option<Location>, String> {
+    let key = match &invite {
+        InviteId::Id(x) => room_key(*x),
+        InviteId::Code(x) => code_key(x),
+    };
+    let mut replies = run(&redis, vec![vec![b"GET".to_vec(), key.into_bytes()]]).await?;
+    match replies.pop() {
+        Some(Reply::Bulk(Some(x))) => serde_json::from_slice(&x).map(Some).map_err(|e| e.to_string()),
+        Some(Reply::Bulk(None)) => Ok(None),
+        Some(Reply::Error(e)) => Err(e),
+        _ => Err("unexpected reply".to_string()),
+    }
+}
+
+/// Opens a connection, sends the commands in a pipeline and reads their replies.
+async fn run(redis: &RedisUrl, mut commands: Vec<Vec<Vec<u8>>>) -> Result<Vec<Reply>, String> {
+    let mut setup = Vec::new();
+    if let Some(password) = &redis.password {
+        setup.push(vec![b"AUTH".to_vec(), password.as_bytes().to_vec()]);
+    }
+    if redis.db != 0 {
+        setup.push(vec![b"SELECT".to_vec(), redis.db.to_string().into_bytes()]);
+    }
+    let skip = setup.len();
+    setup.append(&mut commands);
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(redis.addr.as_str()).await.map_err(|e| e.to_string())?;
+        let request: Vec<u8> = setup.iter().flat_map(|x| encode(x)).collect();
+        stream.write_all(&request).await.map_err(|e| e.to_string())?;
+
+        let mut buf = Vec::new();
+        let mut replies = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while replies.len() < setup.len() {
+            match parse(&buf)? {
+                Some((reply, len)) => {
+                    buf.drain(..len);
+                    replies.push(reply);
+                },
+                None => {
+                    let n = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+                    if n == 0 {
+                        return Err("connection closed".to_string());
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                },
+            }
+        }
+        Ok(replies)
+    };
+    let mut replies = timeout(COMMAND_TIMEOUT, exchange).await.map_err(|_| "timed out".to_string())??;
+    for reply in replies.drain(..skip) {
+        if let Reply::Error(e) = reply {
+            return Err(e);
+        }
+    }
+    Ok(replies)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+fn encode(args: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Parses the first reply of the buffer with its length, none if it isn't complete yet.
+fn parse(buf: &[u8]) -> Result<Option<(Reply, usize)>, String> {
+    let end = match buf.windows(2).position(|x| x == b"\r\n") {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    if end == 0 {
+        return Err("empty reply".to_string());
+    }
+    let line = String::from_utf8_lossy(&buf[1..end]).into_owned();
+    let number = || line.parse::<i64>().map_err(|_| format!("invalid reply \"{}\"", line));
+    let next = end + 2;
+    let reply = match buf[0] {
+        b'+' => Reply::Status(line.clone()),
+        b'-' => Reply::Error(line.clone()),
+        b':' => Reply::Integer(number()?),
+        b'$' => match number()? {
+            len if len < 0 => Reply::Bulk(None),
+            len => {
+                let len = len as usize;
+                if buf.len() < next + len + 2 {
+                    return Ok(None);
+                }
+                return Ok(Some((Reply::Bulk(Some(buf[next..next + len].to_vec())), next + len + 2)));
+            },
+        },
+        b'*' => match number()? {
+            len if len < 0 => Reply::Array(None),
+            len => {
+                let mut items = Vec::new();
+                let mut at = next;
+                for _ in 0..len {
+                    match parse(&buf[at..])? {
+                        Some((item, item_len)) => {
+                            items.push(item);
+                            at += item_len;
+                        },
+                        None => return Ok(None),
+                    }
+                }
+                return Ok(Some((Reply::Array(Some(items)), at)));
+            },
+        },
+        x => return Err(format!("invalid reply type {:?}", x as char)),
+    };
+    Ok(Some((reply, next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_and_replies() {
+        assert_eq!(RedisUrl::parse("redis://cache").unwrap(), RedisUrl { addr: "cache:6379".to_string(), password: None, db: 0 });
+        assert_eq!(
+            RedisUrl::parse("redis://:s3cr@t@10.0.0.2:6380/2").unwrap(),
+            RedisUrl { addr: "10.0.0.2:6380".to_string(), password: Some("s3cr@t".to_string()), db: 2 },
+        );
+        assert_eq!(RedisUrl::parse("redis://[::1]").unwrap().addr, "[::1]:6379");
+        assert_eq!(RedisUrl::parse("redis://[::1]:6380").unwrap().addr, "[::1]:6380");
+        assert!(RedisUrl::parse("http://cache").is_err());
+        assert!(RedisUrl::parse("redis://cache/x").is_err());
+
+        assert_eq!(encode(&[b"GET".to_vec(), b"k".to_vec()]), b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec());
+        let buf = b"+OK\r\n$5\r\nhello\r\n$-1\r\n*2\r\n:3\r\n-ERR no\r\n";
+        let mut at = 0;
+        let mut replies = Vec::new();
+        while let Some((reply, len)) = parse(&buf[at..]).unwrap() {
+            replies.push(reply);
+            at += len;
+            if at == buf.len() {
+                break;
+            }
+        }
+        assert_eq!(replies, vec![
+            Reply::Status("OK".to_string()),
+            Reply::Bulk(Some(b"hello".to_vec())),
+            Reply::Bulk(None),
+            Reply::Array(Some(vec![Reply::Integer(3), Reply::Error("ERR no".to_string())])),
+        ]);
+        // Incomplete replies wait for more data.
+        assert_eq!(parse(b"$5\r\nhel").unwrap(), None);
+        assert_eq!(parse(b"*2\r\n:1\r\n").unwrap(), None);
+    }
+}
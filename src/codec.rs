@@ -0,0 +1,272 @@
+//!
+//! Wire format of the messages sent by the server: JSON text frames by default. With the
+//! `binaryProtocol` feature a client can ask for MessagePack or CBOR with the websocket subprotocol
+//! (`Sec-WebSocket-Protocol: carcassonne.msgpack` or `carcassonne.cbor`), the messages of the server
+//! are then binary frames with the same fields as the JSON ones.
+//!
+//! The relayed messages stay text frames as the clients sent them, so a binary frame is always a
+//! message of the server and doesn't need the `#` prefix of the special messages. The clients keep
+//! sending JSON text.
+//!
+
+use actix_web::{http::header, HttpRequest};
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Codec {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Codec {
+    fn from_protocol(name: &str) -> Option<Codec> {
+        match name {
+            "carcassonne.json" => Some(Codec::Json),
+            "carcassonne.msgpack" => Some(Codec::MessagePack),
+            "carcassonne.cbor" => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Codec::Json => "carcassonne.json",
+            Codec::MessagePack => "carcassonne.msgpack",
+            Codec::Cbor => "carcassonne.cbor",
+        }
+    }
+
+    /// Whether the client asked for a subprotocol at all.
+    pub fn any_requested(req: &HttpRequest) -> bool {
+        req.headers().contains_key(header::SEC_WEBSOCKET_PROTOCOL)
+    }
+
+    /// The first codec allowed in the subprotocols requested by the client (in its order of
+    /// preference), `None` if there isn't any: the messages are JSON without subprotocol.
+    pub fn negotiate(req: &HttpRequest, binary_allowed: bool) -> Option<Codec> {
+        req.headers().get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.split(',')
+                .filter_map(|name| Codec::from_protocol(name.trim()))
+                .find(|x| binary_allowed || !x.is_binary()))
+    }
+
+    pub fn is_binary(self) -> bool {
+        self != Codec::Json
+    }
+
+    /// Encodes a message of the server, `special` messages are prefixed by `#` in the JSON text.
+    pub fn encode<T: ?Sized + Serialize>(self, value: &T, special: bool) -> Frame {
+        match self {
+            Codec::Json => {
+                let mut writer = Vec::with_capacity(128);
+                if special {
+                    writer.push(b'#');
+                }
+                serde_json::to_writer(&mut writer, value).expect("Error serializing message");
+
+                let text = unsafe {// It's safe? well it copies serde's method so I hope it is.
+                    String::from_utf8_unchecked(writer)
+                };
+                Frame::Text(text)
+            },
+            Codec::MessagePack | Codec::Cbor => {
+                let value = serde_json::to_value(value).expect("Error serializing message");
+                let mut out = Vec::with_capacity(128);
+                if self == Codec::MessagePack {
+                    write_msgpack(&mut out, &value);
+                } else {
+                    write_cbor(&mut out, &value);
+                }
+                Frame::Binary(out)
+            },
+        }
+    }
+}
+
+fn write_msgpack(out: &mut Vec<u8>, value: &Value) {
+    // Length of a string, an array or a map: fixed size tag, 16 bits or 32 bits.
+    fn len(out: &mut Vec<u8>, n: usize, fixed: u8, fixed_max: usize, tag16: u8) {
+        if n <= fixed_max {
+            out.push(fixed | n as u8);
+        } else if n <= u16::MAX as usize {
+            out.push(tag16);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        } else {
+            out.push(tag16 + 1);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(x) => out.push(if *x { 0xc3 } else { 0xc2 }),
+        Value::Number(x) => {
+            if let Some(x) = x.as_u64() {
+                if x < 0x80 {
+                    out.push(x as u8);
+                } else if x <= u8::MAX as u64 {
+                    out.extend_from_slice(&[0xcc, x as u8]);
+                } else if x <= u16::MAX as u64 {
+                    out.push(0xcd);
+                    out.extend_from_slice(&(x as u16).to_be_bytes());
+                } else if x <= u32::MAX as u64 {
+                    out.push(0xce);
+                    out.extend_from_slice(&(x as u32).to_be_bytes());
+                } else {
+                    out.push(0xcf);
+                    out.extend_from_slice(&x.to_be_bytes());
+                }
+            } else if let Some(x) = x.as_i64() {
+                // Only the negative numbers are left.
+                if x >= -32 {
+                    out.push(x as i8 as u8);
+                } else if x >= i8::MIN as i64 {
+                    out.extend_from_slice(&[0xd0, x as i8 as u8]);
+                } else if x >= i16::MIN as i64 {
+                    out.push(0xd1);
+                    out.extend_from_slice(&(x as i16).to_be_bytes());
+                } else if x >= i32::MIN as i64 {
+                    out.push(0xd2);
+                    out.extend_from_slice(&(x as i32).to_be_bytes());
+                } else {
+                    out.push(0xd3);
+                    out.extend_from_slice(&x.to_be_bytes());
+                }
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&x.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        },
+        Value::String(x) => {
+            if x.len() >= 32 && x.len() <= u8::MAX as usize {
+                out.extend_from_slice(&[0xd9, x.len() as u8]);
+            } else {
+                len(out, x.len(), 0xa0, 31, 0xda);
+            }
+            out.extend_from_slice(x.as_bytes());
+        },
+        Value::Array(items) => {
+            len(out, items.len(), 0x90, 15, 0xdc);
+            for x in items.iter() {
+                write_msgpack(out, x);
+            }
+        },
+        Value::Object(fields) => {
+            len(out, fields.len(), 0x80, 15, 0xde);
+            for (name, x) in fields.iter() {
+                write_msgpack(out, &Value::String(name.clone()));
+                write_msgpack(out, x);
+            }
+        },
+    }
+}
+
+fn write_cbor(out: &mut Vec<u8>, value: &Value) {
+    // Major type with its argument, in the shortest form.
+    fn head(out: &mut Vec<u8>, major: u8, n: u64) {
+        let major = major << 5;
+        if n < 24 {
+            out.push(major | n as u8);
+        } else if n <= u8::MAX as u64 {
+            out.extend_from_slice(&[major | 24, n as u8]);
+        } else if n <= u16::MAX as u64 {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        } else if n <= u32::MAX as u64 {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        } else {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(x) => out.push(if *x { 0xf5 } else { 0xf4 }),
+        Value::Number(x) => {
+            if let Some(x) = x.as_u64() {
+                head(out, 0, x);
+            } else if let Some(x) = x.as_i64() {
+                head(out, 1, (-1 - x) as u64);
+            } else {
+                out.push(0xfb);
+                out.extend_from_slice(&x.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        },
+        Value::String(x) => {
+            head(out, 3, x.len() as u64);
+            out.extend_from_slice(x.as_bytes());
+        },
+        Value::Array(items) => {
+            head(out, 4, items.len() as u64);
+            for x in items.iter() {
+                write_cbor(out, x);
+            }
+        },
+        Value::Object(fields) => {
+            head(out, 5, fields.len() as u64);
+            for (name, x) in fields.iter() {
+                head(out, 3, name.len() as u64);
+                out.extend_from_slice(name.as_bytes());
+                write_cbor(out, x);
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn binary(codec: Codec, value: &Value) -> Vec<u8> {
+        match codec.encode(value, true) {
+            Frame::Binary(x) => x,
+            Frame::Text(_) => panic!("Text frame"),
+        }
+    }
+
+    #[test]
+    fn json_special_prefix() {
+        match Codec::Json.encode(&json!({ "a": 1 }), true) {
+            Frame::Text(x) => assert_eq!(x, "#{\"a\":1}"),
+            Frame::Binary(_) => panic!("Binary frame"),
+        }
+    }
+
+    #[test]
+    fn msgpack() {
+        let value = json!({ "a": [1, -1, 200, -200, 70000, 1.5, null, true], "b": "x" });
+        assert_eq!(binary(Codec::MessagePack, &value), vec![
+            0x82,
+            0xa1, b'a', 0x98,
+            0x01, 0xff, 0xcc, 200, 0xd1, 0xff, 0x38, 0xce, 0x00, 0x01, 0x11, 0x70,
+            0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0, 0xc0, 0xc3,
+            0xa1, b'b', 0xa1, b'x',
+        ]);
+
+        let long = "y".repeat(40);
+        assert_eq!(binary(Codec::MessagePack, &json!(long))[..2], [0xd9, 40]);
+    }
+
+    #[test]
+    fn cbor() {
+        let value = json!({ "a": [1, -1, 200, -200, 70000, 1.5, null, true], "b": "x" });
+        assert_eq!(binary(Codec::Cbor, &value), vec![
+            0xa2,
+            0x61, b'a', 0x88,
+            0x01, 0x20, 0x18, 200, 0x38, 199, 0x1a, 0x00, 0x01, 0x11, 0x70,
+            0xfb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0, 0xf6, 0xf5,
+            0x61, b'b', 0x61, b'x',
+        ]);
+    }
+}
@@ -0,0 +1,252 @@
+//!
+//! The `--check` mode: validates the configuration of the deployment and exits without binding the
+//! port, for the deploy pipelines and the self-hosters. At runtime an invalid value is ignored (the
+//! default is used) with at most a warning, here every setting is reported and a single error makes
+//! the command exit with 1. The server has no TLS or TURN of its own and stores nothing but the
+//! accounts, the environment is all there is to check.
+//!
+
+use std::{env, fs, path::Path, str::FromStr};
+
+use tracing::level_filters::LevelFilter;
+
+use crate::accounts;
+use crate::cluster::RedisUrl;
+use crate::dev_rooms::DevRooms;
+use crate::features;
+use crate::handshake;
+use crate::listeners;
+use crate::matchmaking::Weights;
+
+type CheckResult = Result<String, String>;
+type Check = (&'static str, fn(&str) -> CheckResult);
+
+/// Runs every check and prints the report, false if one of them failed.
+pub fn run() -> bool {
+    let checks: [Check; 32] = [
+        ("BIND_ADDR", bind_addr),
+        ("ADMIN_BIND_ADDR", bind_addr),
+        ("ADMIN_TOKEN", |_| Ok("admin api enabled".to_string())),
+        ("ADMIN_SOCKET", admin_socket),
+        ("ALLOWED_ORIGINS", urls),
+        ("MAX_CONNECTIONS", number::<usize>),
+        ("TRUSTED_PROXIES", trusted_proxies),
+        ("FEATURES_FILE", features_file),
+        ("BLOCKED_WORDS_FILE", blocked_words_file),
+        ("ACCOUNTS_FILE", accounts_file),
+        ("FEDERATION_PEERS", urls),
+        ("PUBLIC_URL", urls),
+        ("CLUSTER_REDIS_URL", cluster_redis_url),
+        ("REPLICATION_TOKEN", |_| Ok("replication log enabled".to_string())),
+        ("REPLICATION_PRIMARY", replication_primary),
+        ("MOTD", |x| Ok(format!("{} characters", x.chars().count()))),
+        ("MATCHMAKING_WEIGHTS", weights),
+        ("DEV_ROOM_PREFIX", dev_room_prefix),
+        ("DEV_TOKENS", |x| Ok(format!("{} developer accounts", x.split(',').filter(|x| !x.trim().is_empty()).count()))),
+        ("RECONNECT_GRACE_SECS", number::<u64>),
+        ("SHUTDOWN_GRACE_SECS", number::<u64>),
+        ("ROOM_IDLE_TTL_SECS", number::<u64>),
+        ("HEARTBEAT_INTERVAL_SECS", number::<u64>),
+        ("CLIENT_TIMEOUT_SECS", client_timeout),
+        ("SLOW_CLIENT_KB", number::<u64>),
+        ("SLOW_CLIENT_DISCONNECT_KB", number::<u64>),
+        ("RELAY_RATE_LIMIT", limit),
+        ("ROOM_RELAY_LIMIT_KB", limit),
+        ("RELAY_SAMPLE_RATE", rate),
+        ("ROOM_TRACE_DIR", room_trace_dir),
+        ("LOG_LEVEL", log_level),
+        ("LOG_FORMAT", log_format),
+    ];
+
+    let mut failed = 0;
+    for (name, check) in checks.iter() {
+        let result = match env::var(name) {
+            Ok(x) if !x.trim().is_empty() => check(x.trim()),
+            _ => Ok("not set".to_string()),
+        };
+        match result {
+            Ok(x) => println!("ok     {:<22} {}", name, x),
+            Err(e) => {
+                println!("error  {:<22} {}", name, e);
+                failed += 1;
+            },
+        }
+    }
+    println!("{} settings checked, {} errors", checks.len(), failed);
+    failed == 0
+}
+
+fn bind_addr(value: &str) -> CheckResult {
+    let addrs: Vec<String> = listeners::parse(value)?.iter()
+        .map(|(addr, max)| match max {
+            Some(x) => format!("{} (at most {} connections)", addr, x),
+            None => addr.to_string(),
+        })
+        .collect();
+    Ok(addrs.join(", "))
+}
+
+/// The socket is created (or replaces the one of a previous run) in an existing directory.
+fn admin_socket(value: &str) -> CheckResult {
+    if cfg!(not(unix)) {
+        return Err("needs a unix system".to_string());
+    }
+    let path = Path::new(value);
+    match path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        Some(dir) if !dir.is_dir() => return Err(format!("{} is not a directory", dir.display())),
+        _ => {},
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if fs::symlink_metadata(path).map(|x| !x.file_type().is_socket()).unwrap_or(false) {
+            return Err(format!("{} exists and isn't a socket", value));
+        }
+    }
+    Ok(format!("admin api on {}", value))
+}
+
+/// A comma separated list of http(s) urls.
+fn urls(value: &str) -> CheckResult {
+    let urls: Vec<&str> = value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    for url in urls.iter() {
+        let host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+        if host.map(|x| x.is_empty()).unwrap_or(true) {
+            return Err(format!("\"{}\" is not an http(s) url", url));
+        }
+    }
+    Ok(format!("{} urls", urls.len()))
+}
+
+fn trusted_proxies(value: &str) -> CheckResult {
+    Ok(format!("{} proxies", handshake::parse_proxies(value)?.len()))
+}
+
+fn cluster_redis_url(value: &str) -> CheckResult {
+    RedisUrl::parse(value)?;
+    match env::var("PUBLIC_URL") {
+        Ok(x) if !x.trim().is_empty() => Ok(format!("cluster node {}", x.trim())),
+        _ => Err("PUBLIC_URL must be set too".to_string()),
+    }
+}
+
+fn replication_primary(value: &str) -> CheckResult {
+    urls(value)?;
+    match env::var("REPLICATION_TOKEN") {
+        Ok(x) if !x.trim().is_empty() => Ok(format!("standby of {}", value)),
+        _ => Err("REPLICATION_TOKEN must be set too".to_string()),
+    }
+}
+
+fn client_timeout(value: &str) -> CheckResult {
+    let timeout = value.parse::<u64>().map_err(|_| format!("\"{}\" is not a valid number", value))?;
+    let interval = env::var("HEARTBEAT_INTERVAL_SECS").ok().and_then(|x| x.trim().parse().ok()).unwrap_or(5);
+    if timeout <= interval {
+        return Err(format!("{} isn't longer than the heartbeat interval ({})", timeout, interval));
+    }
+    Ok(timeout.to_string())
+}
+
+fn number<T: FromStr + ToString>(value: &str) -> CheckResult {
+    value.parse::<T>()
+        .map(|x| x.to_string())
+        .map_err(|_| format!("\"{}\" is not a valid number", value))
+}
+
+/// A limit of the rate_limit module, 0 disables it.
+fn limit(value: &str) -> CheckResult {
+    match value.parse::<f64>() {
+        Ok(x) if x >= 0.0 => Ok(value.to_string()),
+        _ => Err(format!("\"{}\" is not a positive number", value)),
+    }
+}
+
+fn rate(value: &str) -> CheckResult {
+    match value.parse::<f64>() {
+        Ok(x) if (0.0..=1.0).contains(&x) => Ok(value.to_string()),
+        _ => Err(format!("\"{}\" is not between 0 and 1", value)),
+    }
+}
+
+/// The files of the room traces are created there, see room_trace.
+fn room_trace_dir(value: &str) -> CheckResult {
+    match fs::metadata(value) {
+        Ok(x) if x.is_dir() && !x.permissions().readonly() => Ok(format!("room traces in {}", value)),
+        Ok(x) if x.is_dir() => Err(format!("{} is read only", value)),
+        _ => Err(format!("{} is not a directory", value)),
+    }
+}
+
+fn features_file(value: &str) -> CheckResult {
+    let flags = features::read_flags(Path::new(value))?;
+    let enabled = serde_json::to_value(flags).map_err(|e| e.to_string())?
+        .as_object()
+        .map(|x| x.iter().filter(|x| x.1 == true).map(|x| x.0.clone()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    Ok(format!("enabled: {}", if enabled.is_empty() { "none".to_string() } else { enabled.join(", ") }))
+}
+
+fn blocked_words_file(value: &str) -> CheckResult {
+    let data = fs::read_to_string(value).map_err(|e| e.to_string())?;
+    Ok(format!("{} words", data.lines().filter(|x| !x.trim().is_empty()).count()))
+}
+
+fn accounts_file(value: &str) -> CheckResult {
+    if !Path::new(value).exists() {
+        return Ok("created with the first account".to_string());
+    }
+    let file = accounts::read(Path::new(value))?;
+    let banned = file.accounts.values().filter(|x| x.banned).count();
+    Ok(format!("{} accounts, {} banned, {} banned addresses", file.accounts.len(), banned, file.banned_addresses.len()))
+}
+
+fn weights(value: &str) -> CheckResult {
+    let (_, errors) = Weights::parse(value);
+    if errors.is_empty() {
+        Ok(value.to_string())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn dev_room_prefix(value: &str) -> CheckResult {
+    DevRooms::parse(value, "")?;
+    match env::var("DEV_TOKENS") {
+        Ok(x) if !x.trim().is_empty() => Ok(format!("codes starting with {} reserved", value.to_ascii_uppercase())),
+        _ => Err("DEV_TOKENS must be set too, nobody could use the reserved rooms".to_string()),
+    }
+}
+
+fn log_level(value: &str) -> CheckResult {
+    value.parse::<LevelFilter>()
+        .map(|x| x.to_string())
+        .map_err(|_| format!("\"{}\" is not a level (error, warn, info, debug, trace)", value))
+}
+
+fn log_format(value: &str) -> CheckResult {
+    match value {
+        "json" => Ok(value.to_string()),
+        _ => Err(format!("\"{}\" is not a format, only json is (the readable one is the default)", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_values_are_reported() {
+        assert!(bind_addr("127.0.0.1:8081").is_ok());
+        assert!(bind_addr("127.0.0.1").is_err());
+        assert_eq!(urls("https://a.example, http://b.example/").unwrap(), "2 urls");
+        assert!(urls("https://a.example,b.example").is_err());
+        assert!(number::<u64>("30").is_ok());
+        assert!(number::<u64>("-1").is_err());
+        assert!(limit("0").is_ok());
+        assert!(rate("1.5").is_err());
+        assert!(weights("players=1,wait=0.5").is_ok());
+        assert_eq!(weights("players=x,speed=2").unwrap_err(), "invalid weight \"players=x\", unknown weight \"speed\"");
+        assert!(log_level("debug").is_ok());
+        assert!(log_level("verbose").is_err());
+    }
+}
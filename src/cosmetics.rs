@@ -0,0 +1,98 @@
+//!
+//! Catalog of the cosmetics that are earned: a few avatars and colors are unlocked by an
+//! achievement, a player can only pick them once it owns them. They have their own ids, above the
+//! values the clients already use (the colors are RGB): every avatar and color below them is free
+//! and accepted as before, the older clients don't change.
+//!
+//! The inventory is kept with the account (see accounts.rs): the lobby grants the items of the
+//! achievements as they're unlocked (see achievements.rs) and checks the cosmetics of the login,
+//! the connection of the player keeps a copy to check change_avatar and the logins that follow.
+//!
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Achievement, CatalogEntry, CosmeticItem, PlayerCosmetics};
+
+/// First id of the earned avatars.
+pub const EARNED_AVATARS: u32 = 1 << 31;
+/// First id of the earned colors, above every RGB color.
+pub const EARNED_COLORS: u64 = 1 << 32;
+
+pub const CATALOG: &[(CosmeticItem, Achievement)] = &[
+    (CosmeticItem::Avatar(EARNED_AVATARS), Achievement::FirstWin),
+    (CosmeticItem::Avatar(EARNED_AVATARS + 1), Achievement::RoadBuilder),
+    (CosmeticItem::Avatar(EARNED_AVATARS + 2), Achievement::CityPlanner),
+    (CosmeticItem::Color(EARNED_COLORS), Achievement::Veteran),
+    (CosmeticItem::Color(EARNED_COLORS + 1), Achievement::HighScore),
+];
+
+/// The ids reserved for the catalog are all locked, even the ones without an item yet.
+fn locked(item: CosmeticItem) -> bool {
+    match item {
+        CosmeticItem::Avatar(x) => x >= EARNED_AVATARS,
+        CosmeticItem::Color(x) => x >= EARNED_COLORS,
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Inventory {
+    unlocked: BTreeSet<CosmeticItem>,
+}
+
+impl Inventory {
+    /// False if the avatar or the color needs an achievement the player doesn't have.
+    pub fn owns(&self, cosmetics: &PlayerCosmetics) -> bool {
+        [CosmeticItem::Avatar(cosmetics.avatar), CosmeticItem::Color(cosmetics.color)].iter()
+            .all(|x| !locked(*x) || self.unlocked.contains(x))
+    }
+
+    /// Unlocks the items of the achievements just unlocked, returns the new ones.
+    pub fn grant(&mut self, achievements: &[Achievement]) -> Vec<CatalogEntry> {
+        let unlocked: Vec<CatalogEntry> = CATALOG.iter()
+            .filter(|(item, achievement)| !self.unlocked.contains(item) && achievements.contains(achievement))
+            .map(|(item, achievement)| CatalogEntry { item: *item, achievement: *achievement, owned: true })
+            .collect();
+        self.add(unlocked.iter().map(|x| x.item));
+        unlocked
+    }
+
+    pub fn add(&mut self, items: impl Iterator<Item = CosmeticItem>) {
+        self.unlocked.extend(items);
+    }
+
+    /// The whole catalog, with what the player owns.
+    pub fn catalog(&self) -> Vec<CatalogEntry> {
+        CATALOG.iter()
+            .map(|(item, achievement)| CatalogEntry { item: *item, achievement: *achievement, owned: self.unlocked.contains(item) })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn achievements_unlock_their_items() {
+        let mut inventory = Inventory::default();
+        assert!(inventory.owns(&PlayerCosmetics { avatar: 3, color: 0xff0000 }));
+        // The values the clients used before the catalog stay free.
+        assert!(inventory.owns(&PlayerCosmetics { avatar: 100, color: 101 }));
+        assert!(!inventory.owns(&PlayerCosmetics { avatar: EARNED_AVATARS, color: 1 }));
+        assert!(!inventory.owns(&PlayerCosmetics { avatar: 1, color: EARNED_COLORS + 9 }));
+
+        let unlocked: Vec<CosmeticItem> = inventory.grant(&[Achievement::RoadBuilder]).iter().map(|x| x.item).collect();
+        assert_eq!(unlocked, [CosmeticItem::Avatar(EARNED_AVATARS + 1)]);
+        // Some achievements have no item.
+        let unlocked: Vec<CosmeticItem> = inventory.grant(&[Achievement::Abbot, Achievement::HighScore, Achievement::FirstWin])
+            .iter().map(|x| x.item).collect();
+        assert_eq!(unlocked, [CosmeticItem::Avatar(EARNED_AVATARS), CosmeticItem::Color(EARNED_COLORS + 1)]);
+        assert!(inventory.grant(&[Achievement::FirstWin]).is_empty());
+        assert!(inventory.owns(&PlayerCosmetics { avatar: EARNED_AVATARS, color: EARNED_COLORS + 1 }));
+        assert!(!inventory.owns(&PlayerCosmetics { avatar: EARNED_AVATARS + 2, color: EARNED_COLORS + 1 }));
+        assert_eq!(inventory.catalog().iter().filter(|x| x.owned).count(), 3);
+    }
+}
@@ -0,0 +1,95 @@
+//!
+//! Room codes reserved to the developers, to run staging experiments on the production server
+//! without meeting real players. DEV_ROOM_PREFIX (1 to 3 characters of the room code alphabet)
+//! reserves the codes starting with it, DEV_TOKENS is the comma separated list of the tokens of the
+//! developer accounts, a client sends its token in the "devToken" of the login.
+//!
+//! A developer creates a reserved room with the "devRoom" option of room_create. Only developers
+//! can join, spectate or be invited to a reserved room, the other players get room_not_found as if
+//! it didn't exist. The reserved rooms are never public: room_find, the queue, room_list and the
+//! federation don't see them, and the other rooms never get a reserved code.
+//!
+
+use std::{collections::HashSet, env};
+
+use rand::Rng;
+use tracing::warn;
+
+use crate::protocol::LoginData;
+use crate::room_code;
+
+#[derive(Default)]
+pub struct DevRooms {
+    prefix: Option<String>,// None without a reserved namespace, nobody is a developer then
+    tokens: HashSet<String>,
+}
+
+impl DevRooms {
+    pub fn from_env() -> Self {
+        let prefix = env::var("DEV_ROOM_PREFIX").unwrap_or_default();
+        let tokens = env::var("DEV_TOKENS").unwrap_or_default();
+        DevRooms::parse(&prefix, &tokens).unwrap_or_else(|e| {
+            warn!(error = %e, "DEV_ROOM_PREFIX ignored, no room code is reserved");
+            DevRooms::default()
+        })
+    }
+
+    pub fn parse(prefix: &str, tokens: &str) -> Result<Self, String> {
+        let prefix = prefix.trim().to_ascii_uppercase();
+        if prefix.is_empty() {
+            return Ok(DevRooms::default());
+        }
+        if !room_code::is_prefix(&prefix) {
+            return Err(format!("\"{}\" is not 1 to 3 characters of the room codes", prefix));
+        }
+        Ok(DevRooms {
+            prefix: Some(prefix),
+            tokens: tokens.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).map(String::from).collect(),
+        })
+    }
+
+    pub fn is_developer(&self, details: &LoginData) -> bool {
+        self.prefix.is_some() && details.dev_token.as_ref().map(|x| self.tokens.contains(x)).unwrap_or(false)
+    }
+
+    pub fn is_reserved(&self, code: &str) -> bool {
+        self.prefix.as_ref().map(|x| code.starts_with(x.as_str())).unwrap_or(false)
+    }
+
+    /// False if the room is reserved and the player isn't a developer.
+    pub fn allows(&self, code: &str, details: &LoginData) -> bool {
+        !self.is_reserved(code) || self.is_developer(details)
+    }
+
+    /// A new room code, in the namespace or (most likely) out of it.
+    pub fn generate(&self, rng: &mut impl Rng, reserved: bool) -> String {
+        match (&self.prefix, reserved) {
+            (Some(prefix), true) => room_code::generate_with_prefix(rng, prefix),
+            _ => room_code::generate(rng),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_developers_enter_the_namespace() {
+        let rooms = DevRooms::parse("zz", "dev-1, dev-2").unwrap();
+        let player = |token: Option<&str>| LoginData { dev_token: token.map(String::from), ..LoginData::default() };
+        let code = rooms.generate(&mut rand::thread_rng(), true);
+        assert!(code.starts_with("ZZ") && room_code::normalize(&code).is_some());
+        assert!(rooms.is_reserved(&code));
+        assert!(rooms.allows(&code, &player(Some("dev-2"))));
+        assert!(!rooms.allows(&code, &player(Some("dev-3"))));
+        assert!(!rooms.allows(&code, &player(None)));
+        assert!(rooms.allows("XQ7KPM", &player(None)));
+
+        // Without a prefix there are no developers.
+        let rooms = DevRooms::parse("", "dev-1").unwrap();
+        assert!(!rooms.is_developer(&player(Some("dev-1"))));
+        assert!(DevRooms::parse("Z0", "").is_err());
+        assert!(DevRooms::parse("ZZZZ", "").is_err());
+    }
+}
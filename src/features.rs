@@ -0,0 +1,72 @@
+//!
+//! Per-deployment feature flags used to gate experimental subsystems.
+//!
+//! The flags are read from the JSON file pointed by the `FEATURES_FILE` env variable (every flag
+//! is off if it isn't set) and are reloaded by the server actor whenever the file changes.
+//!
+
+use std::{fs, path::{Path, PathBuf}, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FeatureFlags {
+    pub binary_protocol: bool,
+    pub authoritative_engine: bool,
+    pub clustering: bool,
+    pub room_rebalancing: bool,
+    pub relay_prefilter: bool,
+}
+
+pub struct FeatureConfig {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    pub flags: FeatureFlags,
+}
+
+impl FeatureConfig {
+    pub fn from_env() -> Self {
+        let mut config = FeatureConfig {
+            path: std::env::var_os("FEATURES_FILE").map(PathBuf::from),
+            modified: None,
+            flags: FeatureFlags::default(),
+        };
+        config.reload_if_changed();
+        config
+    }
+
+    /// Reloads the flags if the file has been modified, returns true if they changed.
+    /// If the file cannot be read or parsed the old flags are kept.
+    pub fn reload_if_changed(&mut self) -> bool {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return false,
+        };
+
+        let modified = fs::metadata(path).and_then(|x| x.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+
+        match read_flags(path) {
+            Ok(flags) => {
+                let changed = flags != self.flags;
+                self.flags = flags;
+                changed
+            },
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Cannot load the feature flags");
+                false
+            }
+        }
+    }
+}
+
+pub fn read_flags(path: &Path) -> Result<FeatureFlags, String> {
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|x| serde_json::from_str::<FeatureFlags>(&x).map_err(|e| e.to_string()))
+}
@@ -0,0 +1,81 @@
+//!
+//! Lightweight federation between independent instances: every instance exposes its public rooms
+//! on /federation/rooms and polls the lists of the peers in FEDERATION_PEERS (comma separated base
+//! urls), a player that can't find a room here is redirected to a room of a peer instead of waiting
+//! alone in a new one.
+//!
+//! PUBLIC_URL is the websocket url of this instance as seen by the players, the room list is only
+//! exposed when it's set.
+//!
+
+use std::env;
+
+use actix::prelude::*;
+use actix_web::{client::Client, HttpResponse, web};
+use serde::{Deserialize, Serialize};
+
+use crate::lobby_actor::{self, LobbyActor};
+use crate::protocol::SerId;
+use crate::room_actor::MAX_PLAYERS_PER_ROOM;
+
+pub struct FederationConfig {
+    pub public_url: Option<String>,
+    pub peers: Vec<String>,
+}
+
+impl FederationConfig {
+    pub fn from_env() -> Self {
+        let peers = env::var("FEDERATION_PEERS").unwrap_or_default()
+            .split(',')
+            .map(|x| x.trim().trim_end_matches('/').to_string())
+            .filter(|x| !x.is_empty())
+            .collect();
+
+        FederationConfig {
+            public_url: env::var("PUBLIC_URL").ok(),
+            peers,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomList {
+    pub url: String,// Where the players connect to join the rooms
+    pub rooms: Vec<PublicRoom>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicRoom {
+    pub room_id: SerId,
+    pub player_count: usize,
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,// The player limit of the room, missing in the lists of older peers
+}
+
+fn default_max_players() -> usize {
+    MAX_PLAYERS_PER_ROOM
+}
+
+/// Downloads the public rooms of a peer.
+pub async fn fetch_rooms(peer: String) -> Result<RoomList, String> {
+    let mut res = Client::default()
+        .get(format!("{}/federation/rooms", peer))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    res.json::<RoomList>().await.map_err(|e| e.to_string())
+}
+
+pub async fn rooms(lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    match lobby.send(lobby_actor::GetPublicRooms).await {
+        Ok(Some(list)) => HttpResponse::Ok().json(list),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,536 @@
+//!
+//! Authoritative state of a running game, used to validate the moves relayed by the clients.
+//!
+//! Only the moves described in RELAY_PROTOCOL.md are checked, every other relayed message is
+//! forwarded untouched. The board uses the base game tiles: a tile is identified by its letter
+//! and rotated clockwise by `rotation` quarter turns.
+//!
+
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{IdType, SerId};
+
+pub mod deck;
+#[cfg(test)]
+pub mod fixture;
+pub mod scoring;
+pub mod snapshot;
+
+use deck::Deck;
+use scoring::ScoredFeature;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Edge {
+    Field,
+    Road,
+    City,
+}
+
+pub struct TileKind {
+    pub name: &'static str,
+    pub edges: [Edge; 4],// North, east, south, west with no rotation
+    pub cloister: bool,
+    pub count: u8,
+}
+
+use Edge::{City as C, Field as F, Road as R};
+
+const fn tile(name: &'static str, edges: [Edge; 4], cloister: bool, count: u8) -> TileKind {
+    TileKind { name, edges, cloister, count }
+}
+
+pub const BASE_TILES: &[TileKind] = &[
+    tile("A", [F, F, R, F], true, 2),
+    tile("B", [F, F, F, F], true, 4),
+    tile("C", [C, C, C, C], false, 1),
+    tile("D", [C, R, F, R], false, 4),
+    tile("E", [C, F, F, F], false, 5),
+    tile("F", [F, C, F, C], false, 2),
+    tile("G", [C, F, C, F], false, 1),
+    tile("H", [F, C, F, C], false, 3),
+    tile("I", [F, C, C, F], false, 2),
+    tile("J", [C, R, R, F], false, 3),
+    tile("K", [R, C, F, R], false, 3),
+    tile("L", [R, C, R, R], false, 3),
+    tile("M", [C, F, F, C], false, 2),
+    tile("N", [C, F, F, C], false, 3),
+    tile("O", [C, R, R, C], false, 2),
+    tile("P", [C, R, R, C], false, 3),
+    tile("Q", [C, C, F, C], false, 1),
+    tile("R", [C, C, F, C], false, 3),
+    tile("S", [C, C, R, C], false, 2),
+    tile("T", [C, C, R, C], false, 1),
+    tile("U", [R, F, R, F], false, 8),
+    tile("V", [F, F, R, R], false, 9),
+    tile("W", [F, R, R, R], false, 4),
+    tile("X", [R, R, R, R], false, 1),
+];
+
+/// The tile already on the board (at 0, 0 with no rotation) when the game starts.
+pub const START_TILE: &str = "D";
+
+const NEIGHBOURS: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+/// Farthest a tile can be from the start tile on each axis, the tiles of a game don't get that far.
+const BOARD_LIMIT: i32 = 128;
+/// Supply of every player, a meeple comes back when its feature is scored.
+pub const MEEPLES_PER_PLAYER: usize = 7;
+
+/// Whether the position is within the bounds of the board, the neighbours of a tile are too.
+pub fn on_board(pos: (i32, i32)) -> bool {
+    (-BOARD_LIMIT..=BOARD_LIMIT).contains(&pos.0) && (-BOARD_LIMIT..=BOARD_LIMIT).contains(&pos.1)
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GameMove {
+    PlaceTile {
+        tile: String,
+        x: i32,
+        y: i32,
+        rotation: u8,
+    },
+    PlaceMeeple {
+        x: i32,
+        y: i32,
+        position: MeeplePosition,
+    },
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MeeplePosition {
+    North,
+    East,
+    South,
+    West,
+    Center,
+}
+
+/// The whole board, sent to the players that reconnect during a game.
+#[derive(Serialize, Clone)]
+pub struct BoardSnapshot {
+    pub tiles: Vec<TileSnapshot>,
+    pub meeples: Vec<MeepleSnapshot>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct TileSnapshot {
+    pub tile: &'static str,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: u8,
+}
+
+#[derive(Serialize, Clone)]
+pub struct MeepleSnapshot {
+    pub player: SerId,
+    pub x: i32,
+    pub y: i32,
+    pub position: MeeplePosition,
+}
+
+#[derive(Clone)]
+struct PlacedTile {
+    kind: usize,
+    rotation: u8,
+}
+
+impl PlacedTile {
+    /// Edge on the side (0 = north, clockwise) after the rotation is applied.
+    fn edge(&self, side: usize) -> Edge {
+        BASE_TILES[self.kind].edges[(side + 4 - self.rotation as usize) % 4]
+    }
+}
+
+#[derive(Clone)]
+pub struct GameState {
+    board: HashMap<(i32, i32), PlacedTile>,
+    deck: Vec<u8>,// Tiles left for each kind of BASE_TILES
+    meeples: HashMap<(i32, i32), (IdType, MeeplePosition)>,
+    last_placed: Option<((i32, i32), IdType)>,// The only tile that can receive a meeple
+    placed: Vec<(i32, i32)>,// Tiles placed since the game started on this server, in order
+    draws: Option<Deck>,// Set once the server draws the tiles
+    scores: HashMap<IdType, u32>,
+    scored: Vec<ScoredFeature>,// Not sent yet
+    completed_on_last: Vec<(scoring::Node, bool)>,// Features completed by the last tile, whether they had meeples
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        let mut game = GameState {
+            board: HashMap::new(),
+            deck: BASE_TILES.iter().map(|x| x.count).collect(),
+            meeples: HashMap::new(),
+            last_placed: None,
+            placed: Vec::new(),
+            draws: None,
+            scores: HashMap::new(),
+            scored: Vec::new(),
+            completed_on_last: Vec::new(),
+        };
+        let start = GameState::find_kind(START_TILE).unwrap();
+        game.deck[start] -= 1;
+        game.board.insert((0, 0), PlacedTile { kind: start, rotation: 0 });
+        game
+    }
+}
+
+impl GameState {
+    fn find_kind(name: &str) -> Option<usize> {
+        BASE_TILES.iter().position(|x| x.name == name)
+    }
+
+    /// Parses a relayed message, returns `Ok(None)` if it isn't a move the server knows about.
+    pub fn parse_move(data: &str) -> Result<Option<GameMove>, String> {
+        let value = serde_json::from_str::<serde_json::Value>(data).map_err(|e| e.to_string())?;
+
+        match value.get("type").and_then(|x| x.as_str()) {
+            Some("place_tile") | Some("place_meeple") => {
+                serde_json::from_value(value).map(Some).map_err(|e| e.to_string())
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn snapshot(&self) -> BoardSnapshot {
+        BoardSnapshot {
+            tiles: self.board.iter().map(|((x, y), tile)| TileSnapshot {
+                tile: BASE_TILES[tile.kind].name,
+                x: *x,
+                y: *y,
+                rotation: tile.rotation,
+            }).collect(),
+            meeples: self.meeples.iter().map(|((x, y), (player, position))| MeepleSnapshot {
+                player: (*player).into(),
+                x: *x,
+                y: *y,
+                position: *position,
+            }).collect(),
+        }
+    }
+
+    /// The tiles placed during the game (the start tile and the tiles of a loaded save excluded).
+    pub fn tile_sequence(&self) -> Vec<TileSnapshot> {
+        self.placed.iter().map(|pos| {
+            let tile = &self.board[pos];
+            TileSnapshot {
+                tile: BASE_TILES[tile.kind].name,
+                x: pos.0,
+                y: pos.1,
+                rotation: tile.rotation,
+            }
+        }).collect()
+    }
+
+    /// Applies the move if it's legal, otherwise the game state is left untouched.
+    pub fn apply(&mut self, player: IdType, mv: GameMove) -> Result<(), &'static str> {
+        match mv {
+            GameMove::PlaceTile { tile, x, y, rotation } => self.place_tile(player, &tile, (x, y), rotation),
+            GameMove::PlaceMeeple { x, y, position } => self.place_meeple(player, (x, y), position),
+        }
+    }
+
+    /// Ends the turn without a move, the tile just placed can't receive a meeple anymore.
+    pub fn skip_turn(&mut self) {
+        self.last_placed = None;
+        if let Some(draws) = &mut self.draws {
+            draws.put_back();
+            self.draw();
+        }
+    }
+
+    /// Shuffles the tiles left and draws the first one, from now on the players (in this turn order)
+    /// can only place the tile drawn for them.
+    pub fn start_draws(&mut self, players: Vec<IdType>, rng: &mut impl Rng) {
+        self.draws = Some(Deck::shuffle(&self.deck, players, rng));
+        self.draw();
+    }
+
+    /// The player that has to play and the tile drawn for it, `None` if the server doesn't draw the
+    /// tiles or the deck is empty.
+    pub fn drawn(&self) -> Option<(IdType, &'static str)> {
+        let drawn = self.draws.as_ref()?.drawn()?;
+        Some((drawn.player, BASE_TILES[drawn.kind].name))
+    }
+
+    /// Tiles still in the deck, the one drawn excluded.
+    pub fn tiles_left(&self) -> usize {
+        let count: usize = self.deck.iter().map(|x| *x as usize).sum();
+        count - self.drawn().is_some() as usize
+    }
+
+    /// Removes a player that left from the turn order, returns true if a tile was drawn for the
+    /// next player.
+    pub fn remove_player(&mut self, player: IdType) -> bool {
+        let draws = match &mut self.draws {
+            Some(x) => x,
+            None => return false,
+        };
+        if !draws.remove_player(player) {
+            return false;
+        }
+        self.draw();
+        true
+    }
+
+    /// Gives the meeples and the points of a player to another id, for a game moved to another
+    /// instance where its players logged in again (see migration.rs).
+    pub fn rename_player(&mut self, from: IdType, to: IdType) {
+        for meeple in self.meeples.values_mut().filter(|x| x.0 == from) {
+            meeple.0 = to;
+        }
+        if let Some(last) = self.last_placed.as_mut().filter(|x| x.1 == from) {
+            last.1 = to;
+        }
+        if let Some(points) = self.scores.remove(&from) {
+            self.scores.insert(to, points);
+        }
+    }
+
+    /// The points of a player, the snapshots don't keep them.
+    pub fn set_score(&mut self, player: IdType, points: u32) {
+        self.scores.insert(player, points);
+    }
+
+    /// Draws the tile of the next turn, discarding the ones that fit nowhere.
+    fn draw(&mut self) {
+        let draws = match &mut self.draws {
+            Some(x) => x,
+            None => return,
+        };
+        let board = &self.board;
+        let deck = &mut self.deck;
+        draws.draw(|kind| {
+            let placeable = GameState::fits_anywhere(board, kind);
+            if !placeable {
+                deck[kind] -= 1;
+            }
+            placeable
+        });
+    }
+
+    fn fits_anywhere(board: &HashMap<(i32, i32), PlacedTile>, kind: usize) -> bool {
+        GameState::placements_of(board, kind).next().is_some()
+    }
+
+    /// Positions and rotations where a tile of the kind fits, a position can be listed more than once.
+    fn placements_of(board: &HashMap<(i32, i32), PlacedTile>, kind: usize) -> impl Iterator<Item = ((i32, i32), u8)> + '_ {
+        board.keys()
+            .flat_map(|(x, y)| NEIGHBOURS.iter().map(move |(dx, dy)| (x + dx, y + dy)))
+            .filter(move |pos| !board.contains_key(pos))
+            .flat_map(|pos| (0..4).map(move |rotation| (pos, rotation)))
+            .filter(move |(pos, rotation)| GameState::fits(board, *pos, &PlacedTile { kind, rotation: *rotation }).is_ok())
+    }
+
+    /// Every position and rotation where the tile fits on the board.
+    pub fn placements(&self, tile: &str) -> Vec<((i32, i32), u8)> {
+        let kind = match GameState::find_kind(tile) {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let mut placements: Vec<_> = GameState::placements_of(&self.board, kind).collect();
+        placements.sort_unstable();
+        placements.dedup();
+        placements
+    }
+
+    /// Meeples of the player still on the board.
+    pub fn meeples_of(&self, player: IdType) -> usize {
+        self.meeples.values().filter(|x| x.0 == player).count()
+    }
+
+    /// Meeples the player can still place.
+    pub fn meeples_left(&self, player: IdType) -> usize {
+        MEEPLES_PER_PLAYER.saturating_sub(self.meeples_of(player))
+    }
+
+    /// Checks the edges of a tile against its neighbours.
+    fn fits(board: &HashMap<(i32, i32), PlacedTile>, pos: (i32, i32), placed: &PlacedTile) -> Result<(), &'static str> {
+        if !GameState::edges_match(board, pos, placed) {
+            return Err("Tile edges do not match");
+        }
+        if !NEIGHBOURS.iter().any(|(dx, dy)| board.contains_key(&(pos.0 + dx, pos.1 + dy))) {
+            return Err("Tile must be adjacent to another tile");
+        }
+        Ok(())
+    }
+
+    fn edges_match(board: &HashMap<(i32, i32), PlacedTile>, pos: (i32, i32), placed: &PlacedTile) -> bool {
+        NEIGHBOURS.iter().enumerate().all(|(side, (dx, dy))| {
+            board.get(&(pos.0 + dx, pos.1 + dy)).map(|x| x.edge((side + 2) % 4) == placed.edge(side)).unwrap_or(true)
+        })
+    }
+
+    /// The players of a loaded game that aren't among the players given: the owners of its meeples
+    /// and of its last tile.
+    pub fn unknown_players(&self, players: &[IdType]) -> Vec<IdType> {
+        let mut unknown: Vec<IdType> = self.meeples.values().map(|x| x.0)
+            .chain(self.last_placed.map(|x| x.1))
+            .filter(|x| !players.contains(x))
+            .collect();
+        unknown.sort_unstable();
+        unknown.dedup();
+        unknown
+    }
+
+    fn place_tile(&mut self, player: IdType, tile: &str, pos: (i32, i32), rotation: u8) -> Result<(), &'static str> {
+        if rotation >= 4 {
+            return Err("Invalid rotation");
+        }
+        let kind = GameState::find_kind(tile).ok_or("Unknown tile")?;
+        if self.deck[kind] == 0 {
+            return Err("No tile of this kind left in the deck");
+        }
+        if let Some(draws) = &self.draws {
+            let drawn = draws.drawn().ok_or("No tile left in the deck")?;
+            if drawn.player != player {
+                return Err("Not your turn");
+            }
+            if drawn.kind != kind {
+                return Err("Not the tile drawn");
+            }
+        }
+        if !on_board(pos) {
+            return Err("Position outside of the board");
+        }
+        if self.board.contains_key(&pos) {
+            return Err("Position already occupied");
+        }
+
+        let placed = PlacedTile { kind, rotation };
+        GameState::fits(&self.board, pos, &placed)?;
+
+        self.deck[kind] -= 1;
+        self.board.insert(pos, placed);
+        self.placed.push(pos);
+        self.last_placed = Some((pos, player));
+        self.score_completed(pos);
+        self.draw();
+        Ok(())
+    }
+
+    fn place_meeple(&mut self, player: IdType, pos: (i32, i32), position: MeeplePosition) -> Result<(), &'static str> {
+        if self.last_placed != Some((pos, player)) {
+            return Err("Meeples can only be placed on the tile just placed");
+        }
+        if self.meeples.contains_key(&pos) {
+            return Err("The tile already has a meeple");
+        }
+        if self.meeples_left(player) == 0 {
+            return Err("No meeple left");
+        }
+        if position == MeeplePosition::Center && !BASE_TILES[self.board[&pos].kind].cloister {
+            return Err("Only cloisters have a center");
+        }
+
+        if self.claim(player, pos, position)? {
+            self.meeples.insert(pos, (player, position));
+        }
+        self.last_placed = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::GameStateBuilder;
+    use super::*;
+
+    fn place_tile(tile: &str, x: i32, y: i32, rotation: u8) -> GameMove {
+        GameMove::PlaceTile { tile: tile.to_string(), x, y, rotation }
+    }
+
+    #[test]
+    fn tile_must_match_the_neighbours() {
+        let mut game = GameStateBuilder::new().build();
+
+        // D has a city on the north side, E rotated twice has it on the south side.
+        assert_eq!(game.apply(1, place_tile("E", 0, -1, 1)), Err("Tile edges do not match"));
+        assert_eq!(game.apply(1, place_tile("E", 0, -1, 2)), Ok(()));
+    }
+
+    #[test]
+    fn tile_must_touch_the_board() {
+        let mut game = GameStateBuilder::new().build();
+
+        assert_eq!(game.apply(1, place_tile("E", 0, -2, 2)), Err("Tile must be adjacent to another tile"));
+        assert_eq!(game.apply(1, place_tile("D", 0, 0, 0)), Err("Position already occupied"));
+
+        let mut empty = GameStateBuilder::empty().build();
+        assert_eq!(empty.apply(1, place_tile("D", 0, 0, 0)), Err("Tile must be adjacent to another tile"));
+    }
+
+    #[test]
+    fn tile_must_be_in_the_deck() {
+        let mut game = GameStateBuilder::new().deck("E", 0).build();
+
+        assert_eq!(game.apply(1, place_tile("E", 0, -1, 2)), Err("No tile of this kind left in the deck"));
+    }
+
+    #[test]
+    fn meeple_only_on_the_tile_just_placed() {
+        let mut game = GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .tile("B", (1, 0), 0)
+            .last_placed((1, 0), 1)
+            .build();
+
+        let meeple = |x, y, position| GameMove::PlaceMeeple { x, y, position };
+        assert!(game.apply(2, meeple(1, 0, MeeplePosition::Center)).is_err());
+        assert!(game.apply(1, meeple(0, -1, MeeplePosition::South)).is_err());
+        assert!(game.apply(1, meeple(1, 0, MeeplePosition::Center)).is_ok());
+        assert!(game.apply(1, meeple(1, 0, MeeplePosition::North)).is_err());
+    }
+
+    #[test]
+    fn only_the_tile_drawn() {
+        let mut game = GameStateBuilder::new().build();
+        game.start_draws(vec![1, 2], &mut rand::thread_rng());
+        let (player, tile) = game.drawn().unwrap();
+        assert_eq!(player, 1);
+
+        let other = BASE_TILES.iter().find(|x| x.name != tile).unwrap().name;
+        assert_eq!(game.apply(2, place_tile(tile, 0, -1, 0)), Err("Not your turn"));
+        assert_eq!(game.apply(1, place_tile(other, 0, -1, 0)), Err("Not the tile drawn"));
+
+        let left = game.tiles_left();
+        game.skip_turn();
+        assert_eq!(game.drawn().map(|x| x.0), Some(2));
+        assert_eq!(game.tiles_left(), left);
+    }
+
+    #[test]
+    fn center_only_on_cloisters() {
+        let mut game = GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .last_placed((0, -1), 1)
+            .build();
+
+        let center = GameMove::PlaceMeeple { x: 0, y: -1, position: MeeplePosition::Center };
+        assert_eq!(game.apply(1, center), Err("Only cloisters have a center"));
+    }
+
+    #[test]
+    fn tile_must_be_on_the_board() {
+        let mut game = GameStateBuilder::new().build();
+
+        assert_eq!(game.apply(1, place_tile("E", i32::MAX, 0, 2)), Err("Position outside of the board"));
+        assert_eq!(game.apply(1, place_tile("E", 0, i32::MIN, 2)), Err("Position outside of the board"));
+    }
+
+    #[test]
+    fn meeples_run_out() {
+        let mut builder = GameStateBuilder::new().tile("E", (0, -1), 2);
+        for x in 1..=MEEPLES_PER_PLAYER as i32 {
+            builder = builder.tile("B", (x, 5), 0).meeple((x, 5), 1, MeeplePosition::Center);
+        }
+        let mut game = builder.last_placed((0, -1), 1).build();
+
+        let meeple = GameMove::PlaceMeeple { x: 0, y: -1, position: MeeplePosition::South };
+        assert_eq!(game.meeples_left(1), 0);
+        assert_eq!(game.apply(1, meeple), Err("No meeple left"));
+    }
+}
@@ -0,0 +1,140 @@
+//!
+//! Deck drawn by the server: the tiles left are shuffled when the game starts and the server draws
+//! the tile of every turn, so a client can't choose the tile it plays. The players take turns in
+//! the order given at the start.
+//!
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::protocol::IdType;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Drawn {
+    pub player: IdType,
+    pub kind: usize,// In BASE_TILES
+}
+
+#[derive(Clone)]
+pub struct Deck {
+    tiles: Vec<usize>,// Kinds of BASE_TILES, the top of the deck is the end
+    players: Vec<IdType>,// Turn order
+    next: usize,// Index in players of the next player to draw
+    drawn: Option<Drawn>,
+}
+
+impl Deck {
+    /// Shuffles `counts[kind]` tiles of every kind.
+    pub fn shuffle(counts: &[u8], players: Vec<IdType>, rng: &mut impl Rng) -> Deck {
+        let mut tiles: Vec<usize> = counts.iter().enumerate()
+            .flat_map(|(kind, count)| std::iter::repeat_n(kind, *count as usize))
+            .collect();
+        tiles.shuffle(rng);
+        Deck { tiles, players, next: 0, drawn: None }
+    }
+
+    /// The tile the current player has to place.
+    pub fn drawn(&self) -> Option<Drawn> {
+        self.drawn
+    }
+
+    /// Draws the tile of the next player, the tiles refused by `placeable` (they fit nowhere on the
+    /// board) are discarded. `None` once the deck is empty.
+    pub fn draw(&mut self, mut placeable: impl FnMut(usize) -> bool) -> Option<Drawn> {
+        self.drawn = None;
+        if self.players.is_empty() {
+            return None;
+        }
+        while let Some(kind) = self.tiles.pop() {
+            if placeable(kind) {
+                self.drawn = Some(Drawn { player: self.players[self.next], kind });
+                self.next = (self.next + 1) % self.players.len();
+                break;
+            }
+        }
+        self.drawn
+    }
+
+    /// The turn is skipped, the tile drawn goes back under the deck.
+    pub fn put_back(&mut self) {
+        if let Some(drawn) = self.drawn.take() {
+            self.tiles.insert(0, drawn.kind);
+        }
+    }
+
+    /// Removes a player from the turn order, returns true if it was its turn: its tile is back on
+    /// top of the deck for the next player.
+    pub fn remove_player(&mut self, id: IdType) -> bool {
+        let index = match self.players.iter().position(|x| *x == id) {
+            Some(x) => x,
+            None => return false,
+        };
+        self.players.remove(index);
+        if index < self.next {
+            self.next -= 1;
+        }
+        if self.next >= self.players.len() {
+            self.next = 0;
+        }
+
+        match self.drawn {
+            Some(drawn) if drawn.player == id => {
+                self.tiles.push(drawn.kind);
+                self.drawn = None;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deck(tiles: Vec<usize>, players: Vec<IdType>) -> Deck {
+        Deck { tiles, players, next: 0, drawn: None }
+    }
+
+    #[test]
+    fn players_take_turns() {
+        let mut deck = deck(vec![3, 2, 1], vec![10, 20]);
+
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 10, kind: 1 }));
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 20, kind: 2 }));
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 10, kind: 3 }));
+        assert_eq!(deck.draw(|_| true), None);
+    }
+
+    #[test]
+    fn unplaceable_tiles_are_discarded() {
+        let mut deck = deck(vec![3, 2, 1], vec![10]);
+
+        assert_eq!(deck.draw(|kind| kind != 1), Some(Drawn { player: 10, kind: 2 }));
+        assert_eq!(deck.tiles, [3]);
+    }
+
+    #[test]
+    fn skipped_and_leaving_players() {
+        let mut deck = deck(vec![3, 2, 1], vec![10, 20, 30]);
+
+        deck.draw(|_| true);
+        deck.put_back();
+        assert_eq!(deck.drawn(), None);
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 20, kind: 2 }));
+
+        // The tile of the player that leaves goes to the next one.
+        assert!(deck.remove_player(20));
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 30, kind: 2 }));
+        assert!(!deck.remove_player(10));
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 30, kind: 3 }));
+        assert_eq!(deck.draw(|_| true), Some(Drawn { player: 30, kind: 1 }));
+    }
+
+    #[test]
+    fn shuffle_keeps_every_tile() {
+        let deck = Deck::shuffle(&[2, 0, 3], vec![1], &mut rand::thread_rng());
+        let mut tiles = deck.tiles.clone();
+        tiles.sort_unstable();
+        assert_eq!(tiles, [0, 0, 2, 2, 2]);
+    }
+}
@@ -0,0 +1,59 @@
+//!
+//! Builder for arbitrary game states, used by the tests of the rules.
+//!
+//! The tiles and meeples are put on the board as they are, without checking the rules, so that a
+//! test can start from any position (even an illegal one) and check the next move.
+//!
+
+use super::{GameState, MeeplePosition, PlacedTile};
+use crate::protocol::IdType;
+
+#[derive(Default)]
+pub struct GameStateBuilder {
+    game: GameState,
+}
+
+impl GameStateBuilder {
+    /// Starts from a new game: the start tile on the board and the rest of the deck.
+    pub fn new() -> Self {
+        GameStateBuilder::default()
+    }
+
+    /// Starts from an empty board with the full deck.
+    pub fn empty() -> Self {
+        let mut builder = GameStateBuilder::new();
+        builder.game.board.clear();
+        builder.game.deck = super::BASE_TILES.iter().map(|x| x.count).collect();
+        builder
+    }
+
+    /// Puts a tile on the board, the tile is taken from the deck if there's one left.
+    pub fn tile(mut self, name: &str, pos: (i32, i32), rotation: u8) -> Self {
+        let kind = GameState::find_kind(name).expect("unknown tile");
+        self.game.deck[kind] = self.game.deck[kind].saturating_sub(1);
+        self.game.board.insert(pos, PlacedTile { kind, rotation: rotation % 4 });
+        self
+    }
+
+    pub fn meeple(mut self, pos: (i32, i32), player: IdType, position: MeeplePosition) -> Self {
+        self.game.meeples.insert(pos, (player, position));
+        self
+    }
+
+    /// Sets how many tiles of a kind are left in the deck.
+    pub fn deck(mut self, name: &str, left: u8) -> Self {
+        let kind = GameState::find_kind(name).expect("unknown tile");
+        self.game.deck[kind] = left;
+        self
+    }
+
+    /// Marks the tile as just placed by the player, who can put a meeple on it.
+    pub fn last_placed(mut self, pos: (i32, i32), player: IdType) -> Self {
+        self.game.last_placed = Some((pos, player));
+        self
+    }
+
+    pub fn build(self) -> GameState {
+        self.game
+    }
+}
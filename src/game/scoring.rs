@@ -0,0 +1,445 @@
+//!
+//! Scoring of the features of the board: roads, cities and cloisters are scored when they're
+//! completed, the unfinished ones and the farms at the end of the game. The players with the most
+//! meeples on a feature receive its points.
+//!
+//! The edges of a tile don't say how its parts are connected (the cities of `F` are one city, the
+//! ones of `H` two), so every kind of tile lists its features. A field is described by the halves
+//! of the edges it touches, the ports, clockwise from the north-west corner: 0 and 1 on the north
+//! edge, 2 and 3 on the east edge, 4 and 5 on the south edge, 6 and 7 on the west edge.
+//!
+//! The meeples stand on the feature of the edge of their (board) position, a farmer on a field edge.
+//!
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::Serialize;
+
+use super::{GameState, MeeplePosition, PlacedTile, NEIGHBOURS};
+use crate::protocol::{IdType, SerId};
+
+struct TileFeatures {
+    cities: &'static [(&'static [usize], bool)],// Sides of every city and whether it has a pennant
+    roads: &'static [&'static [usize]],
+    fields: &'static [(&'static [usize], &'static [usize])],// Ports of every field and the cities it touches
+}
+
+const fn features(
+    cities: &'static [(&'static [usize], bool)],
+    roads: &'static [&'static [usize]],
+    fields: &'static [(&'static [usize], &'static [usize])],
+) -> TileFeatures {
+    TileFeatures { cities, roads, fields }
+}
+
+/// Features of every kind of BASE_TILES, in the same order.
+const TILE_FEATURES: &[TileFeatures] = &[
+    features(&[], &[&[2]], &[(&[0, 1, 2, 3, 4, 5, 6, 7], &[])]),// A
+    features(&[], &[], &[(&[0, 1, 2, 3, 4, 5, 6, 7], &[])]),// B
+    features(&[(&[0, 1, 2, 3], true)], &[], &[]),// C
+    features(&[(&[0], false)], &[&[1, 3]], &[(&[2, 7], &[0]), (&[3, 4, 5, 6], &[])]),// D
+    features(&[(&[0], false)], &[], &[(&[2, 3, 4, 5, 6, 7], &[0])]),// E
+    features(&[(&[1, 3], true)], &[], &[(&[0, 1], &[0]), (&[4, 5], &[0])]),// F
+    features(&[(&[0, 2], false)], &[], &[(&[2, 3], &[0]), (&[6, 7], &[0])]),// G
+    features(&[(&[1], false), (&[3], false)], &[], &[(&[0, 1, 4, 5], &[0, 1])]),// H
+    features(&[(&[1], false), (&[2], false)], &[], &[(&[0, 1, 6, 7], &[0, 1])]),// I
+    features(&[(&[0], false)], &[&[1, 2]], &[(&[2, 5, 6, 7], &[0]), (&[3, 4], &[])]),// J
+    features(&[(&[1], false)], &[&[0, 3]], &[(&[0, 7], &[]), (&[1, 4, 5, 6], &[0])]),// K
+    features(&[(&[1], false)], &[&[0], &[2], &[3]], &[(&[0, 7], &[]), (&[5, 6], &[]), (&[1, 4], &[0])]),// L
+    features(&[(&[0, 3], true)], &[], &[(&[2, 3, 4, 5], &[0])]),// M
+    features(&[(&[0, 3], false)], &[], &[(&[2, 3, 4, 5], &[0])]),// N
+    features(&[(&[0, 3], true)], &[&[1, 2]], &[(&[2, 5], &[0]), (&[3, 4], &[])]),// O
+    features(&[(&[0, 3], false)], &[&[1, 2]], &[(&[2, 5], &[0]), (&[3, 4], &[])]),// P
+    features(&[(&[0, 1, 3], true)], &[], &[(&[4, 5], &[0])]),// Q
+    features(&[(&[0, 1, 3], false)], &[], &[(&[4, 5], &[0])]),// R
+    features(&[(&[0, 1, 3], true)], &[&[2]], &[(&[4], &[0]), (&[5], &[0])]),// S
+    features(&[(&[0, 1, 3], false)], &[&[2]], &[(&[4], &[0]), (&[5], &[0])]),// T
+    features(&[], &[&[0, 2]], &[(&[1, 2, 3, 4], &[]), (&[5, 6, 7, 0], &[])]),// U
+    features(&[], &[&[2, 3]], &[(&[0, 1, 2, 3, 4, 7], &[]), (&[5, 6], &[])]),// V
+    features(&[], &[&[1], &[2], &[3]], &[(&[7, 0, 1, 2], &[]), (&[3, 4], &[]), (&[5, 6], &[])]),// W
+    features(&[], &[&[0], &[1], &[2], &[3]], &[(&[1, 2], &[]), (&[3, 4], &[]), (&[5, 6], &[]), (&[7, 0], &[])]),// X
+];
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureKind {
+    City,
+    Road,
+    Cloister,
+    Farm,
+}
+
+/// A feature of a tile: its position, its kind and its index in the features of the tile.
+pub(super) type Node = ((i32, i32), FeatureKind, usize);
+
+/// Points given to the players with the most meeples on a feature.
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct ScoredFeature {
+    pub feature: FeatureKind,
+    pub points: u32,
+    pub players: Vec<SerId>,
+    #[serde(skip)]
+    pub tiles: u32,// Size of the feature, for the statistics of the players (see player_stats.rs)
+    #[serde(skip)]
+    pub complete: bool,// Unless scored at the end of the game, for the achievements
+}
+
+/// A connected feature of the board.
+struct Feature {
+    kind: FeatureKind,
+    nodes: BTreeSet<Node>,
+    complete: bool,
+}
+
+impl Feature {
+    fn tiles(&self) -> u32 {
+        self.nodes.iter().map(|x| x.0).collect::<HashSet<_>>().len() as u32
+    }
+
+    fn pennants(&self, board: &HashMap<(i32, i32), PlacedTile>) -> u32 {
+        self.nodes.iter().filter(|(pos, _, i)| TILE_FEATURES[board[pos].kind].cities[*i].1).count() as u32
+    }
+
+    /// Canonical key of the feature, its smallest node.
+    fn key(&self) -> Node {
+        *self.nodes.iter().next().unwrap()
+    }
+}
+
+fn neighbour(pos: (i32, i32), side: usize) -> (i32, i32) {
+    (pos.0 + NEIGHBOURS[side].0, pos.1 + NEIGHBOURS[side].1)
+}
+
+/// Feature of a tile touching the (board) side.
+fn node_on_side(board: &HashMap<(i32, i32), PlacedTile>, pos: (i32, i32), side: usize) -> Option<Node> {
+    let tile = board.get(&pos)?;
+    let features = &TILE_FEATURES[tile.kind];
+    let local = (side + 4 - tile.rotation as usize) % 4;
+    if let Some(i) = features.cities.iter().position(|x| x.0.contains(&local)) {
+        return Some((pos, FeatureKind::City, i));
+    }
+    if let Some(i) = features.roads.iter().position(|x| x.contains(&local)) {
+        return Some((pos, FeatureKind::Road, i));
+    }
+    node_on_port(board, pos, side * 2)
+}
+
+fn node_on_port(board: &HashMap<(i32, i32), PlacedTile>, pos: (i32, i32), port: usize) -> Option<Node> {
+    let tile = board.get(&pos)?;
+    let local = (port + 8 - 2 * tile.rotation as usize) % 8;
+    TILE_FEATURES[tile.kind].fields.iter().position(|x| x.0.contains(&local)).map(|i| (pos, FeatureKind::Farm, i))
+}
+
+fn meeple_node(board: &HashMap<(i32, i32), PlacedTile>, pos: (i32, i32), position: MeeplePosition) -> Option<Node> {
+    let side = match position {
+        MeeplePosition::North => 0,
+        MeeplePosition::East => 1,
+        MeeplePosition::South => 2,
+        MeeplePosition::West => 3,
+        MeeplePosition::Center => return Some((pos, FeatureKind::Cloister, 0)),
+    };
+    node_on_side(board, pos, side)
+}
+
+/// The whole feature a node belongs to.
+fn collect(board: &HashMap<(i32, i32), PlacedTile>, start: Node) -> Feature {
+    let (_, kind, _) = start;
+    if kind == FeatureKind::Cloister {
+        let complete = (-1..=1).all(|dx| (-1..=1).all(|dy| board.contains_key(&(start.0.0 + dx, start.0.1 + dy))));
+        return Feature { kind, nodes: std::iter::once(start).collect(), complete };
+    }
+
+    let mut nodes = BTreeSet::new();
+    let mut complete = true;
+    let mut todo = vec![start];
+    while let Some(node) = todo.pop() {
+        if !nodes.insert(node) {
+            continue;
+        }
+        let (pos, _, i) = node;
+        let tile = &board[&pos];
+        let features = &TILE_FEATURES[tile.kind];
+        let rotation = tile.rotation as usize;
+
+        if kind == FeatureKind::Farm {
+            for port in features.fields[i].0.iter() {
+                let port = (port + 2 * rotation) % 8;
+                let (side, half) = (port / 2, port % 2);
+                let next = 2 * ((side + 2) % 4) + 1 - half;
+                if let Some(x) = node_on_port(board, neighbour(pos, side), next) {
+                    todo.push(x);
+                }
+            }
+            continue;
+        }
+
+        let sides = if kind == FeatureKind::City { features.cities[i].0 } else { features.roads[i] };
+        for side in sides.iter().map(|x| (x + rotation) % 4) {
+            match node_on_side(board, neighbour(pos, side), (side + 2) % 4) {
+                Some(x) => todo.push(x),
+                None => complete = false,
+            }
+        }
+    }
+    Feature { kind, nodes, complete }
+}
+
+/// The players with the most meeples on the feature.
+fn winners(meeples: &[(IdType, Node)], feature: &Feature) -> Vec<SerId> {
+    let mut count: HashMap<IdType, usize> = HashMap::new();
+    for (player, _) in meeples.iter().filter(|(_, node)| feature.nodes.contains(node)) {
+        *count.entry(*player).or_default() += 1;
+    }
+    let max = count.values().copied().max().unwrap_or(0);
+    let mut players: Vec<SerId> = count.into_iter().filter(|x| x.1 == max).map(|x| x.0.into()).collect();
+    players.sort_by_key(|x| x.0);
+    players
+}
+
+impl GameState {
+    fn meeple_nodes(&self) -> Vec<(IdType, Node)> {
+        self.meeples.iter()
+            .filter_map(|(pos, (player, position))| Some((*player, meeple_node(&self.board, *pos, *position)?)))
+            .collect()
+    }
+
+    fn completed_points(&self, feature: &Feature) -> u32 {
+        match feature.kind {
+            FeatureKind::City => 2 * (feature.tiles() + feature.pennants(&self.board)),
+            FeatureKind::Road => feature.tiles(),
+            FeatureKind::Cloister => 9,
+            FeatureKind::Farm => 0,
+        }
+    }
+
+    /// Scores the feature for the players with the most meeples on it, their meeples go back to them.
+    fn score(&mut self, feature: &Feature, points: u32, meeples: &[(IdType, Node)]) {
+        let players = winners(meeples, feature);
+        if players.is_empty() {
+            return;
+        }
+        for player in players.iter() {
+            *self.scores.entry(player.0).or_default() += points;
+        }
+        let board = &self.board;
+        self.meeples.retain(|pos, (_, position)| {
+            meeple_node(board, *pos, *position).map(|x| !feature.nodes.contains(&x)).unwrap_or(true)
+        });
+        // A farm is only scored at the end, it's never complete.
+        let complete = feature.complete && feature.kind != FeatureKind::Farm;
+        self.scored.push(ScoredFeature { feature: feature.kind, points, players, tiles: feature.tiles(), complete });
+    }
+
+    /// Scores the features completed by the tile just placed. The ones of the tile are remembered
+    /// for its meeple, with whether they had meeples.
+    pub(super) fn score_completed(&mut self, pos: (i32, i32)) {
+        let mut starts: Vec<Node> = (0..4).filter_map(|side| node_on_side(&self.board, pos, side))
+            .filter(|x| x.1 != FeatureKind::Farm)
+            .collect();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let around = (pos.0 + dx, pos.1 + dy);
+                if self.board.get(&around).map(|x| super::BASE_TILES[x.kind].cloister).unwrap_or(false) {
+                    starts.push((around, FeatureKind::Cloister, 0));
+                }
+            }
+        }
+
+        let meeples = self.meeple_nodes();
+        let mut done = HashSet::new();
+        self.completed_on_last.clear();
+        for start in starts {
+            let feature = collect(&self.board, start);
+            if !feature.complete || !done.insert(feature.key()) {
+                continue;
+            }
+            if feature.nodes.iter().any(|x| x.0 == pos) {
+                let occupied = meeples.iter().any(|(_, node)| feature.nodes.contains(node));
+                self.completed_on_last.push((feature.key(), occupied));
+            }
+            let points = self.completed_points(&feature);
+            self.score(&feature, points, &meeples);
+        }
+    }
+
+    /// Checks that a meeple can stand on the feature and scores it right away if the tile just
+    /// completed it, returns false if the meeple has already gone back to the player.
+    pub(super) fn claim(&mut self, player: IdType, pos: (i32, i32), position: MeeplePosition) -> Result<bool, &'static str> {
+        let node = meeple_node(&self.board, pos, position).ok_or("No feature there")?;
+        let feature = collect(&self.board, node);
+        if let Some((_, occupied)) = self.completed_on_last.iter().find(|(key, _)| *key == feature.key()) {
+            if *occupied {
+                return Err("The feature is already taken");
+            }
+            let points = self.completed_points(&feature);
+            self.score(&feature, points, &[(player, node)]);
+            return Ok(false);
+        }
+        if self.meeple_nodes().iter().any(|(_, x)| feature.nodes.contains(x)) {
+            return Err("The feature is already taken");
+        }
+        Ok(true)
+    }
+
+    /// Scores the features left with meeples at the end of the game: the unfinished roads, cities
+    /// and cloisters and, unless `farms` is false, the farms (3 points per completed city they touch).
+    pub fn score_end(&mut self, farms: bool) -> Vec<ScoredFeature> {
+        let meeples = self.meeple_nodes();
+        let mut done = HashSet::new();
+        for (_, node) in meeples.iter() {
+            let feature = collect(&self.board, *node);
+            if !done.insert(feature.key()) {
+                continue;
+            }
+            let points = match feature.kind {
+                FeatureKind::City => feature.tiles() + feature.pennants(&self.board),
+                FeatureKind::Road => feature.tiles(),
+                FeatureKind::Cloister => {
+                    let pos = node.0;
+                    let around = (-1..=1).flat_map(|dx| (-1..=1).map(move |dy| (pos.0 + dx, pos.1 + dy)));
+                    around.filter(|x| self.board.contains_key(x)).count() as u32
+                },
+                FeatureKind::Farm if farms => 3 * self.completed_cities(&feature),
+                FeatureKind::Farm => continue,
+            };
+            self.score(&feature, points, &meeples);
+        }
+        self.take_scored()
+    }
+
+    fn completed_cities(&self, farm: &Feature) -> u32 {
+        let mut cities = HashSet::new();
+        for (pos, _, i) in farm.nodes.iter() {
+            for city in TILE_FEATURES[self.board[pos].kind].fields[*i].1.iter() {
+                let city = collect(&self.board, (*pos, FeatureKind::City, *city));
+                if city.complete {
+                    cities.insert(city.key());
+                }
+            }
+        }
+        cities.len() as u32
+    }
+
+    /// Checks the meeples of a loaded game: each stands alone on a feature that isn't complete (it
+    /// would have been scored and the meeple given back).
+    pub(super) fn check_meeples(&self) -> Result<(), &'static str> {
+        let mut taken = HashSet::new();
+        for (pos, (_, position)) in self.meeples.iter() {
+            if *position == MeeplePosition::Center && !super::BASE_TILES[self.board[pos].kind].cloister {
+                return Err("Only cloisters have a center");
+            }
+            let node = meeple_node(&self.board, *pos, *position).ok_or("No feature there")?;
+            let feature = collect(&self.board, node);
+            if feature.complete && feature.kind != FeatureKind::Farm {
+                return Err("Meeple on a completed feature");
+            }
+            if !taken.insert(feature.key()) {
+                return Err("The feature is already taken");
+            }
+        }
+        Ok(())
+    }
+
+    /// The features scored since the last call.
+    pub fn take_scored(&mut self) -> Vec<ScoredFeature> {
+        std::mem::take(&mut self.scored)
+    }
+
+    pub fn scores(&self) -> &HashMap<IdType, u32> {
+        &self.scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::fixture::GameStateBuilder;
+    use crate::game::{Edge, GameMove, BASE_TILES};
+
+    fn place_tile(tile: &str, x: i32, y: i32, rotation: u8) -> GameMove {
+        GameMove::PlaceTile { tile: tile.to_string(), x, y, rotation }
+    }
+
+    #[test]
+    fn features_match_the_edges() {
+        assert_eq!(TILE_FEATURES.len(), BASE_TILES.len());
+        for (tile, features) in BASE_TILES.iter().zip(TILE_FEATURES.iter()) {
+            for side in 0..4 {
+                let cities = features.cities.iter().filter(|x| x.0.contains(&side)).count();
+                let roads = features.roads.iter().filter(|x| x.contains(&side)).count();
+                let fields = features.fields.iter().filter(|x| x.0.contains(&(side * 2)) || x.0.contains(&(side * 2 + 1))).count();
+                let expected = match tile.edges[side] {
+                    Edge::City => (1, 0, 0),
+                    Edge::Road => (0, 1, if fields == 1 { 1 } else { 2 }),
+                    Edge::Field => (0, 0, 1),
+                };
+                assert_eq!((cities, roads, fields), expected, "tile {} side {}", tile.name, side);
+            }
+            // Every port of a field edge or a road edge is in exactly one field.
+            let ports: Vec<usize> = features.fields.iter().flat_map(|x| x.0.iter().copied()).collect();
+            assert_eq!(ports.len(), ports.iter().collect::<HashSet<_>>().len(), "tile {}", tile.name);
+        }
+    }
+
+    #[test]
+    fn completed_city_and_road() {
+        let mut game = GameStateBuilder::new().meeple((0, 0), 1, MeeplePosition::North).build();
+
+        // The start tile D has a city on the north side, E rotated twice closes it.
+        game.apply(1, place_tile("E", 0, -1, 2)).unwrap();
+        assert_eq!(game.take_scored(), [ScoredFeature { feature: FeatureKind::City, points: 4, players: vec![SerId(1)], tiles: 2, complete: true }]);
+        assert!(game.meeples.is_empty());
+
+        // The road of D ends at two junctions (W), 3 tiles.
+        game.apply(2, place_tile("W", 1, 0, 0)).unwrap();
+        game.apply(2, GameMove::PlaceMeeple { x: 1, y: 0, position: MeeplePosition::West }).unwrap();
+        assert!(game.take_scored().is_empty());
+        game.apply(1, place_tile("W", -1, 0, 2)).unwrap();
+        assert_eq!(game.take_scored(), [ScoredFeature { feature: FeatureKind::Road, points: 3, players: vec![SerId(2)], tiles: 3, complete: true }]);
+        assert_eq!(game.scores()[&1], 4);
+        assert_eq!(game.scores()[&2], 3);
+    }
+
+    #[test]
+    fn meeple_on_a_taken_feature() {
+        let mut game = GameStateBuilder::new().meeple((0, 0), 1, MeeplePosition::East).build();
+
+        // U rotated once continues the road of D.
+        game.apply(2, place_tile("U", 1, 0, 1)).unwrap();
+        let meeple = GameMove::PlaceMeeple { x: 1, y: 0, position: MeeplePosition::West };
+        assert_eq!(game.apply(2, meeple), Err("The feature is already taken"));
+    }
+
+    #[test]
+    fn meeple_on_a_feature_just_completed() {
+        let mut game = GameStateBuilder::new().build();
+
+        game.apply(1, place_tile("E", 0, -1, 2)).unwrap();
+        assert!(game.take_scored().is_empty());
+        let meeple = GameMove::PlaceMeeple { x: 0, y: -1, position: MeeplePosition::South };
+        game.apply(1, meeple).unwrap();
+        assert_eq!(game.take_scored()[0].points, 4);
+        assert!(game.meeples.is_empty());
+    }
+
+    #[test]
+    fn end_of_the_game() {
+        let mut game = GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .tile("B", (0, 1), 0)
+            .meeple((0, -1), 1, MeeplePosition::North)
+            .meeple((0, 1), 2, MeeplePosition::Center)
+            .build();
+
+        let scored = game.score_end(true);
+        // The farm of E touches the completed city, the cloister has one tile around.
+        assert!(scored.contains(&ScoredFeature { feature: FeatureKind::Farm, points: 3, players: vec![SerId(1)], tiles: 1, complete: false }));
+        assert!(scored.contains(&ScoredFeature { feature: FeatureKind::Cloister, points: 2, players: vec![SerId(2)], tiles: 1, complete: false }));
+
+        let mut game = GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .meeple((0, -1), 1, MeeplePosition::North)
+            .build();
+        assert!(game.score_end(false).is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+//!
+//! Binary format of the saved games (see protocol/SNAPSHOT_FORMAT.md).
+//!
+//! A snapshot starts with a header: the magic "CSNP", the schema version (u16), the payload length
+//! (u32) and the CRC32 of the payload (u32), everything is big endian. Snapshots are content
+//! addressed: the id of a snapshot is the SHA1 of its whole encoding.
+//!
+//! The encoder always writes SCHEMA_VERSION, the decoder keeps a shim for every older version that
+//! converts it to the current GameState. When the payload changes bump SCHEMA_VERSION and add a
+//! new `decode_vN`, never change the old ones or the saves made with them won't load anymore.
+//!
+
+use std::collections::HashMap;
+
+use super::{on_board, BASE_TILES, GameState, MeeplePosition, PlacedTile, MEEPLES_PER_PLAYER};
+use crate::protocol::IdType;
+
+const MAGIC: &[u8; 4] = b"CSNP";
+pub const SCHEMA_VERSION: u16 = 1;
+const HEADER_LEN: usize = 14;
+
+pub fn encode(game: &GameState) -> Vec<u8> {
+    let payload = encode_payload(game);
+
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&SCHEMA_VERSION.to_be_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    data.extend_from_slice(&payload);
+    data
+}
+
+/// Id of an encoded snapshot, the same game state always has the same id.
+pub fn content_id(data: &[u8]) -> String {
+    sha1::Sha1::from(data).digest().to_string()
+}
+
+pub fn decode(data: &[u8]) -> Result<GameState, &'static str> {
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        return Err("Not a snapshot");
+    }
+    let mut header = Reader { data: &data[4..HEADER_LEN] };
+    let version = header.u16()?;
+    let len = header.u32()? as usize;
+    let checksum = header.u32()?;
+
+    let payload = &data[HEADER_LEN..];
+    if payload.len() != len {
+        return Err("Invalid snapshot length");
+    }
+    if crc32fast::hash(payload) != checksum {
+        return Err("Corrupted snapshot");
+    }
+
+    let mut reader = Reader { data: payload };
+    let game = match version {
+        1 => decode_v1(&mut reader)?,
+        _ => return Err("Unsupported snapshot version"),
+    };
+    if !reader.data.is_empty() {
+        return Err("Trailing data in snapshot");
+    }
+    Ok(game)
+}
+
+// Tiles are saved by name and not by index so that BASE_TILES can be extended, tiles and meeples
+// are sorted by position so the encoding (and the id) doesn't depend on the hash map order.
+fn encode_payload(game: &GameState) -> Vec<u8> {
+    let mut w = Vec::new();
+
+    w.push(BASE_TILES.len() as u8);
+    for (kind, left) in BASE_TILES.iter().zip(game.deck.iter()) {
+        w.push(kind.name.as_bytes()[0]);
+        w.push(*left);
+    }
+
+    let mut board: Vec<_> = game.board.iter().collect();
+    board.sort_by_key(|(pos, _)| **pos);
+    w.extend_from_slice(&(board.len() as u16).to_be_bytes());
+    for ((x, y), tile) in board {
+        w.push(BASE_TILES[tile.kind].name.as_bytes()[0]);
+        w.extend_from_slice(&x.to_be_bytes());
+        w.extend_from_slice(&y.to_be_bytes());
+        w.push(tile.rotation);
+    }
+
+    let mut meeples: Vec<_> = game.meeples.iter().collect();
+    meeples.sort_by_key(|(pos, _)| **pos);
+    w.extend_from_slice(&(meeples.len() as u16).to_be_bytes());
+    for ((x, y), (player, position)) in meeples {
+        w.extend_from_slice(&x.to_be_bytes());
+        w.extend_from_slice(&y.to_be_bytes());
+        w.extend_from_slice(&(*player as u64).to_be_bytes());
+        w.push(match position {
+            MeeplePosition::North => 0,
+            MeeplePosition::East => 1,
+            MeeplePosition::South => 2,
+            MeeplePosition::West => 3,
+            MeeplePosition::Center => 4,
+        });
+    }
+
+    match game.last_placed {
+        Some(((x, y), player)) => {
+            w.push(1);
+            w.extend_from_slice(&x.to_be_bytes());
+            w.extend_from_slice(&y.to_be_bytes());
+            w.extend_from_slice(&(player as u64).to_be_bytes());
+        },
+        None => w.push(0),
+    }
+    w
+}
+
+fn decode_v1(r: &mut Reader) -> Result<GameState, &'static str> {
+    let mut deck = vec![0; BASE_TILES.len()];
+    for _ in 0..r.u8()? {
+        let kind = r.tile_kind()?;
+        deck[kind] = r.u8()?;
+    }
+
+    let mut board = HashMap::new();
+    for _ in 0..r.u16()? {
+        let kind = r.tile_kind()?;
+        let pos = (r.i32()?, r.i32()?);
+        let rotation = r.u8()?;
+        if rotation >= 4 {
+            return Err("Invalid rotation");
+        }
+        if !on_board(pos) {
+            return Err("Tile outside of the board");
+        }
+        board.insert(pos, PlacedTile { kind, rotation });
+    }
+    for (pos, tile) in board.iter() {
+        if !GameState::edges_match(&board, *pos, tile) {
+            return Err("Tile edges do not match");
+        }
+    }
+    for (kind, tile) in BASE_TILES.iter().enumerate() {
+        if board.values().filter(|x| x.kind == kind).count() + deck[kind] as usize > tile.count as usize {
+            return Err("More tiles than in the deck");
+        }
+    }
+
+    let mut meeples = HashMap::new();
+    for _ in 0..r.u16()? {
+        let pos = (r.i32()?, r.i32()?);
+        let player = r.u64()? as IdType;
+        let position = match r.u8()? {
+            0 => MeeplePosition::North,
+            1 => MeeplePosition::East,
+            2 => MeeplePosition::South,
+            3 => MeeplePosition::West,
+            4 => MeeplePosition::Center,
+            _ => return Err("Invalid meeple position"),
+        };
+        if !board.contains_key(&pos) {
+            return Err("Meeple outside of the board");
+        }
+        meeples.insert(pos, (player, position));
+    }
+    let mut owners: HashMap<IdType, usize> = HashMap::new();
+    for (player, _) in meeples.values() {
+        *owners.entry(*player).or_default() += 1;
+    }
+    if owners.values().any(|x| *x > MEEPLES_PER_PLAYER) {
+        return Err("Too many meeples for a player");
+    }
+
+    let last_placed = match r.u8()? {
+        0 => None,
+        _ => Some(((r.i32()?, r.i32()?), r.u64()? as IdType)),
+    };
+    if last_placed.map(|x| !board.contains_key(&x.0)).unwrap_or(false) {
+        return Err("Last tile outside of the board");
+    }
+
+    let game = GameState {
+        board,
+        deck,
+        meeples,
+        last_placed,
+        placed: Vec::new(),
+        draws: None,
+        scores: HashMap::new(),
+        scored: Vec::new(),
+        completed_on_last: Vec::new(),
+    };
+    game.check_meeples()?;
+    Ok(game)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.data.len() < n {
+            return Err("Truncated snapshot");
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn i32(&mut self) -> Result<i32, &'static str> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn tile_kind(&mut self) -> Result<usize, &'static str> {
+        let name = self.u8()?;
+        BASE_TILES.iter().position(|x| x.name.as_bytes()[0] == name).ok_or("Unknown tile")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixture::GameStateBuilder;
+    use super::*;
+
+    fn sample() -> GameState {
+        GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .tile("A", (-1, 0), 3)
+            .meeple((0, -1), 7, MeeplePosition::North)
+            .deck("X", 0)
+            .last_placed((-1, 0), 8)
+            .build()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = encode(&sample());
+        let game = decode(&data).unwrap();
+        assert_eq!(encode(&game), data);
+        assert_eq!(game.last_placed, Some(((-1, 0), 8)));
+        assert_eq!(game.meeples.len(), 1);
+    }
+
+    #[test]
+    fn same_state_same_id() {
+        assert_eq!(content_id(&encode(&sample())), content_id(&encode(&sample())));
+        assert_ne!(content_id(&encode(&sample())), content_id(&encode(&GameState::default())));
+    }
+
+    #[test]
+    fn rejects_corrupted_data() {
+        let mut data = encode(&sample());
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(decode(&data).err(), Some("Corrupted snapshot"));
+
+        data.truncate(HEADER_LEN - 1);
+        assert_eq!(decode(&data).err(), Some("Not a snapshot"));
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut data = encode(&sample());
+        data[4..6].copy_from_slice(&(SCHEMA_VERSION + 1).to_be_bytes());
+        assert_eq!(decode(&data).err(), Some("Unsupported snapshot version"));
+    }
+
+    #[test]
+    fn rejects_impossible_games() {
+        let game = GameStateBuilder::new().tile("B", (-1, 0), 0).build();
+        assert_eq!(decode(&encode(&game)).err(), Some("Tile edges do not match"));
+
+        let game = GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .meeple((0, -1), 7, MeeplePosition::South)
+            .build();
+        assert_eq!(decode(&encode(&game)).err(), Some("Meeple on a completed feature"));
+
+        let game = GameStateBuilder::new()
+            .tile("U", (1, 0), 1)
+            .meeple((0, 0), 7, MeeplePosition::East)
+            .meeple((1, 0), 8, MeeplePosition::West)
+            .build();
+        assert_eq!(decode(&encode(&game)).err(), Some("The feature is already taken"));
+    }
+}
@@ -0,0 +1,223 @@
+//!
+//! Checks done before a connection is upgraded to a websocket, with the count of every failure.
+//!
+//! A refused handshake is answered with a json body (`error` and `message`) so that the client
+//! developers can tell why without the server logs, the counters are exposed on /stats/handshakes.
+//! ALLOWED_ORIGINS (comma separated, any origin if not set) and MAX_CONNECTIONS (no limit if not
+//! set) configure the checks, every address of BIND_ADDR can have its own limit (see listeners).
+//!
+//! Behind a reverse proxy every client has the address of the proxy. TRUSTED_PROXIES (comma
+//! separated addresses) lists the proxies whose X-Forwarded-For header is believed: the address of
+//! the client is the last one of the header that isn't a trusted proxy, the ones before it could be
+//! forged by the client. The header of any other peer is ignored.
+//!
+
+use std::{env, net::{IpAddr, SocketAddr}, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+
+use actix_web::{http::{header, StatusCode}, HttpRequest, HttpResponse};
+use actix_web_actors::ws::{self, HandshakeError};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::listeners::{self, ListenerStats};
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static FAILURES: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+#[derive(Copy, Clone)]
+enum Failure {
+    BadOrigin,
+    UnsupportedVersion,
+    InvalidHandshake,
+    ServerFull,
+}
+
+impl Failure {
+    fn name(self) -> &'static str {
+        match self {
+            Failure::BadOrigin => "bad_origin",
+            Failure::UnsupportedVersion => "unsupported_version",
+            Failure::InvalidHandshake => "invalid_handshake",
+            Failure::ServerFull => "server_full",
+        }
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            Failure::BadOrigin => StatusCode::FORBIDDEN,
+            Failure::UnsupportedVersion | Failure::InvalidHandshake => StatusCode::BAD_REQUEST,
+            Failure::ServerFull => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FailureBody {
+    error: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HandshakeStats {
+    connections: usize,
+    accepted: u64,
+    bad_origin: u64,
+    unsupported_version: u64,
+    invalid_handshake: u64,
+    server_full: u64,
+    listeners: Vec<ListenerStats>,
+}
+
+#[derive(Clone)]
+pub struct HandshakeConfig {
+    allowed_origins: Option<Vec<String>>,
+    max_connections: Option<usize>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+/// The addresses of TRUSTED_PROXIES.
+pub fn parse_proxies(value: &str) -> Result<Vec<IpAddr>, String> {
+    value.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().map_err(|_| format!("\"{}\" is not an IP address", x)))
+        .collect()
+}
+
+impl HandshakeConfig {
+    pub fn from_env() -> Self {
+        let allowed_origins = env::var("ALLOWED_ORIGINS").ok().map(|x| {
+            x.split(',')
+                .map(|x| x.trim().trim_end_matches('/').to_string())
+                .filter(|x| !x.is_empty())
+                .collect()
+        });
+
+        let trusted_proxies = env::var("TRUSTED_PROXIES").ok()
+            .map(|x| parse_proxies(&x).unwrap_or_else(|e| {
+                warn!("TRUSTED_PROXIES ignored: {}", e);
+                Vec::new()
+            }))
+            .unwrap_or_default();
+
+        HandshakeConfig {
+            allowed_origins,
+            max_connections: env::var("MAX_CONNECTIONS").ok().and_then(|x| x.parse().ok()),
+            trusted_proxies,
+        }
+    }
+
+    /// The address of the client, none on a unix socket.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        let forwarded: Vec<&str> = req.headers().get_all("x-forwarded-for")
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .collect();
+        // Each proxy appends the address it got the request from.
+        for x in forwarded.iter().rev() {
+            match x.trim().parse::<IpAddr>() {
+                Ok(ip) if self.trusted_proxies.contains(&ip) => continue,
+                Ok(ip) => return Some(ip),
+                Err(_) => break,
+            }
+        }
+        Some(peer)
+    }
+
+    /// Returns the response to send back if the connection can't be upgraded.
+    pub fn check(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        if let Some(allowed) = &self.allowed_origins {
+            let origin = req.headers().get(header::ORIGIN).and_then(|x| x.to_str().ok()).unwrap_or("");
+            if !allowed.iter().any(|x| x == origin) {
+                return Err(refuse(Failure::BadOrigin, format!("Origin \"{}\" is not allowed", origin)));
+            }
+        }
+
+        if let Err(e) = ws::handshake(req) {
+            let failure = match e {
+                HandshakeError::NoVersionHeader | HandshakeError::UnsupportedVersion => Failure::UnsupportedVersion,
+                _ => Failure::InvalidHandshake,
+            };
+            return Err(refuse(failure, e.to_string()));
+        }
+
+        if let Some(max) = self.max_connections {
+            if CONNECTIONS.load(Ordering::Relaxed) >= max {
+                return Err(refuse(Failure::ServerFull, format!("The server accepts at most {} connections, retry later", max)));
+            }
+        }
+        if let Some(max) = listeners::full(listeners::local_addr(req)) {
+            return Err(refuse(Failure::ServerFull, format!("This address accepts at most {} connections, retry later", max)));
+        }
+        ACCEPTED.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn refuse(failure: Failure, message: String) -> HttpResponse {
+    FAILURES[failure as usize].fetch_add(1, Ordering::Relaxed);
+    info!(reason = failure.name(), "Refused a connection: {}", message);
+
+    HttpResponse::build(failure.status()).json(FailureBody {
+        error: failure.name(),
+        message,
+    })
+}
+
+pub fn connection_opened(local: SocketAddr) {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    listeners::connection_opened(local);
+}
+
+pub fn connection_closed(local: SocketAddr) {
+    CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    listeners::connection_closed(local);
+}
+
+pub async fn stats() -> HttpResponse {
+    let failures = |x: Failure| FAILURES[x as usize].load(Ordering::Relaxed);
+
+    HttpResponse::Ok().json(HandshakeStats {
+        connections: CONNECTIONS.load(Ordering::Relaxed),
+        accepted: ACCEPTED.load(Ordering::Relaxed),
+        bad_origin: failures(Failure::BadOrigin),
+        unsupported_version: failures(Failure::UnsupportedVersion),
+        invalid_handshake: failures(Failure::InvalidHandshake),
+        server_full: failures(Failure::ServerFull),
+        listeners: listeners::stats(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn config() -> HandshakeConfig {
+        HandshakeConfig { allowed_origins: None, max_connections: None, trusted_proxies: parse_proxies("10.0.0.1, 10.0.0.2").unwrap() }
+    }
+
+    fn request(peer: &str, forwarded: Option<&str>) -> HttpRequest {
+        let req = TestRequest::default().peer_addr(format!("{}:4000", peer).parse().unwrap());
+        match forwarded {
+            Some(x) => req.header("x-forwarded-for", x),
+            None => req,
+        }.to_http_request()
+    }
+
+    #[test]
+    fn forwarded_only_by_trusted_proxies() {
+        assert_eq!(config().client_ip(&request("10.0.0.1", Some("1.2.3.4"))), Some("1.2.3.4".parse().unwrap()));
+        // The client wrote the first address, the proxies appended the others.
+        assert_eq!(config().client_ip(&request("10.0.0.1", Some("9.9.9.9, 1.2.3.4, 10.0.0.2"))), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(config().client_ip(&request("10.0.0.1", None)), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(config().client_ip(&request("5.6.7.8", Some("1.2.3.4"))), Some("5.6.7.8".parse().unwrap()));
+        assert!(parse_proxies("10.0.0.1,proxy").is_err());
+    }
+}
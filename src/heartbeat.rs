@@ -0,0 +1,177 @@
+//!
+//! The heartbeat of the websocket connections and the policy for the clients that can't keep up.
+//!
+//! Every HEARTBEAT_INTERVAL_SECS (5 by default) the server pings the client, a client that sends
+//! nothing, not even a pong, for CLIENT_TIMEOUT_SECS (10 by default) is disconnected.
+//!
+//! The pings also measure the backlog of a client: a ping is written after everything sent before
+//! it, so its pong means that the client read all of that. What was written before the last ping
+//! and isn't acknowledged yet is the backlog, checked on every heartbeat. Over SLOW_CLIENT_KB (256
+//! by default) the client stops receiving the events it can do without (cosmetics, network
+//! quality, cursors), over SLOW_CLIENT_DISCONNECT_KB (1024 by default) it's disconnected and
+//! leaves its room, the other players are told as for any leave. 0 disables either limit. The
+//! counts are on /stats/heartbeat.
+//!
+
+use std::{collections::VecDeque, env, sync::{atomic::{AtomicU64, Ordering}, OnceLock}, time::Duration};
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+use tracing::warn;
+
+/// Pings waiting for a pong that are remembered, the older ones count as read with the next pong.
+const MAX_PENDING_PINGS: usize = 16;
+
+static CONFIG: OnceLock<HeartbeatConfig> = OnceLock::new();
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+static SLOW_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub slow_backlog: Option<u64>,// Bytes
+    pub max_backlog: Option<u64>,// Bytes
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|x| x.parse().ok()).unwrap_or(default)
+}
+
+pub fn config() -> &'static HeartbeatConfig {
+    CONFIG.get_or_init(|| {
+        let interval = Duration::from_secs(env_number("HEARTBEAT_INTERVAL_SECS", 5).max(1));
+        let mut timeout = Duration::from_secs(env_number("CLIENT_TIMEOUT_SECS", 10));
+        if timeout <= interval {
+            warn!("CLIENT_TIMEOUT_SECS must be longer than HEARTBEAT_INTERVAL_SECS, using twice the interval");
+            timeout = interval * 2;
+        }
+        let limit = |name, default| Some(env_number(name, default) * 1024).filter(|x| *x > 0);
+        HeartbeatConfig {
+            interval,
+            timeout,
+            slow_backlog: limit("SLOW_CLIENT_KB", 256),
+            max_backlog: limit("SLOW_CLIENT_DISCONNECT_KB", 1024),
+        }
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pressure {
+    Normal,
+    Slow,// Only the essential events are sent
+    TooSlow,// Disconnected
+}
+
+/// The bytes written to a client and not acknowledged by a pong yet.
+#[derive(Default)]
+pub struct Backlog {
+    written: u64,
+    read: u64,
+    pings: VecDeque<([u8; 8], u64)>,// Payload and bytes written before it, the oldest first
+    slow: bool,
+}
+
+impl Backlog {
+    pub fn written(&mut self, bytes: usize) {
+        self.written += bytes as u64;
+    }
+
+    pub fn ping_sent(&mut self, payload: [u8; 8]) {
+        if self.pings.len() == MAX_PENDING_PINGS {
+            self.pings.pop_front();
+        }
+        self.pings.push_back((payload, self.written));
+    }
+
+    pub fn pong_received(&mut self, payload: &[u8]) {
+        if let Some(i) = self.pings.iter().position(|x| x.0 == payload) {
+            self.read = self.pings[i].1;
+            self.pings.drain(..=i);
+        }
+    }
+
+    /// What was written before the last ping and isn't read yet.
+    pub fn bytes(&self) -> u64 {
+        self.pings.back().map(|x| x.1 - self.read).unwrap_or(0)
+    }
+
+    /// Checked on every heartbeat, before the next ping.
+    pub fn check(&mut self, config: &HeartbeatConfig) -> Pressure {
+        let bytes = self.bytes();
+        if config.max_backlog.map(|x| bytes > x).unwrap_or(false) {
+            return Pressure::TooSlow;
+        }
+        self.slow = config.slow_backlog.map(|x| bytes > x).unwrap_or(false);
+        if self.slow { Pressure::Slow } else { Pressure::Normal }
+    }
+
+    /// True if an optional event must be dropped, it's counted.
+    pub fn drops_optional(&self) -> bool {
+        if self.slow {
+            DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+        }
+        self.slow
+    }
+}
+
+pub fn timed_out() {
+    TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn slow_disconnect() {
+    SLOW_DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HeartbeatStats {
+    timeouts: u64,
+    dropped_events: u64,
+    slow_disconnects: u64,
+}
+
+pub async fn stats() -> HttpResponse {
+    HttpResponse::Ok().json(HeartbeatStats {
+        timeouts: TIMEOUTS.load(Ordering::Relaxed),
+        dropped_events: DROPPED_EVENTS.load(Ordering::Relaxed),
+        slow_disconnects: SLOW_DISCONNECTS.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_follows_the_pongs() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+            slow_backlog: Some(100),
+            max_backlog: Some(1000),
+        };
+        let mut backlog = Backlog::default();
+        backlog.written(500);
+        assert_eq!(backlog.check(&config), Pressure::Normal);// Not written before a ping yet
+
+        backlog.ping_sent(0u64.to_be_bytes());
+        backlog.written(400);
+        backlog.ping_sent(1u64.to_be_bytes());
+        assert_eq!(backlog.bytes(), 900);
+        assert_eq!(backlog.check(&config), Pressure::Slow);
+        assert!(backlog.drops_optional());
+
+        backlog.pong_received(&0u64.to_be_bytes());
+        assert_eq!(backlog.bytes(), 400);
+        backlog.written(700);
+        backlog.ping_sent(2u64.to_be_bytes());
+        assert_eq!(backlog.check(&config), Pressure::TooSlow);
+
+        // A pong answers the older pings too.
+        backlog.pong_received(&2u64.to_be_bytes());
+        assert_eq!(backlog.bytes(), 0);
+        assert_eq!(backlog.check(&config), Pressure::Normal);
+        assert!(!backlog.drops_optional());
+    }
+}
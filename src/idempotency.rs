@@ -0,0 +1,166 @@
+//!
+//! Retries of the commands of a client. Any message of the matchmaking protocol can carry a
+//! `requestKey` chosen by the client (unique per command, at most MAX_KEY_LEN characters): a
+//! message with the key of a command received in the last DEDUP_WINDOW isn't handled again, the
+//! response of the first one is sent again with the id of the retry, or nothing if it isn't
+//! answered yet. The response (or the error) of a command with a key carries the key too. The
+//! commands answered only on an error (change_avatar, event_room_start_ack) are done once handled.
+//!
+//! A client that retries after a timeout (room_join, room_start, change_avatar...) doesn't join,
+//! start or change anything twice. The answered keys are kept with a suspended session, a command
+//! still waiting for its response when the connection was lost is forgotten: its response is lost
+//! with the connection, the retry is handled again.
+//!
+
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use serde_json::Value;
+
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(30);
+pub const MAX_KEY_LEN: usize = 64;
+/// Keys remembered per session, the oldest are forgotten first.
+const MAX_KEYS: usize = 64;
+
+enum Reply {
+    Waiting,
+    Sent(Value),
+    Silent,// Handled without a response
+}
+
+struct Entry {
+    key: String,
+    id: u64,// Of the first message with the key
+    at: Instant,
+    reply: Reply,
+}
+
+pub enum Seen {
+    New,
+    Pending,// Handled, not answered yet
+    Answered(Value),// To send again
+    Done,// Handled, there's nothing to send
+}
+
+#[derive(Default)]
+pub struct RecentRequests {
+    entries: VecDeque<Entry>,// The oldest first
+}
+
+impl RecentRequests {
+    /// Remembers the key of a command, or tells what to do with a retry.
+    pub fn begin(&mut self, key: &str, id: u64, now: Instant) -> Seen {
+        self.expire(now);
+        if let Some(entry) = self.entries.iter().find(|x| x.key == key) {
+            return match &entry.reply {
+                Reply::Waiting => Seen::Pending,
+                Reply::Sent(x) => Seen::Answered(retry_response(x.clone(), id)),
+                Reply::Silent => Seen::Done,
+            };
+        }
+        if self.entries.len() == MAX_KEYS {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry { key: key.to_string(), id, at: now, reply: Reply::Waiting });
+        Seen::New
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.entries.front().map(|x| now.duration_since(x.at) > DEDUP_WINDOW).unwrap_or(false) {
+            self.entries.pop_front();
+        }
+    }
+
+    /// True while a command with a key received in the window waits for its response.
+    pub fn waiting(&mut self, now: Instant) -> bool {
+        self.expire(now);
+        self.entries.iter().any(|x| matches!(x.reply, Reply::Waiting))
+    }
+
+    /// Ends the wait of a command that got no response once handled.
+    pub fn handled(&mut self, id: u64) {
+        if let Some(x) = self.entries.iter_mut().find(|x| x.id == id && matches!(x.reply, Reply::Waiting)) {
+            x.reply = Reply::Silent;
+        }
+    }
+
+    /// Adds the key to the message if it answers a command waiting for its response and keeps it,
+    /// false if it doesn't answer one.
+    pub fn answer(&mut self, message: &mut Value) -> bool {
+        let id = match message.get("requestId").or_else(|| message.get("originId")).and_then(Value::as_u64) {
+            Some(x) => x,
+            None => return false,
+        };
+        let entry = match self.entries.iter_mut().find(|x| x.id == id && matches!(x.reply, Reply::Waiting)) {
+            Some(x) => x,
+            None => return false,
+        };
+        match message.as_object_mut() {
+            Some(x) => x.insert("requestKey".to_string(), entry.key.clone().into()),
+            None => return false,
+        };
+        entry.reply = Reply::Sent(message.clone());
+        true
+    }
+
+    /// The keys to keep with a suspended session.
+    pub fn suspend(mut self) -> Self {
+        self.entries.retain(|x| !matches!(x.reply, Reply::Waiting));
+        self
+    }
+}
+
+fn retry_response(mut response: Value, id: u64) -> Value {
+    for field in ["requestId", "originId"].iter() {
+        if let Some(x) = response.get_mut(*field) {
+            *x = id.into();
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn retries_get_the_first_response() {
+        let now = Instant::now();
+        let mut requests = RecentRequests::default();
+        assert!(matches!(requests.begin("join-1", 4, now), Seen::New));
+        assert!(requests.waiting(now));
+        assert!(matches!(requests.begin("join-1", 5, now), Seen::Pending));
+
+        let mut other = json!({ "type": "event_player_joined" });
+        assert!(!requests.answer(&mut other));
+        let mut response = json!({ "type": "room_join_response", "requestId": 4, "result": "ok" });
+        assert!(requests.answer(&mut response));
+        assert_eq!(response["requestKey"], "join-1");
+        assert!(!requests.waiting(now));
+
+        match requests.begin("join-1", 6, now + Duration::from_secs(1)) {
+            Seen::Answered(x) => assert_eq!(x, json!({ "type": "room_join_response", "requestId": 6, "result": "ok", "requestKey": "join-1" })),
+            _ => panic!("the retry isn't answered"),
+        }
+        // Unanswered commands are forgotten with the connection, the others after the window.
+        assert!(matches!(requests.begin("start-1", 7, now), Seen::New));
+        let mut requests = requests.suspend();
+        assert!(matches!(requests.begin("start-1", 8, now), Seen::New));
+        assert!(matches!(requests.begin("join-1", 9, now + DEDUP_WINDOW * 2), Seen::New));
+    }
+
+    #[test]
+    fn commands_without_response_stop_the_wait() {
+        let now = Instant::now();
+        let mut requests = RecentRequests::default();
+        requests.begin("avatar-1", 4, now);
+        requests.handled(4);
+        assert!(!requests.waiting(now));
+        assert!(matches!(requests.begin("avatar-1", 5, now), Seen::Done));
+
+        // A command that is never answered stops the wait after the window.
+        requests.begin("lost-1", 6, now);
+        assert!(requests.waiting(now));
+        assert!(!requests.waiting(now + DEDUP_WINDOW * 2));
+    }
+}
@@ -0,0 +1,85 @@
+//!
+//! Panic isolation of the subsystems that aren't needed to play: the relay sampling, the replays, the
+//! bots and the predictions run inside the room actors, a bug in them must not take down the arbiter
+//! thread and every room on it. Their work is wrapped in isolate, a panic is caught and logged and the subsystem is
+//! restarted from a clean state by its caller (the sampling report is reset, the replay of the game
+//! is dropped, the bot skips its move, the prediction is skipped). The restarts are counted and exposed on /stats/subsystems.
+//!
+
+use std::{panic::{self, AssertUnwindSafe}, sync::atomic::{AtomicU64, Ordering}};
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+use tracing::error;
+
+static RESTARTS: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+#[derive(Copy, Clone)]
+pub enum Subsystem {
+    RelaySampling,
+    Replays,
+    Bots,
+    Predictions,
+}
+
+impl Subsystem {
+    fn name(self) -> &'static str {
+        match self {
+            Subsystem::RelaySampling => "relay_sampling",
+            Subsystem::Replays => "replays",
+            Subsystem::Bots => "bots",
+            Subsystem::Predictions => "predictions",
+        }
+    }
+}
+
+/// Runs f, none if it panicked: the caller has to restart the subsystem.
+pub fn isolate<R>(subsystem: Subsystem, f: impl FnOnce() -> R) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(x) => Some(x),
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(|x| x.as_str()))
+                .unwrap_or("(unknown)");
+            let restarts = RESTARTS[subsystem as usize].fetch_add(1, Ordering::Relaxed) + 1;
+            error!(subsystem = subsystem.name(), restarts, panic = message, "Subsystem panicked, restarting it");
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SubsystemStats {
+    relay_sampling: u64,
+    replays: u64,
+    bots: u64,
+    predictions: u64,
+}
+
+pub async fn stats() -> HttpResponse {
+    let restarts = |x: Subsystem| RESTARTS[x as usize].load(Ordering::Relaxed);
+
+    HttpResponse::Ok().json(SubsystemStats {
+        relay_sampling: restarts(Subsystem::RelaySampling),
+        replays: restarts(Subsystem::Replays),
+        bots: restarts(Subsystem::Bots),
+        predictions: restarts(Subsystem::Predictions),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_are_caught_and_counted() {
+        let restarts = || RESTARTS[Subsystem::Bots as usize].load(Ordering::Relaxed);
+        let before = restarts();
+
+        assert_eq!(isolate(Subsystem::Bots, || 3), Some(3));
+        assert_eq!(restarts(), before);
+        assert_eq!(isolate(Subsystem::Bots, || -> u32 { panic!("bot bug") }), None);
+        assert_eq!(restarts(), before + 1);
+    }
+}
@@ -0,0 +1,184 @@
+//!
+//! The addresses the server listens on. BIND_ADDR is a comma separated list of addresses, IPv4 or
+//! IPv6 (`0.0.0.0:8081,[::]:8081`, a host name binds every address it resolves to), each one with
+//! an optional limit of websocket connections after a `=` (`[::1]:8081=100`), on top of the one of
+//! MAX_CONNECTIONS for the whole server.
+//!
+//! ADMIN_BIND_ADDR is one more address, for the admin api and the replication log: once it's set
+//! they're only served there (a private interface for instance), the other addresses answer them
+//! with not found.
+//!
+//! ADMIN_SOCKET is the path of a unix socket serving the admin api (and the rest) to the operators
+//! of the host, without the token: the permissions of the socket are the authentication. It's
+//! created with the mode 0660, readable by the user and the group of the server only, a stale
+//! socket left by a previous run is replaced.
+//!
+
+use std::{env, net::{SocketAddr, ToSocketAddrs}, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, OnceLock}};
+
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:8081";
+
+static LISTENERS: OnceLock<Listeners> = OnceLock::new();
+
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub max_connections: Option<usize>,
+    connections: AtomicUsize,
+}
+
+pub struct Listeners {
+    pub public: Vec<Listener>,
+    pub admin: Option<SocketAddr>,
+    pub admin_socket: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerStats {
+    addr: String,
+    connections: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+}
+
+/// Parses a list of addresses with their optional limits.
+pub fn parse(value: &str) -> Result<Vec<(SocketAddr, Option<usize>)>, String> {
+    let mut listeners = Vec::new();
+    for item in value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let (addr, max) = match item.rfind('=') {
+            Some(i) => {
+                let max = item[i + 1..].trim().parse::<usize>()
+                    .map_err(|_| format!("\"{}\" is not a valid connection limit", &item[i + 1..]))?;
+                (item[..i].trim(), Some(max))
+            },
+            None => (item, None),
+        };
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()
+            .map_err(|e| format!("\"{}\": {}", addr, e))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("\"{}\" resolves to no address", addr));
+        }
+        for addr in addrs {
+            if listeners.iter().any(|x: &(SocketAddr, Option<usize>)| x.0 == addr) {
+                return Err(format!("{} is listed twice", addr));
+            }
+            listeners.push((addr, max));
+        }
+    }
+    if listeners.is_empty() {
+        return Err("no address".to_string());
+    }
+    Ok(listeners)
+}
+
+/// Reads the addresses, called once when the server starts.
+pub fn init() -> Result<&'static Listeners, String> {
+    let public = parse(&env::var("BIND_ADDR").unwrap_or_else(|_| DEFAULT_BIND_ADDR.to_string()))
+        .map_err(|e| format!("BIND_ADDR: {}", e))?;
+    let admin = match env::var("ADMIN_BIND_ADDR") {
+        Ok(x) if !x.trim().is_empty() => match parse(&x).map_err(|e| format!("ADMIN_BIND_ADDR: {}", e))?.as_slice() {
+            [(addr, None)] if public.iter().all(|x| x.0 != *addr) => Some(*addr),
+            _ => return Err("ADMIN_BIND_ADDR must be a single address without limit, not one of BIND_ADDR".to_string()),
+        },
+        _ => None,
+    };
+    let admin_socket = env::var("ADMIN_SOCKET").ok().filter(|x| !x.trim().is_empty()).map(|x| PathBuf::from(x.trim()));
+    if admin_socket.is_some() && cfg!(not(unix)) {
+        return Err("ADMIN_SOCKET needs a unix system".to_string());
+    }
+
+    Ok(LISTENERS.get_or_init(|| Listeners {
+        public: public.into_iter()
+            .map(|(addr, max_connections)| Listener { addr, max_connections, connections: AtomicUsize::new(0) })
+            .collect(),
+        admin,
+        admin_socket,
+    }))
+}
+
+/// Creates the admin socket, replacing the socket of a previous run (but nothing else).
+#[cfg(unix)]
+pub fn bind_admin_socket(path: &std::path::Path) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::{fs, io, os::unix::fs::{FileTypeExt, PermissionsExt}};
+
+    match fs::symlink_metadata(path) {
+        Ok(x) if x.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and isn't a socket", path.display()))),
+        Err(_) => {},
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+/// True if the request came in on the admin socket, the only connections without a peer address.
+pub fn on_admin_socket(req: &HttpRequest) -> bool {
+    LISTENERS.get().map(|x| x.admin_socket.is_some()).unwrap_or(false) && req.peer_addr().is_none()
+}
+
+fn listener(local: SocketAddr) -> Option<&'static Listener> {
+    LISTENERS.get()?.public.iter().find(|x| x.addr == local)
+}
+
+/// The address a request came in on.
+pub fn local_addr(req: &HttpRequest) -> SocketAddr {
+    req.app_config().local_addr()
+}
+
+/// False if the request for the admin api came in on another address than ADMIN_BIND_ADDR.
+pub fn admin_allowed(req: &HttpRequest) -> bool {
+    match LISTENERS.get().and_then(|x| x.admin) {
+        Some(addr) => local_addr(req) == addr,
+        None => true,
+    }
+}
+
+/// The limit of the address if it's reached.
+pub fn full(local: SocketAddr) -> Option<usize> {
+    let listener = listener(local)?;
+    listener.max_connections.filter(|x| listener.connections.load(Ordering::Relaxed) >= *x)
+}
+
+pub fn connection_opened(local: SocketAddr) {
+    if let Some(listener) = listener(local) {
+        listener.connections.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn connection_closed(local: SocketAddr) {
+    if let Some(listener) = listener(local) {
+        listener.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn stats() -> Vec<ListenerStats> {
+    LISTENERS.get().map(|x| x.public.as_slice()).unwrap_or_default().iter()
+        .map(|x| ListenerStats {
+            addr: x.addr.to_string(),
+            connections: x.connections.load(Ordering::Relaxed),
+            max_connections: x.max_connections,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_parsed() {
+        let listeners = parse("127.0.0.1:8081=500, [::1]:8082").unwrap();
+        assert_eq!(listeners, vec![
+            ("127.0.0.1:8081".parse().unwrap(), Some(500)),
+            ("[::1]:8082".parse().unwrap(), None),
+        ]);
+        assert!(parse("127.0.0.1:8081=many").is_err());
+        assert!(parse("127.0.0.1").is_err());
+        assert!(parse("127.0.0.1:8081,127.0.0.1:8081").is_err());
+        assert!(parse(" , ").is_err());
+    }
+}
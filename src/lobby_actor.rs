@@ -0,0 +1,2627 @@
+//!
+//! The lobby is the entry point of every client: it allocates the player ids, does the matchmaking
+//! and creates the room actors.
+//!
+//! It used to be the central actor handling every event of every room, making it the bottleneck of
+//! the whole server. Now each room runs in its own actor (see room_actor) and once a player is in a
+//! room its client talks directly to it, the lobby only keeps a summary of every room that the
+//! rooms keep updated.
+//!
+//! The lobby also keeps the sessions of the players that lost their connection for a grace period
+//! (RECONNECT_GRACE_SECS, 30 by default, 0 disables it) so that a new connection can resume them
+//! with the token issued on login.
+//!
+//! The friends of a player are kept with its account (two players are friends once both added the
+//! other, see accounts.rs) and a player can invite a friend to their room: the invite is kept on the
+//! session of the friend until it's answered or the room closes, accepting it joins the room without
+//! its password.
+//!
+//! The registry of the rooms can be replicated to a standby instance, see replication.rs, or
+//! shared with the other instances of a cluster, see cluster.rs.
+//!
+//! The lobby runs the tournaments too (see tournament.rs): it creates the room of every match,
+//! learns its winner from the end of its game and starts the next round.
+//!
+
+use std::{collections::{HashMap, HashSet, VecDeque}, env, net::IpAddr, path::PathBuf, sync::Arc, thread, time::{Duration, Instant}};
+
+use actix::prelude::*;
+use rand::{self, Rng, rngs::ThreadRng};
+use tracing::{debug_span, info, info_span, warn};
+
+use crate::admin::{AdminBans, AdminJobKind, AdminJobReport, AdminPlayer, AdminRoom, BannedAccount, RoomFilter};
+use crate::cleanup::{self, Sweep};
+use crate::client_ws::{self, ClientWs};
+use crate::cluster::{self, ClusterConfig};
+use crate::accounts::{self, Account, Accounts};
+use crate::achievements::{self, AchievementEvent};
+use crate::cosmetics::Inventory;
+use crate::dev_rooms::DevRooms;
+use crate::features::FeatureConfig;
+use crate::idempotency::RecentRequests;
+use crate::federation::{self, FederationConfig, PublicRoom, RoomList};
+use crate::matchmaking::{self, QueuedPlayer, WaitEstimate, Weights};
+use crate::migration::{RoomData, Seats, MIGRATION_TTL};
+use crate::moderation;
+use crate::player_stats::GameResult;
+use crate::ratings::{self, Rating};
+use crate::protocol::{Achievement, EventClass, Friend, FriendListResponse, Hello, IdType, InviteId, SerId, LoginData, MatchHistoryResponse, MatchRecord, OutEvent, PlayerCosmetics, PlayerObject, PlayerStatsResponse, PrivacySettings, RoomListResponse, RoomOptions, RoomSettings, RoomSummary, TournamentResponse, TournamentState};
+use crate::replay::{Replay, MAX_REPLAYS};
+use crate::replication::{self, Batch, Mirror, Mutation, ReplicatedRoom, ReplicationConfig};
+use crate::spectator_delay::MAX_SPECTATOR_DELAY_SECS;
+use crate::status_page::ServerStatus;
+use crate::room_actor::{self, Event, JoinRoomResult, Member, RoomActor, RoomState, SpectateResult, MIN_PLAYERS_PER_ROOM};
+use crate::tournament::{self, Tournament, MAX_TOURNAMENT_PLAYERS, MIN_TOURNAMENT_PLAYERS};
+
+const FEATURES_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Bulk admin operations whose progress is kept.
+const MAX_ADMIN_JOBS: usize = 32;
+/// How long a public room must wait for players before it's proposed for a merge.
+const REBALANCE_MIN_ROOM_AGE: Duration = Duration::from_secs(60);
+/// A public room still alone this long after it was created can be merged right away with another
+/// one, e.g. when two players looking for a room at the same time both ended up creating one.
+const MERGEABLE_WINDOW: Duration = Duration::from_secs(20);
+/// Delay between the creation of a public room and the search of another one to merge it with,
+/// so that its player gets the find_room_response first.
+const MERGE_CHECK_DELAY: Duration = Duration::from_secs(2);
+const DEFAULT_RECONNECT_GRACE_SECS: u64 = 30;
+const FEDERATION_POLL_INTERVAL: Duration = Duration::from_secs(15);
+const ROOM_LIST_PAGE_SIZE: usize = 20;
+/// Games kept in the history of a session, the oldest ones are dropped.
+const MATCH_HISTORY_LEN: usize = 10;
+const MAX_FRIENDS: usize = 100;
+
+/// The error is banned (the account or the address of the player is banned), invalid_account (an unknown account
+/// token), account_in_use (the account has another session) or cosmetics_not_owned (see
+/// cosmetics.rs).
+#[derive(Message)]
+#[rtype(result = "Result<SessionInfo, &'static str>")]
+pub struct RegisterSession {
+    pub addr: Addr<ClientWs>,
+    pub username: String,
+    pub privacy: PrivacySettings,
+    pub cosmetics: PlayerCosmetics,
+    pub ip: Option<IpAddr>,// None on a unix socket
+    pub account_token: Option<String>,// A new account without, see accounts.rs
+}
+
+/// Sent when a player logs in again with new details.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdatePrivacy {
+    pub id: IdType,
+    pub privacy: PrivacySettings,
+}
+
+pub struct SessionInfo {
+    pub id: IdType,
+    pub token: String,// Used to resume the session after a disconnection
+    pub account: IdType,
+    pub account_token: Option<String>,// Of a new account
+    pub inventory: Inventory,// Of the account
+}
+
+/// What a client needs to restore its state when its session is resumed.
+pub struct SessionSnapshot {
+    pub details: LoginData,
+    pub unsubscribed: HashSet<EventClass>,
+    pub room: Option<Addr<RoomActor>>,
+    pub requests: RecentRequests,
+}
+
+/// Sent when a logged client stops, the snapshot is present if the session can be resumed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub id: IdType,
+    pub snapshot: Option<SessionSnapshot>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<ResumedSession>")]
+pub struct ResumeSession {
+    pub token: String,
+    pub addr: Addr<ClientWs>,// The new connection
+}
+
+pub struct ResumedSession {
+    pub id: IdType,
+    pub snapshot: SessionSnapshot,
+    pub inventory: Inventory,// Of the account, granted while it was away too
+}
+
+simple_result!(ResumedSession);
+
+/// A page of the public rooms for the room browser, the oldest rooms come first.
+#[derive(Message)]
+#[rtype(RoomListResponse)]
+pub struct ListRooms {
+    pub page: usize,
+}
+
+simple_result!(RoomListResponse);
+
+#[derive(Message)]
+#[rtype(result = "Option<RoomList>")]
+pub struct GetPublicRooms;
+
+simple_result!(RoomList);
+
+/// None if the replication is disabled.
+#[derive(Message)]
+#[rtype(result = "Option<Batch>")]
+pub struct GetReplicationLog {
+    pub epoch: u64,
+    pub after: u64,
+}
+
+simple_result!(Batch);
+
+/// Freezes a room before it moves to another instance, see migration.rs. Refused if the room
+/// doesn't exist or is already moving.
+#[derive(Message)]
+#[rtype(result = "Result<RoomData, ()>")]
+pub struct MigrateRoom {
+    pub room_id: IdType,
+}
+
+/// Where the players of a room frozen by MigrateRoom go, none if the target refused it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EndMigration {
+    pub room_id: IdType,
+    pub url: Option<String>,
+}
+
+/// A room moved here from another instance, answered with the url its players connect to.
+#[derive(Message)]
+#[rtype(result = "Result<String, &'static str>")]
+pub struct ImportRoom(pub RoomData);
+
+#[derive(Message)]
+#[rtype(Hello)]
+pub struct GetHello;
+
+simple_result!(Hello);
+
+/// Sent by a room when its game ends, the match is added to the history of its players with its
+/// replay if it has one.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordMatch {
+    pub record: MatchRecord,
+    pub replay: Option<Replay>,
+    pub scores: HashMap<IdType, u32>,// Final scores, only with the authoritative engine
+    pub results: HashMap<IdType, GameResult>,// For the statistics of the players
+    pub violations: HashMap<IdType, u32>,// Of the time limit, only in a tournament match
+}
+
+/// Sent by a room at the end of a series, for the statistics and the ratings of its players (see
+/// series.rs).
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordSeries {
+    pub players: Vec<IdType>,
+    pub winner: Option<IdType>,
+}
+
+/// Sent by a room when players reach achievements during a game, see achievements.rs.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReachAchievements {
+    pub reached: Vec<(IdType, Achievement)>,
+}
+
+/// A replay kept by the lobby, see replay.rs.
+#[derive(Message)]
+#[rtype(result = "Option<Arc<Replay>>")]
+pub struct GetReplay {
+    pub replay_id: IdType,
+}
+
+/// The last games of a session, the most recent first.
+#[derive(Message)]
+#[rtype(MatchHistoryResponse)]
+pub struct GetMatchHistory {
+    pub id: IdType,
+}
+
+simple_result!(MatchHistoryResponse);
+
+/// The statistics of a player, none if it isn't found or if its profile is private and someone
+/// else asks for it (`requester` is none over http).
+#[derive(Message)]
+#[rtype(result = "Option<PlayerStatsResponse>")]
+pub struct GetPlayerStats {
+    pub player: PlayerRef,
+    pub requester: Option<IdType>,
+}
+
+/// The error if the friend can't be added: player_not_found, friend_requests_blocked or
+/// too_many_friends.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct AddFriend {
+    pub id: IdType,
+    pub friend: PlayerRef,
+}
+
+/// A player is named by its player id while it's connected, else by its account.
+#[derive(Copy, Clone)]
+pub enum PlayerRef {
+    Player(IdType),
+    Account(IdType),
+}
+
+impl PlayerRef {
+    /// From the ids of a request (e.g. friend_add or player_stats), the player id first.
+    pub fn new(player_id: Option<SerId>, account_id: Option<SerId>) -> Option<Self> {
+        player_id.map(|x| PlayerRef::Player(x.0)).or_else(|| account_id.map(|x| PlayerRef::Account(x.0)))
+    }
+}
+
+/// Removes the friendship both ways.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RemoveFriend {
+    pub id: IdType,
+    pub friend: PlayerRef,
+}
+
+#[derive(Message)]
+#[rtype(FriendListResponse)]
+pub struct GetFriends {
+    pub id: IdType,
+}
+
+simple_result!(FriendListResponse);
+
+/// Invites a friend to the room of the player, the error is not_in_room, already_playing,
+/// not_friends, player_offline or already_in_room.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct InviteToRoom {
+    pub from: IdType,
+    pub to: IdType,
+}
+
+/// Joins the room of an invite, the room isn't found without one.
+#[derive(Message)]
+#[rtype(result = "Result<JoinRoomResult, ()>")]
+pub struct AcceptInvite {
+    pub member: Member,
+    pub room_id: IdType,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeclineInvite {
+    pub id: IdType,
+    pub room_id: IdType,
+}
+
+/// Sent when the server is asked to stop: the connected players are warned and no room is created
+/// anymore, the server stops after `seconds` or once the last game ends.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub seconds: u64,
+}
+
+/// What the status page shows, see status_page.rs.
+#[derive(Message)]
+#[rtype(ServerStatus)]
+pub struct GetStatus;
+
+simple_result!(ServerStatus);
+
+/// Number of rooms with a game in progress.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct CountPlayingRooms;
+
+/// Writes the accounts that changed since the last save, once the server stopped.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveAccounts;
+
+// Messages of the admin api, see admin.rs.
+
+#[derive(Message)]
+#[rtype(result = "Vec<AdminRoom>")]
+pub struct AdminListRooms;
+
+#[derive(Message)]
+#[rtype(result = "Vec<AdminPlayer>")]
+pub struct AdminListPlayers;
+
+/// Closes a room, its players go back to the matchmaking. False if the room doesn't exist.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct AdminCloseRoom {
+    pub room_id: IdType,
+}
+
+/// Traces the messages of a room for `secs`, or ends its trace with 0 (see room_trace). Refused if
+/// the room doesn't exist, the file of the running trace otherwise.
+#[derive(Message)]
+#[rtype(result = "Result<Option<PathBuf>, ()>")]
+pub struct AdminTraceRoom {
+    pub room_id: IdType,
+    pub secs: u64,
+}
+
+/// Closes the connection of a player and ends its session. False if the player doesn't exist.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct AdminKickPlayer {
+    pub id: IdType,
+}
+
+/// Shows a message to every connected player.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AdminAnnounce {
+    pub message: String,
+}
+
+/// Starts or ends the incident mode: the players are told, and until it ends no room is created or
+/// found and no game starts. The games in progress go on.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AdminSetIncident {
+    pub active: bool,
+    pub message: Option<String>,
+}
+
+/// Replaces the message of the day sent to every new session, none removes it. The players already
+/// connected don't receive it, an announcement is for them.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AdminSetMotd {
+    pub message: Option<String>,
+}
+
+/// Shows a message to the connected players among `players` and the players of `rooms`.
+/// Nothing is sent if one of them doesn't exist, the unknown ids are returned instead.
+#[derive(Message)]
+#[rtype(result = "Result<AdminJobReport, Vec<SerId>>")]
+pub struct AdminAnnounceTo {
+    pub message: String,
+    pub players: Vec<IdType>,
+    pub rooms: Vec<IdType>,
+}
+
+/// Closes every room matching the filter.
+#[derive(Message)]
+#[rtype(result = "AdminJobReport")]
+pub struct AdminCloseRooms {
+    pub filter: RoomFilter,
+}
+
+/// Ends the sessions of the players, none if one of them doesn't exist (the unknown ids are returned).
+#[derive(Message)]
+#[rtype(result = "Result<AdminJobReport, Vec<SerId>>")]
+pub struct AdminKickPlayers {
+    pub ids: Vec<IdType>,
+}
+
+/// Kicks the players like AdminKickPlayers and bans their accounts, and their addresses too with
+/// `addresses` (every client behind the same proxy or NAT is refused), see accounts.rs.
+#[derive(Message)]
+#[rtype(result = "Result<AdminJobReport, Vec<SerId>>")]
+pub struct AdminBanPlayers {
+    pub ids: Vec<IdType>,
+    pub addresses: bool,
+}
+
+/// Lifts the bans of accounts and addresses, none if one of the accounts doesn't exist (the
+/// unknown ids are returned).
+#[derive(Message)]
+#[rtype(result = "Result<(), Vec<SerId>>")]
+pub struct AdminUnban {
+    pub accounts: Vec<IdType>,
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Message)]
+#[rtype(result = "AdminBans")]
+pub struct AdminListBans;
+
+/// Progress of a bulk operation, `None` if it's unknown or too old.
+#[derive(Message)]
+#[rtype(result = "Option<AdminJobReport>")]
+pub struct AdminGetJob {
+    pub id: u64,
+}
+
+// ----------------------------------------------------------------
+
+/// Puts the player in the matchmaking queue (see matchmaking.rs), its room_find request is
+/// answered with a client_ws::QueueResult once it's matched. The reason if no room can be found.
+#[derive(Message)]
+#[rtype(result = "Option<FindRoomResult>")]
+pub struct JoinQueue {
+    pub member: Member,
+    pub region: Option<String>,
+    pub request_id: u64,
+}
+
+/// Takes the player out of the matchmaking queue, false if it wasn't in it.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct LeaveQueue {
+    pub id: IdType,
+}
+
+// The async handlers of actix need a Result, the lobby never fails these messages though.
+#[derive(Message)]
+#[rtype(result = "Result<FindRoomResult, ()>")]
+pub struct FindRoom {
+    pub member: Member,
+    pub region: Option<String>,
+}
+
+pub enum FindRoomResult {
+    Success {
+        room_id: IdType,
+        room: Addr<RoomActor>,
+        players: Vec<PlayerObject>,
+        just_created: bool,
+        settings: RoomSettings,
+        pinned: Option<String>,
+        countdown: Option<u32>,
+    },
+    /// No room here, but a federated instance has one.
+    Redirect {
+        url: String,
+        room_id: IdType,
+    },
+    #[allow(dead_code)] // The matchmaker currently always finds or creates a room.
+    GameIsFull,
+    Cancelled,// The player left the matchmaking queue
+    ShuttingDown,// No room available and the server doesn't create new ones
+    ReadOnly,// The lobby is read only during an incident
+}
+
+// ----------------------------------------------------------------
+
+/// The error if no room can be created: server_shutting_down, server_read_only, not_developer (a
+/// developer room asked by another player) or invalid_spectator_delay (above MAX_SPECTATOR_DELAY_SECS).
+#[derive(Message)]
+#[rtype(result = "Result<CreateRoomResult, &'static str>")]
+pub struct CreateRoom {
+    pub member: Member,
+    pub options: RoomOptions,
+}
+
+pub struct CreateRoomResult {
+    pub room_id: IdType,
+    pub code: String,
+    pub room: Addr<RoomActor>,
+    pub player: PlayerObject,
+}
+
+simple_result!(CreateRoomResult);
+
+#[derive(Message)]
+#[rtype(result = "Result<JoinRoomResult, ()>")]
+pub struct JoinRoom {
+    pub member: Member,
+    pub invite: InviteId,
+    pub password: Option<String>,
+    pub ticket: Option<String>,// See migration.rs
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<SpectateResult, ()>")]
+pub struct SpectateRoom {
+    pub member: Member,
+    pub invite: InviteId,
+    pub password: Option<String>,
+}
+
+// ----------------------------------------------------------------
+
+/// Creates a tournament organized by the player. The error: server_shutting_down,
+/// server_read_only, engine_required, invalid_name, blocked_words or invalid_max_players.
+#[derive(Message)]
+#[rtype(result = "Result<TournamentResponse, &'static str>")]
+pub struct CreateTournament {
+    pub member: Member,
+    pub name: String,
+    pub max_players: usize,
+}
+
+/// The error: tournament_not_found, already_started, already_registered or tournament_full.
+#[derive(Message)]
+#[rtype(result = "Result<TournamentResponse, &'static str>")]
+pub struct RegisterTournament {
+    pub member: Member,
+    pub tournament_id: IdType,
+}
+
+/// A player leaving loses its match once the tournament started, the organizer leaving before the
+/// start cancels it. The error: tournament_not_found or not_registered.
+#[derive(Message)]
+#[rtype(result = "Result<TournamentResponse, &'static str>")]
+pub struct LeaveTournament {
+    pub id: IdType,
+    pub tournament_id: IdType,
+}
+
+/// Sent by the organizer. The error: tournament_not_found, not_organizer, already_started or
+/// not_enough_players.
+#[derive(Message)]
+#[rtype(result = "Result<TournamentResponse, &'static str>")]
+pub struct StartTournament {
+    pub id: IdType,
+    pub tournament_id: IdType,
+}
+
+// ----------------------------------------------------------------
+
+/// Sent by a room every time its player count or state changes.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomUpdate {
+    pub room_id: IdType,
+    pub player_count: usize,
+    pub state: RoomState,
+    pub locked: bool,
+    pub host: String,
+    pub settings: RoomSettings,
+    pub players: Vec<IdType>,
+}
+
+/// Sent by a room when its last player leaves, right before stopping.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomClosed {
+    pub room_id: IdType,
+}
+
+
+struct RoomEntry {
+    addr: Addr<RoomActor>,
+    public: bool, // Public rooms are created for players that wants to play alone.
+    player_count: usize,
+    state: RoomState,
+    locked: bool,
+    host: String,
+    settings: RoomSettings,
+    created_at: Instant,
+    merge_proposed: bool,
+    region: Option<String>,// Region of the player the public room was created for
+    languages: Vec<String>,// Languages of the player the public room was created for
+    players: Vec<IdType>,
+    code: String,
+    active_at: Instant,// Last update of the room, see cleanup.rs
+    options: RoomOptions,
+}
+
+/// A bulk admin operation, done once every target is gone.
+struct AdminJob {
+    id: u64,
+    kind: AdminJobKind,
+    total: usize,
+    pending: HashSet<IdType>,// Rooms or players still there
+    started_at: Instant,
+}
+
+impl AdminJob {
+    fn report(&self) -> AdminJobReport {
+        let mut pending: Vec<SerId> = self.pending.iter().map(|x| (*x).into()).collect();
+        pending.sort_by_key(|x| x.0);
+        AdminJobReport {
+            job_id: self.id,
+            kind: self.kind,
+            total: self.total,
+            done: self.total - self.pending.len(),
+            pending,
+            elapsed_ms: self.started_at.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+impl RoomFilter {
+    fn matches(&self, room: &RoomEntry, now: Instant) -> bool {
+        self.state.map(|x| x == room.state).unwrap_or(true)
+            && self.public.map(|x| x == room.public).unwrap_or(true)
+            && self.locked.map(|x| x == room.locked).unwrap_or(true)
+            && self.host.as_ref().map(|x| *x == room.host).unwrap_or(true)
+            && self.min_age_secs.map(|x| now.duration_since(room.created_at).as_secs() >= x).unwrap_or(true)
+    }
+}
+
+/// The state of a standby instance.
+struct Standby {
+    primary: String,
+    token: String,
+    mirror: Mirror,
+    contact_at: Instant,// Last answer of the primary
+    polling: bool,
+    promoted: bool,// The primary is gone, the rooms of the mirror can be restored
+}
+
+struct QueueEntry {
+    member: Member,
+    region: Option<String>,
+    request_id: u64,// The room_find request to answer
+    since: Instant,
+}
+
+struct Session {
+    addr: Addr<ClientWs>,
+    username: String,
+    token: String,
+    suspended: Option<(SessionSnapshot, SpawnHandle)>,// Set while waiting for the client to reconnect
+    account: IdType,// See accounts.rs
+    history: VecDeque<MatchRecord>,// Only kept in memory, lost with the session
+    invites: HashMap<IdType, IdType>,// Room -> player that sent the invite
+    ip: Option<IpAddr>,
+}
+
+impl Session {
+    /// Unlocks on its account the achievements reached by the player and the cosmetics they earn.
+    fn unlock_achievements(&self, player: IdType, account: &mut Account, reached: impl Iterator<Item = Achievement>) {
+        let achievements = account.achievements.unlock(reached);
+        if achievements.is_empty() {
+            return;
+        }
+        let cosmetics = account.inventory.grant(&achievements);
+        info!(player, achievements = achievements.len(), cosmetics = cosmetics.len(), "Achievements unlocked");
+        // A suspended session gets the cosmetics with its inventory when it's resumed.
+        if self.suspended.is_none() {
+            self.addr.do_send(client_ws::AchievementsUnlocked { achievements, cosmetics });
+        }
+    }
+}
+
+impl RoomEntry {
+    /// Public rooms that are not full.
+    fn is_available(&self) -> bool {
+        self.public && !self.locked && self.state == RoomState::Matchmaking && self.player_count < self.settings.player_limit()
+    }
+
+    /// Public rooms just created and still waiting for a second player.
+    fn is_mergeable(&self, now: Instant) -> bool {
+        self.is_available() && self.player_count == 1 && now.duration_since(self.created_at) < MERGEABLE_WINDOW
+    }
+
+    /// The language chosen by the host, otherwise the ones of the player the room was created for.
+    fn languages(&self) -> &[String] {
+        match &self.settings.language {
+            Some(x) => std::slice::from_ref(x),
+            None => &self.languages,
+        }
+    }
+}
+
+pub struct LobbyActor {
+    players: HashMap<IdType, Session>,
+    tokens: HashMap<String, IdType>,
+    accounts: Accounts,
+    account_sessions: HashMap<IdType, IdType>,// Account -> its session
+    reconnect_grace: Duration,
+    rooms: HashMap<IdType, RoomEntry>,
+    features: FeatureConfig,
+    federation: FederationConfig,
+    peer_rooms: HashMap<String, RoomList>,// Last room list of every peer that answered
+    rng: ThreadRng,
+    arbiters: Vec<Arbiter>, // The rooms are spread over these threads.
+    next_arbiter: usize,
+    matchmaking: Weights,
+    shutting_down: bool,
+    incident: Option<Arc<OutEvent>>,// The event of the incident in progress, sent to every new session
+    motd: Option<Arc<OutEvent>>,// The announcement sent to every new session, MOTD at first
+    admin_jobs: VecDeque<AdminJob>,// The latest last
+    next_admin_job: u64,
+    replays: VecDeque<(IdType, Arc<Replay>)>,// The latest last
+    room_codes: HashMap<String, IdType>,
+    // Found with a stopped actor by the last sweep: the message telling the lobby may be on its way.
+    dead_rooms: HashSet<IdType>,
+    dead_sessions: HashSet<IdType>,
+    replication_log: Option<replication::Log>,
+    standby: Option<Standby>,
+    queue: Vec<QueueEntry>,// The oldest first
+    queue_waits: WaitEstimate,
+    cluster: Option<ClusterConfig>,
+    cluster_closed: Vec<(IdType, String)>,// Rooms to delete from the registry with the next refresh
+    cluster_refreshing: bool,
+    migrated: HashMap<IdType, (RoomData, Seats)>,// Imported from another instance, waiting for their first player
+    dev_rooms: DevRooms,
+    tournaments: HashMap<IdType, (Tournament, HashMap<IdType, Member>)>,// With the players that registered
+}
+
+impl Default for LobbyActor {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map(|x| x.get()).unwrap_or(1);
+        let reconnect_grace = env::var("RECONNECT_GRACE_SECS").ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_RECONNECT_GRACE_SECS);
+        let federation = FederationConfig::from_env();
+        let replication = ReplicationConfig::from_env();
+        if replication.primary.is_some() && replication.token.is_none() {
+            warn!("REPLICATION_PRIMARY is set without REPLICATION_TOKEN, this instance isn't a standby");
+        }
+
+        LobbyActor {
+            players: HashMap::new(),
+            tokens: HashMap::new(),
+            accounts: Accounts::from_env(),
+            account_sessions: HashMap::new(),
+            reconnect_grace: Duration::from_secs(reconnect_grace),
+            rooms: HashMap::new(),
+            features: FeatureConfig::from_env(),
+            cluster: ClusterConfig::from_env(federation.public_url.as_deref()),
+            federation,
+            peer_rooms: HashMap::new(),
+            rng: rand::thread_rng(),
+            arbiters: (0..threads).map(|_| Arbiter::new()).collect(),
+            next_arbiter: 0,
+            matchmaking: Weights::from_env(),
+            queue: Vec::new(),
+            queue_waits: WaitEstimate::default(),
+            shutting_down: false,
+            incident: None,
+            motd: env::var("MOTD").ok()
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .map(|message| Arc::new(OutEvent::ServerAnnouncement { message })),
+            admin_jobs: VecDeque::new(),
+            next_admin_job: 1,
+            replays: VecDeque::new(),
+            room_codes: HashMap::new(),
+            dead_rooms: HashSet::new(),
+            dead_sessions: HashSet::new(),
+            replication_log: replication.token.as_ref().map(|_| replication::Log::new(rand::random())),
+            standby: replication.primary.zip(replication.token).map(|(primary, token)| Standby {
+                primary,
+                token,
+                mirror: Mirror::default(),
+                contact_at: Instant::now(),
+                polling: false,
+                promoted: false,
+            }),
+            cluster_closed: Vec::new(),
+            cluster_refreshing: false,
+            migrated: HashMap::new(),
+            dev_rooms: DevRooms::from_env(),
+            tournaments: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for LobbyActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(FEATURES_RELOAD_INTERVAL, |act, _| {
+            if act.features.reload_if_changed() {
+                info!("Feature flags reloaded");
+                for room in act.rooms.values() {
+                    room.addr.do_send(room_actor::UpdateFeatures(act.features.flags.clone()));
+                }
+            }
+        });
+        ctx.run_interval(REBALANCE_INTERVAL, |act, _| {
+            if act.features.flags.room_rebalancing {
+                act.rebalance_pub_rooms();
+            }
+        });
+        ctx.run_interval(matchmaking::QUEUE_INTERVAL, |act, ctx| act.match_queue(ctx));
+        ctx.run_interval(cleanup::SWEEP_INTERVAL, |act, ctx| act.sweep(ctx));
+        ctx.run_interval(accounts::SAVE_INTERVAL, |act, _| act.accounts.save());
+        if !self.federation.peers.is_empty() {
+            ctx.run_interval(FEDERATION_POLL_INTERVAL, |act, ctx| act.poll_peers(ctx));
+        }
+        if let Some(standby) = &self.standby {
+            info!(primary = %standby.primary, "Standby of the primary");
+            ctx.run_interval(replication::POLL_INTERVAL, |act, ctx| act.poll_primary(ctx));
+        }
+        if self.cluster.is_some() {
+            ctx.run_interval(cluster::REFRESH_INTERVAL, |act, ctx| act.refresh_cluster(ctx));
+        }
+    }
+}
+
+impl LobbyActor {
+    fn create_room(&mut self, ctx: &mut Context<Self>, host: Member, public: bool, options: RoomOptions) -> CreateRoomResult {
+        let mut id;
+
+        loop {
+            id = self.rng.gen::<IdType>();
+
+            if !self.rooms.contains_key(&id) && !self.is_mirrored(Some(id), None) {
+                break;
+            }
+        }
+
+        let code = loop {
+            let code = self.dev_rooms.generate(&mut self.rng, options.dev_room);
+            if !self.room_codes.contains_key(&code) && !self.is_mirrored(None, Some(&code))
+                && (options.dev_room || !self.dev_rooms.is_reserved(&code)) {
+                break code;
+            }
+        };
+        self.start_room(ctx, id, code, host, public, options)
+    }
+
+    fn start_room(&mut self, ctx: &mut Context<Self>, id: IdType, code: String, host: Member, public: bool, options: RoomOptions) -> CreateRoomResult {
+        self.replicate(Mutation::Created { room_id: id.into(), code: code.clone(), public, options: options.clone() });
+        let player = host.player_object(true);
+        let host_id = host.id;
+        let host_name = host.details.username.clone();
+        let lobby = ctx.address();
+        let features = self.features.flags.clone();
+        let arbiter = &self.arbiters[self.next_arbiter];
+        self.next_arbiter = (self.next_arbiter + 1) % self.arbiters.len();
+
+        self.room_codes.insert(code.clone(), id);
+        let room_code = code.clone();
+        let room_options = options.clone();
+        let room = RoomActor::start_in_arbiter(arbiter, move |_| RoomActor::new(id, room_code, lobby, host, features, room_options));
+
+        self.rooms.insert(id, RoomEntry {
+            addr: room.clone(),
+            public,
+            player_count: 1,
+            state: RoomState::Matchmaking,
+            locked: false,
+            host: host_name,
+            settings: RoomSettings::default(),
+            created_at: Instant::now(),
+            merge_proposed: false,
+            region: None,
+            languages: Vec::new(),
+            players: vec![host_id],
+            code: code.clone(),
+            active_at: Instant::now(),
+            options,
+        });
+
+        CreateRoomResult {
+            room_id: id,
+            code,
+            room,
+            player,
+        }
+    }
+
+    /// Proposes to the players of lingering half-empty public rooms to merge into a bigger one.
+    /// Every player can accept on its own, the proposals are replaced on the next run.
+    fn rebalance_pub_rooms(&mut self) {
+        let now = Instant::now();
+        let mut candidates: Vec<(IdType, usize)> = Vec::new();
+
+        for (room_id, room) in self.rooms.iter_mut() {
+            // The proposals between young rooms (see merge_young_room) are left alone.
+            if room.merge_proposed && !room.is_mergeable(now) {
+                room.merge_proposed = false;
+                room.addr.do_send(room_actor::ProposeMerge { target: None, player_count: 0 });
+            }
+
+            if room.is_available() && room.player_count < MIN_PLAYERS_PER_ROOM &&
+                now.duration_since(room.created_at) >= REBALANCE_MIN_ROOM_AGE {
+                candidates.push((*room_id, room.player_count));
+            }
+        }
+
+        if candidates.len() < 2 {
+            return;
+        }
+        // The most crowded room is the one the others can merge into.
+        candidates.sort_by_key(|x| std::cmp::Reverse(x.1));
+        let (target_id, mut target_count) = candidates[0];
+        let target_max = self.rooms[&target_id].settings.player_limit();
+
+        for (room_id, count) in candidates.into_iter().skip(1) {
+            if target_count + count > target_max {
+                continue;
+            }
+
+            let room = self.rooms.get_mut(&room_id).unwrap();
+            room.merge_proposed = true;
+            room.addr.do_send(room_actor::ProposeMerge {
+                target: Some(target_id),
+                player_count: target_count,
+            });
+            target_count += count;
+            info!(room = room_id, target = target_id, "Room proposed to merge");
+        }
+    }
+
+    /// Proposes a freshly created public room to merge into another one still alone, the oldest.
+    fn merge_young_room(&mut self, room_id: IdType) {
+        let now = Instant::now();
+        match self.rooms.get(&room_id) {
+            Some(x) if x.is_mergeable(now) && !x.merge_proposed => {},
+            _ => return,
+        }
+
+        let target = self.rooms.iter()
+            .filter(|(id, x)| **id != room_id && x.is_mergeable(now) && !x.merge_proposed)
+            .min_by_key(|(_, x)| x.created_at)
+            .map(|(id, _)| *id);
+        let target = match target {
+            Some(x) => x,
+            None => return,
+        };
+
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.merge_proposed = true;
+        room.addr.do_send(room_actor::ProposeMerge { target: Some(target), player_count: 1 });
+        info!(room = room_id, target, "Young room proposed to merge");
+    }
+
+    fn poll_peers(&mut self, ctx: &mut Context<Self>) {
+        for peer in self.federation.peers.iter() {
+            let peer = peer.clone();
+            ctx.spawn(federation::fetch_rooms(peer.clone()).into_actor(self).map(move |res, act, _| {
+                match res {
+                    Ok(list) => {
+                        act.peer_rooms.insert(peer, list);
+                    },
+                    Err(e) => {
+                        if act.peer_rooms.remove(&peer).is_some() {
+                            warn!(peer = %peer, error = %e, "Federation peer unreachable");
+                        }
+                    },
+                }
+            }));
+        }
+    }
+
+    fn poll_primary(&mut self, ctx: &mut Context<Self>) {
+        let standby = match &mut self.standby {
+            Some(x) if !x.promoted && !x.polling => x,
+            _ => return,
+        };
+        standby.polling = true;
+        let fetch = replication::fetch(standby.primary.clone(), standby.token.clone(), standby.mirror.epoch, standby.mirror.seq);
+        ctx.spawn(fetch.into_actor(self).map(|res, act, _| {
+            let standby = match &mut act.standby {
+                Some(x) => x,
+                None => return,
+            };
+            standby.polling = false;
+            match res {
+                Ok(batch) => {
+                    if batch.snapshot {
+                        info!(rooms = batch.mutations.len() / 2, "Registry snapshot received from the primary");
+                    }
+                    standby.mirror.apply(batch);
+                    standby.contact_at = Instant::now();
+                },
+                Err(e) if standby.contact_at.elapsed() >= replication::FAILOVER_AFTER => {
+                    warn!(error = %e, rooms = standby.mirror.rooms.len(), "Primary unreachable, the standby takes over");
+                    standby.promoted = true;
+                },
+                Err(e) => warn!(error = %e, "Primary didn't answer"),
+            }
+        }));
+    }
+
+    /// Writes the rooms of this instance to the registry of the cluster.
+    fn refresh_cluster(&mut self, ctx: &mut Context<Self>) {
+        let cluster = match &self.cluster {
+            Some(x) if self.features.flags.clustering && !self.cluster_refreshing => x,
+            _ => return,
+        };
+        let rooms = self.rooms.iter().map(|(id, room)| (*id, room.code.clone())).collect();
+        let closed = std::mem::take(&mut self.cluster_closed);
+        let refresh = cluster::refresh(cluster.redis.clone(), cluster.node_url.clone(), rooms, closed.clone());
+        self.cluster_refreshing = true;
+        ctx.spawn(refresh.into_actor(self).map(move |res, act, _| {
+            act.cluster_refreshing = false;
+            if let Err(e) = res {
+                warn!(error = %e, "Cannot refresh the cluster registry");
+                act.cluster_closed.extend(closed);
+            }
+        }));
+    }
+
+    /// Takes a place in a room of a peer, the count is an estimate until the next poll.
+    fn find_peer_room(&mut self) -> Option<(String, IdType)> {
+        for list in self.peer_rooms.values_mut() {
+            if let Some(room) = list.rooms.iter_mut().find(|x| x.player_count < x.max_players) {
+                room.player_count += 1;
+                return Some((list.url.clone(), room.room_id.into()));
+            }
+        }
+        None
+    }
+
+    /// The available public room with the best matchmaking score for a player of the region.
+    fn find_best_room(&self, region: Option<&str>, languages: &[String]) -> Option<IdType> {
+        let now = Instant::now();
+
+        self.rooms.iter()
+            .filter(|(_, x)| x.is_available())
+            .map(|(id, x)| {
+                let candidate = matchmaking::Candidate {
+                    player_count: x.player_count,
+                    max_players: x.settings.player_limit(),
+                    waiting: now.duration_since(x.created_at),
+                    region: x.region.as_deref(),
+                    languages: x.languages(),
+                };
+                (*id, self.matchmaking.score(&candidate, region, languages))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id)
+    }
+
+    /// The best public room for the player, a new one if there's none.
+    fn find_room(&mut self, ctx: &mut Context<Self>, member: Member, region: Option<String>) -> ResponseActFuture<Self, Result<FindRoomResult, ()>> {
+        let room_id = match self.find_best_room(region.as_deref(), &member.details.languages) {
+            Some(x) => x,
+            None => {
+                if let Some((url, room_id)) = self.find_peer_room() {
+                    info!(room = room_id, url = %url, "Player redirected to a federated room");
+                    return Box::new(fut::ready(Ok(FindRoomResult::Redirect { url, room_id })));
+                }
+                if self.shutting_down {
+                    return Box::new(fut::ready(Ok(FindRoomResult::ShuttingDown)));
+                }
+                info!("Creating a room for the player");
+                return Box::new(fut::ready(Ok(self.create_public_room(ctx, member, region))));
+            }
+        };
+
+        let join = self.rooms[&room_id].addr.send(room_actor::Join { member: member.clone(), password: None, invited: false, ticket: None });
+        Box::new(join.into_actor(self).map(move |res, act, ctx| {
+            Ok(match res {
+                Ok(JoinRoomResult::Success { room, players, settings, pinned, countdown }) => {
+                    info!(room = room_id, player = member.id, "Room found for the player");
+                    FindRoomResult::Success { room_id, room, players, just_created: false, settings, pinned, countdown }
+                },
+                // The room filled up or started in the meantime.
+                _ if act.shutting_down => FindRoomResult::ShuttingDown,
+                _ => act.create_public_room(ctx, member, region),
+            })
+        }))
+    }
+
+    /// Takes the player out of the matchmaking queue, its room_find request is answered if `tell`.
+    fn leave_queue(&mut self, id: IdType, tell: bool) -> bool {
+        let entry = match self.queue.iter().position(|x| x.member.id == id) {
+            Some(i) => self.queue.remove(i),
+            None => return false,
+        };
+        if tell {
+            entry.member.addr.do_send(client_ws::QueueResult { request_id: entry.request_id, result: FindRoomResult::Cancelled });
+        }
+        true
+    }
+
+    /// Gives a room to the groups of queued players that are ready and tells the others where they are.
+    fn match_queue(&mut self, ctx: &mut Context<Self>) {
+        if self.queue.is_empty() || self.shutting_down || self.incident.is_some() {
+            return;
+        }
+        let now = Instant::now();
+        let players: Vec<QueuedPlayer> = self.queue.iter()
+            .map(|x| QueuedPlayer { region: x.region.as_deref(), waited: now.duration_since(x.since) })
+            .collect();
+        let groups = matchmaking::batch(&players, RoomSettings::default().player_limit());
+
+        let mut entries: Vec<Option<QueueEntry>> = self.queue.drain(..).map(Some).collect();
+        for group in groups {
+            let group: Vec<QueueEntry> = group.into_iter().filter_map(|i| entries[i].take()).collect();
+            for entry in group.iter() {
+                self.queue_waits.record(now.duration_since(entry.since));
+            }
+            self.match_group(ctx, group);
+        }
+        self.queue = entries.into_iter().flatten().collect();
+
+        for (i, entry) in self.queue.iter().enumerate() {
+            let eta = self.queue_waits.eta(now.duration_since(entry.since));
+            entry.member.addr.do_send(Event(Arc::new(OutEvent::EventQueueUpdate { position: i + 1, eta })));
+        }
+    }
+
+    /// Creates a room for the group, the first player is its host. A player alone finds a room as
+    /// without the queue.
+    fn match_group(&mut self, ctx: &mut Context<Self>, mut group: Vec<QueueEntry>) {
+        let first = group.remove(0);
+        let (addr, request_id) = (first.member.addr.clone(), first.request_id);
+        if group.is_empty() {
+            let find = self.find_room(ctx, first.member, first.region);
+            ctx.spawn(find.map(move |res, _, _| {
+                if let Ok(result) = res {
+                    addr.do_send(client_ws::QueueResult { request_id, result });
+                }
+            }));
+            return;
+        }
+
+        let result = self.create_public_room(ctx, first.member, first.region);
+        let (room_id, room) = match &result {
+            FindRoomResult::Success { room_id, room, .. } => (*room_id, room.clone()),
+            _ => return,
+        };
+        info!(room = room_id, players = group.len() + 1, "Room created for queued players");
+        addr.do_send(client_ws::QueueResult { request_id, result });
+
+        for entry in group {
+            let join = room.send(room_actor::Join { member: entry.member.clone(), password: None, invited: false, ticket: None });
+            ctx.spawn(join.into_actor(self).map(move |res, act, _| match res {
+                Ok(JoinRoomResult::Success { room, players, settings, pinned, countdown }) => {
+                    let result = FindRoomResult::Success { room_id, room, players, just_created: false, settings, pinned, countdown };
+                    entry.member.addr.do_send(client_ws::QueueResult { request_id: entry.request_id, result });
+                },
+                // The room filled up or started in the meantime, the player keeps its place.
+                _ if act.players.contains_key(&entry.member.id) && act.queue.iter().all(|x| x.member.id != entry.member.id) => {
+                    let at = act.queue.iter().position(|x| x.since > entry.since).unwrap_or(act.queue.len());
+                    act.queue.insert(at, entry);
+                },
+                _ => {},
+            }));
+        }
+    }
+
+    fn create_public_room(&mut self, ctx: &mut Context<Self>, member: Member, region: Option<String>) -> FindRoomResult {
+        let languages = member.details.languages.clone();
+        let res = self.create_room(ctx, member, true, RoomOptions::default());
+        let entry = self.rooms.get_mut(&res.room_id).unwrap();
+        entry.region = region;
+        entry.languages = languages;
+        if self.features.flags.room_rebalancing {
+            let room_id = res.room_id;
+            ctx.run_later(MERGE_CHECK_DELAY, move |act, _| act.merge_young_room(room_id));
+        }
+
+        FindRoomResult::Success {
+            room_id: res.room_id,
+            room: res.room,
+            players: vec![res.player],
+            just_created: true,
+            settings: RoomSettings::default(),
+            pinned: None,
+            countdown: None,
+        }
+    }
+
+    /// Creates the rooms of the matches to play, tells the organizer and the players of the
+    /// tournament (but `except`) and forgets it once it's over. The tournament as it is now.
+    fn update_tournament(&mut self, ctx: &mut Context<Self>, id: IdType, except: Option<IdType>) -> TournamentResponse {
+        while let Some(index) = self.tournaments.get(&id).and_then(|x| x.0.to_play()) {
+            self.start_match(ctx, id, index);
+        }
+        let tournament = &self.tournaments[&id].0;
+        let response = TournamentResponse { tournament: tournament.view() };
+        let event = Arc::new(OutEvent::EventTournamentUpdate { tournament: response.tournament.clone() });
+        for follower in tournament.followers().filter(|x| Some(*x) != except) {
+            if let Some(session) = self.players.get(&follower).filter(|x| x.suspended.is_none()) {
+                session.addr.do_send(Event(event.clone()));
+            }
+        }
+        if tournament.state == TournamentState::Finished || tournament.state == TournamentState::Canceled {
+            info!(tournament = id, state = ?tournament.state, winner = ?tournament.winner(), "Tournament over");
+            self.tournaments.remove(&id);
+        }
+        response
+    }
+
+    /// Puts both players of a match in a new room, the first one is its host. A player without a
+    /// connection loses the match.
+    fn start_match(&mut self, ctx: &mut Context<Self>, id: IdType, index: usize) {
+        let (tournament, members) = self.tournaments.get_mut(&id).unwrap();
+        let sessions = &self.players;
+        let round = tournament.rounds.len();
+        let players = &tournament.rounds[round - 1][index].players;
+        let present: Vec<Member> = players.iter()
+            .filter_map(|x| {
+                let session = sessions.get(x).filter(|x| x.suspended.is_none())?;
+                // The connection changes when the session is resumed.
+                members.get(x).map(|x| Member { addr: session.addr.clone(), ..x.clone() })
+            })
+            .collect();
+        if present.len() < 2 {
+            tournament.decide(index, present.first().map(|x| x.id));
+            return;
+        }
+
+        for member in present.iter() {
+            self.leave_queue(member.id, true);
+        }
+        let (host, other) = (present[0].clone(), present[1].clone());
+        let created = self.create_room(ctx, host.clone(), false, RoomOptions { tournament: true, ..RoomOptions::default() });
+        let room_id = created.room_id;
+        self.tournaments.get_mut(&id).unwrap().0.rounds[round - 1][index].room = Some(room_id);
+        info!(tournament = id, round, room = room_id, "Tournament match created");
+
+        host.addr.do_send(client_ws::TournamentMatch {
+            tournament_id: id,
+            round,
+            room_id,
+            room: created.room.clone(),
+            players: vec![created.player],
+            settings: tournament::match_settings(),
+            pinned: None,
+            countdown: None,
+        });
+        let join = created.room.send(room_actor::Join { member: other.clone(), password: None, invited: true, ticket: None });
+        ctx.spawn(join.into_actor(self).map(move |res, act, ctx| match res {
+            Ok(JoinRoomResult::Success { room, players, settings, pinned, countdown }) => {
+                other.addr.do_send(client_ws::TournamentMatch { tournament_id: id, round, room_id, room, players, settings, pinned, countdown });
+            },
+            // The room closed in the meantime, the match went to the host if it's still undecided.
+            _ => act.end_match(ctx, room_id, Some(host.id)),
+        }));
+    }
+
+    /// Gives the match played in the room to a player, to the better seed without one.
+    fn end_match(&mut self, ctx: &mut Context<Self>, room_id: IdType, winner: Option<IdType>) {
+        let found = self.tournaments.iter().find_map(|(id, x)| x.0.match_of_room(room_id).map(|index| (*id, index)));
+        if let Some((id, index)) = found {
+            self.tournaments.get_mut(&id).unwrap().0.decide(index, winner);
+            let _ = self.update_tournament(ctx, id, None);
+        }
+    }
+
+    fn leave_tournament(&mut self, ctx: &mut Context<Self>, id: IdType, tournament_id: IdType) -> Result<TournamentResponse, &'static str> {
+        let (tournament, members) = self.tournaments.get_mut(&tournament_id).ok_or("tournament_not_found")?;
+        if tournament.organizer == id && tournament.state == TournamentState::Registering {
+            tournament.state = TournamentState::Canceled;
+        } else if members.remove(&id).is_some() {
+            tournament.remove_player(id);
+        } else {
+            return Err("not_registered");
+        }
+        Ok(self.update_tournament(ctx, tournament_id, Some(id)))
+    }
+}
+
+impl Handler<RegisterSession> for LobbyActor {
+    type Result = Result<SessionInfo, &'static str>;
+
+    fn handle(&mut self, msg: RegisterSession, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("register_session").entered();
+        if msg.ip.map(|x| self.accounts.is_banned_address(x)).unwrap_or(false) {
+            info!(ip = ?msg.ip, "Login from a banned address refused");
+            return Err("banned");
+        }
+        let (account, account_token) = self.accounts.login(msg.account_token.as_deref(), &mut self.rng)?;
+        if self.accounts.get(account).unwrap().banned {
+            info!(account, "Login of a banned account refused");
+            return Err("banned");
+        }
+        // The client should resume the session it has.
+        if self.account_sessions.contains_key(&account) {
+            return Err("account_in_use");
+        }
+        let inventory = self.accounts.get(account).unwrap().inventory.clone();
+        if !inventory.owns(&msg.cosmetics) {
+            return Err("cosmetics_not_owned");
+        }
+        let mut id;
+
+        loop {
+            id = self.rng.gen::<IdType>();
+
+            if !self.players.contains_key(&id) {
+                break;
+            }
+        }
+        let token = base64::encode(self.rng.gen::<[u8; 16]>());
+        self.tokens.insert(token.clone(), id);
+        for event in self.motd.iter().chain(self.incident.iter()) {
+            msg.addr.do_send(Event(event.clone()));
+        }
+        let stored = self.accounts.get_mut(account).unwrap();
+        stored.username = msg.username.clone();
+        stored.privacy = msg.privacy;
+        self.account_sessions.insert(account, id);
+        self.players.insert(id, Session {
+            addr: msg.addr,
+            username: msg.username,
+            token: token.clone(),
+            suspended: None,
+            account,
+            history: VecDeque::new(),
+            invites: HashMap::new(),
+            ip: msg.ip,
+        });
+        Ok(SessionInfo { id, token, account, account_token, inventory })
+    }
+}
+
+impl Handler<ListRooms> for LobbyActor {
+    type Result = RoomListResponse;
+
+    fn handle(&mut self, msg: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("list_rooms", page = msg.page).entered();
+        let mut rooms: Vec<(&IdType, &RoomEntry)> = self.rooms.iter()
+            .filter(|(_, x)| x.public)
+            .collect();
+        rooms.sort_by_key(|(_, x)| x.created_at);
+
+        let page_count = rooms.len().div_ceil(ROOM_LIST_PAGE_SIZE);
+        let rooms = rooms.into_iter()
+            .skip(msg.page * ROOM_LIST_PAGE_SIZE)
+            .take(ROOM_LIST_PAGE_SIZE)
+            .map(|(id, x)| RoomSummary {
+                room_id: (*id).into(),
+                host: x.host.clone(),
+                player_count: x.player_count,
+                state: x.state,
+                locked: x.locked,
+                rules: x.settings.clone(),
+                player_limit: x.settings.player_limit(),
+            })
+            .collect();
+
+        RoomListResponse { rooms, page: msg.page, page_count }
+    }
+}
+
+impl Handler<GetPublicRooms> for LobbyActor {
+    type Result = Option<RoomList>;
+
+    fn handle(&mut self, _: GetPublicRooms, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_public_rooms").entered();
+        let url = self.federation.public_url.clone()?;
+        let rooms = self.rooms.iter()
+            .filter(|(_, x)| x.is_available())
+            .map(|(id, x)| PublicRoom {
+                room_id: (*id).into(),
+                player_count: x.player_count,
+                max_players: x.settings.player_limit(),
+            })
+            .collect();
+
+        Some(RoomList { url, rooms })
+    }
+}
+
+impl Handler<GetReplicationLog> for LobbyActor {
+    type Result = Option<Batch>;
+
+    fn handle(&mut self, msg: GetReplicationLog, _: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("get_replication_log", after = msg.after).entered();
+        let log = self.replication_log.as_ref()?;
+        let (snapshot, mutations) = match log.since(msg.epoch, msg.after) {
+            Some(x) => (false, x),
+            None => (true, self.rooms.iter().flat_map(|(id, room)| vec![
+                Mutation::Created {
+                    room_id: (*id).into(),
+                    code: room.code.clone(),
+                    public: room.public,
+                    options: room.options.clone(),
+                },
+                Mutation::Updated {
+                    room_id: (*id).into(),
+                    state: room.state,
+                    player_count: room.player_count,
+                    host: room.host.clone(),
+                    settings: room.settings.clone(),
+                },
+            ]).collect()),
+        };
+        Some(Batch { epoch: log.epoch(), seq: log.seq(), snapshot, mutations })
+    }
+}
+
+impl Handler<GetHello> for LobbyActor {
+    type Result = Hello;
+
+    fn handle(&mut self, _: GetHello, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_hello").entered();
+        let reconnect_window = Some(self.reconnect_grace.as_secs()).filter(|x| *x > 0);
+        Hello::new(self.features.flags.clone(), reconnect_window)
+    }
+}
+
+impl Handler<RecordMatch> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: RecordMatch, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("record_match", room = msg.record.room_id.0).entered();
+        if let Some(replay) = msg.replay {
+            let replay_id = loop {
+                let id = self.rng.gen::<IdType>();
+                if self.replays.iter().all(|x| x.0 != id) {
+                    break id;
+                }
+            };
+            if self.replays.len() >= MAX_REPLAYS {
+                self.replays.pop_front();
+            }
+            self.replays.push_back((replay_id, Arc::new(replay)));
+            msg.record.replay_id = Some(replay_id.into());
+        }
+
+        for player in msg.record.players.iter() {
+            if let Some(session) = self.players.get_mut(&player.id.0) {
+                session.history.push_front(msg.record.clone());
+                session.history.truncate(MATCH_HISTORY_LEN);
+                if let Some(result) = msg.results.get(&player.id.0) {
+                    let account = self.accounts.get_mut(session.account).unwrap();
+                    account.stats.record(result);
+                    let reached: Vec<Achievement> = achievements::evaluate(&AchievementEvent::Ended(&account.stats, result)).collect();
+                    session.unlock_achievements(player.id.0, account, reached.into_iter());
+                }
+            }
+        }
+
+        let winner = tournament::adjudicate(&msg.scores, &msg.violations);
+        self.end_match(ctx, msg.record.room_id.0, winner);
+    }
+}
+
+impl Handler<GetReplay> for LobbyActor {
+    type Result = Option<Arc<Replay>>;
+
+    fn handle(&mut self, msg: GetReplay, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_replay", replay = msg.replay_id).entered();
+        self.replays.iter().find(|x| x.0 == msg.replay_id).map(|x| x.1.clone())
+    }
+}
+
+impl Handler<GetMatchHistory> for LobbyActor {
+    type Result = MatchHistoryResponse;
+
+    fn handle(&mut self, msg: GetMatchHistory, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_match_history", player = msg.id).entered();
+        let matches = self.players.get(&msg.id)
+            .map(|x| x.history.iter().cloned().collect())
+            .unwrap_or_default();
+        MatchHistoryResponse { matches }
+    }
+}
+
+impl Handler<RecordSeries> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordSeries, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("record_series").entered();
+        // The players whose session ended aren't rated.
+        let accounts: Vec<(IdType, IdType)> = msg.players.iter()
+            .filter_map(|x| self.players.get(x).map(|session| (*x, session.account)))
+            .collect();
+        let ratings: Vec<Rating> = accounts.iter().map(|(_, x)| self.accounts.get(*x).unwrap().rating).collect();
+        let winner = accounts.iter().position(|(x, _)| msg.winner == Some(*x));
+        for ((player, account), rating) in accounts.iter().zip(ratings::rate_series(&ratings, winner)) {
+            let account = self.accounts.get_mut(*account).unwrap();
+            account.stats.record_series(msg.winner == Some(*player));
+            info!(player, change = rating.0 - account.rating.0, "Series rated");
+            account.rating = rating;
+        }
+    }
+}
+
+impl Handler<ReachAchievements> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReachAchievements, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("reach_achievements").entered();
+        for (player, achievement) in msg.reached {
+            if let Some(session) = self.players.get(&player) {
+                let account = self.accounts.get_mut(session.account).unwrap();
+                session.unlock_achievements(player, account, std::iter::once(achievement));
+            }
+        }
+    }
+}
+
+impl Handler<UpdatePrivacy> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdatePrivacy, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("update_privacy", player = msg.id).entered();
+        if let Some(account) = self.session_account(msg.id) {
+            account.privacy = msg.privacy;
+        }
+    }
+}
+
+impl Handler<GetPlayerStats> for LobbyActor {
+    type Result = Option<PlayerStatsResponse>;
+
+    fn handle(&mut self, msg: GetPlayerStats, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_player_stats").entered();
+        let id = self.account_of(msg.player)?;
+        let account = self.accounts.get(id)?;
+        let requester = msg.requester.and_then(|x| self.players.get(&x)).map(|x| x.account);
+        if account.privacy.private_profile && requester != Some(id) {
+            return None;
+        }
+        let session = self.account_sessions.get(&id).copied();
+        Some(PlayerStatsResponse {
+            rating: account.rating.0.round() as u32,
+            ..account.stats.profile(session, id, account.username.clone(), account.achievements.list())
+        })
+    }
+}
+
+impl Handler<AddFriend> for LobbyActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: AddFriend, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("add_friend", player = msg.id).entered();
+        let session = self.players.get(&msg.id).ok_or("player_not_found")?;
+        let (account, username) = (session.account, session.username.clone());
+        let friend = self.account_of(msg.friend).filter(|x| *x != account).ok_or("player_not_found")?;
+        let own = self.accounts.get(account).unwrap();
+        if own.friends.contains(&friend) {
+            return Ok(());
+        }
+        if own.friends.len() >= MAX_FRIENDS {
+            return Err("too_many_friends");
+        }
+        // Accepting a request is always possible.
+        let other = self.accounts.get(friend).unwrap();
+        let accepted = other.friends.contains(&account);
+        if other.privacy.block_friend_requests && !accepted {
+            return Err("friend_requests_blocked");
+        }
+        let own = self.accounts.get_mut(account).unwrap();
+        own.friends.insert(friend);
+        own.requests.remove(&friend);
+        if !accepted {
+            self.accounts.get_mut(friend).unwrap().requests.insert(account);
+        }
+
+        if let Some(x) = self.account_sessions.get(&friend).and_then(|x| self.players.get(x)) {
+            let event = if accepted {
+                OutEvent::EventFriendAccepted { player: msg.id.into(), username }
+            } else {
+                OutEvent::EventFriendRequest { from: msg.id.into(), username }
+            };
+            x.addr.do_send(Event(Arc::new(event)));
+        }
+        Ok(())
+    }
+}
+
+impl Handler<RemoveFriend> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveFriend, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("remove_friend", player = msg.id).entered();
+        let account = match self.players.get(&msg.id) {
+            Some(x) => x.account,
+            None => return,
+        };
+        let friend = match self.account_of(msg.friend) {
+            Some(x) => x,
+            None => return,
+        };
+        for (a, b) in [(account, friend), (friend, account)] {
+            if let Some(x) = self.accounts.get_mut(a) {
+                x.friends.remove(&b);
+                x.requests.remove(&b);
+            }
+        }
+    }
+}
+
+impl Handler<GetFriends> for LobbyActor {
+    type Result = FriendListResponse;
+
+    fn handle(&mut self, msg: GetFriends, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_friends", player = msg.id).entered();
+        let (id, account) = match self.players.get(&msg.id).and_then(|x| Some((x.account, self.accounts.get(x.account)?))) {
+            Some(x) => x,
+            None => return FriendListResponse { friends: vec![], requests: vec![] },
+        };
+        let friend = |account_id: &IdType| {
+            let account = self.accounts.get(*account_id)?;
+            // Hidden the same while offline.
+            let session = self.account_sessions.get(account_id)
+                .filter(|_| !account.privacy.hide_presence)
+                .and_then(|x| Some((*x, self.players.get(x)?)));
+            Some(Friend {
+                id: session.map(|x| x.0.into()),
+                account_id: (*account_id).into(),
+                username: account.username.clone(),
+                online: session.map(|x| x.1.suspended.is_none()).unwrap_or(false),
+            })
+        };
+
+        let mut friends: Vec<Friend> = account.friends.iter()
+            .filter(|x| self.are_friends(id, **x))
+            .filter_map(friend)
+            .collect();
+        let mut requests: Vec<Friend> = account.requests.iter().filter_map(friend).collect();
+        friends.sort_by(|a, b| a.username.cmp(&b.username));
+        requests.sort_by(|a, b| a.username.cmp(&b.username));
+        FriendListResponse { friends, requests }
+    }
+}
+
+impl Handler<InviteToRoom> for LobbyActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: InviteToRoom, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("invite_to_room", player = msg.from, friend = msg.to).entered();
+        let (room_id, room) = self.rooms.iter()
+            .find(|(_, x)| x.players.contains(&msg.from))
+            .ok_or("not_in_room")?;
+        if room.state != RoomState::Matchmaking {
+            return Err("already_playing");
+        }
+        let account = |id: IdType| self.players.get(&id).map(|x| x.account);
+        match (account(msg.from), account(msg.to)) {
+            (Some(from), Some(to)) if self.are_friends(from, to) => {},
+            _ => return Err("not_friends"),
+        }
+        if room.players.contains(&msg.to) {
+            return Err("already_in_room");
+        }
+        let room_id = *room_id;
+        let target = self.players.get_mut(&msg.to).unwrap();
+        if target.suspended.is_some() {
+            return Err("player_offline");
+        }
+
+        target.invites.insert(room_id, msg.from);
+        target.addr.do_send(Event(Arc::new(OutEvent::EventRoomInvite {
+            room_id: room_id.into(),
+            from: msg.from.into(),
+        })));
+        info!(room = room_id, "Friend invited");
+        Ok(())
+    }
+}
+
+impl Handler<AcceptInvite> for LobbyActor {
+    type Result = ResponseActFuture<Self, Result<JoinRoomResult, ()>>;
+
+    fn handle(&mut self, msg: AcceptInvite, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("accept_invite", room = msg.room_id, player = msg.member.id).entered();
+        self.leave_queue(msg.member.id, true);
+        let invited = self.players.get_mut(&msg.member.id)
+            .and_then(|x| x.invites.remove(&msg.room_id))
+            .is_some();
+        let room = match self.rooms.get(&msg.room_id) {
+            Some(x) if invited && self.dev_rooms.allows(&x.code, &msg.member.details) => &x.addr,
+            _ => return Box::new(fut::ready(Ok(JoinRoomResult::RoomNotFound))),
+        };
+
+        let join = room.send(room_actor::Join { member: msg.member, password: None, invited: true, ticket: None });
+        Box::new(join.into_actor(self).map(|res, _, _| {
+            Ok(res.unwrap_or(JoinRoomResult::RoomNotFound))
+        }))
+    }
+}
+
+impl Handler<DeclineInvite> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeclineInvite, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("decline_invite", room = msg.room_id, player = msg.id).entered();
+        let from = match self.players.get_mut(&msg.id).and_then(|x| x.invites.remove(&msg.room_id)) {
+            Some(x) => x,
+            None => return,
+        };
+        if let Some(x) = self.players.get(&from) {
+            x.addr.do_send(Event(Arc::new(OutEvent::EventRoomInviteDeclined {
+                room_id: msg.room_id.into(),
+                player: msg.id.into(),
+            })));
+        }
+    }
+}
+
+impl LobbyActor {
+    /// The room of an id or a code, if it's still open.
+    fn resolve_invite(&self, invite: &InviteId) -> Option<IdType> {
+        let id = match invite {
+            InviteId::Id(x) => *x,
+            InviteId::Code(x) => *self.room_codes.get(x)?,
+        };
+        Some(id).filter(|x| self.rooms.contains_key(x))
+    }
+
+    fn forget_room(&mut self, room_id: IdType) {
+        if let Some(room) = self.rooms.remove(&room_id) {
+            self.room_codes.remove(&room.code);
+            self.replicate(Mutation::Closed { room_id: room_id.into() });
+            if self.cluster.is_some() {
+                self.cluster_closed.push((room_id, room.code));
+            }
+        }
+        self.admin_target_gone(AdminJobKind::CloseRooms, room_id);
+        for session in self.players.values_mut() {
+            session.invites.remove(&room_id);
+        }
+    }
+
+    fn replicate(&mut self, mutation: Mutation) {
+        if let Some(log) = &mut self.replication_log {
+            log.push(mutation);
+        }
+    }
+
+    /// True if the id or the code belongs to a room of the primary that could still be restored.
+    fn is_mirrored(&self, id: Option<IdType>, code: Option<&str>) -> bool {
+        let mirror = match &self.standby {
+            Some(x) => &x.mirror,
+            None => return false,
+        };
+        id.map(|x| mirror.rooms.contains_key(&x)).unwrap_or(false)
+            || code.and_then(|x| mirror.find_code(x)).is_some()
+    }
+
+    /// Recreates a room of the primary for the first player joining it once the standby took over,
+    /// the player becomes its host.
+    fn restore_room(&mut self, ctx: &mut Context<Self>, member: Member, invite: &InviteId, password: Option<String>) -> JoinRoomResult {
+        let mirror = match &mut self.standby {
+            Some(x) if x.promoted && !self.shutting_down => &mut x.mirror,
+            _ => return JoinRoomResult::RoomNotFound,
+        };
+        let room_id = match invite {
+            InviteId::Id(x) => *x,
+            InviteId::Code(x) => match mirror.find_code(x) {
+                Some(x) => x,
+                None => return JoinRoomResult::RoomNotFound,
+            },
+        };
+        let room: ReplicatedRoom = match mirror.rooms.get(&room_id) {
+            None => return JoinRoomResult::RoomNotFound,
+            Some(x) if !self.dev_rooms.allows(&x.code, &member.details) => return JoinRoomResult::RoomNotFound,
+            Some(x) if x.options.password.is_some() && x.options.password != password => return JoinRoomResult::WrongPassword,
+            Some(_) => mirror.rooms.remove(&room_id).unwrap(),
+        };
+
+        let _span = info_span!("restore_room", room = room_id, player = member.id).entered();
+        let host_id = member.id;
+        let settings = room.settings;
+        let created = self.start_room(ctx, room_id, room.code, member, room.public, room.options);
+        created.room.do_send(room_actor::UpdateRoomSettings { id: host_id, settings: settings.clone() });
+        info!(was = ?room.state, "Room of the primary restored");
+        JoinRoomResult::Success {
+            room: created.room,
+            players: vec![created.player],
+            settings,
+            pinned: None,
+            countdown: None,
+        }
+    }
+
+    /// The room moved here with this id or code that no player recreated yet.
+    fn find_migrated(&self, invite: &InviteId) -> Option<IdType> {
+        match invite {
+            InviteId::Id(x) => Some(*x).filter(|x| self.migrated.contains_key(x)),
+            InviteId::Code(x) => self.migrated.iter().find(|(_, room)| room.0.code == *x).map(|(id, _)| *id),
+        }
+    }
+
+    /// Recreates a room moved here from another instance for the first of its players back, the
+    /// ticket of its event_room_migrated is needed.
+    fn restore_migrated(&mut self, ctx: &mut Context<Self>, room_id: IdType, member: Member, ticket: Option<String>) -> JoinRoomResult {
+        let claimed = match (self.migrated.get_mut(&room_id), ticket) {
+            (Some(room), Some(ticket)) => room.1.claim(&ticket, member.id),
+            _ => false,
+        };
+        if self.shutting_down {
+            return JoinRoomResult::RoomNotFound;
+        }
+        if !claimed {
+            return JoinRoomResult::InvalidTicket;
+        }
+        let (data, seats) = self.migrated.remove(&room_id).unwrap();
+
+        let _span = info_span!("restore_migrated", room = room_id, player = member.id).entered();
+        let created = self.start_room(ctx, room_id, data.code, member, data.public, data.options);
+        created.room.do_send(room_actor::RestoreMigrated { settings: data.settings.clone(), pinned: data.pinned.clone(), seats });
+        info!(seats = data.seats.len(), "Room of another instance restored");
+        JoinRoomResult::Success {
+            room: created.room,
+            players: vec![created.player],
+            settings: data.settings,
+            pinned: data.pinned,
+            countdown: None,
+        }
+    }
+
+    /// Closes the idle rooms and forgets the rooms and the sessions whose actor stopped, see cleanup.rs.
+    fn sweep(&mut self, ctx: &mut Context<Self>) {
+        let _span = debug_span!("sweep").entered();
+        let now = Instant::now();
+        let mut sweep = Sweep::default();
+
+        let dead: HashSet<IdType> = self.rooms.iter()
+            .filter(|(_, x)| !x.addr.connected())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in dead.intersection(&self.dead_rooms).copied().collect::<Vec<_>>() {
+            self.forget_room(id);
+            sweep.dead_rooms += 1;
+        }
+        self.dead_rooms = dead;
+
+        if let Some(ttl) = cleanup::room_idle_ttl() {
+            for room in self.rooms.values_mut() {
+                if room.state == RoomState::Matchmaking && now.duration_since(room.active_at) >= ttl {
+                    room.addr.do_send(room_actor::Close);
+                    room.active_at = now;
+                    sweep.idle_rooms += 1;
+                }
+            }
+        }
+
+        // The sessions waiting for a reconnection have no actor and expire on their own.
+        let dead: HashSet<IdType> = self.players.iter()
+            .filter(|(_, x)| x.suspended.is_none() && !x.addr.connected())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in dead.intersection(&self.dead_sessions).copied().collect::<Vec<_>>() {
+            self.remove_session(ctx, id);
+            if let Some(room) = self.rooms.values().find(|x| x.players.contains(&id)) {
+                room.addr.do_send(room_actor::Leave { id });
+            }
+            sweep.dead_sessions += 1;
+        }
+        self.dead_sessions = dead;
+
+        sweep.record();
+        if !sweep.is_empty() {
+            info!(idle_rooms = sweep.idle_rooms, dead_rooms = sweep.dead_rooms, dead_sessions = sweep.dead_sessions, "Lobby swept");
+        }
+    }
+
+    fn remove_session(&mut self, ctx: &mut Context<Self>, id: IdType) -> Option<Session> {
+        let session = self.players.remove(&id)?;
+        self.tokens.remove(&session.token);
+        self.leave_queue(id, false);
+        let tournaments: Vec<IdType> = self.tournaments.iter()
+            .filter(|(_, x)| x.0.followers().any(|x| x == id))
+            .map(|(id, _)| *id)
+            .collect();
+        for tournament_id in tournaments {
+            let _ = self.leave_tournament(ctx, id, tournament_id);
+        }
+        self.account_sessions.remove(&session.account);
+        self.admin_target_gone(AdminJobKind::KickPlayers, id);
+        self.admin_target_gone(AdminJobKind::BanPlayers, id);
+        Some(session)
+    }
+
+    /// Starts tracking a bulk admin operation, the oldest ones are forgotten.
+    fn add_admin_job(&mut self, kind: AdminJobKind, total: usize, pending: HashSet<IdType>) -> u64 {
+        let id = self.next_admin_job;
+        self.next_admin_job += 1;
+        if self.admin_jobs.len() >= MAX_ADMIN_JOBS {
+            self.admin_jobs.pop_front();
+        }
+        self.admin_jobs.push_back(AdminJob { id, kind, total, pending, started_at: Instant::now() });
+        id
+    }
+
+    fn admin_job_report(&self, id: u64) -> Option<AdminJobReport> {
+        self.admin_jobs.iter().find(|x| x.id == id).map(|x| x.report())
+    }
+
+    fn admin_target_gone(&mut self, kind: AdminJobKind, id: IdType) {
+        for job in self.admin_jobs.iter_mut().filter(|x| x.kind == kind) {
+            job.pending.remove(&id);
+        }
+    }
+
+    /// The players of a bulk operation, the unknown ids if there are some.
+    fn known_players(&self, ids: Vec<IdType>) -> Result<HashSet<IdType>, Vec<SerId>> {
+        let not_found: Vec<SerId> = ids.iter().filter(|x| !self.players.contains_key(x)).map(|x| (*x).into()).collect();
+        if !not_found.is_empty() {
+            return Err(not_found);
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Ends the sessions of a bulk kick or ban.
+    fn end_sessions(&mut self, ctx: &mut Context<Self>, kind: AdminJobKind, ids: HashSet<IdType>) -> AdminJobReport {
+        // The job is tracked first, the suspended sessions end right away.
+        let job = self.add_admin_job(kind, ids.len(), ids.clone());
+        for id in ids.iter() {
+            let session = &self.players[id];
+            match &session.suspended {
+                Some((_, handle)) => {
+                    ctx.cancel_future(*handle);
+                    self.end_suspended_session(ctx, *id);
+                },
+                None => session.addr.do_send(client_ws::Kicked),
+            }
+        }
+        self.admin_job_report(job).unwrap()
+    }
+
+    /// The account of a session, saved with the next save.
+    fn session_account(&mut self, id: IdType) -> Option<&mut Account> {
+        let account = self.players.get(&id)?.account;
+        self.accounts.get_mut(account)
+    }
+
+    /// The account of a player, none if it doesn't exist.
+    fn account_of(&self, player: PlayerRef) -> Option<IdType> {
+        match player {
+            PlayerRef::Player(id) => self.players.get(&id).map(|x| x.account),
+            PlayerRef::Account(id) => self.accounts.get(id).map(|_| id),
+        }
+    }
+
+    /// Both accounts added the other.
+    fn are_friends(&self, a: IdType, b: IdType) -> bool {
+        let added = |a: IdType, b: IdType| self.accounts.get(a).map(|x| x.friends.contains(&b)).unwrap_or(false);
+        added(a, b) && added(b, a)
+    }
+
+    /// Removes a session waiting for a reconnection, its player leaves the room.
+    fn end_suspended_session(&mut self, ctx: &mut Context<Self>, id: IdType) -> bool {
+        let session = match self.remove_session(ctx, id) {
+            Some(x) => x,
+            None => return false,
+        };
+        if let Some((SessionSnapshot { room: Some(room), .. }, _)) = session.suspended {
+            room.do_send(room_actor::Leave { id });
+        }
+        true
+    }
+}
+
+impl Handler<Disconnect> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("disconnect", player = msg.id).entered();
+        self.leave_queue(msg.id, false);
+        let snapshot = match msg.snapshot {
+            Some(x) if self.reconnect_grace > Duration::from_secs(0) => x,
+            _ => {
+                self.remove_session(ctx, msg.id);
+                return;
+            }
+        };
+        let session = match self.players.get_mut(&msg.id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let id = msg.id;
+        let handle = ctx.run_later(self.reconnect_grace, move |act, ctx| {
+            if act.end_suspended_session(ctx, id) {
+                info!(player = id, "Session expired");
+            }
+        });
+        session.suspended = Some((snapshot, handle));
+    }
+}
+
+impl Handler<ResumeSession> for LobbyActor {
+    type Result = Option<ResumedSession>;
+
+    fn handle(&mut self, msg: ResumeSession, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("resume_session").entered();
+        let id = *self.tokens.get(&msg.token)?;
+        let session = self.players.get_mut(&id)?;
+        // Only a session that lost its connection can be resumed.
+        let (snapshot, handle) = session.suspended.take()?;
+        ctx.cancel_future(handle);
+        session.addr = msg.addr;
+        if let Some(event) = &self.incident {
+            session.addr.do_send(Event(event.clone()));
+        }
+
+        info!(player = id, "Session resumed");
+        let inventory = self.accounts.get(session.account).unwrap().inventory.clone();
+        Some(ResumedSession { id, snapshot, inventory })
+    }
+}
+
+impl Handler<FindRoom> for LobbyActor {
+    type Result = ResponseActFuture<Self, Result<FindRoomResult, ()>>;
+
+    fn handle(&mut self, msg: FindRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("find_room", player = msg.member.id).entered();
+        self.leave_queue(msg.member.id, true);
+        if self.incident.is_some() {
+            return Box::new(fut::ready(Ok(FindRoomResult::ReadOnly)));
+        }
+        self.find_room(ctx, msg.member, msg.region)
+    }
+}
+
+impl Handler<JoinQueue> for LobbyActor {
+    type Result = Option<FindRoomResult>;
+
+    fn handle(&mut self, msg: JoinQueue, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("join_queue", player = msg.member.id).entered();
+        self.leave_queue(msg.member.id, true);
+        if self.incident.is_some() {
+            return Some(FindRoomResult::ReadOnly);
+        }
+        if self.shutting_down {
+            return Some(FindRoomResult::ShuttingDown);
+        }
+
+        let eta = self.queue_waits.eta(Duration::from_secs(0));
+        msg.member.addr.do_send(Event(Arc::new(OutEvent::EventQueueUpdate { position: self.queue.len() + 1, eta })));
+        self.queue.push(QueueEntry { member: msg.member, region: msg.region, request_id: msg.request_id, since: Instant::now() });
+        None
+    }
+}
+
+impl Handler<LeaveQueue> for LobbyActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: LeaveQueue, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("leave_queue", player = msg.id).entered();
+        self.leave_queue(msg.id, true)
+    }
+}
+
+impl Handler<CreateRoom> for LobbyActor {
+    type Result = Result<CreateRoomResult, &'static str>;
+
+    fn handle(&mut self, msg: CreateRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("create_room", player = msg.member.id).entered();
+        self.leave_queue(msg.member.id, true);
+        if self.shutting_down {
+            return Err("server_shutting_down");
+        }
+        if self.incident.is_some() {
+            return Err("server_read_only");
+        }
+        if msg.options.dev_room && !self.dev_rooms.is_developer(&msg.member.details) {
+            return Err("not_developer");
+        }
+        if msg.options.spectator_delay_secs.map(|x| x > MAX_SPECTATOR_DELAY_SECS).unwrap_or(false) {
+            return Err("invalid_spectator_delay");
+        }
+        Ok(self.create_room(ctx, msg.member, false, msg.options))
+    }
+}
+
+impl Handler<Shutdown> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("shutdown", seconds = msg.seconds).entered();
+        self.shutting_down = true;
+
+        // The suspended sessions have no connection to warn.
+        let event = Arc::new(OutEvent::ServerShutdown { seconds: msg.seconds });
+        for session in self.players.values().filter(|x| x.suspended.is_none()) {
+            session.addr.do_send(Event(event.clone()));
+        }
+    }
+}
+
+impl Handler<GetStatus> for LobbyActor {
+    type Result = ServerStatus;
+
+    fn handle(&mut self, _: GetStatus, _: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("get_status").entered();
+        let incident = self.incident.as_ref().map(|x| match &**x {
+            OutEvent::ServerIncident { message, .. } => message.clone().unwrap_or_default(),
+            _ => String::new(),
+        });
+        ServerStatus {
+            players: self.players.values().filter(|x| x.suspended.is_none()).count(),
+            rooms: self.rooms.len(),
+            playing_rooms: self.rooms.values().filter(|x| x.state == RoomState::Playing).count(),
+            shutting_down: self.shutting_down,
+            incident,
+        }
+    }
+}
+
+impl Handler<CountPlayingRooms> for LobbyActor {
+    type Result = usize;
+
+    fn handle(&mut self, _: CountPlayingRooms, _: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("count_playing_rooms").entered();
+        self.rooms.values().filter(|x| x.state == RoomState::Playing).count()
+    }
+}
+
+impl Handler<SaveAccounts> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, _: SaveAccounts, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("save_accounts").entered();
+        self.accounts.save();
+    }
+}
+
+impl Handler<AdminListRooms> for LobbyActor {
+    type Result = MessageResult<AdminListRooms>;
+
+    fn handle(&mut self, _: AdminListRooms, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_list_rooms").entered();
+        MessageResult(self.rooms.iter()
+            .map(|(id, x)| AdminRoom {
+                room_id: (*id).into(),
+                public: x.public,
+                state: x.state,
+                locked: x.locked,
+                host: x.host.clone(),
+                players: x.players.iter().map(|x| (*x).into()).collect(),
+                settings: x.settings.clone(),
+                age_secs: x.created_at.elapsed().as_secs(),
+            })
+            .collect())
+    }
+}
+
+impl Handler<AdminListPlayers> for LobbyActor {
+    type Result = MessageResult<AdminListPlayers>;
+
+    fn handle(&mut self, _: AdminListPlayers, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_list_players").entered();
+        let rooms: HashMap<IdType, IdType> = self.rooms.iter()
+            .flat_map(|(room_id, x)| x.players.iter().map(move |id| (*id, *room_id)))
+            .collect();
+
+        MessageResult(self.players.iter()
+            .map(|(id, x)| AdminPlayer {
+                player_id: (*id).into(),
+                account_id: x.account.into(),
+                username: x.username.clone(),
+                connected: x.suspended.is_none(),
+                room_id: rooms.get(id).map(|x| (*x).into()),
+            })
+            .collect())
+    }
+}
+
+impl Handler<AdminCloseRoom> for LobbyActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: AdminCloseRoom, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_close_room", room = msg.room_id).entered();
+        match self.rooms.get(&msg.room_id) {
+            Some(room) => {
+                info!("Room closed by an admin");
+                room.addr.do_send(room_actor::Close);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl Handler<AdminTraceRoom> for LobbyActor {
+    type Result = ResponseActFuture<Self, Result<Option<PathBuf>, ()>>;
+
+    fn handle(&mut self, msg: AdminTraceRoom, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_trace_room", room = msg.room_id).entered();
+        let room = match self.rooms.get(&msg.room_id) {
+            Some(x) => x,
+            None => return Box::new(fut::ready(Err(()))),
+        };
+        let trace = room.addr.send(room_actor::Trace { secs: msg.secs });
+        Box::new(trace.into_actor(self).map(|res, _, _| res.map_err(|_| ())))
+    }
+}
+
+impl Handler<AdminKickPlayer> for LobbyActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: AdminKickPlayer, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_kick_player", player = msg.id).entered();
+        let session = match self.players.get(&msg.id) {
+            Some(x) => x,
+            None => return false,
+        };
+        info!("Player kicked by an admin");
+
+        match &session.suspended {
+            Some((_, handle)) => {
+                ctx.cancel_future(*handle);
+                self.end_suspended_session(ctx, msg.id);
+            },
+            // The connection ends the session once it's closed.
+            None => session.addr.do_send(client_ws::Kicked),
+        }
+        true
+    }
+}
+
+impl Handler<AdminAnnounce> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AdminAnnounce, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_announce").entered();
+        info!(announcement = %msg.message, "Announcement sent");
+
+        let event = Arc::new(OutEvent::ServerAnnouncement { message: msg.message });
+        for session in self.players.values().filter(|x| x.suspended.is_none()) {
+            session.addr.do_send(Event(event.clone()));
+        }
+    }
+}
+
+impl Handler<AdminSetMotd> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AdminSetMotd, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_set_motd").entered();
+        info!(motd = ?msg.message, "Message of the day changed");
+        self.motd = msg.message.map(|message| Arc::new(OutEvent::ServerAnnouncement { message }));
+    }
+}
+
+impl Handler<AdminSetIncident> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AdminSetIncident, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_set_incident", active = msg.active).entered();
+        if msg.active {
+            warn!(message = ?msg.message, "Incident mode started, the lobby is read only");
+        } else {
+            info!("Incident mode ended");
+        }
+
+        let event = Arc::new(OutEvent::ServerIncident { active: msg.active, message: msg.message });
+        self.incident = if msg.active { Some(event.clone()) } else { None };
+        for session in self.players.values().filter(|x| x.suspended.is_none()) {
+            session.addr.do_send(Event(event.clone()));
+        }
+        for room in self.rooms.values() {
+            room.addr.do_send(room_actor::SetReadOnly(msg.active));
+        }
+    }
+}
+
+impl Handler<AdminAnnounceTo> for LobbyActor {
+    type Result = Result<AdminJobReport, Vec<SerId>>;
+
+    fn handle(&mut self, msg: AdminAnnounceTo, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_announce_to").entered();
+        let mut not_found: Vec<SerId> = msg.players.iter().filter(|x| !self.players.contains_key(x)).map(|x| (*x).into()).collect();
+        not_found.extend(msg.rooms.iter().filter(|x| !self.rooms.contains_key(x)).map(|x| SerId(*x)));
+        if !not_found.is_empty() {
+            return Err(not_found);
+        }
+
+        let mut players: HashSet<IdType> = msg.players.into_iter().collect();
+        for room in msg.rooms.iter() {
+            players.extend(self.rooms[room].players.iter().copied());
+        }
+        info!(announcement = %msg.message, players = players.len(), "Announcement sent");
+
+        // Nothing to wait for, the disconnected players are skipped.
+        let event = Arc::new(OutEvent::ServerAnnouncement { message: msg.message });
+        let mut sent = 0;
+        for session in players.iter().filter_map(|x| self.players.get(x)).filter(|x| x.suspended.is_none()) {
+            session.addr.do_send(Event(event.clone()));
+            sent += 1;
+        }
+        let job = self.add_admin_job(AdminJobKind::Announce, sent, HashSet::new());
+        Ok(self.admin_job_report(job).unwrap())
+    }
+}
+
+impl Handler<AdminCloseRooms> for LobbyActor {
+    type Result = MessageResult<AdminCloseRooms>;
+
+    fn handle(&mut self, msg: AdminCloseRooms, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_close_rooms").entered();
+        let now = Instant::now();
+        let rooms: HashSet<IdType> = self.rooms.iter()
+            .filter(|(_, x)| msg.filter.matches(x, now))
+            .map(|(id, _)| *id)
+            .collect();
+        info!(rooms = rooms.len(), "Rooms closed by an admin");
+
+        for id in rooms.iter() {
+            self.rooms[id].addr.do_send(room_actor::Close);
+        }
+        let job = self.add_admin_job(AdminJobKind::CloseRooms, rooms.len(), rooms);
+        MessageResult(self.admin_job_report(job).unwrap())
+    }
+}
+
+impl Handler<AdminKickPlayers> for LobbyActor {
+    type Result = Result<AdminJobReport, Vec<SerId>>;
+
+    fn handle(&mut self, msg: AdminKickPlayers, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_kick_players").entered();
+        let ids = self.known_players(msg.ids)?;
+        info!(players = ids.len(), "Players kicked by an admin");
+        Ok(self.end_sessions(ctx, AdminJobKind::KickPlayers, ids))
+    }
+}
+
+impl Handler<AdminBanPlayers> for LobbyActor {
+    type Result = Result<AdminJobReport, Vec<SerId>>;
+
+    fn handle(&mut self, msg: AdminBanPlayers, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_ban_players").entered();
+        let ids = self.known_players(msg.ids)?;
+        for id in ids.iter() {
+            self.accounts.get_mut(self.players[id].account).unwrap().banned = true;
+        }
+        // A player connected through the unix socket has no address to ban.
+        let ips: Vec<IpAddr> = if msg.addresses {
+            ids.iter().filter_map(|x| self.players[x].ip).collect()
+        } else {
+            Vec::new()
+        };
+        warn!(players = ids.len(), addresses = ips.len(), "Players banned by an admin");
+        self.accounts.ban_addresses(ips.into_iter());
+        Ok(self.end_sessions(ctx, AdminJobKind::BanPlayers, ids))
+    }
+}
+
+impl Handler<AdminUnban> for LobbyActor {
+    type Result = Result<(), Vec<SerId>>;
+
+    fn handle(&mut self, msg: AdminUnban, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_unban").entered();
+        let not_found: Vec<SerId> = msg.accounts.iter().filter(|x| self.accounts.get(**x).is_none()).map(|x| (*x).into()).collect();
+        if !not_found.is_empty() {
+            return Err(not_found);
+        }
+        warn!(accounts = msg.accounts.len(), addresses = msg.addresses.len(), "Bans lifted by an admin");
+        self.accounts.unban(&msg.accounts, &msg.addresses);
+        Ok(())
+    }
+}
+
+impl Handler<AdminListBans> for LobbyActor {
+    type Result = MessageResult<AdminListBans>;
+
+    fn handle(&mut self, _: AdminListBans, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_list_bans").entered();
+        let (accounts, addresses) = self.accounts.bans();
+        let accounts = accounts.into_iter()
+            .map(|(id, x)| BannedAccount { account_id: id.into(), username: x.username.clone() })
+            .collect();
+        MessageResult(AdminBans { accounts, addresses })
+    }
+}
+
+impl Handler<AdminGetJob> for LobbyActor {
+    type Result = Option<AdminJobReport>;
+
+    fn handle(&mut self, msg: AdminGetJob, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("admin_get_job").entered();
+        self.admin_job_report(msg.id)
+    }
+}
+
+impl Handler<JoinRoom> for LobbyActor {
+    type Result = ResponseActFuture<Self, Result<JoinRoomResult, ()>>;
+
+    fn handle(&mut self, msg: JoinRoom, ctx: &mut Context<Self>) -> Self::Result {
+        self.leave_queue(msg.member.id, true);
+        let room_id = match self.resolve_invite(&msg.invite) {
+            Some(x) => x,
+            None => {
+                if let Some(room_id) = self.find_migrated(&msg.invite) {
+                    return Box::new(fut::ready(Ok(self.restore_migrated(ctx, room_id, msg.member, msg.ticket))));
+                }
+                let result = self.restore_room(ctx, msg.member, &msg.invite, msg.password);
+                let cluster = match &self.cluster {
+                    Some(x) if self.features.flags.clustering && matches!(result, JoinRoomResult::RoomNotFound) => x,
+                    _ => return Box::new(fut::ready(Ok(result))),
+                };
+                // The room may be on another instance of the cluster.
+                let node_url = cluster.node_url.clone();
+                let lookup = cluster::lookup(cluster.redis.clone(), msg.invite);
+                return Box::new(lookup.into_actor(self).map(move |res, _, _| Ok(match res {
+                    Ok(Some(x)) if x.url != node_url => {
+                        info!(room = %x.room_id, url = %x.url, "Player redirected to the instance of the room");
+                        JoinRoomResult::Redirect { url: x.url, room_id: x.room_id.into() }
+                    },
+                    Ok(_) => JoinRoomResult::RoomNotFound,
+                    Err(e) => {
+                        warn!(error = %e, "Cannot read the cluster registry");
+                        JoinRoomResult::RoomNotFound
+                    },
+                })));
+            },
+        };
+        let _span = info_span!("join_room", room = room_id, player = msg.member.id).entered();
+        let room = &self.rooms[&room_id];
+        if !self.dev_rooms.allows(&room.code, &msg.member.details) {
+            return Box::new(fut::ready(Ok(JoinRoomResult::RoomNotFound)));
+        }
+        let room = &room.addr;
+
+        let join = room.send(room_actor::Join { member: msg.member, password: msg.password, invited: false, ticket: msg.ticket });
+        Box::new(join.into_actor(self).map(|res, _, _| {
+            // The room might have been closed in the meantime.
+            Ok(res.unwrap_or(JoinRoomResult::RoomNotFound))
+        }))
+    }
+}
+
+impl Handler<MigrateRoom> for LobbyActor {
+    type Result = ResponseActFuture<Self, Result<RoomData, ()>>;
+
+    fn handle(&mut self, msg: MigrateRoom, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("migrate_room", room = msg.room_id).entered();
+        let room = match self.rooms.get(&msg.room_id) {
+            Some(x) => x,
+            None => return Box::new(fut::ready(Err(()))),
+        };
+        let migrate = room.addr.send(room_actor::Migrate { public: room.public });
+        Box::new(migrate.into_actor(self).map(|res, _, _| res.ok().flatten().ok_or(())))
+    }
+}
+
+impl Handler<EndMigration> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndMigration, _: &mut Context<Self>) -> Self::Result {
+        if let Some(room) = self.rooms.get(&msg.room_id) {
+            room.addr.do_send(room_actor::MigrationEnd { url: msg.url });
+        }
+    }
+}
+
+impl Handler<ImportRoom> for LobbyActor {
+    type Result = Result<String, &'static str>;
+
+    fn handle(&mut self, msg: ImportRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let data = msg.0;
+        let room_id = data.room_id.0;
+        let _span = info_span!("import_room", room = room_id).entered();
+        let url = self.federation.public_url.clone().ok_or("no_public_url")?;
+        if self.shutting_down {
+            return Err("shutting_down");
+        }
+        if self.rooms.contains_key(&room_id) || self.room_codes.contains_key(&data.code)
+            || self.find_migrated(&InviteId::Id(room_id)).or_else(|| self.find_migrated(&InviteId::Code(data.code.clone()))).is_some() {
+            return Err("room_exists");
+        }
+        let seats = Seats::new(&data)?;
+
+        info!(seats = data.seats.len(), with_game = data.game.is_some(), "Room imported from another instance");
+        self.migrated.insert(room_id, (data, seats));
+        ctx.run_later(MIGRATION_TTL, move |act, _| {
+            if act.migrated.remove(&room_id).is_some() {
+                info!(room = room_id, "Imported room expired, no player came back");
+            }
+        });
+        Ok(url)
+    }
+}
+
+impl Handler<SpectateRoom> for LobbyActor {
+    type Result = ResponseActFuture<Self, Result<SpectateResult, ()>>;
+
+    fn handle(&mut self, msg: SpectateRoom, _: &mut Context<Self>) -> Self::Result {
+        self.leave_queue(msg.member.id, true);
+        let room_id = match self.resolve_invite(&msg.invite) {
+            Some(x) => x,
+            None => return Box::new(fut::ready(Ok(SpectateResult::RoomNotFound))),
+        };
+        let _span = info_span!("spectate_room", room = room_id, player = msg.member.id).entered();
+        let room = &self.rooms[&room_id];
+        if !self.dev_rooms.allows(&room.code, &msg.member.details) {
+            return Box::new(fut::ready(Ok(SpectateResult::RoomNotFound)));
+        }
+        let room = &room.addr;
+
+        let spectate = room.send(room_actor::Spectate { member: msg.member, password: msg.password });
+        Box::new(spectate.into_actor(self).map(|res, _, _| {
+            Ok(res.unwrap_or(SpectateResult::RoomNotFound))
+        }))
+    }
+}
+
+impl Handler<CreateTournament> for LobbyActor {
+    type Result = Result<TournamentResponse, &'static str>;
+
+    fn handle(&mut self, msg: CreateTournament, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("create_tournament", player = msg.member.id).entered();
+        if self.shutting_down {
+            return Err("server_shutting_down");
+        }
+        if self.incident.is_some() {
+            return Err("server_read_only");
+        }
+        // The winners are given by the final scores.
+        if !self.features.flags.authoritative_engine {
+            return Err("engine_required");
+        }
+        let name = msg.name.trim().to_string();
+        moderation::check_tournament_name(&name)?;
+        if !(MIN_TOURNAMENT_PLAYERS..=MAX_TOURNAMENT_PLAYERS).contains(&msg.max_players) {
+            return Err("invalid_max_players");
+        }
+
+        let id = loop {
+            let id = self.rng.gen::<IdType>();
+            if !self.tournaments.contains_key(&id) {
+                break id;
+            }
+        };
+        let tournament = Tournament::new(id, name, msg.member.id, msg.max_players);
+        let response = TournamentResponse { tournament: tournament.view() };
+        info!(tournament = id, max_players = msg.max_players, "Tournament created");
+        self.tournaments.insert(id, (tournament, HashMap::new()));
+        Ok(response)
+    }
+}
+
+impl Handler<RegisterTournament> for LobbyActor {
+    type Result = Result<TournamentResponse, &'static str>;
+
+    fn handle(&mut self, msg: RegisterTournament, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("register_tournament", tournament = msg.tournament_id, player = msg.member.id).entered();
+        let (tournament, members) = self.tournaments.get_mut(&msg.tournament_id).ok_or("tournament_not_found")?;
+        tournament.register(msg.member.id)?;
+        let player = msg.member.id;
+        members.insert(player, msg.member);
+        Ok(self.update_tournament(ctx, msg.tournament_id, Some(player)))
+    }
+}
+
+impl Handler<LeaveTournament> for LobbyActor {
+    type Result = Result<TournamentResponse, &'static str>;
+
+    fn handle(&mut self, msg: LeaveTournament, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("leave_tournament", tournament = msg.tournament_id, player = msg.id).entered();
+        self.leave_tournament(ctx, msg.id, msg.tournament_id)
+    }
+}
+
+impl Handler<StartTournament> for LobbyActor {
+    type Result = Result<TournamentResponse, &'static str>;
+
+    fn handle(&mut self, msg: StartTournament, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("start_tournament", tournament = msg.tournament_id, player = msg.id).entered();
+        let (tournament, _) = self.tournaments.get_mut(&msg.tournament_id).ok_or("tournament_not_found")?;
+        if tournament.organizer != msg.id {
+            return Err("not_organizer");
+        }
+        tournament.start(&mut self.rng)?;
+        info!(players = tournament.players.len(), "Tournament started");
+        Ok(self.update_tournament(ctx, msg.tournament_id, Some(msg.id)))
+    }
+}
+
+impl Handler<RoomUpdate> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomUpdate, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("room_update", room = msg.room_id).entered();
+        // A player leaving the room of its match before the game loses it.
+        if msg.state == RoomState::Matchmaking {
+            let gone = self.tournaments.values()
+                .find_map(|x| x.0.match_of_room(msg.room_id).map(|index| &x.0.rounds.last().unwrap()[index].players))
+                .filter(|players| players.iter().any(|x| !msg.players.contains(x)))
+                .map(|players| players.iter().copied().find(|x| msg.players.contains(x)));
+            if let Some(winner) = gone {
+                self.end_match(ctx, msg.room_id, winner);
+            }
+        }
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.player_count = msg.player_count;
+            room.state = msg.state;
+            room.locked = msg.locked;
+            room.host = msg.host;
+            room.settings = msg.settings;
+            room.players = msg.players;
+            room.active_at = Instant::now();
+            let mutation = Mutation::Updated {
+                room_id: msg.room_id.into(),
+                state: room.state,
+                player_count: room.player_count,
+                host: room.host.clone(),
+                settings: room.settings.clone(),
+            };
+            self.replicate(mutation);
+        }
+    }
+}
+
+impl Handler<RoomClosed> for LobbyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomClosed, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("room_closed", room = msg.room_id).entered();
+        self.forget_room(msg.room_id);
+        self.end_match(ctx, msg.room_id, None);
+    }
+}
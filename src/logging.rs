@@ -0,0 +1,293 @@
+//!
+//! Structured logs: the handlers of the actors open a span with the room and the player they work
+//! on, every log line carries the fields of the spans it's written in.
+//!
+//! LOG_LEVEL (`error`, `warn`, `info`, `debug`, `trace` or `off`, `info` by default) and LOG_FORMAT
+//! (`pretty` or `json`, one object per line, `pretty` by default) are read at startup.
+//! The logs of the dependencies still go through env_logger (RUST_LOG).
+//!
+//! The spans are guards entered and dropped by one handler, so each thread keeps the spans it
+//! opened without any lock; the span of a websocket frame is at the debug level and costs nothing
+//! at the default level.
+//!
+
+use std::{cell::RefCell, collections::HashMap, env, fmt, io::{self, Write}, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_json::{Map, Value};
+use tracing::{field::{Field, Visit}, level_filters::LevelFilter, span, Event, Metadata, Subscriber};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Pretty,
+    Json,
+}
+
+struct SpanData {
+    name: &'static str,
+    fields: Map<String, Value>,
+    refs: usize,
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: Format,
+    next_id: AtomicU64,
+}
+
+thread_local! {
+    // Spans entered by the current thread, the innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    // Spans opened by the current thread and not closed yet.
+    static SPANS: RefCell<HashMap<u64, SpanData>> = RefCell::new(HashMap::new());
+}
+
+pub fn init() {
+    let level = env::var("LOG_LEVEL").ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(LevelFilter::INFO);
+    let format = match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => Format::Json,
+        _ => Format::Pretty,
+    };
+
+    let logger = Logger {
+        level,
+        format,
+        next_id: AtomicU64::new(1),
+    };
+    if tracing::subscriber::set_global_default(logger).is_err() {
+        tracing::warn!("Logging already initialized");
+    }
+}
+
+/// Collects the fields of a span or an event, the message goes with the others as "message".
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// A log line, before it's formatted.
+struct Line {
+    level: String,
+    target: String,
+    spans: Vec<(&'static str, Map<String, Value>)>,// Outermost first
+    fields: Map<String, Value>,
+}
+
+impl Line {
+    fn pretty(&self) -> String {
+        let mut out = format!("{:<5} ", self.level);
+        for (name, fields) in self.spans.iter() {
+            out.push_str(name);
+            if !fields.is_empty() {
+                out.push('{');
+                push_fields(&mut out, fields);
+                out.push('}');
+            }
+            out.push(':');
+        }
+        if !self.spans.is_empty() {
+            out.push(' ');
+        }
+
+        if let Some(x) = self.fields.get("message") {
+            push_value(&mut out, x);
+        }
+        let mut others = self.fields.clone();
+        others.remove("message");
+        if !others.is_empty() {
+            out.push(' ');
+            push_fields(&mut out, &others);
+        }
+        out
+    }
+
+    fn json(&self, timestamp: f64) -> String {
+        let mut fields = Map::new();
+        for (_, x) in self.spans.iter() {
+            fields.extend(x.clone());
+        }
+        fields.extend(self.fields.clone());
+        let message = fields.remove("message").unwrap_or(Value::Null);
+
+        let mut obj = Map::new();
+        obj.insert("timestamp".into(), timestamp.into());
+        obj.insert("level".into(), self.level.clone().into());
+        obj.insert("target".into(), self.target.clone().into());
+        obj.insert("spans".into(), self.spans.iter().map(|(name, _)| Value::from(*name)).collect());
+        obj.insert("message".into(), message);
+        obj.insert("fields".into(), fields.into());
+        Value::Object(obj).to_string()
+    }
+}
+
+fn push_value(out: &mut String, value: &Value) {
+    match value {
+        Value::String(x) => out.push_str(x),
+        x => out.push_str(&x.to_string()),
+    }
+}
+
+fn push_fields(out: &mut String, fields: &Map<String, Value>) {
+    for (i, (name, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        out.push_str(name);
+        out.push('=');
+        push_value(out, value);
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.level >= *metadata.level()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Map::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        let data = SpanData { name: attrs.metadata().name(), fields, refs: 1 };
+        SPANS.with(|x| x.borrow_mut().insert(id, data));
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        SPANS.with(|x| {
+            if let Some(data) = x.borrow_mut().get_mut(&span.into_u64()) {
+                values.record(&mut FieldVisitor(&mut data.fields));
+            }
+        });
+    }
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Map::new();
+        event.record(&mut FieldVisitor(&mut fields));
+
+        let spans = SPANS.with(|spans| ENTERED.with(|x| {
+            let spans = spans.borrow();
+            x.borrow().iter()
+                .filter_map(|id| spans.get(id))
+                .map(|x| (x.name, x.fields.clone()))
+                .collect()
+        }));
+
+        let metadata = event.metadata();
+        let line = Line {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            spans,
+            fields,
+        };
+        let text = match self.format {
+            Format::Pretty => line.pretty(),
+            Format::Json => {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs_f64()).unwrap_or(0.0);
+                line.json(timestamp)
+            },
+        };
+        let _ = writeln!(io::stdout().lock(), "{}", text);
+    }
+
+    fn enter(&self, span: &span::Id) {
+        ENTERED.with(|x| x.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        ENTERED.with(|x| {
+            let mut entered = x.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|x| *x == span.into_u64()) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        SPANS.with(|x| {
+            if let Some(data) = x.borrow_mut().get_mut(&span.into_u64()) {
+                data.refs += 1;
+            }
+        });
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        SPANS.with(|x| {
+            let mut spans = x.borrow_mut();
+            let closed = match spans.get_mut(&span.into_u64()) {
+                Some(data) => {
+                    data.refs -= 1;
+                    data.refs == 0
+                },
+                None => false,
+            };
+            if closed {
+                spans.remove(&span.into_u64());
+            }
+            closed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line() -> Line {
+        let mut span = Map::new();
+        span.insert("room".into(), 42.into());
+        span.insert("player".into(), 7.into());
+        let mut fields = Map::new();
+        fields.insert("message".into(), "Room joined".into());
+        fields.insert("players".into(), 3.into());
+
+        Line {
+            level: "INFO".into(),
+            target: "carcassonne_server::room_actor".into(),
+            spans: vec![("join", span)],
+            fields,
+        }
+    }
+
+    #[test]
+    fn pretty_format() {
+        assert_eq!(line().pretty(), "INFO  join{player=7 room=42}: Room joined players=3");
+    }
+
+    #[test]
+    fn json_format() {
+        let value: Value = serde_json::from_str(&line().json(1.5)).unwrap();
+
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["spans"], serde_json::json!(["join"]));
+        assert_eq!(value["message"], "Room joined");
+        assert_eq!(value["fields"], serde_json::json!({ "room": 42, "player": 7, "players": 3 }));
+    }
+}
@@ -1,3 +1,6 @@
+// The pinned serde_derive and actix_derive versions generate code that newer compilers lint.
+#![allow(non_local_definitions, unexpected_cfgs)]
+
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
@@ -5,30 +8,159 @@ static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use actix::prelude::*;
 use actix_web::{HttpServer, App, web};
-use env_logger;
+use tracing::info;
+
 
+// Copied from actix, love the library but it seems a bit rushed in the "actor" part.
+// This should generate the code to share a result between actors.
+macro_rules! simple_result {
+    ($type:ty) => {
+        impl<A, M> actix::dev::MessageResponse<A, M> for $type
+        where
+            A: Actor,
+            M: Message<Result = $type>,
+        {
+            fn handle<R: actix::dev::ResponseChannel<M>>(self, _: &mut A::Context, tx: Option<R>) {
+                if let Some(tx) = tx {
+                    tx.send(self);
+                }
+            }
+        }
+    };
+}
 
+mod accounts;
+mod achievements;
+mod admin;
+mod bot_actor;
+mod cleanup;
 mod client_ws;
+mod clock;
+mod cluster;
+mod codec;
+mod config_check;
+mod cosmetics;
+mod dev_rooms;
+mod features;
+mod federation;
+mod game;
+mod handshake;
+mod heartbeat;
+mod idempotency;
+mod isolation;
+mod listeners;
+mod lobby_actor;
+mod logging;
+mod matchmaking;
+mod metrics;
+mod migration;
+mod moderation;
+mod network;
+mod player_stats;
+mod prediction;
+mod privacy;
 mod protocol;
-mod server_actor;
+mod rate_limit;
+mod ratings;
+mod relay_filter;
+mod relay_queue;
+mod relay_sampling;
+mod replay;
+mod replication;
+mod room_actor;
+mod room_code;
+mod room_trace;
+mod series;
+mod shutdown;
+mod spectator_delay;
+mod start_checks;
+mod status_page;
+#[cfg(test)]
+mod test_server;
+mod tournament;
+mod turn_times;
+mod votes;
 
 
+/// Every route of the server, public and admin.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/", web::get().to(client_ws::matchmaking_start))
+        .route("/federation/rooms", web::get().to(federation::rooms))
+        .route("/players/stats", web::get().to(player_stats::profile))
+        .route("/stats/handshakes", web::get().to(handshake::stats))
+        .route("/stats/subsystems", web::get().to(isolation::stats))
+        .route("/stats/cleanup", web::get().to(cleanup::stats))
+        .route("/stats/heartbeat", web::get().to(heartbeat::stats))
+        .route("/metrics", web::get().to(metrics::metrics))
+        .route("/replication/log", web::get().to(replication::log))
+        .route("/admin/rooms", web::get().to(admin::rooms))
+        .route("/admin/rooms/close", web::post().to(admin::close_room))
+        .route("/admin/rooms/close_matching", web::post().to(admin::close_rooms))
+        .route("/admin/rooms/migrate", web::post().to(admin::migrate_room))
+        .route("/admin/rooms/import", web::post().to(admin::import_room))
+        .route("/admin/rooms/trace", web::post().to(admin::trace_room))
+        .route("/admin/players", web::get().to(admin::players))
+        .route("/admin/players/kick", web::post().to(admin::kick_player))
+        .route("/admin/players/kick_many", web::post().to(admin::kick_players))
+        .route("/admin/players/ban_many", web::post().to(admin::ban_players))
+        .route("/admin/players/unban", web::post().to(admin::unban))
+        .route("/admin/bans", web::get().to(admin::bans))
+        .route("/admin/announce", web::post().to(admin::announce))
+        .route("/admin/motd", web::post().to(admin::motd))
+        .route("/admin/incident", web::post().to(admin::incident))
+        .route("/admin/jobs/{id}", web::get().to(admin::job_progress))
+        .route("/admin/relay/schemas", web::get().to(admin::relay_schemas));
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    if std::env::args().any(|x| x == "--check") {
+        std::process::exit(if config_check::run() { 0 } else { 1 });
+    }
     env_logger::init();
+    logging::init();
+    status_page::init();
 
-    let room_db = server_actor::ServerActor::default().start();
+    let lobby = lobby_actor::LobbyActor::default().start();
+    let handshake = handshake::HandshakeConfig::from_env();
+    let admin = admin::AdminConfig::from_env();
+    let replication = replication::ReplicationConfig::from_env();
 
-    let bind_addr = std::env::var("BIND_ADDR")
-        .unwrap_or_else(|_| "0.0.0.0:8081".to_string());
+    let listeners = listeners::init()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
-    println!("Starting server on {}", bind_addr);
-    HttpServer::new(move || {
+    let server_lobby = lobby.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
-            .data(room_db.clone())
-            .route("/", web::get().to(client_ws::matchmaking_start))
+            .data(lobby.clone())
+            .data(handshake.clone())
+            .data(admin.clone())
+            .data(replication.clone())
+            .configure(routes)
     })
-        .bind(bind_addr)?
-        .run()
-        .await
+        .disable_signals()// See shutdown
+        .shutdown_timeout(5);
+    for listener in listeners.public.iter() {
+        info!(addr = %listener.addr, max_connections = ?listener.max_connections, "Starting server");
+        server = server.bind(listener.addr)?;
+    }
+    if let Some(addr) = listeners.admin {
+        info!(addr = %addr, "Admin api only served on this address");
+        server = server.bind(addr)?;
+    }
+    #[cfg(unix)]
+    if let Some(path) = &listeners.admin_socket {
+        info!(path = %path.display(), "Admin api served on this socket");
+        server = server.listen_uds(listeners::bind_admin_socket(path)?)?;
+    }
+    let server = server.run();
+
+    shutdown::handle_signals(server.clone(), server_lobby.clone());
+    let res = server.await;
+    let _ = server_lobby.send(lobby_actor::SaveAccounts).await;
+    if let Some(path) = &listeners.admin_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    res
 }
@@ -0,0 +1,240 @@
+//!
+//! Scoring of the public rooms for room_find, the player joins the available room with the best score.
+//!
+//! A room scores higher the more it's full (the game starts sooner), the longer it has been waiting,
+//! if its host is in the same region as the player (a proxy for the latency, the region is chosen
+//! by the client) and if the player speaks its language (so that the players can chat).
+//! MATCHMAKING_WEIGHTS changes the weight of every term, e.g. `players=1,wait=0.5,region=2,language=1.5`
+//! (missing terms keep their default).
+//!
+//! With `"queue": true` room_find puts the player in a queue instead. Every QUEUE_INTERVAL the
+//! lobby batches the queued players into new rooms of balanced sizes, the players of the same
+//! region together. A player still alone in its region after QUEUE_REGION_WAIT is matched with
+//! the ones alone in the others, after QUEUE_MAX_WAIT it's given a room as without the queue.
+//! Until then it receives its position and an estimate of its wait, from the waits of the players
+//! matched last.
+//!
+
+use std::{collections::VecDeque, env, time::Duration};
+use tracing::warn;
+
+/// A room waiting for longer than this isn't considered any more urgent.
+const MAX_WAIT: Duration = Duration::from_secs(120);
+pub const QUEUE_INTERVAL: Duration = Duration::from_secs(3);
+pub const QUEUE_REGION_WAIT: Duration = Duration::from_secs(10);
+pub const QUEUE_MAX_WAIT: Duration = Duration::from_secs(30);
+/// Waits remembered for the estimates.
+const WAIT_SAMPLES: usize = 32;
+
+pub struct Candidate<'a> {
+    pub player_count: usize,
+    pub max_players: usize,
+    pub waiting: Duration,
+    pub region: Option<&'a str>,
+    pub languages: &'a [String],
+}
+
+pub struct Weights {
+    players: f64,
+    wait: f64,
+    region: f64,
+    language: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            players: 1.0,
+            wait: 0.5,
+            region: 2.0,
+            language: 1.5,
+        }
+    }
+}
+
+impl Weights {
+    pub fn from_env() -> Self {
+        let (weights, errors) = Weights::parse(&env::var("MATCHMAKING_WEIGHTS").unwrap_or_default());
+        for e in errors {
+            warn!(error = %e, "Matchmaking weight ignored");
+        }
+        weights
+    }
+
+    /// The weights of a MATCHMAKING_WEIGHTS value, with the terms that were ignored.
+    pub fn parse(config: &str) -> (Self, Vec<String>) {
+        let mut weights = Weights::default();
+        let mut errors = Vec::new();
+
+        for term in config.split(',').filter(|x| !x.trim().is_empty()) {
+            let parsed = term.split_once('=')
+                .and_then(|(name, value)| Some((name.trim(), value.trim().parse::<f64>().ok()?)));
+            let (name, value) = match parsed {
+                Some(x) => x,
+                None => {
+                    errors.push(format!("invalid weight \"{}\"", term.trim()));
+                    continue;
+                },
+            };
+            match name {
+                "players" => weights.players = value,
+                "wait" => weights.wait = value,
+                "region" => weights.region = value,
+                "language" => weights.language = value,
+                _ => errors.push(format!("unknown weight \"{}\"", name)),
+            }
+        }
+        (weights, errors)
+    }
+
+    /// Score of a room for a player of the given region and languages, every term is between 0 and
+    /// its weight.
+    pub fn score(&self, room: &Candidate, region: Option<&str>, languages: &[String]) -> f64 {
+        let fill = room.player_count as f64 / room.max_players.max(1) as f64;
+        let wait = room.waiting.min(MAX_WAIT).as_secs_f64() / MAX_WAIT.as_secs_f64();
+        let same_region = match (room.region, region) {
+            (Some(a), Some(b)) if a == b => 1.0,
+            _ => 0.0,
+        };
+        let same_language = if room.languages.iter().any(|x| languages.contains(x)) { 1.0 } else { 0.0 };
+
+        self.players * fill + self.wait * wait + self.region * same_region + self.language * same_language
+    }
+}
+
+pub struct QueuedPlayer<'a> {
+    pub region: Option<&'a str>,
+    pub waited: Duration,
+}
+
+/// Splits the queue (the oldest first) into the groups of players that get a room together, as
+/// indexes in the queue. A group of one is a player that waited too long, the others stay queued.
+pub fn batch(queue: &[QueuedPlayer], max_players: usize) -> Vec<Vec<usize>> {
+    let mut regions: Vec<(Option<&str>, Vec<usize>)> = Vec::new();
+    for (i, player) in queue.iter().enumerate() {
+        match regions.iter_mut().find(|x| x.0 == player.region) {
+            Some(x) => x.1.push(i),
+            None => regions.push((player.region, vec![i])),
+        }
+    }
+
+    let mut groups = Vec::new();
+    let mut alone = Vec::new();
+    for (_, players) in regions {
+        match players.as_slice() {
+            [i] if queue[*i].waited >= QUEUE_REGION_WAIT => alone.push(*i),
+            [_] => {},
+            _ => split(players, max_players, &mut groups),
+        }
+    }
+    alone.sort_unstable();
+    match alone.as_slice() {
+        [i] if queue[*i].waited >= QUEUE_MAX_WAIT => groups.push(alone),
+        [_] => {},
+        _ => split(alone, max_players, &mut groups),
+    }
+    groups
+}
+
+/// The fewest rooms that fit the players, with as many players in each.
+fn split(players: Vec<usize>, max_players: usize, groups: &mut Vec<Vec<usize>>) {
+    let rooms = (players.len() + max_players - 1) / max_players.max(1);
+    let mut players = players.into_iter();
+    for room in 0..rooms {
+        let size = (players.len() + rooms - room - 1) / (rooms - room);
+        groups.push(players.by_ref().take(size).collect());
+    }
+}
+
+/// The recent waits in the queue, for the estimates sent to the players still waiting.
+#[derive(Default)]
+pub struct WaitEstimate {
+    waits: VecDeque<Duration>,
+}
+
+impl WaitEstimate {
+    pub fn record(&mut self, wait: Duration) {
+        if self.waits.len() == WAIT_SAMPLES {
+            self.waits.pop_front();
+        }
+        self.waits.push_back(wait);
+    }
+
+    /// Seconds left for a player that waited `waited`: what the others waited on average, at
+    /// least the next batch and at most QUEUE_MAX_WAIT.
+    pub fn eta(&self, waited: Duration) -> u64 {
+        let expected = match self.waits.len() {
+            0 => QUEUE_MAX_WAIT,
+            n => self.waits.iter().sum::<Duration>() / n as u32,
+        };
+        let left = expected.min(QUEUE_MAX_WAIT).saturating_sub(waited).max(QUEUE_INTERVAL);
+        (left.as_secs_f64().ceil()) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(player_count: usize, waiting: u64, region: Option<&str>) -> Candidate<'_> {
+        Candidate { player_count, max_players: 4, waiting: Duration::from_secs(waiting), region, languages: &[] }
+    }
+
+    #[test]
+    fn fuller_and_older_rooms_first() {
+        let weights = Weights::default();
+
+        assert!(weights.score(&room(3, 0, None), None, &[]) > weights.score(&room(1, 0, None), None, &[]));
+        assert!(weights.score(&room(1, 60, None), None, &[]) > weights.score(&room(1, 10, None), None, &[]));
+        // The wait time stops counting after MAX_WAIT.
+        assert_eq!(weights.score(&room(1, 500, None), None, &[]), weights.score(&room(1, 120, None), None, &[]));
+    }
+
+    #[test]
+    fn same_region_first() {
+        let weights = Weights::default();
+
+        let near = weights.score(&room(1, 0, Some("eu")), Some("eu"), &[]);
+        let far = weights.score(&room(3, 120, Some("us")), Some("eu"), &[]);
+        assert!(near > far);
+    }
+
+    #[test]
+    fn same_language_first() {
+        let weights = Weights::default();
+        let italian = ["it".to_string()];
+        let player = ["it".to_string(), "en".to_string()];
+
+        let near = weights.score(&Candidate { languages: &italian, ..room(1, 0, None) }, None, &player);
+        let crowded = weights.score(&room(3, 30, None), None, &player);
+        assert!(near > crowded);
+        // A player without languages has no preference.
+        assert_eq!(
+            weights.score(&Candidate { languages: &italian, ..room(1, 0, None) }, None, &[]),
+            weights.score(&room(1, 0, None), None, &[]),
+        );
+    }
+
+    #[test]
+    fn queue_batches_balanced_rooms() {
+        let player = |region, waited| QueuedPlayer { region, waited: Duration::from_secs(waited) };
+
+        // Seven players of a region in two rooms of four and three, the lone player waits.
+        let mut queue: Vec<QueuedPlayer> = (0..7).map(|_| player(Some("eu"), 0)).collect();
+        queue.insert(2, player(Some("us"), 0));
+        assert_eq!(batch(&queue, 5), vec![vec![0, 1, 3, 4], vec![5, 6, 7]]);
+
+        // Alone in their region for a while, they're matched together, then alone after a while more.
+        let queue = [player(Some("eu"), 12), player(None, 2), player(Some("us"), 11)];
+        assert_eq!(batch(&queue, 5), vec![vec![0, 2]]);
+        assert!(batch(&[player(Some("eu"), 12)], 5).is_empty());
+        assert_eq!(batch(&[player(Some("eu"), 31)], 5), vec![vec![0]]);
+
+        let mut waits = WaitEstimate::default();
+        assert_eq!(waits.eta(Duration::from_secs(10)), 20);
+        waits.record(Duration::from_secs(4));
+        waits.record(Duration::from_secs(8));
+        assert_eq!(waits.eta(Duration::from_secs(1)), 5);
+        assert_eq!(waits.eta(Duration::from_secs(6)), 3);// The next batch
+    }
+}
@@ -0,0 +1,118 @@
+//!
+//! Usage of the rulesets and the expansions, served on /metrics in the Prometheus text format: the
+//! games started and the duration of the ended ones (average is sum / count) for every combination
+//! of scoring variant, expansions and mode (`engine` with the authoritative engine, `relay`
+//! otherwise). The expansions of a room are checked against SUPPORTED_EXPANSIONS so the
+//! combinations stay few.
+//!
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use actix_web::HttpResponse;
+
+use crate::protocol::{RoomSettings, ScoringVariant};
+
+static USAGE: Mutex<Usage> = Mutex::new(Usage::new());
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    ruleset: &'static str,
+    expansions: String,// Sorted and joined with +, "base" without any
+    mode: &'static str,
+}
+
+impl Key {
+    fn new(settings: &RoomSettings, engine: bool) -> Self {
+        let mut expansions = settings.expansions.clone();
+        expansions.sort();
+        Key {
+            ruleset: match settings.scoring {
+                ScoringVariant::Standard => "standard",
+                ScoringVariant::NoFarmers => "no_farmers",
+            },
+            expansions: if expansions.is_empty() { "base".to_string() } else { expansions.join("+") },
+            mode: if engine { "engine" } else { "relay" },
+        }
+    }
+
+    fn labels(&self) -> String {
+        format!("ruleset=\"{}\",expansions=\"{}\",mode=\"{}\"", self.ruleset, self.expansions, self.mode)
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    started: u64,
+    ended: u64,
+    duration_secs: u64,// Of the ended games
+}
+
+struct Usage {
+    games: BTreeMap<Key, Counts>,
+}
+
+impl Usage {
+    const fn new() -> Self {
+        Usage { games: BTreeMap::new() }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP carcassonne_games_started_total Games started per ruleset, expansions and mode.\n");
+        out.push_str("# TYPE carcassonne_games_started_total counter\n");
+        for (key, counts) in self.games.iter() {
+            let _ = writeln!(out, "carcassonne_games_started_total{{{}}} {}", key.labels(), counts.started);
+        }
+        out.push_str("# HELP carcassonne_game_duration_seconds Duration of the ended games per ruleset, expansions and mode.\n");
+        out.push_str("# TYPE carcassonne_game_duration_seconds summary\n");
+        for (key, counts) in self.games.iter() {
+            let _ = writeln!(out, "carcassonne_game_duration_seconds_sum{{{}}} {}", key.labels(), counts.duration_secs);
+            let _ = writeln!(out, "carcassonne_game_duration_seconds_count{{{}}} {}", key.labels(), counts.ended);
+        }
+        out
+    }
+}
+
+fn usage() -> std::sync::MutexGuard<'static, Usage> {
+    USAGE.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+pub fn game_started(settings: &RoomSettings, engine: bool) {
+    usage().games.entry(Key::new(settings, engine)).or_default().started += 1;
+}
+
+pub fn game_ended(settings: &RoomSettings, engine: bool, duration: Duration) {
+    let mut usage = usage();
+    let counts = usage.games.entry(Key::new(settings, engine)).or_default();
+    counts.ended += 1;
+    counts.duration_secs += duration.as_secs();
+}
+
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(usage().render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_is_rendered() {
+        let settings = RoomSettings { scoring: ScoringVariant::NoFarmers, ..RoomSettings::default() };
+        let mut usage = Usage::new();
+        for engine in [true, true, false].iter() {
+            usage.games.entry(Key::new(&settings, *engine)).or_default().started += 1;
+        }
+        let counts = usage.games.get_mut(&Key::new(&settings, true)).unwrap();
+        counts.ended = 2;
+        counts.duration_secs = 1500;
+
+        let text = usage.render();
+        assert!(text.contains("carcassonne_games_started_total{ruleset=\"no_farmers\",expansions=\"base\",mode=\"engine\"} 2\n"));
+        assert!(text.contains("carcassonne_games_started_total{ruleset=\"no_farmers\",expansions=\"base\",mode=\"relay\"} 1\n"));
+        assert!(text.contains("carcassonne_game_duration_seconds_sum{ruleset=\"no_farmers\",expansions=\"base\",mode=\"engine\"} 1500\n"));
+        assert!(text.contains("carcassonne_game_duration_seconds_count{ruleset=\"no_farmers\",expansions=\"base\",mode=\"engine\"} 2\n"));
+    }
+}
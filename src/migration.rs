@@ -0,0 +1,204 @@
+//!
+//! Moving a room to another instance, to drain an instance for a maintenance without ending its
+//! games. `POST /admin/rooms/migrate` on the instance of the room freezes it (no join, no start,
+//! no move of the authoritative engine), sends its data (options, settings, pinned message and the
+//! game with its scores) to /admin/rooms/import of the target, then every player receives
+//! event_room_migrated with the PUBLIC_URL of the target and a ticket and the room closes here.
+//! If the target refuses the room it's unfrozen and nothing changes. The instances share their
+//! ADMIN_TOKEN.
+//!
+//! The target keeps the room for MIGRATION_TTL. The players connect to it, log in again and join
+//! the room with their ticket: the first one recreates it and becomes its host, the others take
+//! their seat (and their team) back even if the room is locked or has a password. With the
+//! authoritative engine the game starts again once every player is back (the host can start it
+//! earlier), the meeples and the points of the players follow their new ids and the tiles left are
+//! shuffled again. In relay mode the server has no game to move: the clients continue theirs with
+//! a saved game.
+//!
+
+use std::{collections::HashMap, time::Duration};
+
+use actix_web::client::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::game::{snapshot, GameState};
+use crate::protocol::{IdType, PlayerScore, RoomOptions, RoomSettings, SerId};
+
+/// How long the target waits for the first player of an imported room.
+pub const MIGRATION_TTL: Duration = Duration::from_secs(120);
+
+/// Everything the target needs to recreate the room.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomData {
+    pub room_id: SerId,
+    pub code: String,
+    pub public: bool,
+    pub options: RoomOptions,
+    pub settings: RoomSettings,
+    pub pinned: Option<String>,
+    pub seats: Vec<Seat>,
+    pub game: Option<MigratedGame>,// Only with the authoritative engine, while the game runs
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Seat {
+    pub ticket: String,
+    pub player_id: SerId,// Before the move
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigratedGame {
+    pub snapshot: String,// Base64, see game::snapshot
+    pub scores: Vec<PlayerScore>,
+}
+
+impl MigratedGame {
+    pub fn new(game: &GameState) -> Self {
+        MigratedGame {
+            snapshot: base64::encode(snapshot::encode(game)),
+            scores: game.scores().iter().map(|(id, points)| PlayerScore { player: (*id).into(), points: *points }).collect(),
+        }
+    }
+
+    fn restore(&self) -> Result<GameState, &'static str> {
+        let data = base64::decode(&self.snapshot).map_err(|_| "Invalid base64")?;
+        let mut game = snapshot::decode(&data)?;
+        for score in self.scores.iter() {
+            game.set_score(score.player.0, score.points);
+        }
+        Ok(game)
+    }
+}
+
+pub fn new_ticket() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// The players expected back in a room moved here.
+pub struct Seats {
+    tickets: HashMap<String, (IdType, Option<u8>)>,// Ticket, id before the move and team of the players not back yet
+    back: Vec<(IdType, IdType)>,// Id before the move and new id
+    teams: HashMap<IdType, u8>,// Of the players back, by new id
+    game: Option<GameState>,
+}
+
+impl Seats {
+    pub fn new(data: &RoomData) -> Result<Self, &'static str> {
+        Ok(Seats {
+            tickets: data.seats.iter().map(|x| (x.ticket.clone(), (x.player_id.0, x.team))).collect(),
+            back: Vec::new(),
+            teams: HashMap::new(),
+            game: data.game.as_ref().map(MigratedGame::restore).transpose()?,
+        })
+    }
+
+    pub fn holds(&self, ticket: &str) -> bool {
+        self.tickets.contains_key(ticket)
+    }
+
+    /// Gives the seat of the ticket to the player, false if it isn't a ticket of the room.
+    pub fn claim(&mut self, ticket: &str, player: IdType) -> bool {
+        match self.tickets.remove(ticket) {
+            Some((old, team)) => {
+                self.back.push((old, player));
+                if let Some(team) = team {
+                    self.teams.insert(player, team);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The team a player back had before the move.
+    pub fn team_of(&self, player: IdType) -> Option<u8> {
+        self.teams.get(&player).copied()
+    }
+
+    /// True once every player is back and there's a game to start again.
+    pub fn ready(&self) -> bool {
+        self.tickets.is_empty() && self.game.is_some()
+    }
+
+    /// The game with the ids of the players back, the others keep their old ids.
+    pub fn into_game(self) -> Option<GameState> {
+        let mut game = self.game?;
+        for (old, new) in self.back {
+            game.rename_player(old, new);
+        }
+        Some(game)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Imported {
+    pub url: String,// PUBLIC_URL of the target
+}
+
+/// Sends the room to the admin api of the target, returns where its players have to connect.
+pub async fn send(target: String, token: String, data: &RoomData) -> Result<String, String> {
+    let mut res = Client::default()
+        .post(format!("{}/admin/rooms/import", target))
+        .bearer_auth(token)
+        .send_json(data)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    res.json::<Imported>().await.map(|x| x.url).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{fixture::GameStateBuilder, MeeplePosition};
+
+    #[test]
+    fn players_get_their_game_back() {
+        let mut game = GameStateBuilder::new()
+            .tile("E", (0, -1), 2)
+            .meeple((0, -1), 1, MeeplePosition::North)
+            .meeple((0, 0), 2, MeeplePosition::East)
+            .build();
+        game.set_score(1, 4);
+        let data = RoomData {
+            room_id: 42.into(),
+            code: "XQ7KPM".to_string(),
+            public: false,
+            options: RoomOptions::default(),
+            settings: RoomSettings::default(),
+            pinned: None,
+            seats: vec![
+                Seat { ticket: "a".to_string(), player_id: 1.into(), team: Some(1) },
+                Seat { ticket: "b".to_string(), player_id: 2.into(), team: None },
+            ],
+            game: Some(MigratedGame::new(&game)),
+        };
+        let data: RoomData = serde_json::from_str(&serde_json::to_string(&data).unwrap()).unwrap();
+
+        let mut seats = Seats::new(&data).unwrap();
+        assert!(!seats.claim("c", 10));
+        assert!(seats.claim("a", 10));
+        assert!(!seats.claim("a", 11));
+        assert!(!seats.ready());
+        assert!(seats.holds("b"));
+        assert_eq!(seats.team_of(10), Some(1));
+        assert!(seats.claim("b", 20));
+        assert!(seats.ready());
+
+        let game = seats.into_game().unwrap();
+        assert_eq!(game.meeples_of(10), 1);
+        assert_eq!(game.meeples_of(20), 1);
+        assert_eq!(game.meeples_of(1), 0);
+        assert_eq!(game.scores().get(&10), Some(&4));
+        assert!(game.scores().get(&1).is_none());
+    }
+}
@@ -0,0 +1,66 @@
+//!
+//! Checks of the text written by a player and shown to the others (the pinned messages of the rooms, the
+//! names of the tournaments).
+//!
+//! BLOCKED_WORDS_FILE points to a file with a blocked word per line, a text containing one of them
+//! (case insensitive) is refused. Without it only the length and the characters are checked.
+//!
+
+use std::{env, fs, sync::OnceLock};
+use tracing::warn;
+
+pub const MAX_PINNED_MESSAGE_LEN: usize = 200;
+pub const MAX_TOURNAMENT_NAME_LEN: usize = 48;
+
+static BLOCKED_WORDS: OnceLock<Vec<String>> = OnceLock::new();
+
+fn blocked_words() -> &'static [String] {
+    BLOCKED_WORDS.get_or_init(|| {
+        let path = match env::var_os("BLOCKED_WORDS_FILE") {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        match fs::read_to_string(&path) {
+            Ok(data) => data.lines()
+                .map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect(),
+            Err(e) => {
+                warn!(path = ?path, error = %e, "Cannot read the blocked words");
+                Vec::new()
+            },
+        }
+    })
+}
+
+/// Checks a pinned message, the error is sent back to the player.
+pub fn check_pinned_message(message: &str) -> Result<(), &'static str> {
+    if message.chars().count() > MAX_PINNED_MESSAGE_LEN {
+        return Err("message_too_long");
+    }
+    if message.chars().any(|x| x.is_control() && x != '\n') {
+        return Err("invalid_message");
+    }
+    if has_blocked_word(message) {
+        return Err("blocked_words");
+    }
+    Ok(())
+}
+
+/// Checks the name of a new tournament, the error is sent back to the organizer.
+pub fn check_tournament_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.chars().count() > MAX_TOURNAMENT_NAME_LEN || name.chars().any(char::is_control) {
+        return Err("invalid_name");
+    }
+    if has_blocked_word(name) {
+        return Err("blocked_words");
+    }
+    Ok(())
+}
+
+fn has_blocked_word(text: &str) -> bool {
+    let blocked = blocked_words();
+    text.split(|x: char| !x.is_alphanumeric())
+        .map(|x| x.to_lowercase())
+        .any(|word| blocked.contains(&word))
+}
@@ -0,0 +1,119 @@
+//!
+//! Quality of the connection of a player, measured with the heartbeat pings.
+//!
+//! Every ping carries a sequence number that the client echoes in its pong (websocket clients do it
+//! by themselves), the latency is the median round trip of the recent pings and the loss the share
+//! of them that was never answered. The values are coarse on purpose: they only tell the players
+//! whether a P2P game with the others is viable.
+//!
+
+use std::{collections::VecDeque, convert::TryInto, time::{Duration, Instant}};
+
+use crate::protocol::{NetworkQuality, QualityLevel};
+
+/// Pings considered, a minute with a heartbeat every 5 seconds.
+const WINDOW: usize = 12;
+
+struct Ping {
+    seq: u64,
+    sent_at: Instant,
+    rtt: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct NetworkStats {
+    next_seq: u64,
+    pings: VecDeque<Ping>,
+}
+
+impl NetworkStats {
+    /// Records a new ping, returns its payload.
+    pub fn ping_sent(&mut self, now: Instant) -> [u8; 8] {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.pings.len() == WINDOW {
+            self.pings.pop_front();
+        }
+        self.pings.push_back(Ping { seq, sent_at: now, rtt: None });
+        seq.to_be_bytes()
+    }
+
+    pub fn pong_received(&mut self, payload: &[u8], now: Instant) {
+        let seq = match payload.try_into() {
+            Ok(x) => u64::from_be_bytes(x),
+            Err(_) => return,
+        };
+        if let Some(ping) = self.pings.iter_mut().find(|x| x.seq == seq && x.rtt.is_none()) {
+            ping.rtt = Some(now.duration_since(ping.sent_at));
+        }
+    }
+
+    /// Quality of the pings sent so far, `None` until one of them is answered.
+    pub fn quality(&self) -> Option<NetworkQuality> {
+        let mut rtts: Vec<Duration> = self.pings.iter().filter_map(|x| x.rtt).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        rtts.sort();
+
+        let latency_ms = rtts[rtts.len() / 2].as_millis() as u32;
+        let loss_percent = ((self.pings.len() - rtts.len()) * 100 / self.pings.len()) as u32;
+        let level = if latency_ms < 100 && loss_percent < 5 {
+            QualityLevel::Good
+        } else if latency_ms < 250 && loss_percent < 15 {
+            QualityLevel::Fair
+        } else {
+            QualityLevel::Poor
+        };
+
+        Some(NetworkQuality {
+            // Rounded so that the small changes aren't broadcast to the room
+            latency_ms: (latency_ms + 5) / 10 * 10,
+            loss_percent: (loss_percent + 2) / 5 * 5,
+            level,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_and_loss() {
+        let start = Instant::now();
+        let mut stats = NetworkStats::default();
+        assert!(stats.quality().is_none());
+
+        for i in 0..10 {
+            let sent_at = start + Duration::from_secs(i * 5);
+            let payload = stats.ping_sent(sent_at);
+            if i != 3 {
+                stats.pong_received(&payload, sent_at + Duration::from_millis(40 + i));
+            }
+        }
+
+        let quality = stats.quality().unwrap();
+        assert_eq!(quality.latency_ms, 50);
+        assert_eq!(quality.loss_percent, 10);
+        assert!(quality.level == QualityLevel::Fair);
+    }
+
+    #[test]
+    fn old_pings_are_forgotten() {
+        let start = Instant::now();
+        let mut stats = NetworkStats::default();
+
+        for i in 0..(WINDOW as u64 * 2) {
+            let sent_at = start + Duration::from_secs(i * 5);
+            let payload = stats.ping_sent(sent_at);
+            if i >= WINDOW as u64 {
+                stats.pong_received(&payload, sent_at + Duration::from_millis(20));
+            }
+        }
+
+        let quality = stats.quality().unwrap();
+        assert_eq!(quality.loss_percent, 0);
+        assert!(quality.level == QualityLevel::Good);
+    }
+}
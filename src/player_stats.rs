@@ -0,0 +1,179 @@
+//!
+//! Statistics of the players: the games they played, their wins and points, the longest road and
+//! the biggest city they scored. They're kept with the account of the player (see accounts.rs),
+//! so they survive a reconnection and, with ACCOUNTS_FILE, a restart.
+//!
+//! They're updated at the end of every game. The points, the wins and the features need the
+//! authoritative engine, a game relayed without it only counts as played. The players with the best
+//! final score of a game all win it. A series of games (see series.rs) counts once more, at its end.
+//!
+//! A client asks for the profile of a player (its own or the one of a player it met) with
+//! player_stats, the same profile is served on `/players/stats?playerId=<player id>` without any
+//! authentication: a player with the privateProfile privacy setting is only shown its own profile,
+//! the others and the http endpoint get none.
+//!
+
+use std::collections::HashMap;
+
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::game::scoring::{FeatureKind, ScoredFeature};
+use crate::lobby_actor::{self, LobbyActor, PlayerRef};
+use crate::ratings::INITIAL_RATING;
+use crate::protocol::{Achievement, IdType, PlayerStatsResponse, SerId};
+
+pub struct GameResult {
+    pub points: Option<u32>,// Only with the authoritative engine
+    pub won: bool,
+    pub longest_road: u32,// In tiles
+    pub biggest_city: u32,
+    pub cloisters: u32,// Completed, for the achievements
+}
+
+/// The biggest road and city and the completed cloisters scored by every player of the running game.
+#[derive(Default)]
+pub struct GameRecords {
+    features: HashMap<IdType, (u32, u32, u32)>,
+}
+
+impl GameRecords {
+    pub fn add(&mut self, features: &[ScoredFeature]) {
+        for feature in features.iter() {
+            for player in feature.players.iter() {
+                let (road, city, cloisters) = self.features.entry(player.0).or_default();
+                match feature.feature {
+                    FeatureKind::Road => *road = (*road).max(feature.tiles),
+                    FeatureKind::City => *city = (*city).max(feature.tiles),
+                    FeatureKind::Cloister => *cloisters += feature.complete as u32,
+                    FeatureKind::Farm => {},
+                }
+            }
+        }
+    }
+
+    /// The results of the players of a game that ended with these final scores, none without the engine.
+    pub fn results(&self, players: impl Iterator<Item = IdType>, scores: Option<&HashMap<IdType, u32>>) -> HashMap<IdType, GameResult> {
+        let best = scores.and_then(|x| x.values().max().copied()).unwrap_or(0);
+        players
+            .map(|player| {
+                let points = scores.map(|x| x.get(&player).copied().unwrap_or(0));
+                let (longest_road, biggest_city, cloisters) = self.features.get(&player).copied().unwrap_or_default();
+                (player, GameResult { points, won: points == Some(best), longest_road, biggest_city, cloisters })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PlayerStats {
+    games_played: u32,
+    games_scored: u32,// With the authoritative engine
+    wins: u32,
+    points: u64,
+    longest_road: u32,
+    biggest_city: u32,
+    series_played: u32,// See series.rs
+    series_won: u32,
+}
+
+impl PlayerStats {
+    pub fn record(&mut self, result: &GameResult) {
+        self.games_played += 1;
+        if let Some(points) = result.points {
+            self.games_scored += 1;
+            self.points += points as u64;
+            self.wins += result.won as u32;
+        }
+        self.longest_road = self.longest_road.max(result.longest_road);
+        self.biggest_city = self.biggest_city.max(result.biggest_city);
+    }
+
+    pub fn record_series(&mut self, won: bool) {
+        self.series_played += 1;
+        self.series_won += won as u32;
+    }
+
+    pub fn games_played(&self) -> u32 {
+        self.games_played
+    }
+
+    /// The player is the session of the account, if it has one.
+    pub fn profile(&self, player: Option<IdType>, account: IdType, username: String, achievements: Vec<Achievement>) -> PlayerStatsResponse {
+        let per_game = |x: f64| if self.games_scored == 0 { 0.0 } else { x / self.games_scored as f64 };
+        PlayerStatsResponse {
+            player_id: player.map(SerId),
+            account_id: account.into(),
+            username,
+            games_played: self.games_played,
+            games_scored: self.games_scored,
+            wins: self.wins,
+            win_rate: per_game(self.wins as f64),
+            average_score: per_game(self.points as f64),
+            longest_road: self.longest_road,
+            biggest_city: self.biggest_city,
+            achievements,
+            series_played: self.series_played,
+            series_won: self.series_won,
+            rating: INITIAL_RATING as u32,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsQuery {
+    player_id: Option<SerId>,
+    account_id: Option<SerId>,
+}
+
+pub async fn profile(query: web::Query<StatsQuery>, lobby: web::Data<Addr<LobbyActor>>) -> HttpResponse {
+    let query = query.into_inner();
+    let player = match PlayerRef::new(query.player_id, query.account_id) {
+        Some(x) => x,
+        None => return HttpResponse::BadRequest().finish(),
+    };
+    match lobby.send(lobby_actor::GetPlayerStats { player, requester: None }).await {
+        Ok(Some(profile)) => HttpResponse::Ok().json(profile),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(feature: FeatureKind, tiles: u32, players: &[IdType]) -> ScoredFeature {
+        ScoredFeature { feature, points: 0, players: players.iter().map(|x| SerId(*x)).collect(), tiles, complete: true }
+    }
+
+    #[test]
+    fn games_add_up() {
+        let mut records = GameRecords::default();
+        records.add(&[feature(FeatureKind::Road, 3, &[1]), feature(FeatureKind::City, 4, &[1, 2])]);
+        records.add(&[feature(FeatureKind::Road, 5, &[2]), feature(FeatureKind::Road, 2, &[1]), feature(FeatureKind::Cloister, 9, &[1])]);
+        let scores: HashMap<IdType, u32> = vec![(1, 20), (2, 20)].into_iter().collect();
+        let results = records.results(vec![1, 2, 3].into_iter(), Some(&scores));
+        assert!(results[&1].won && results[&2].won && !results[&3].won);
+        assert_eq!((results[&1].longest_road, results[&1].biggest_city), (3, 4));
+        assert_eq!((results[&2].longest_road, results[&3].points), (5, Some(0)));
+        assert_eq!((results[&1].cloisters, results[&2].cloisters), (1, 0));
+
+        let mut stats = PlayerStats::default();
+        stats.record(&results[&3]);
+        stats.record(&results[&1]);
+        // Relayed without the engine: played, without points.
+        stats.record(&GameRecords::default().results(std::iter::once(1), None).remove(&1).unwrap());
+        let profile = stats.profile(Some(1), 2, "alice".to_string(), Vec::new());
+        assert_eq!((profile.games_played, profile.games_scored, profile.wins), (3, 2, 1));
+        assert_eq!((profile.win_rate, profile.average_score), (0.5, 10.0));
+        assert_eq!((profile.longest_road, profile.biggest_city), (3, 4));
+        stats.record_series(true);
+        stats.record_series(false);
+        let profile = stats.profile(None, 2, "alice".to_string(), Vec::new());
+        assert_eq!((profile.series_played, profile.series_won), (2, 1));
+    }
+}
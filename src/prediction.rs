@@ -0,0 +1,71 @@
+//!
+//! Score projections and win probabilities of a running game, for the spectator overlays and the
+//! graphs after the game. A room created with the `winProbability` option (authoritative engine
+//! only) sends a win_probability special message every PREDICTION_INTERVAL while the board keeps
+//! changing, it's recorded in the replay with the other events.
+//!
+//! The evaluation is a light one, like the one of the bots: the points of a player if the game
+//! ended now (the unfinished features with its meeples scored as at the end) plus its share of the
+//! tiles left at an average number of points per turn. The probabilities follow the gaps between
+//! the projections, with an uncertainty that shrinks as the deck empties.
+//!
+
+use std::time::Duration;
+
+use crate::game::GameState;
+use crate::protocol::{IdType, PlayerPrediction};
+
+pub const PREDICTION_INTERVAL: Duration = Duration::from_secs(10);
+/// Points a player scores per tile placed, on average over a base game.
+const POINTS_PER_TURN: f64 = 1.5;
+/// Spread of the projections, in points, for every tile left in the deck.
+const UNCERTAINTY_PER_TILE: f64 = 0.5;
+
+/// The projection of every player of the game, in the order given.
+pub fn predict(game: &GameState, players: &[IdType], farms: bool) -> Vec<PlayerPrediction> {
+    if players.is_empty() {
+        return Vec::new();
+    }
+    let mut end = game.clone();
+    end.score_end(farms);
+    let tiles_left = game.tiles_left() as f64;
+    let future = tiles_left / players.len() as f64 * POINTS_PER_TURN;
+    let projected: Vec<f64> = players.iter()
+        .map(|x| end.scores().get(x).copied().unwrap_or(0) as f64 + future)
+        .collect();
+
+    // Softmax of the projections: an even game early on, the leader almost sure at the end.
+    let spread = 1.0 + UNCERTAINTY_PER_TILE * tiles_left;
+    let best = projected.iter().copied().fold(f64::MIN, f64::max);
+    let weights: Vec<f64> = projected.iter().map(|x| ((x - best) / spread).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    players.iter().zip(projected.iter().zip(weights.iter()))
+        .map(|(player, (projected, weight))| PlayerPrediction {
+            player: (*player).into(),
+            projected_score: projected.round() as u32,
+            win_probability: weight / total,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_leader_is_favoured() {
+        let mut game = GameState::default();
+        game.set_score(1, 30);
+        game.set_score(2, 10);
+        let predictions = predict(&game, &[1, 2, 3], true);
+        let total: f64 = predictions.iter().map(|x| x.win_probability).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(predictions[0].win_probability > predictions[1].win_probability);
+        assert!(predictions[1].win_probability > predictions[2].win_probability);
+        // The whole deck is left: the gap of 20 points is far from decisive yet.
+        assert!(predictions[0].win_probability < 0.6);
+        assert!(predictions[0].projected_score > 30);
+        assert_eq!(predictions[1].projected_score - predictions[2].projected_score, 10);
+        assert!(predict(&game, &[], true).is_empty());
+    }
+}
@@ -0,0 +1,35 @@
+//!
+//! Central place where the privacy settings of a player are applied to the data sent to them,
+//! every event leaving a room passes through here. The lobby doesn't send the room codes, it
+//! applies the other settings where it handles the data they cover: the friend list and requests
+//! (hidePresence, blockFriendRequests) and the profiles (privateProfile). The rooms drop the cursor
+//! of a player hiding its presence.
+//!
+//! Rooms created in streamer mode apply the streamer mode to every player, whatever their settings.
+//!
+
+use std::borrow::Cow;
+
+use crate::protocol::{OutEvent, PrivacySettings, RoomOptions};
+
+/// Returns the event as it should be seen by a player with the given settings in the given room.
+pub fn redact_event<'a>(event: &'a OutEvent, privacy: &PrivacySettings, room: &RoomOptions) -> Cow<'a, OutEvent> {
+    if !privacy.streamer_mode && !room.streamer_mode {
+        return Cow::Borrowed(event);
+    }
+
+    // Streamer mode: never show the room code, someone could be watching.
+    match event {
+        OutEvent::EventRoomStart { connection_type, board, teams, .. } => Cow::Owned(OutEvent::EventRoomStart {
+            connection_type: *connection_type,
+            broadcast_id: String::new(),
+            board: board.clone(),
+            teams: teams.clone(),
+        }),
+        OutEvent::EventRoomMergeProposal { player_count, .. } => Cow::Owned(OutEvent::EventRoomMergeProposal {
+            room_id: None,
+            player_count: *player_count,
+        }),
+        _ => Cow::Borrowed(event),
+    }
+}
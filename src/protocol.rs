@@ -8,6 +8,12 @@ use serde::de::Visitor;
 use serde::export::Formatter;
 use std::borrow::Cow;
 
+use crate::features::FeatureFlags;
+use crate::game::{scoring::ScoredFeature, BoardSnapshot, TileSnapshot};
+use crate::replay::ReplayEvent;
+use crate::room_code;
+use crate::room_actor::{RoomState, MAX_PLAYERS_PER_ROOM, MIN_PLAYERS_PER_ROOM, ROOM_COUNTDOWN_ON_MIN_PLAYERS};
+
 pub type IdType = usize;
 
 // Common data
@@ -20,26 +26,170 @@ pub struct PlayerObject {
     #[serde(flatten)]
     pub cosmetics: PlayerCosmetics,
     pub is_host: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<u8>,// Only in the rooms with teams
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
 pub struct LoginData {
     pub username: String,
     #[serde(flatten)]
     pub cosmetics: PlayerCosmetics,
+    #[serde(default)]
+    pub privacy: PrivacySettings,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub languages: Vec<String>,// Spoken by the player, favourite first (e.g. ["it", "en"])
+    #[serde(default, rename = "devToken", skip_serializing)]
+    pub dev_token: Option<String>,// Of a developer account, see dev_rooms.rs
+    #[serde(default, rename = "accountToken", skip_serializing)]
+    pub account_token: Option<String>,// Of the account of the player, a new one without, see accounts.rs
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+/// Languages kept for a player, the others are ignored.
+pub const MAX_LANGUAGES: usize = 4;
+
+/// Lowercase language tag (e.g. "en", "pt-br"), `None` if it doesn't look like one.
+pub fn normalize_language(tag: &str) -> Option<String> {
+    let tag = tag.trim();
+    if !(2..=8).contains(&tag.len()) || !tag.chars().all(|x| x.is_ascii_alphanumeric() || x == '-') {
+        return None;
+    }
+    Some(tag.to_ascii_lowercase())
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PrivacySettings {
+    pub streamer_mode: bool,// Hides the room code from the events
+    pub share_relay_samples: bool,// Lets the server sample the relay messages, see relay_sampling.rs
+    pub hide_presence: bool,// No cursor in game, offline in the friend list of the others
+    pub block_friend_requests: bool,// Only the players it added can add it
+    pub private_profile: bool,// Its statistics are only shown to itself
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
 pub struct PlayerCosmetics {
     pub avatar: u32,
     pub color: u64,
 }
 
+/// Rules of a room chosen by the host, the server only enforces the player count and the
+/// expansions, the rest is applied by the clients.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RoomSettings {
+    pub max_players: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub turn_timer_secs: Option<u32>,
+    pub expansions: Vec<String>,
+    pub scoring: ScoringVariant,
+    pub auto_start: Option<AutoStart>,// null disables it, the host has to start the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,// Spoken in the room, public rooms are found by players who speak it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub teams: Option<u8>,// Team count of the team variants, the players of a team share their points
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<u8>,// Best of 3 or 5 games, see series.rs
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            max_players: MAX_PLAYERS_PER_ROOM,
+            turn_timer_secs: None,
+            expansions: Vec::new(),
+            scoring: ScoringVariant::Standard,
+            auto_start: Some(AutoStart::default()),
+            language: None,
+            teams: None,
+            series: None,
+        }
+    }
+}
+
+/// Most teams of a room, each one needs at least a player.
+pub const MAX_TEAMS: u8 = 4;
+
+/// Players supported by the rules of the base game.
+pub const BASE_GAME_MAX_PLAYERS: usize = 5;
+/// Expansions that raise the players supported by the rules (e.g. with the sixth color of meeples).
+const EXPANSION_MAX_PLAYERS: &[(&str, usize)] = &[("inns_and_cathedrals", 6), ("big_box", 6)];
+
+impl RoomSettings {
+    /// Most players the rules support with the chosen expansions.
+    pub fn ruleset_max_players(&self) -> usize {
+        self.expansions.iter()
+            .filter_map(|x| EXPANSION_MAX_PLAYERS.iter().find(|(name, _)| name == x).map(|(_, max)| *max))
+            .fold(BASE_GAME_MAX_PLAYERS, usize::max)
+    }
+
+    /// Most players the room can start with, the lowest of max_players and the rules.
+    pub fn player_limit(&self) -> usize {
+        self.max_players.min(self.ruleset_max_players())
+    }
+}
+
+/// The room starts by itself `after_secs` seconds after `players` players are in it.
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoStart {
+    pub players: usize,
+    pub after_secs: u32,
+}
+
+impl Default for AutoStart {
+    fn default() -> Self {
+        AutoStart {
+            players: MIN_PLAYERS_PER_ROOM,
+            after_secs: ROOM_COUNTDOWN_ON_MIN_PLAYERS,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringVariant {
+    Standard,
+    NoFarmers,// Fields are not scored at the end of the game
+}
+
+#[derive(Deserialize, Copy, Clone, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BotDifficulty {
+    Easy,
+    #[default]
+    Normal,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RoomOptions {
+    pub streamer_mode: bool,// The room code is only sent on request, never in the events
+    pub password: Option<String>,
+    pub dev_room: bool,// In the namespace reserved to the developers, see dev_rooms.rs
+    #[serde(skip)]
+    pub tournament: bool,// A match of a tournament, created by the lobby, see tournament.rs
+    pub win_probability: bool,// Sends the win probabilities during the game, see prediction.rs
+    pub spectator_delay_secs: Option<u32>,// The spectators are that far behind, see spectator_delay.rs
+}
+
 // Client to Server data
+//
+// The protocol only grows, so that older clients keep working with newer servers and the other
+// way around:
+// - a new field of a client message must be optional (`#[serde(default)]`), older clients don't send it;
+// - a new field of a server message must be something the clients can ignore, optional fields are
+//   skipped when empty (`skip_serializing_if`);
+// - every enum received from the clients has an `Unknown` variant (`#[serde(other)]`), a message
+//   from a newer client gets an "Unknown message type" error instead of an "Invalid Json" one;
+// - variants and fields are never renamed or removed, the golden files in protocol/golden pin the
+//   json of the server messages.
 
 #[derive(Deserialize)]
 pub struct IdMessage {
     pub id: Option<u64>,
+    #[serde(default, rename = "requestKey")]
+    pub request_key: Option<String>,// See idempotency.rs
 }
 
 #[derive(Deserialize)]
@@ -48,30 +198,177 @@ pub enum ReceivedMessage {
     Login {
         details: LoginData
     },
+    Resume {
+        token: String,
+    },
     ChangeAvatar {
         #[serde(flatten)]
         cosmetics: PlayerCosmetics,
     },
-    RoomFind {  
+    RoomFind {
+        #[serde(default)]
+        region: Option<String>,// Chosen by the client, players of the same region are matched first
+        #[serde(default)]
+        queue: bool,// Waits in the matchmaking queue, see matchmaking.rs
+    },
+    QueueLeave {
+    },
+    RoomList {
+        #[serde(default)]
+        page: usize,
     },
     RoomCreate {
+        #[serde(default)]
+        options: RoomOptions,
+    },
+    RoomCode {
     },
     RoomLeave {
     },
     #[serde(rename_all = "camelCase")]
     RoomJoin {
-        invite_id: SerId,
+        invite_id: InviteId,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        ticket: Option<String>,// Of an event_room_migrated, takes the seat back in the moved room
+    },
+    RoomLock {
+        locked: bool,
+    },
+    RoomSettings {
+        settings: RoomSettings,
+    },
+    RoomTeam {
+        team: u8,
+    },
+    RoomPin {
+        #[serde(default)]
+        message: Option<String>,// None removes the pinned message
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomKick {
+        player_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomBan {
+        player_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomTransferHost {
+        player_id: SerId,
+    },
+    RoomAddBot {
+        #[serde(default)]
+        difficulty: BotDifficulty,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomSpectate {
+        invite_id: InviteId,
+        #[serde(default)]
+        password: Option<String>,
     },
     #[serde(rename_all = "camelCase")]
     RoomStart {
         connection_type: RoomConnectionType,
+        #[serde(default)]
+        snapshot: Option<String>,// Base64 of a saved game to continue
+        #[serde(default)]
+        countdown: bool,// Starts after a countdown that the players can cancel
+    },
+    RoomStartCancel {
+    },
+    RoomMergeAccept {
     },
     #[serde(rename_all = "camelCase")]
     EventRoomStartAck {
         request_id: u64,
+    },
+    Subscribe {
+        events: Vec<EventClass>,
+    },
+    Unsubscribe {
+        events: Vec<EventClass>,
+    },
+    MatchHistory {
+    },
+    #[serde(rename_all = "camelCase")]
+    PlayerStats {
+        #[serde(default)]
+        player_id: Option<SerId>,// Or the account of a player that isn't connected, yourself without either
+        #[serde(default)]
+        account_id: Option<SerId>,
+    },
+    CosmeticsCatalog {
+    },
+    #[serde(rename_all = "camelCase")]
+    ReplayGet {
+        replay_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    FriendAdd {
+        player_id: Option<SerId>,// Or the account of a player that isn't connected
+        account_id: Option<SerId>,
+    },
+    #[serde(rename_all = "camelCase")]
+    FriendRemove {
+        player_id: Option<SerId>,
+        account_id: Option<SerId>,
+    },
+    FriendList {
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomInvite {
+        player_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomInviteAccept {
+        room_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomInviteDecline {
+        room_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    TournamentCreate {
+        name: String,
+        max_players: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    TournamentRegister {
+        tournament_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    TournamentLeave {
+        tournament_id: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    TournamentStart {
+        tournament_id: SerId,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+impl ReceivedMessage {
+    /// False for the commands that are answered only on an error.
+    pub fn has_response(&self) -> bool {
+        !matches!(self, ReceivedMessage::ChangeAvatar { .. } | ReceivedMessage::EventRoomStartAck { .. })
     }
 }
 
+/// Optional event classes, clients are subscribed to all of them by default.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventClass {
+    Presence,
+    SpectatorCount,
+    Cosmetics,
+    NetworkQuality,
+    #[serde(other)]
+    Unknown,// Classes of newer clients (and global_chat, this server has no global chat), ignored
+}
+
 
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -122,6 +419,7 @@ pub struct NoData {}
 
 #[derive(Serialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)] // Variant names are part of the wire format.
 pub enum OutEvent {
     EventPlayerJoined {
         player: PlayerObject,
@@ -137,10 +435,322 @@ pub enum OutEvent {
         #[serde(flatten)]
         cosmetics: PlayerCosmetics
     },
+    EventPlayerTeamChange {
+        player: SerId,
+        team: Option<u8>,// null once the host disables the teams
+    },
     #[serde(rename_all = "camelCase")]
     EventRoomStart {
         connection_type: RoomConnectionType,
         broadcast_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        board: Option<BoardSnapshot>,// Only when a saved game is loaded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        teams: Option<Vec<Team>>,// Only in the rooms with teams
+    },
+    #[serde(rename_all = "camelCase")]
+    EventRoomMergeProposal {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room_id: Option<SerId>,
+        player_count: usize,
+    },
+    EventRoomLockChange {
+        locked: bool,
+    },
+    EventRoomSettingsChange {
+        settings: RoomSettings,
+    },
+    // Sent when the host changes it and to every player joining a room with a pinned message.
+    EventRoomPinnedMessage {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    // Sent when a start countdown begins, without `seconds` when it's canceled.
+    #[serde(rename_all = "camelCase")]
+    EventRoomStartCountdown {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seconds: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        canceled_by: Option<SerId>,// The player that canceled it with room_start_cancel
+    },
+    // Sent periodically in matchmaking when the connection quality of a player changes.
+    EventRoomNetworkQuality {
+        players: Vec<PlayerNetworkQuality>,
+    },
+    // Sent to the player removed by the host, the others receive an event_player_left.
+    EventKicked {
+        banned: bool,
+    },
+    // Sent to every player, in game too, when the host hands the room to another player.
+    EventHostChanged {
+        host: SerId,
+    },
+    // Sent by the operators to every connected player.
+    #[serde(rename = "event_server_announcement")]
+    ServerAnnouncement {
+        message: String,
+    },
+    // Sent every few seconds to the players in the matchmaking queue, `eta` is in seconds.
+    EventQueueUpdate {
+        position: usize,
+        eta: u64,
+    },
+    // The room has been closed by the operators, the players are back to the matchmaking.
+    EventRoomClosed {},
+    // The room moved to another server: the player connects to `url` and joins the room with the ticket.
+    #[serde(rename_all = "camelCase")]
+    EventRoomMigrated {
+        url: String,
+        room_id: SerId,
+        ticket: String,
+    },
+    // The server is going to stop in at most `seconds`, sooner if every game ends.
+    #[serde(rename = "event_server_shutdown")]
+    ServerShutdown {
+        seconds: u64,
+    },
+    // The operators are handling an incident: the lobby is read only, no game can start until it ends.
+    #[serde(rename = "event_server_incident")]
+    ServerIncident {
+        active: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    // Part of the events of a replay asked with replay_get, in order.
+    #[serde(rename_all = "camelCase")]
+    EventReplayChunk {
+        replay_id: SerId,
+        events: Vec<ReplayEvent>,
+        done: bool,// The last chunk
+    },
+    // Someone added the player as a friend, they become friends once the player adds them back.
+    EventFriendRequest {
+        from: SerId,
+        username: String,
+    },
+    // A player added back as a friend the one receiving the event.
+    EventFriendAccepted {
+        player: SerId,
+        username: String,
+    },
+    // A friend invites the player to their room, answered with room_invite_accept or room_invite_decline.
+    #[serde(rename_all = "camelCase")]
+    EventRoomInvite {
+        room_id: SerId,
+        from: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    EventRoomInviteDeclined {
+        room_id: SerId,
+        player: SerId,
+    },
+    EventTournamentUpdate {
+        tournament: TournamentView,
+    },
+    // The player is in the room of its next match, as after a room_join. The first round is 1.
+    #[serde(rename_all = "camelCase")]
+    EventTournamentMatch {
+        tournament_id: SerId,
+        round: usize,
+        room_id: SerId,
+        players: Vec<PlayerObject>,
+        settings: RoomSettings,
+    },
+    // Items of the achievements just unlocked, they can be picked from now on.
+    EventCosmeticsUnlocked {
+        unlocked: Vec<CatalogEntry>,
+    },
+    // Sent as soon as the player reaches it, during the game or at its end.
+    EventAchievementUnlocked {
+        achievement: Achievement,
+    },
+    // To a player of a series that ends its game, see series.rs.
+    EventSeriesUpdate {
+        series: SeriesView,
+    },
+    EventSeriesEnded {
+        series: SeriesView,// With its winner
+    },
+    // Relay messages over a limit are being dropped, see rate_limit.rs.
+    #[serde(rename = "event_rate_limited")]
+    RateLimited {
+        scope: RateLimitScope,
+    },
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitScope {
+    Connection,// The messages sent by this client
+    Room,// The traffic relayed by the whole room
+}
+
+impl OutEvent {
+    /// The optional class of the event, `None` if every client must receive it.
+    pub fn class(&self) -> Option<EventClass> {
+        match self {
+            OutEvent::EventPlayerAvatarChange { .. } => Some(EventClass::Cosmetics),
+            OutEvent::EventRoomNetworkQuality { .. } => Some(EventClass::NetworkQuality),
+            _ => None,
+        }
+    }
+}
+
+/// Coarse quality of the connection of a player, see network.rs.
+#[derive(Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkQuality {
+    pub latency_ms: u32,
+    pub loss_percent: u32,
+    pub level: QualityLevel,
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityLevel {
+    Good,
+    Fair,
+    Poor,
+}
+
+#[derive(Serialize, Clone, PartialEq, Eq)]
+pub struct PlayerNetworkQuality {
+    pub player: SerId,
+    #[serde(flatten)]
+    pub quality: NetworkQuality,
+}
+
+/// Expansions the server knows the rules of (the base game is always supported).
+pub const SUPPORTED_EXPANSIONS: &[&str] = &[];
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub expansions: &'static [&'static str],
+    pub binary_protocol: bool,
+    pub spectating: bool,
+    pub voice_signaling: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconnect_window_secs: Option<u64>,
+}
+
+/// A game played by the player, from when the game started to the first end_game of a player.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchRecord {
+    pub room_id: SerId,
+    pub players: Vec<PlayerObject>,// Players at the start of the game
+    pub ended_at: u64,// Unix time in seconds
+    pub duration_secs: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<TileSnapshot>,// In the order they were placed, only with the authoritative engine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_id: Option<SerId>,// Only with the authoritative engine, see replay_get
+}
+
+#[derive(Serialize)]
+pub struct MatchHistoryResponse {
+    pub matches: Vec<MatchRecord>,
+}
+
+/// The profile of a player, see player_stats.rs.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStatsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_id: Option<SerId>,// While the player has a session
+    pub account_id: SerId,
+    pub username: String,
+    pub games_played: u32,
+    pub games_scored: u32,// With the authoritative engine, the others have no points
+    pub wins: u32,
+    pub win_rate: f64,// Of the games scored
+    pub average_score: f64,
+    pub longest_road: u32,// In tiles
+    pub biggest_city: u32,
+    pub achievements: Vec<Achievement>,
+    pub series_played: u32,
+    pub series_won: u32,
+    pub rating: u32,// See ratings.rs
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesView {
+    pub best_of: u8,
+    pub games: u32,// Played so far
+    pub wins: Vec<SeriesWins>,// Every player of the series, in its turn order
+    pub winner: Option<SerId>,// Once it's over, none for a tie
+}
+
+#[derive(Serialize, Clone)]
+pub struct SeriesWins {
+    pub player: SerId,
+    pub wins: u32,
+}
+
+/// A cosmetic earned with an achievement, see cosmetics.rs. The others are free.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CosmeticItem {
+    Avatar(u32),
+    Color(u64),
+}
+
+/// See achievements.rs for their rules.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Achievement {
+    FirstWin,// A game won with the authoritative engine
+    RoadBuilder,// A completed road of 10 tiles
+    CityPlanner,// A completed city of 10 tiles
+    Veteran,// 10 games played
+    HighScore,// 100 points in a game
+    Abbot,// A game won with 3 completed cloisters
+}
+
+#[derive(Serialize, Clone)]
+pub struct CatalogEntry {
+    pub item: CosmeticItem,
+    pub achievement: Achievement,
+    pub owned: bool,
+}
+
+#[derive(Serialize)]
+pub struct CosmeticsCatalogResponse {
+    pub catalog: Vec<CatalogEntry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayResponse {
+    pub room_id: SerId,
+    pub players: Vec<PlayerObject>,
+    pub duration_ms: u64,
+    pub event_count: usize,// Sent afterwards in event_replay_chunk
+}
+
+#[derive(Serialize)]
+pub struct Hello {
+    #[serde(rename = "type")]
+    pub mtype: &'static str,// always "hello"
+    pub capabilities: Capabilities,
+    pub features: FeatureFlags,
+}
+
+impl Hello {
+    pub fn new(features: FeatureFlags, reconnect_window_secs: Option<u64>) -> Self {
+        Hello {
+            mtype: "hello",
+            capabilities: Capabilities {
+                expansions: SUPPORTED_EXPANSIONS,
+                binary_protocol: features.binary_protocol,
+                spectating: false,
+                voice_signaling: false,
+                reconnect_window_secs,
+            },
+            features,
+        }
     }
 }
 
@@ -148,6 +758,35 @@ pub enum OutEvent {
 #[serde(rename_all = "camelCase")]
 pub struct LoginResponse {
     pub player_id: SerId,
+    pub resume_token: String,
+    pub account_id: SerId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_token: Option<String>,// Only when the account was just created
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumedState {
+    MatchMaking,
+    Lobby,
+    Playing,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeResponse {
+    pub player_id: SerId,
+    pub state: ResumedState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub players: Option<Vec<PlayerObject>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub board: Option<BoardSnapshot>,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub relay_lost: u64,// Relay messages missed while disconnected that the server no longer has
+}
+
+fn is_zero(x: &u64) -> bool {
+    *x == 0
 }
 
 #[derive(Serialize)]
@@ -155,20 +794,144 @@ pub struct LoginResponse {
 pub struct RoomFindResponse {
     pub players: Vec<PlayerObject>,
     pub room_id: SerId,
-    pub just_created: bool
+    pub just_created: bool,
+    pub settings: RoomSettings,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Friend {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<SerId>,// Only while online
+    pub account_id: SerId,
+    pub username: String,// Of its last login
+    pub online: bool,// False while the session waits for a reconnection or is over
+}
+
+#[derive(Serialize)]
+pub struct FriendListResponse {
+    pub friends: Vec<Friend>,
+    pub requests: Vec<Friend>,// The players that added this one, not added back yet
+}
+
+#[derive(Serialize)]
+pub struct RoomAddBotResponse {
+    pub player: PlayerObject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentState {
+    Registering,
+    Running,
+    Finished,
+    Canceled,// The organizer left before the start
+}
+
+/// A tournament and its bracket, see tournament.rs.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TournamentView {
+    pub tournament_id: SerId,
+    pub name: String,
+    pub organizer: SerId,
+    pub max_players: usize,
+    pub state: TournamentState,
+    pub players: Vec<SerId>,// By seed once started
+    pub rounds: Vec<Vec<TournamentMatchView>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub winner: Option<SerId>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TournamentMatchView {
+    pub players: Vec<SerId>,// A single player has a bye
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<SerId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub winner: Option<SerId>,
+}
+
+#[derive(Serialize)]
+pub struct TournamentResponse {
+    pub tournament: TournamentView,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomCreateResponse {
     pub players: [PlayerObject; 1],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite_id: Option<SerId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomRedirectResponse {
+    pub url: String,
+    pub room_id: SerId,
+}
+
+/// A precondition of room_start that isn't met, see start_checks.rs.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartFailure {
+    pub reason: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub players: Vec<SerId>,// The players that block the start, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_players: Option<usize>,// The limit exceeded by a too_many_players failure
+}
+
+#[derive(Serialize)]
+pub struct RoomStartFailures {
+    pub failures: Vec<StartFailure>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomCodeResponse {
     pub invite_id: SerId,
+    pub code: String,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomJoinResponse {
     pub players: Vec<PlayerObject>,
+    pub settings: RoomSettings,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomListResponse {
+    pub rooms: Vec<RoomSummary>,
+    pub page: usize,
+    pub page_count: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSummary {
+    pub room_id: SerId,
+    pub host: String,// Username of the host
+    pub player_count: usize,
+    pub state: RoomState,
+    pub locked: bool,
+    pub rules: RoomSettings,
+    pub player_limit: usize,// Most players the room can start with, see RoomSettings::player_limit
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSpectateResponse {
+    pub players: Vec<PlayerObject>,
+    pub spectators: Vec<PlayerObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub board: Option<BoardSnapshot>,
 }
 
 #[derive(Serialize)]
@@ -258,6 +1021,25 @@ impl<'de> Deserialize<'de> for SerId {
     }
 }
 
+/// The room asked by room_join and room_spectate: its id or its code, see room_code.rs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InviteId {
+    Id(IdType),
+    Code(String),
+}
+
+impl<'de> Deserialize<'de> for InviteId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de> {
+        let text = String::deserialize(deserializer)?;
+        match room_code::normalize(&text) {
+            Some(code) => Ok(InviteId::Code(code)),
+            None => SerIdVisitor.visit_str(&text).map(|x| InviteId::Id(x.0)),
+        }
+    }
+}
+
 impl From<IdType> for SerId {
     fn from(x: IdType) -> Self {
         SerId(x)
@@ -277,14 +1059,90 @@ impl From<SerId> for IdType {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ReceivedGameMessage {
     EndGame {},
+    SaveGame {},
+    SpectateJoin {},// Spectators only
+    SpectateLeave {},// Spectators only
+    Cursor {
+        #[serde(flatten)]
+        position: CursorPosition,
+    },
+    TimeSync {
+        #[serde(default, rename = "clientTime")]
+        client_time: Option<u64>,// Echoed, on the clock of the client
+    },
+    VoteStart {
+        #[serde(flatten)]
+        proposal: VoteProposal,
+    },
+    Vote {
+        yes: bool,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Where a player is hovering, with the tile they are about to place.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CursorPosition {
+    pub x: f32,
+    pub y: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tile: Option<String>,
+    #[serde(default)]
+    pub rotation: u8,
+}
+
+/// What the players in game vote on, see votes.rs.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "proposal", rename_all = "snake_case")]
+pub enum VoteProposal {
+    Kick { target: SerId },// A player that stopped playing, it doesn't vote
+    Abort,// The game ends without a result
+    ReturnToLobby,// The game ends with the scores as they are
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VoteView {
+    #[serde(flatten)]
+    pub proposal: VoteProposal,
+    pub started_by: SerId,
+    pub yes: u32,
+    pub no: u32,
+    pub needed: u32,// Yes votes to pass
+    pub deadline: u64,// In server time, see clock.rs
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteResult {
+    Passed,
+    Failed,// Refused or timed out
+    Cancelled,// The game ended or the player to kick left
 }
 
 #[derive(Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)] // Variant names are part of the wire format.
 pub enum OutGameMessage {
     EndGameAck {
+        players: Vec<PlayerObject>,
+        spectators: Vec<PlayerObject>,// Still watching, they can join the room
+    },
+    SpectateJoinAck {
         players: Vec<PlayerObject>
     },
+    SpectateLeaveAck {},
+    #[serde(rename_all = "camelCase")]
+    SaveGameAck {
+        snapshot_id: String,
+        data: String,// Base64
+    },
+    #[serde(rename_all = "camelCase")]
+    TimeSyncAck {
+        client_time: Option<u64>,
+        server_time: u64,// Milliseconds since the Unix epoch, see clock.rs
+    },
 }
 
 #[derive(Serialize, Clone)]
@@ -296,4 +1154,458 @@ pub enum OutGameEvent {
         #[serde(skip_serializing_if = "Option::is_none")]
         new_host: Option<SerId>,
     },
+    MoveRejected {
+        reason: Cow<'static, str>,
+    },
+    SpectatorsChange {
+        spectators: Vec<PlayerObject>,
+    },
+    Cursor {
+        player: SerId,
+        #[serde(flatten)]
+        position: CursorPosition,
+    },
+    GameEnded {},// Sent to the spectators, they can now join the room
+    RoomClosed {},// Sent to the spectators when the last player leaves
+    #[serde(rename_all = "camelCase")]
+    RoomMigrated {// As event_room_migrated, to the players in game
+        url: String,
+        room_id: SerId,
+        ticket: String,
+    },
+    // The current player took too long, the turn is skipped.
+    #[serde(rename_all = "camelCase")]
+    TurnTimeout {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        server_time: Option<u64>,// When it was sent, see clock.rs
+    },
+    #[serde(rename_all = "camelCase")]
+    TileDrawn {
+        player: SerId,// Who has to place it
+        tile: &'static str,
+        tiles_left: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        server_time: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        deadline: Option<u64>,// End of the turn with a turn timer, in server time
+    },
+    ScoreUpdate {
+        scores: Vec<PlayerScore>,
+        features: Vec<ScoredFeature>,// Scored since the last update
+        #[serde(rename = "final")]
+        is_final: bool,// Scores of the end of the game
+        #[serde(skip_serializing_if = "Option::is_none")]
+        teams: Option<Vec<TeamScore>>,// Sums of the scores of the players of each team
+    },
+    #[serde(rename_all = "camelCase")]
+    WinProbability {
+        tiles_left: usize,
+        players: Vec<PlayerPrediction>,
+    },
+    // At the end of the games of the tournaments, see turn_times.rs.
+    #[serde(rename_all = "camelCase")]
+    TimeReport {
+        limit_secs: u32,// A longer move is a violation
+        players: Vec<PlayerTimes>,
+    },
+    // To the players in game, see votes.rs.
+    VoteUpdate {
+        vote: VoteView,
+    },
+    VoteEnded {
+        vote: VoteView,
+        result: VoteResult,
+    },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerPrediction {
+    pub player: SerId,
+    pub projected_score: u32,// Final score expected
+    pub win_probability: f64,// 0 to 1, they add up to 1
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerTimes {
+    pub player: SerId,
+    pub moves: u32,// Tiles placed
+    pub average_ms: u64,
+    pub longest_ms: u64,
+    pub violations: u32,// Moves over the limit and turns skipped by the turn timer
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PlayerScore {
+    pub player: SerId,
+    pub points: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Team {
+    pub team: u8,
+    pub players: Vec<SerId>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct TeamScore {
+    pub team: u8,
+    pub points: u32,
+}
+
+/// Golden files of the wire format in protocol/golden, a test fails when the json of a message
+/// changes. Run the tests with UPDATE_GOLDEN=1 to write the new files after an intended change.
+#[cfg(test)]
+mod golden_tests {
+    use std::{env, fs, path::Path};
+
+    use super::*;
+    use crate::game::{MeeplePosition, MeepleSnapshot, TileSnapshot};
+    use crate::game::scoring::FeatureKind;
+
+    fn check(dir: &str, name: &str, value: &impl Serialize) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("protocol/golden").join(dir).join(format!("{}.json", name));
+        let actual = serde_json::to_string_pretty(&OutMessage { id: 1, mex: value }).unwrap() + "\n";
+
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("Missing golden file {}, run with UPDATE_GOLDEN=1", path.display()));
+        assert_eq!(actual, expected, "Wire format of {}/{} changed", dir, name);
+    }
+
+    fn player(id: IdType, is_host: bool) -> PlayerObject {
+        PlayerObject {
+            id: SerId(id),
+            username: format!("player{}", id),
+            cosmetics: PlayerCosmetics { avatar: 3, color: 0xff0000 },
+            is_host,
+            team: None,
+        }
+    }
+
+    fn board() -> BoardSnapshot {
+        BoardSnapshot {
+            tiles: vec![TileSnapshot { tile: "D", x: 0, y: 0, rotation: 0 }],
+            meeples: vec![MeepleSnapshot { player: SerId(1), x: 0, y: 0, position: MeeplePosition::East }],
+        }
+    }
+
+    // Doesn't compile when a variant is added: add its golden file in the tests below.
+    fn _every_variant_is_tested(event: &OutEvent, game_event: &OutGameEvent) {
+        match event {
+            OutEvent::EventPlayerJoined { .. } | OutEvent::EventPlayerLeft { .. } |
+            OutEvent::EventPlayerAvatarChange { .. } | OutEvent::EventPlayerTeamChange { .. } | OutEvent::EventRoomStart { .. } |
+            OutEvent::EventRoomMergeProposal { .. } | OutEvent::EventRoomLockChange { .. } |
+            OutEvent::EventRoomSettingsChange { .. } | OutEvent::EventRoomPinnedMessage { .. } |
+            OutEvent::EventRoomStartCountdown { .. } | OutEvent::EventRoomNetworkQuality { .. } |
+            OutEvent::EventKicked { .. } | OutEvent::EventHostChanged { .. } | OutEvent::ServerAnnouncement { .. } | OutEvent::EventRoomClosed {} |
+            OutEvent::EventQueueUpdate { .. } |
+            OutEvent::EventRoomMigrated { .. } | OutEvent::ServerShutdown { .. } | OutEvent::ServerIncident { .. } | OutEvent::EventReplayChunk { .. } |
+            OutEvent::EventFriendRequest { .. } | OutEvent::EventFriendAccepted { .. } |
+            OutEvent::EventRoomInvite { .. } | OutEvent::EventRoomInviteDeclined { .. } |
+            OutEvent::EventTournamentUpdate { .. } | OutEvent::EventTournamentMatch { .. } |
+            OutEvent::EventCosmeticsUnlocked { .. } | OutEvent::EventAchievementUnlocked { .. } | OutEvent::RateLimited { .. } |
+            OutEvent::EventSeriesUpdate { .. } | OutEvent::EventSeriesEnded { .. } => {},
+        }
+        match game_event {
+            OutGameEvent::PlayerLeft { .. } | OutGameEvent::MoveRejected { .. } |
+            OutGameEvent::SpectatorsChange { .. } | OutGameEvent::Cursor { .. } |
+            OutGameEvent::GameEnded {} | OutGameEvent::RoomClosed {} | OutGameEvent::RoomMigrated { .. } |
+            OutGameEvent::TurnTimeout { .. } | OutGameEvent::TileDrawn { .. } | OutGameEvent::ScoreUpdate { .. } |
+            OutGameEvent::WinProbability { .. } | OutGameEvent::TimeReport { .. } |
+            OutGameEvent::VoteUpdate { .. } | OutGameEvent::VoteEnded { .. } => {},
+        }
+    }
+
+    #[test]
+    fn out_events() {
+        let events = [
+            ("event_player_joined", OutEvent::EventPlayerJoined { player: player(2, false) }),
+            ("event_player_left", OutEvent::EventPlayerLeft { player: SerId(1), new_host: Some(SerId(2)) }),
+            ("event_player_left_same_host", OutEvent::EventPlayerLeft { player: SerId(2), new_host: None }),
+            ("event_player_avatar_change", OutEvent::EventPlayerAvatarChange {
+                player: SerId(2),
+                cosmetics: PlayerCosmetics { avatar: 4, color: 0x00ff00 },
+            }),
+            ("event_player_team_change", OutEvent::EventPlayerTeamChange { player: SerId(2), team: Some(1) }),
+            ("event_room_start", OutEvent::EventRoomStart {
+                connection_type: RoomConnectionType::ServerBroadcast,
+                broadcast_id: "42".to_string(),
+                board: None,
+                teams: None,
+            }),
+            ("event_room_start_saved_game", OutEvent::EventRoomStart {
+                connection_type: RoomConnectionType::ServerBroadcast,
+                broadcast_id: "42".to_string(),
+                board: Some(board()),
+                teams: None,
+            }),
+            ("event_room_start_teams", OutEvent::EventRoomStart {
+                connection_type: RoomConnectionType::ServerBroadcast,
+                broadcast_id: "42".to_string(),
+                board: None,
+                teams: Some(vec![
+                    Team { team: 0, players: vec![SerId(1), SerId(3)] },
+                    Team { team: 1, players: vec![SerId(2), SerId(4)] },
+                ]),
+            }),
+            ("event_room_merge_proposal", OutEvent::EventRoomMergeProposal { room_id: Some(SerId(42)), player_count: 3 }),
+            ("event_room_merge_proposal_hidden", OutEvent::EventRoomMergeProposal { room_id: None, player_count: 3 }),
+            ("event_room_lock_change", OutEvent::EventRoomLockChange { locked: true }),
+            ("event_room_settings_change", OutEvent::EventRoomSettingsChange {
+                settings: RoomSettings {
+                    max_players: 4,
+                    turn_timer_secs: Some(60),
+                    expansions: vec![],
+                    scoring: ScoringVariant::NoFarmers,
+                    auto_start: Some(AutoStart { players: 4, after_secs: 10 }),
+                    language: Some("it".to_string()),
+                    teams: None,
+                    series: None,
+                },
+            }),
+            ("event_room_pinned_message", OutEvent::EventRoomPinnedMessage { message: Some("Be nice!".to_string()) }),
+            ("event_room_pinned_message_removed", OutEvent::EventRoomPinnedMessage { message: None }),
+            ("event_room_start_countdown", OutEvent::EventRoomStartCountdown { seconds: Some(30), canceled_by: None }),
+            ("event_room_start_countdown_canceled", OutEvent::EventRoomStartCountdown { seconds: None, canceled_by: None }),
+            ("event_room_start_countdown_canceled_by_player", OutEvent::EventRoomStartCountdown {
+                seconds: None,
+                canceled_by: Some(SerId(2)),
+            }),
+            ("event_room_network_quality", OutEvent::EventRoomNetworkQuality {
+                players: vec![PlayerNetworkQuality {
+                    player: SerId(2),
+                    quality: NetworkQuality { latency_ms: 120, loss_percent: 5, level: QualityLevel::Fair },
+                }],
+            }),
+            ("event_kicked", OutEvent::EventKicked { banned: true }),
+            ("event_host_changed", OutEvent::EventHostChanged { host: SerId(2) }),
+            ("event_server_announcement", OutEvent::ServerAnnouncement { message: "Maintenance at 20:00 UTC".to_string() }),
+            ("event_room_closed", OutEvent::EventRoomClosed {}),
+            ("event_queue_update", OutEvent::EventQueueUpdate { position: 3, eta: 12 }),
+            ("event_room_migrated", OutEvent::EventRoomMigrated {
+                url: "wss://eu-2.example.com/".to_string(),
+                room_id: SerId(42),
+                ticket: "5f0c2a9e41d7b386".to_string(),
+            }),
+            ("event_server_shutdown", OutEvent::ServerShutdown { seconds: 300 }),
+            ("event_replay_chunk", OutEvent::EventReplayChunk {
+                replay_id: SerId(9),
+                events: vec![
+                    ReplayEvent { at_ms: 1200, sender: Some(SerId(1)), event: serde_json::json!({ "type": "place_tile", "tile": "E", "x": 0, "y": -1, "rotation": 2 }) },
+                    ReplayEvent { at_ms: 1201, sender: None, event: serde_json::json!({ "type": "tile_drawn", "player": "AAAAAAAAAAI=", "tile": "U", "tilesLeft": 69 }) },
+                ],
+                done: true,
+            }),
+            ("event_server_incident", OutEvent::ServerIncident { active: true, message: Some("Games are paused while we fix the database".to_string()) }),
+            ("event_friend_request", OutEvent::EventFriendRequest { from: SerId(2), username: "Bob".to_string() }),
+            ("event_friend_accepted", OutEvent::EventFriendAccepted { player: SerId(2), username: "Bob".to_string() }),
+            ("event_room_invite", OutEvent::EventRoomInvite { room_id: SerId(42), from: SerId(1) }),
+            ("event_room_invite_declined", OutEvent::EventRoomInviteDeclined { room_id: SerId(42), player: SerId(2) }),
+            ("event_tournament_update", OutEvent::EventTournamentUpdate {
+                tournament: TournamentView {
+                    tournament_id: SerId(7),
+                    name: "Sunday cup".to_string(),
+                    organizer: SerId(9),
+                    max_players: 8,
+                    state: TournamentState::Running,
+                    players: vec![SerId(1), SerId(2), SerId(3)],
+                    rounds: vec![vec![
+                        TournamentMatchView { players: vec![SerId(1)], room_id: None, winner: Some(SerId(1)) },
+                        TournamentMatchView { players: vec![SerId(2), SerId(3)], room_id: Some(SerId(42)), winner: None },
+                    ]],
+                    winner: None,
+                },
+            }),
+            ("event_tournament_match", OutEvent::EventTournamentMatch {
+                tournament_id: SerId(7),
+                round: 1,
+                room_id: SerId(42),
+                players: vec![player(2, true), player(3, false)],
+                settings: RoomSettings::default(),
+            }),
+            ("event_cosmetics_unlocked", OutEvent::EventCosmeticsUnlocked {
+                unlocked: vec![
+                    CatalogEntry { item: CosmeticItem::Avatar(1 << 31), achievement: Achievement::FirstWin, owned: true },
+                    CatalogEntry { item: CosmeticItem::Color(1 << 32), achievement: Achievement::Veteran, owned: true },
+                ],
+            }),
+            ("event_achievement_unlocked", OutEvent::EventAchievementUnlocked { achievement: Achievement::Abbot }),
+            ("event_series_update", OutEvent::EventSeriesUpdate { series: SeriesView {
+                best_of: 3,
+                games: 1,
+                wins: vec![SeriesWins { player: SerId(1), wins: 1 }, SeriesWins { player: SerId(2), wins: 0 }],
+                winner: None,
+            } }),
+            ("event_series_ended", OutEvent::EventSeriesEnded { series: SeriesView {
+                best_of: 5,
+                games: 4,
+                wins: vec![SeriesWins { player: SerId(1), wins: 1 }, SeriesWins { player: SerId(2), wins: 3 }],
+                winner: Some(SerId(2)),
+            } }),
+            ("event_rate_limited", OutEvent::RateLimited { scope: RateLimitScope::Connection }),
+            ("event_rate_limited_room", OutEvent::RateLimited { scope: RateLimitScope::Room }),
+        ];
+
+        for (name, event) in events.iter() {
+            check("out_event", name, event);
+        }
+    }
+
+    #[test]
+    fn out_game_events() {
+        let events = [
+            ("player_left", OutGameEvent::PlayerLeft { player: SerId(1), new_host: Some(SerId(2)) }),
+            ("move_rejected", OutGameEvent::MoveRejected { reason: "Tile edges do not match".into() }),
+            ("spectators_change", OutGameEvent::SpectatorsChange { spectators: vec![player(3, false)] }),
+            ("cursor", OutGameEvent::Cursor {
+                player: SerId(1),
+                position: CursorPosition { x: 1.5, y: -2.0, tile: Some("E".to_string()), rotation: 2 },
+            }),
+            ("game_ended", OutGameEvent::GameEnded {}),
+            ("room_closed", OutGameEvent::RoomClosed {}),
+            ("room_migrated", OutGameEvent::RoomMigrated {
+                url: "wss://eu-2.example.com/".to_string(),
+                room_id: SerId(42),
+                ticket: "5f0c2a9e41d7b386".to_string(),
+            }),
+            ("turn_timeout", OutGameEvent::TurnTimeout { server_time: None }),
+            ("turn_timeout_server_time", OutGameEvent::TurnTimeout { server_time: Some(1_767_225_600_000) }),
+            ("tile_drawn", OutGameEvent::TileDrawn { player: SerId(1), tile: "D", tiles_left: 70, server_time: None, deadline: None }),
+            ("tile_drawn_deadline", OutGameEvent::TileDrawn {
+                player: SerId(1),
+                tile: "D",
+                tiles_left: 70,
+                server_time: Some(1_767_225_600_000),
+                deadline: Some(1_767_225_660_000),
+            }),
+            ("score_update", OutGameEvent::ScoreUpdate {
+                scores: vec![PlayerScore { player: SerId(1), points: 4 }, PlayerScore { player: SerId(2), points: 0 }],
+                features: vec![ScoredFeature { feature: FeatureKind::City, points: 4, players: vec![SerId(1)], tiles: 2, complete: true }],
+                is_final: false,
+                teams: None,
+            }),
+            ("score_update_teams", OutGameEvent::ScoreUpdate {
+                scores: vec![PlayerScore { player: SerId(1), points: 4 }, PlayerScore { player: SerId(2), points: 0 }, PlayerScore { player: SerId(3), points: 6 }, PlayerScore { player: SerId(4), points: 0 }],
+                features: vec![],
+                is_final: true,
+                teams: Some(vec![TeamScore { team: 0, points: 10 }, TeamScore { team: 1, points: 0 }]),
+            }),
+            ("win_probability", OutGameEvent::WinProbability {
+                tiles_left: 42,
+                players: vec![
+                    PlayerPrediction { player: SerId(1), projected_score: 61, win_probability: 0.75 },
+                    PlayerPrediction { player: SerId(2), projected_score: 48, win_probability: 0.25 },
+                ],
+            }),
+            ("time_report", OutGameEvent::TimeReport {
+                limit_secs: 90,
+                players: vec![
+                    PlayerTimes { player: SerId(1), moves: 35, average_ms: 12_400, longest_ms: 95_000, violations: 1 },
+                    PlayerTimes { player: SerId(2), moves: 34, average_ms: 8_100, longest_ms: 41_000, violations: 0 },
+                ],
+            }),
+            ("vote_update", OutGameEvent::VoteUpdate {
+                vote: VoteView {
+                    proposal: VoteProposal::Kick { target: SerId(3) },
+                    started_by: SerId(1),
+                    yes: 1,
+                    no: 0,
+                    needed: 2,
+                    deadline: 1_767_225_630_000,
+                },
+            }),
+            ("vote_ended", OutGameEvent::VoteEnded {
+                vote: VoteView {
+                    proposal: VoteProposal::ReturnToLobby,
+                    started_by: SerId(2),
+                    yes: 2,
+                    no: 1,
+                    needed: 2,
+                    deadline: 1_767_225_630_000,
+                },
+                result: VoteResult::Passed,
+            }),
+        ];
+
+        for (name, event) in events.iter() {
+            check("out_game_event", name, event);
+        }
+    }
+}
+
+/// Messages as they are sent by the clients of the previous protocol version (before the optional
+/// fields were added) and by newer clients, every one of them must still be understood.
+#[cfg(test)]
+mod compat_tests {
+    use super::*;
+
+    fn parse(text: &str) -> ReceivedMessage {
+        serde_json::from_str(text).unwrap_or_else(|e| panic!("{} doesn't parse: {}", text, e))
+    }
+
+    #[test]
+    fn previous_version_messages() {
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "login", "details": {"username": "a", "avatar": 1, "color": 2}}"#),
+            ReceivedMessage::Login { details } if !details.privacy.streamer_mode
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_create"}"#),
+            ReceivedMessage::RoomCreate { options } if options.password.is_none() && !options.streamer_mode
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_join", "inviteId": "AAAAAAAAACo="}"#),
+            ReceivedMessage::RoomJoin { invite_id: InviteId::Id(42), password: None, ticket: None }
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_find"}"#),
+            ReceivedMessage::RoomFind { region: None, queue: false }
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_list"}"#),
+            ReceivedMessage::RoomList { page: 0 }
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_start", "connectionType": "server_broadcast"}"#),
+            ReceivedMessage::RoomStart { snapshot: None, .. }
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_settings", "settings": {"maxPlayers": 4}}"#),
+            ReceivedMessage::RoomSettings { settings } if settings.max_players == 4 && settings.scoring == ScoringVariant::Standard
+        ));
+    }
+
+    #[test]
+    fn newer_client_messages() {
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_join", "inviteId": "xq7-kpm"}"#),
+            ReceivedMessage::RoomJoin { invite_id: InviteId::Code(code), .. } if code == "XQ7KPM"
+        ));
+        assert!(matches!(parse(r#"{"id": 1, "type": "room_teleport", "x": 3}"#), ReceivedMessage::Unknown));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "login", "details": {"username": "a", "avatar": 1, "color": 2, "privacy": {"privateProfile": true}}}"#),
+            ReceivedMessage::Login { details } if details.privacy.private_profile && !details.privacy.hide_presence
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "room_find", "skill": 1200}"#),
+            ReceivedMessage::RoomFind { region: None, queue: false }
+        ));
+        assert!(matches!(
+            parse(r#"{"id": 1, "type": "unsubscribe", "events": ["cosmetics", "weather", "global_chat"]}"#),
+            ReceivedMessage::Unsubscribe { events } if events == [EventClass::Cosmetics, EventClass::Unknown, EventClass::Unknown]
+        ));
+
+        let special = serde_json::from_str::<ReceivedGameMessage>(r#"{"type": "emote", "emote": 3}"#).unwrap();
+        assert!(matches!(special, ReceivedGameMessage::Unknown));
+        let special = serde_json::from_str::<ReceivedGameMessage>(r#"{"type": "time_sync"}"#).unwrap();
+        assert!(matches!(special, ReceivedGameMessage::TimeSync { client_time: None }));
+        let special = serde_json::from_str::<ReceivedGameMessage>(r#"{"type": "vote_start", "proposal": "kick", "target": "AAAAAAAAAAM="}"#).unwrap();
+        assert!(matches!(special, ReceivedGameMessage::VoteStart { proposal: VoteProposal::Kick { target: SerId(3) } }));
+    }
 }
@@ -0,0 +1,108 @@
+//!
+//! Flood protection of the relay: a token bucket per connection limits the messages a client can
+//! relay (RELAY_RATE_LIMIT messages per second, 30 by default) and a bucket per room limits the bytes
+//! the room sends to its peers (ROOM_RELAY_LIMIT_KB kilobytes per second, 1024 by default).
+//! Both accept a burst of two seconds of traffic, 0 disables them.
+//!
+//! The messages over a limit are dropped and the sender is warned with an event_rate_limited, a
+//! client that keeps flooding the room is disconnected.
+//!
+
+use std::{env, sync::OnceLock, time::Instant};
+
+const DEFAULT_RELAY_RATE_LIMIT: f64 = 30.0;
+const DEFAULT_ROOM_RELAY_LIMIT_KB: f64 = 1024.0;
+/// Seconds of traffic accepted at once.
+const BURST_SECS: f64 = 2.0;
+/// Messages dropped in a row before the client is disconnected.
+pub const MAX_DROPPED_RELAY: u32 = 100;
+
+struct Limits {
+    relay_per_sec: f64,
+    room_bytes_per_sec: f64,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+fn limits() -> &'static Limits {
+    LIMITS.get_or_init(|| {
+        let read = |name: &str, default: f64| env::var(name).ok()
+            .and_then(|x| x.parse::<f64>().ok())
+            .filter(|x| *x >= 0.0)
+            .unwrap_or(default);
+
+        Limits {
+            relay_per_sec: read("RELAY_RATE_LIMIT", DEFAULT_RELAY_RATE_LIMIT),
+            room_bytes_per_sec: read("ROOM_RELAY_LIMIT_KB", DEFAULT_ROOM_RELAY_LIMIT_KB) * 1024.0,
+        }
+    })
+}
+
+/// Bucket of the relay messages of a connection, `None` if it's not limited.
+pub fn connection_bucket() -> Option<TokenBucket> {
+    TokenBucket::new(limits().relay_per_sec, Instant::now())
+}
+
+/// Bucket of the bytes relayed by a room, `None` if it's not limited.
+pub fn room_bucket() -> Option<TokenBucket> {
+    TokenBucket::new(limits().room_bytes_per_sec, Instant::now())
+}
+
+pub struct TokenBucket {
+    rate: f64,// Tokens per second
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Option<Self> {
+        if rate <= 0.0 {
+            return None;
+        }
+        let burst = rate * BURST_SECS;
+        Some(TokenBucket { rate, burst, tokens: burst, last: now })
+    }
+
+    /// Takes `cost` tokens if there are enough of them.
+    pub fn try_take(&mut self, cost: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn burst_then_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start).unwrap();
+
+        assert_eq!((0..30).filter(|_| bucket.try_take(1.0, start)).count(), 20);
+        // Half a second later 5 more messages are allowed.
+        let later = start + Duration::from_millis(500);
+        assert_eq!((0..30).filter(|_| bucket.try_take(1.0, later)).count(), 5);
+    }
+
+    #[test]
+    fn refill_is_capped() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start).unwrap();
+        assert!(bucket.try_take(20.0, start));
+
+        let later = start + Duration::from_secs(60);
+        assert!(!bucket.try_take(21.0, later));
+        assert!(bucket.try_take(20.0, later));
+        assert!(TokenBucket::new(0.0, start).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+//!
+//! Ratings: an Elo rating for every account, moved once per series by its result (see series.rs),
+//! the single games outside of a series don't change it. Every player of a series plays the
+//! others: the winner beats them all and the others draw among themselves, a series without a
+//! winner is a draw for everybody. The rating is kept with the account and shown in its profile
+//! (see player_stats.rs).
+//!
+
+use serde::{Deserialize, Serialize};
+
+pub const INITIAL_RATING: f64 = 1500.0;
+/// The most a series can move a rating.
+const K: f64 = 32.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(transparent)]
+pub struct Rating(pub f64);
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating(INITIAL_RATING)
+    }
+}
+
+impl Rating {
+    /// The expected score against another rating, from 0 to 1.
+    fn expected(self, other: Rating) -> f64 {
+        1.0 / (1.0 + 10f64.powf((other.0 - self.0) / 400.0))
+    }
+}
+
+/// The ratings after a series, in the order of the players, the winner is an index of them.
+pub fn rate_series(ratings: &[Rating], winner: Option<usize>) -> Vec<Rating> {
+    if ratings.len() < 2 {
+        return ratings.to_vec();
+    }
+    // Spread over the opponents, a series moves a rating as much with 4 players as with 2.
+    let k = K / (ratings.len() - 1) as f64;
+    ratings.iter().enumerate()
+        .map(|(i, rating)| {
+            let change: f64 = ratings.iter().enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(j, other)| {
+                    let score = match winner {
+                        Some(x) if x == i => 1.0,
+                        Some(x) if x == j => 0.0,
+                        _ => 0.5,
+                    };
+                    score - rating.expected(*other)
+                })
+                .sum();
+            Rating(rating.0 + k * change)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_move_the_ratings() {
+        let even = [Rating::default(), Rating::default()];
+        assert_eq!(rate_series(&even, Some(0)), [Rating(1516.0), Rating(1484.0)]);
+        assert_eq!(rate_series(&even, None), even);
+
+        // Beating a stronger player is worth more, the total doesn't change.
+        let ratings = rate_series(&[Rating(1400.0), Rating(1600.0), Rating(1500.0)], Some(0));
+        assert!(ratings[0].0 - 1400.0 > 16.0);
+        assert!(ratings[1].0 < 1600.0 && ratings[2].0 < 1500.0);
+        assert!((ratings.iter().map(|x| x.0).sum::<f64>() - 4500.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,158 @@
+//!
+//! Prefilter of the relayed moves, a first step towards the authoritative engine for the games
+//! without it (the `relayPrefilter` feature flag). The server doesn't know the tiles, it only
+//! refuses the moves that are obviously invalid with the same move_rejected as the engine: a tile
+//! on a position already taken or not touching the board, a tile placed out of turn, a meeple
+//! somewhere else than on the tile just placed by its player. Every other message is relayed as
+//! before, the clients don't change.
+//!
+//! The server doesn't choose the order of the players in relay mode either: it learns it from the
+//! first round, every player in game places one tile in the order the clients agreed on, then the
+//! players must follow it. A player leaving the game is taken out of the order.
+//!
+
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::game::{self, BoardSnapshot, GameMove};
+use crate::protocol::IdType;
+
+const NEIGHBOURS: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+
+pub struct RelayFilter {
+    occupied: HashSet<(i32, i32)>,
+    players: Vec<IdType>,// In game, an order is known once every one of them is in it
+    order: Vec<IdType>,
+    turn: usize,// In the order, once it's known
+    last_tile: Option<(IdType, (i32, i32), bool)>,// Player, position and if it has a meeple
+}
+
+impl RelayFilter {
+    /// The filter of a game starting with these players, on an empty board or a saved one.
+    pub fn new(players: Vec<IdType>, board: Option<&BoardSnapshot>) -> Self {
+        let occupied = match board {
+            Some(x) => x.tiles.iter().map(|x| (x.x, x.y)).collect(),
+            None => std::iter::once((0, 0)).collect(),
+        };
+        RelayFilter { occupied, players, order: Vec::new(), turn: 0, last_tile: None }
+    }
+
+    fn learning(&self) -> bool {
+        self.order.len() < self.players.len()
+    }
+
+    /// The reason to refuse the message, relayed if it's not a move.
+    pub fn check(&mut self, sender: IdType, data: &str) -> Result<(), &'static str> {
+        let value = match serde_json::from_str::<Value>(data) {
+            Ok(x) => x,
+            Err(_) => return Ok(()),
+        };
+        match value.get("type").and_then(|x| x.as_str()) {
+            Some("place_tile") | Some("place_meeple") => {},
+            _ => return Ok(()),
+        }
+        match serde_json::from_value::<GameMove>(value).map_err(|_| "Invalid move")? {
+            GameMove::PlaceTile { x, y, .. } => self.place_tile(sender, (x, y)),
+            GameMove::PlaceMeeple { x, y, .. } => self.place_meeple(sender, (x, y)),
+        }
+    }
+
+    fn place_tile(&mut self, sender: IdType, at: (i32, i32)) -> Result<(), &'static str> {
+        if !game::on_board(at) {
+            return Err("Position outside of the board");
+        }
+        if self.occupied.contains(&at) {
+            return Err("Position already occupied");
+        }
+        if NEIGHBOURS.iter().all(|(dx, dy)| !self.occupied.contains(&(at.0 + dx, at.1 + dy))) {
+            return Err("Tile must be adjacent to another tile");
+        }
+        if self.learning() {
+            if !self.players.contains(&sender) || self.order.contains(&sender) {
+                return Err("Not your turn");
+            }
+            self.order.push(sender);
+        } else {
+            if self.order.get(self.turn) != Some(&sender) {
+                return Err("Not your turn");
+            }
+            self.turn = (self.turn + 1) % self.order.len();
+        }
+        self.occupied.insert(at);
+        self.last_tile = Some((sender, at, false));
+        Ok(())
+    }
+
+    fn place_meeple(&mut self, sender: IdType, at: (i32, i32)) -> Result<(), &'static str> {
+        match &mut self.last_tile {
+            Some((player, position, _)) if *player != sender || *position != at => {
+                Err("Meeples can only be placed on the tile just placed")
+            },
+            Some((_, _, true)) => Err("The tile already has a meeple"),
+            Some((_, _, meeple)) => {
+                *meeple = true;
+                Ok(())
+            },
+            None => Err("Meeples can only be placed on the tile just placed"),
+        }
+    }
+
+    /// Takes a player out of the game, its turn goes to the next one.
+    pub fn remove_player(&mut self, player: IdType) {
+        self.players.retain(|x| *x != player);
+        if let Some(i) = self.order.iter().position(|x| *x == player) {
+            self.order.remove(i);
+            if i < self.turn {
+                self.turn -= 1;
+            }
+        }
+        if self.turn >= self.order.len() {
+            self.turn = 0;
+        }
+        if self.last_tile.map(|x| x.0 == player).unwrap_or(false) {
+            self.last_tile = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x: i32, y: i32) -> String {
+        format!(r#"{{"type": "place_tile", "tile": "E", "x": {}, "y": {}, "rotation": 0}}"#, x, y)
+    }
+
+    fn meeple(x: i32, y: i32) -> String {
+        format!(r#"{{"type": "place_meeple", "x": {}, "y": {}, "position": "north"}}"#, x, y)
+    }
+
+    #[test]
+    fn obviously_invalid_moves_are_refused() {
+        let mut filter = RelayFilter::new(vec![1, 2, 3], None);
+        assert_eq!(filter.check(1, r#"{"type": "chat", "text": "hi"}"#), Ok(()));
+        assert_eq!(filter.check(1, "not json"), Ok(()));
+        assert_eq!(filter.check(1, r#"{"type": "place_tile", "x": 1}"#), Err("Invalid move"));
+        assert_eq!(filter.check(1, &tile(0, 0)), Err("Position already occupied"));
+        assert_eq!(filter.check(1, &tile(0, 2)), Err("Tile must be adjacent to another tile"));
+        assert_eq!(filter.check(1, &tile(i32::MAX, 0)), Err("Position outside of the board"));
+
+        // The first round gives the order: 2, 1, 3.
+        assert_eq!(filter.check(2, &tile(0, 1)), Ok(()));
+        assert_eq!(filter.check(2, &tile(0, 2)), Err("Not your turn"));
+        assert_eq!(filter.check(1, &meeple(0, 1)), Err("Meeples can only be placed on the tile just placed"));
+        assert_eq!(filter.check(2, &meeple(0, 1)), Ok(()));
+        assert_eq!(filter.check(2, &meeple(0, 1)), Err("The tile already has a meeple"));
+        assert_eq!(filter.check(1, &tile(1, 0)), Ok(()));
+        assert_eq!(filter.check(3, &tile(-1, 0)), Ok(()));
+        assert_eq!(filter.check(1, &tile(0, 2)), Err("Not your turn"));
+        assert_eq!(filter.check(2, &tile(0, 2)), Ok(()));
+        assert_eq!(filter.check(2, &meeple(0, 2)), Ok(()));
+
+        // The turn of a player leaving goes to the next one.
+        filter.remove_player(1);
+        assert_eq!(filter.check(3, &tile(0, 3)), Ok(()));
+        assert_eq!(filter.check(2, &tile(0, 4)), Ok(()));
+    }
+}
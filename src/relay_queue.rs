@@ -0,0 +1,99 @@
+//!
+//! Relay packets of a room, kept once in a ring buffer shared by its members. Every member has a
+//! cursor, the sequence number of the next packet it has to receive:
+//!
+//!  A B C D E
+//! ^     ^   ^
+//! p1    p2  p4
+//!       p3
+//!
+//! The packets are sent as soon as the member can take them: right away while it's playing, once it
+//! has acknowledged event_room_start, or when it resumes its session after a disconnection. The
+//! queue keeps the last RELAY_QUEUE_SIZE packets, a member further behind has lost the oldest ones.
+//!
+
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::protocol::IdType;
+
+const RELAY_QUEUE_SIZE: usize = 256;
+
+pub struct RelayPacket {
+    pub sender: IdType,
+    pub data: Arc<str>,
+}
+
+pub struct RelayQueue {
+    packets: VecDeque<RelayPacket>,
+    start: u64,// Sequence number of the oldest packet kept
+    capacity: usize,
+}
+
+impl Default for RelayQueue {
+    fn default() -> Self {
+        RelayQueue::with_capacity(RELAY_QUEUE_SIZE)
+    }
+}
+
+impl RelayQueue {
+    fn with_capacity(capacity: usize) -> Self {
+        RelayQueue {
+            packets: VecDeque::new(),
+            start: 0,
+            capacity,
+        }
+    }
+
+    /// Sequence number of the next packet, the cursor of a member that is up to date.
+    pub fn head(&self) -> u64 {
+        self.start + self.packets.len() as u64
+    }
+
+    pub fn push(&mut self, sender: IdType, data: Arc<str>) {
+        if self.packets.len() >= self.capacity {
+            self.packets.pop_front();
+            self.start += 1;
+        }
+        self.packets.push_back(RelayPacket { sender, data });
+    }
+
+    /// The packets from `cursor` on, and how many of the packets before them are lost.
+    pub fn read(&self, cursor: u64) -> (impl Iterator<Item = &RelayPacket>, u64) {
+        let lost = self.start.saturating_sub(cursor);
+        let skip = cursor.saturating_sub(self.start) as usize;
+        (self.packets.iter().skip(skip), lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn senders(queue: &RelayQueue, cursor: u64) -> (Vec<IdType>, u64) {
+        let (packets, lost) = queue.read(cursor);
+        (packets.map(|x| x.sender).collect(), lost)
+    }
+
+    #[test]
+    fn cursors_read_from_their_position() {
+        let mut queue = RelayQueue::with_capacity(4);
+        for sender in 1..=3 {
+            queue.push(sender, "{}".into());
+        }
+        assert_eq!(queue.head(), 3);
+        assert_eq!(senders(&queue, 0), (vec![1, 2, 3], 0));
+        assert_eq!(senders(&queue, 2), (vec![3], 0));
+        assert_eq!(senders(&queue, 3), (vec![], 0));
+    }
+
+    #[test]
+    fn oldest_packets_are_overwritten() {
+        let mut queue = RelayQueue::with_capacity(4);
+        for sender in 1..=6 {
+            queue.push(sender, "{}".into());
+        }
+        assert_eq!(queue.head(), 6);
+        assert_eq!(senders(&queue, 0), (vec![3, 4, 5, 6], 2));
+        assert_eq!(senders(&queue, 4), (vec![5, 6], 0));
+    }
+}
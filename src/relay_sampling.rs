@@ -0,0 +1,202 @@
+//!
+//! Sampling of the relay traffic to learn what the clients actually send, as a guide for the typed
+//! relay messages and the authoritative protocol.
+//!
+//! Only the messages of the players that opted in (the `shareRelaySamples` privacy setting) are
+//! sampled, with a probability of RELAY_SAMPLE_RATE (0 to 1, disabled by default). The payloads are
+//! never stored: only the names of their fields and the kinds of their values are kept, merged in
+//! a schema per message type that the admin api serves on /admin/relay/schemas.
+//!
+
+use std::{collections::{BTreeMap, BTreeSet}, env, sync::{Mutex, MutexGuard, OnceLock, PoisonError}};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::isolation::{self, Subsystem};
+
+/// Bounds of the report, the clients must not be able to grow it forever.
+const MAX_TYPES: usize = 64;
+const MAX_FIELDS_PER_TYPE: usize = 128;
+const MAX_DEPTH: usize = 8;
+const MAX_TYPE_LEN: usize = 32;
+/// Messages grouped together when there are too many types or they have none.
+const OTHER_TYPE: &str = "(other)";
+
+static SAMPLE_RATE: OnceLock<f64> = OnceLock::new();
+static REPORT: Mutex<Report> = Mutex::new(Report::new());
+
+fn sample_rate() -> f64 {
+    *SAMPLE_RATE.get_or_init(|| {
+        env::var("RELAY_SAMPLE_RATE").ok()
+            .and_then(|x| x.parse::<f64>().ok())
+            .map(|x| x.clamp(0.0, 1.0))
+            .unwrap_or(0.0)
+    })
+}
+
+/// Called with every relay message of a player that opted in.
+pub fn sample(data: &str) {
+    let rate = sample_rate();
+    if rate <= 0.0 || rand::random::<f64>() >= rate {
+        return;
+    }
+    if isolation::isolate(Subsystem::RelaySampling, || report_lock().add(data)).is_none() {
+        // The report may be half updated, start over.
+        *report_lock() = Report::new();
+    }
+}
+
+/// The schemas inferred so far.
+pub fn report() -> Report {
+    report_lock().clone()
+}
+
+fn report_lock() -> MutexGuard<'static, Report> {
+    REPORT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    samples: u64,
+    invalid: u64,// Not a json object
+    types: BTreeMap<String, TypeSchema>,
+}
+
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct TypeSchema {
+    samples: u64,
+    fields: BTreeMap<String, FieldSchema>,// By path, e.g. "meeple.position" or "tiles[].x"
+    #[serde(skip_serializing_if = "is_zero")]
+    dropped_fields: u64,// Not recorded because of MAX_FIELDS_PER_TYPE
+}
+
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct FieldSchema {
+    seen: u64,// Samples with the field, it's optional if lower than the samples of the type
+    kinds: BTreeSet<&'static str>,
+}
+
+fn is_zero(x: &u64) -> bool {
+    *x == 0
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(x) if x.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Collects the kinds of every path of a value (once per sample, even inside arrays).
+fn walk(value: &Value, path: &str, depth: usize, out: &mut BTreeMap<String, BTreeSet<&'static str>>) {
+    if depth >= MAX_DEPTH {
+        return;
+    }
+    match value {
+        Value::Object(fields) => {
+            for (name, x) in fields.iter() {
+                let path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+                out.entry(path.clone()).or_default().insert(kind(x));
+                walk(x, &path, depth + 1, out);
+            }
+        },
+        Value::Array(items) => {
+            let path = format!("{}[]", path);
+            for x in items.iter() {
+                out.entry(path.clone()).or_default().insert(kind(x));
+                walk(x, &path, depth + 1, out);
+            }
+        },
+        _ => {},
+    }
+}
+
+impl Report {
+    const fn new() -> Self {
+        Report {
+            samples: 0,
+            invalid: 0,
+            types: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, data: &str) {
+        self.samples += 1;
+        let value = match serde_json::from_str::<Value>(data) {
+            Ok(x @ Value::Object(_)) => x,
+            _ => {
+                self.invalid += 1;
+                return;
+            },
+        };
+
+        let name = match value.get("type").and_then(|x| x.as_str()) {
+            Some(x) if x.len() <= MAX_TYPE_LEN && (self.types.contains_key(x) || self.types.len() < MAX_TYPES) => x,
+            _ => OTHER_TYPE,
+        };
+        let schema = self.types.entry(name.to_string()).or_default();
+        schema.samples += 1;
+
+        let mut paths = BTreeMap::new();
+        walk(&value, "", 0, &mut paths);
+        for (path, kinds) in paths {
+            if !schema.fields.contains_key(&path) && schema.fields.len() >= MAX_FIELDS_PER_TYPE {
+                schema.dropped_fields += 1;
+                continue;
+            }
+            let field = schema.fields.entry(path).or_default();
+            field.seen += 1;
+            field.kinds.extend(kinds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_of_every_type() {
+        let mut report = Report::new();
+        report.add(r#"{"type": "place_tile", "tile": "D", "x": 1, "y": 0, "rotation": 1}"#);
+        report.add(r#"{"type": "place_tile", "tile": "E", "x": -1, "y": 2, "rotation": 0, "meeple": {"position": "N"}}"#);
+        report.add(r#"{"type": "chat", "lines": [{"text": "hi"}, {"text": "gg", "bold": true}]}"#);
+        report.add("not json");
+
+        assert_eq!(report.samples, 4);
+        assert_eq!(report.invalid, 1);
+
+        let place = &report.types["place_tile"];
+        assert_eq!(place.samples, 2);
+        assert_eq!(place.fields["x"].seen, 2);
+        assert!(place.fields["x"].kinds.contains("integer"));
+        assert_eq!(place.fields["meeple.position"].seen, 1);
+
+        // Paths inside arrays are counted once per sample.
+        let chat = &report.types["chat"];
+        assert_eq!(chat.fields["lines[]"].seen, 1);
+        assert!(chat.fields["lines[]"].kinds.contains("object"));
+        assert_eq!(chat.fields["lines[].bold"].seen, 1);
+    }
+
+    #[test]
+    fn bounded_report() {
+        let mut report = Report::new();
+        for i in 0..(MAX_TYPES + 10) {
+            report.add(&format!(r#"{{"type": "t{}"}}"#, i));
+        }
+        report.add(r#"{"x": 1}"#);
+
+        assert_eq!(report.types.len(), MAX_TYPES + 1);
+        assert_eq!(report.types[OTHER_TYPE].samples, 11);
+    }
+}
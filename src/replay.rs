@@ -0,0 +1,97 @@
+//!
+//! Replays of the games played with the authoritative engine: the room records every validated move
+//! and every event of the game sent by the server, with the time since the start. When the game ends
+//! the replay is kept by the lobby (the last MAX_REPLAYS ones) and a client can ask for it with
+//! replay_get, the events are streamed in chunks of REPLAY_CHUNK_SIZE.
+//!
+
+use std::time::Instant;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::protocol::{IdType, PlayerObject, SerId};
+
+pub const MAX_REPLAYS: usize = 64;
+pub const REPLAY_CHUNK_SIZE: usize = 64;
+const MAX_REPLAY_EVENTS: usize = 2048;// A base game has about 200 of them
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayEvent {
+    pub at_ms: u64,// Since the start of the game
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<SerId>,// The player of a move, none for the events of the server
+    pub event: Value,
+}
+
+pub struct Replay {
+    pub room_id: IdType,
+    pub players: Vec<PlayerObject>,
+    pub duration_ms: u64,
+    pub events: Vec<ReplayEvent>,
+}
+
+impl Replay {
+    pub fn chunks(&self) -> impl Iterator<Item = &[ReplayEvent]> {
+        self.events.chunks(REPLAY_CHUNK_SIZE)
+    }
+}
+
+/// Events of the game in progress.
+pub struct ReplayLog {
+    started: Instant,
+    events: Vec<ReplayEvent>,
+}
+
+impl Default for ReplayLog {
+    fn default() -> Self {
+        ReplayLog { started: Instant::now(), events: Vec::new() }
+    }
+}
+
+impl ReplayLog {
+    pub fn record(&mut self, sender: Option<IdType>, event: Value) {
+        if self.events.len() >= MAX_REPLAY_EVENTS {
+            return;
+        }
+        self.events.push(ReplayEvent {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            sender: sender.map(SerId),
+            event,
+        });
+    }
+
+    pub fn finish(self, room_id: IdType, players: Vec<PlayerObject>) -> Replay {
+        Replay {
+            room_id,
+            players,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            events: self.events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn events_are_kept_in_order() {
+        let mut log = ReplayLog::default();
+        for i in 0..(MAX_REPLAY_EVENTS + 10) {
+            log.record(Some(1), json!({ "type": "place_tile", "x": i }));
+        }
+        log.record(None, json!({ "type": "turn_timeout" }));
+
+        let replay = log.finish(7, vec![]);
+        assert_eq!(replay.events.len(), MAX_REPLAY_EVENTS);
+        assert_eq!(replay.events[3].event["x"], 3);
+        assert!(replay.events.windows(2).all(|x| x[0].at_ms <= x[1].at_ms));
+
+        let chunks: Vec<usize> = replay.chunks().map(|x| x.len()).collect();
+        assert_eq!(chunks.len(), MAX_REPLAY_EVENTS / REPLAY_CHUNK_SIZE);
+        assert!(chunks.iter().all(|x| *x == REPLAY_CHUNK_SIZE));
+    }
+}
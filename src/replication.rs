@@ -0,0 +1,259 @@
+//!
+//! Warm standby of the room registry, without a full cluster. When REPLICATION_TOKEN is set the
+//! lobby keeps a log of the mutations of its registry (a room created, joined or left, started,
+//! closed) and serves it on /replication/log to the requests carrying the token as
+//! `Authorization: Bearer <token>`. An instance started with REPLICATION_PRIMARY (the base url of
+//! the primary) and the same token is its standby: it polls the log every second and keeps a
+//! mirror of the rooms.
+//!
+//! Once the primary hasn't answered for FAILOVER_AFTER the standby takes over: the players that
+//! reconnect to it (the clients or the load balancer switch over) join their room with the same id
+//! or code, the first one recreates it with its options and settings and becomes its host. Only
+//! the registry is replicated, not the sessions nor the games: a game in progress comes back as a
+//! room waiting in the matchmaking.
+//!
+
+use std::{collections::{HashMap, VecDeque}, env, time::Duration};
+
+use actix::prelude::*;
+use actix_web::{client::Client, HttpRequest, HttpResponse, web};
+use serde::{Deserialize, Serialize};
+
+use crate::admin;
+use crate::lobby_actor::{self, LobbyActor};
+use crate::protocol::{IdType, RoomOptions, RoomSettings, SerId};
+use crate::room_actor::RoomState;
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Without an answer of the primary for this long, the standby takes over.
+pub const FAILOVER_AFTER: Duration = Duration::from_secs(5);
+/// Mutations kept by the primary, a standby further behind starts again from a snapshot.
+const MAX_LOG_LEN: usize = 4096;
+
+#[derive(Clone)]
+pub struct ReplicationConfig {
+    pub token: Option<String>,
+    pub primary: Option<String>,// Set on the standby
+}
+
+impl ReplicationConfig {
+    pub fn from_env() -> Self {
+        ReplicationConfig {
+            token: env::var("REPLICATION_TOKEN").ok().filter(|x| !x.is_empty()),
+            primary: env::var("REPLICATION_PRIMARY").ok()
+                .map(|x| x.trim().trim_end_matches('/').to_string())
+                .filter(|x| !x.is_empty()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Mutation {
+    #[serde(rename_all = "camelCase")]
+    Created {
+        room_id: SerId,
+        code: String,
+        public: bool,
+        options: RoomOptions,
+    },
+    // Sent on every join, leave and state change of the room.
+    #[serde(rename_all = "camelCase")]
+    Updated {
+        room_id: SerId,
+        state: RoomState,
+        player_count: usize,
+        host: String,
+        settings: RoomSettings,
+    },
+    #[serde(rename_all = "camelCase")]
+    Closed {
+        room_id: SerId,
+    },
+}
+
+/// The mutations after the sequence number asked by the standby, or the whole registry if it's
+/// too far behind.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Batch {
+    pub epoch: u64,
+    pub seq: u64,// Of the last mutation
+    pub snapshot: bool,
+    pub mutations: Vec<Mutation>,
+}
+
+/// The log of the primary. The sequence numbers start again when it restarts, its epoch tells the
+/// standby that it has to start from a snapshot.
+pub struct Log {
+    epoch: u64,
+    seq: u64,
+    entries: VecDeque<(u64, Mutation)>,
+}
+
+impl Log {
+    pub fn new(epoch: u64) -> Self {
+        Log { epoch, seq: 0, entries: VecDeque::new() }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn push(&mut self, mutation: Mutation) {
+        self.seq += 1;
+        self.entries.push_back((self.seq, mutation));
+        if self.entries.len() > MAX_LOG_LEN {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The mutations after `after`, none if some of them were already dropped or if the standby
+    /// followed another epoch.
+    pub fn since(&self, epoch: u64, after: u64) -> Option<Vec<Mutation>> {
+        let first = self.entries.front().map(|x| x.0).unwrap_or(self.seq + 1);
+        if epoch != self.epoch || after + 1 < first || after > self.seq {
+            return None;
+        }
+        Some(self.entries.iter().skip_while(|x| x.0 <= after).map(|x| x.1.clone()).collect())
+    }
+}
+
+pub struct ReplicatedRoom {
+    pub code: String,
+    pub public: bool,
+    pub options: RoomOptions,
+    pub state: RoomState,
+    pub player_count: usize,
+    pub settings: RoomSettings,
+}
+
+/// The registry of the primary as seen by the standby.
+#[derive(Default)]
+pub struct Mirror {
+    pub epoch: u64,
+    pub seq: u64,
+    pub rooms: HashMap<IdType, ReplicatedRoom>,
+}
+
+impl Mirror {
+    pub fn apply(&mut self, batch: Batch) {
+        if batch.snapshot {
+            self.rooms.clear();
+        }
+        for mutation in batch.mutations {
+            match mutation {
+                Mutation::Created { room_id, code, public, options } => {
+                    self.rooms.insert(room_id.into(), ReplicatedRoom {
+                        code,
+                        public,
+                        options,
+                        state: RoomState::Matchmaking,
+                        player_count: 1,
+                        settings: RoomSettings::default(),
+                    });
+                },
+                Mutation::Updated { room_id, state, player_count, settings, .. } => {
+                    if let Some(room) = self.rooms.get_mut(&room_id.into()) {
+                        room.state = state;
+                        room.player_count = player_count;
+                        room.settings = settings;
+                    }
+                },
+                Mutation::Closed { room_id } => {
+                    self.rooms.remove(&room_id.into());
+                },
+            }
+        }
+        self.epoch = batch.epoch;
+        self.seq = batch.seq;
+    }
+
+    pub fn find_code(&self, code: &str) -> Option<IdType> {
+        self.rooms.iter().find(|x| x.1.code == code).map(|x| *x.0)
+    }
+}
+
+/// Downloads the mutations of the primary after `after`.
+pub async fn fetch(primary: String, token: String, epoch: u64, after: u64) -> Result<Batch, String> {
+    let mut res = Client::default()
+        .get(format!("{}/replication/log?epoch={}&after={}", primary, epoch, after))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    res.json::<Batch>().limit(16 * 1024 * 1024).await.map_err(|e| e.to_string())
+}
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    #[serde(default)]
+    epoch: u64,
+    #[serde(default)]
+    after: u64,
+}
+
+pub async fn log(
+    req: HttpRequest,
+    query: web::Query<LogQuery>,
+    config: web::Data<ReplicationConfig>,
+    lobby: web::Data<Addr<LobbyActor>>,
+) -> HttpResponse {
+    if let Err(res) = admin::check_bearer(&req, config.token.as_deref()) {
+        return res;
+    }
+    match lobby.send(lobby_actor::GetReplicationLog { epoch: query.epoch, after: query.after }).await {
+        Ok(Some(batch)) => HttpResponse::Ok().json(batch),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created(id: IdType) -> Mutation {
+        Mutation::Created { room_id: id.into(), code: format!("CODE{:02}", id), public: false, options: RoomOptions::default() }
+    }
+
+    #[test]
+    fn log_is_applied_to_the_mirror() {
+        let mut log = Log::new(7);
+        log.push(created(1));
+        log.push(created(2));
+        log.push(Mutation::Updated {
+            room_id: 1.into(),
+            state: RoomState::Playing,
+            player_count: 3,
+            host: "a".to_string(),
+            settings: RoomSettings::default(),
+        });
+        log.push(Mutation::Closed { room_id: 2.into() });
+
+        let mut mirror = Mirror::default();
+        assert!(log.since(mirror.epoch, mirror.seq).is_none());
+        mirror.apply(Batch { epoch: 7, seq: 2, snapshot: true, mutations: vec![created(1), created(2)] });
+        mirror.apply(Batch { epoch: 7, seq: log.seq(), snapshot: false, mutations: log.since(7, mirror.seq).unwrap() });
+        assert_eq!(mirror.seq, 4);
+        assert_eq!(mirror.rooms.len(), 1);
+        assert_eq!(mirror.rooms[&1].state, RoomState::Playing);
+        assert_eq!(mirror.find_code("CODE01"), Some(1));
+        assert_eq!(log.since(7, 4).map(|x| x.len()), Some(0));
+
+        // A standby too far behind, or following a previous run of the primary, needs a snapshot.
+        assert!(log.since(6, 4).is_none());
+        for _ in 0..=MAX_LOG_LEN {
+            log.push(created(3));
+        }
+        assert!(log.since(7, 4).is_none());
+    }
+}
@@ -0,0 +1,2478 @@
+//!
+//! Every room is managed by its own actor: it keeps the players of the room, broadcasts the room
+//! events and relays the game traffic.
+//!
+//! The room actors are spread over a pool of arbiters (see the lobby) so different rooms are
+//! processed concurrently on different threads, the lobby only receives a summary of each room
+//! (player count and state) to do the matchmaking.
+//!
+
+use std::{borrow::Cow, collections::{HashMap, HashSet}, path::PathBuf, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use actix::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug_span, info, info_span, warn};
+
+use crate::achievements::{self, AchievementEvent};
+use crate::bot_actor::{self, BotActor};
+use crate::client_ws::{self, ClientWs};
+use crate::clock;
+use crate::features::FeatureFlags;
+use crate::game::{scoring::ScoredFeature, snapshot, BoardSnapshot, GameMove, GameState};
+use crate::lobby_actor::{LobbyActor, ReachAchievements, RecordMatch, RecordSeries, RoomClosed, RoomUpdate};
+use crate::isolation::{self, Subsystem};
+use crate::metrics;
+use crate::migration::{self, MigratedGame, RoomData, Seat, Seats};
+use crate::moderation;
+use crate::player_stats::GameRecords;
+use crate::prediction::{self, PREDICTION_INTERVAL};
+use crate::start_checks::{self, StartingPlayer, StartingRoom};
+use crate::series::{Series, SERIES_LENGTHS};
+use crate::spectator_delay::{Delayed, SpectatorDelay, FLUSH_INTERVAL as SPECTATOR_FLUSH_INTERVAL};
+use crate::tournament;
+use crate::turn_times::{TurnClock, MOVE_TIME_LIMIT_SECS};
+use crate::votes::{self, Vote, VOTE_COOLDOWN, VOTE_TIMEOUT};
+use crate::{privacy, rate_limit::{self, TokenBucket}, relay_sampling};
+use crate::relay_filter::RelayFilter;
+use crate::relay_queue::RelayQueue;
+use crate::replay::ReplayLog;
+use crate::room_trace::{self, RoomTrace, MAX_TRACE_SECS};
+use crate::protocol::{BotDifficulty, CursorPosition, EventClass, IdType, LoginData, normalize_language, MatchRecord, NetworkQuality, OutEvent, OutGameEvent, PlayerCosmetics, PlayerNetworkQuality, PlayerObject, PlayerScore, PrivacySettings, RateLimitScope, RoomConnectionType, RoomOptions, RoomSettings, ScoringVariant, SerId, StartFailure, SUPPORTED_EXPANSIONS, Team, TeamScore, VoteProposal, VoteResult, MAX_TEAMS};
+
+pub const MAX_PLAYERS_PER_ROOM: usize = 8;
+pub const MIN_PLAYERS_PER_ROOM: usize = 3;
+/// Default auto-start countdown, once the room reaches the min players.
+pub const ROOM_COUNTDOWN_ON_MIN_PLAYERS: u32 = 30;
+const MAX_AUTO_START_SECS: u32 = 300;
+/// How often the players in matchmaking receive the connection quality of the others.
+const NETWORK_QUALITY_INTERVAL: Duration = Duration::from_secs(10);
+/// Countdown of a room_start asking for it, any player can cancel it.
+const START_COUNTDOWN: u32 = 5;
+/// Cosmetic changes of a player are broadcast at most once per window, only the latest is sent.
+const COSMETICS_CHANGE_WINDOW: Duration = Duration::from_secs(2);
+/// Max cosmetic changes of a player during a single game.
+const MAX_COSMETICS_CHANGES_IN_GAME: u32 = 3;
+/// Between the last player back in a room moved here and the start of its game.
+const MIGRATED_START_DELAY: Duration = Duration::from_secs(2);
+
+// The payloads are shared by every recipient of a broadcast, they're only serialized by the clients.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Event(pub Arc<OutEvent>);
+
+impl From<OutEvent> for Event {
+    fn from(event: OutEvent) -> Self {
+        Event(Arc::new(event))
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct GameEvent(pub Arc<OutGameEvent>);
+
+impl From<OutGameEvent> for GameEvent {
+    fn from(event: OutGameEvent) -> Self {
+        GameEvent(Arc::new(event))
+    }
+}
+
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct SendRelayMexRaw {
+    pub data: Arc<str>,
+}
+
+/// A player that is entering a room.
+#[derive(Clone)]
+pub struct Member {
+    pub id: IdType,
+    pub addr: Addr<ClientWs>,
+    pub details: LoginData,
+    pub unsubscribed: HashSet<EventClass>,
+}
+
+impl Member {
+    pub fn player_object(&self, is_host: bool) -> PlayerObject {
+        PlayerObject {
+            id: self.id.into(),
+            username: self.details.username.clone(),
+            cosmetics: self.details.cosmetics.clone(),
+            is_host,
+            team: None,
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(JoinRoomResult)]
+pub struct Join {
+    pub member: Member,
+    pub password: Option<String>,
+    pub invited: bool,// Invited by a friend, no password needed
+    pub ticket: Option<String>,// Seat of a room moved here, see migration.rs
+}
+
+pub enum JoinRoomResult {
+    Success {
+        room: Addr<RoomActor>,
+        players: Vec<PlayerObject>,
+        settings: RoomSettings,
+        pinned: Option<String>,
+        countdown: Option<u32>,// Seconds left before the auto-start
+    },
+    RoomNotFound,
+    RoomIsFull,
+    AlreadyPlaying,
+    WrongPassword,
+    RoomLocked,
+    Banned,
+    InvalidTicket,// Of a room moved here, see migration.rs
+    Redirect {// The room is on another instance of the cluster
+        url: String,
+        room_id: IdType,
+    },
+}
+simple_result!(JoinRoomResult);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub id: IdType,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EditCosmetics {
+    pub id: IdType,
+    pub obj: PlayerCosmetics,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EditSubscriptions {
+    pub id: IdType,
+    pub events: Vec<EventClass>,
+    pub subscribe: bool,
+}
+
+/// Sent by the player's connection after every heartbeat, see network.rs.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateNetworkQuality {
+    pub id: IdType,
+    pub quality: Option<NetworkQuality>,
+}
+
+/// Answered with every precondition that isn't met (see start_checks) if the room can't start.
+#[derive(Message)]
+#[rtype(result = "Result<(), Vec<StartFailure>>")]
+pub struct StartRoom {
+    pub by: Option<IdType>,// The player asking, none for the starts of the server
+    pub conn_type: RoomConnectionType,
+    pub game: Option<GameState>,// Saved game to continue
+    pub countdown: bool,// Starts after START_COUNTDOWN instead of right away
+}
+
+/// Sent by a player to stop the start countdown (the one asked by room_start or the auto-start).
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CancelStart {
+    pub id: IdType,
+}
+
+/// Freezes the room before it moves to another instance (see migration.rs), none if it's already
+/// moving.
+#[derive(Message)]
+#[rtype(result = "Option<RoomData>")]
+pub struct Migrate {
+    pub public: bool,
+}
+
+/// End of the move: the players are sent to `url` and the room closes, it's unfrozen if the target
+/// refused it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MigrationEnd {
+    pub url: Option<String>,
+}
+
+/// Sent to a room moved here once its first player recreated it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RestoreMigrated {
+    pub settings: RoomSettings,
+    pub pinned: Option<String>,
+    pub seats: Seats,
+}
+
+/// Asks for the encoded snapshot of the running game (see game::snapshot).
+#[derive(Message)]
+#[rtype(result = "Option<Vec<u8>>")]
+pub struct SaveGame;
+
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct SendRelayMex {
+    pub sender_id: IdType,
+    pub data: String,
+}
+
+/// Presence updates are best effort: they are dropped when a client's mailbox is full.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendCursor {
+    pub sender_id: IdType,
+    pub position: CursorPosition,
+}
+
+#[derive(Message, Clone)]
+#[rtype(result = "Option<GameEndAck>")]
+pub struct GameEndRequest {
+    pub id: IdType,
+}
+
+pub struct GameEndAck {
+    pub players: Vec<PlayerObject>,
+    pub spectators: Vec<PlayerObject>,
+}
+simple_result!(GameEndAck);
+
+/// Sent by a player in game to put a proposal to the vote of the players in game, see votes.rs.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct StartVote {
+    pub id: IdType,
+    pub proposal: VoteProposal,
+}
+
+/// Sent by a player in game for the running vote.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct CastVote {
+    pub id: IdType,
+    pub yes: bool,
+}
+
+/// Sent by the lobby to invite the players to move to another room (or to drop the invite).
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ProposeMerge {
+    pub target: Option<IdType>,
+    pub player_count: usize,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<IdType>")]
+pub struct GetMergeProposal;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateFeatures(pub FeatureFlags);
+
+/// Sent by the host to add a bot to the room, see bot_actor.
+#[derive(Message)]
+#[rtype(result = "Result<PlayerObject, &'static str>")]
+pub struct AddBot {
+    pub id: IdType,
+    pub difficulty: BotDifficulty,
+}
+
+/// The moves of a bot for its turn, relayed in order.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BotMoves {
+    pub id: IdType,
+    pub moves: Vec<String>,
+}
+
+/// Sent by the lobby when an incident starts or ends, a read only room can't start.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetReadOnly(pub bool);
+
+/// Sent by a player to open or close the room to new players, only the host can do it.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct SetLocked {
+    pub id: IdType,
+    pub locked: bool,
+}
+
+/// Sent by the host to change the rules of the room before the game starts.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct UpdateRoomSettings {
+    pub id: IdType,
+    pub settings: RoomSettings,
+}
+
+/// Sent by a player to move to another team, in the rooms with teams.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct ChangeTeam {
+    pub id: IdType,
+    pub team: u8,
+}
+
+/// Sent by the host to pin a message shown to every player of the room.
+#[derive(Message)]
+#[rtype(result = "Result<(), &'static str>")]
+pub struct PinMessage {
+    pub id: IdType,
+    pub message: Option<String>,
+}
+
+/// Sent by the host to remove a player from the room.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct KickPlayer {
+    pub id: IdType,
+    pub target: IdType,
+}
+
+/// Sent by the host to remove a player and refuse them from now on.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct BanPlayer {
+    pub id: IdType,
+    pub target: IdType,
+}
+
+/// Sent by the host to hand the room to another player.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct TransferHost {
+    pub id: IdType,
+    pub target: IdType,
+}
+
+/// Sent by the lobby when an admin closes the room, its players go back to the matchmaking.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Close;
+
+/// Sent by the lobby when an admin traces the room for `secs` (see room_trace), 0 ends the trace. A
+/// new trace replaces the running one. The file of the trace, none if it ended or couldn't be created.
+#[derive(Message)]
+#[rtype(result = "Option<PathBuf>")]
+pub struct Trace {
+    pub secs: u64,
+}
+
+/// Asked by a player that wants to see the room code and the invite id.
+#[derive(Message)]
+#[rtype(RoomCode)]
+pub struct GetRoomCode;
+
+pub struct RoomCode {
+    pub id: IdType,
+    pub code: String,
+}
+simple_result!(RoomCode);
+
+/// A player that wants to watch the game, spectators receive the relay traffic but can't send any.
+#[derive(Message)]
+#[rtype(SpectateResult)]
+pub struct Spectate {
+    pub member: Member,
+    pub password: Option<String>,
+}
+
+pub enum SpectateResult {
+    Success {
+        room: Addr<RoomActor>,
+        players: Vec<PlayerObject>,
+        spectators: Vec<PlayerObject>,
+        board: Option<BoardSnapshot>,
+    },
+    RoomNotFound,
+    NotPlaying,
+    WrongPassword,
+    RoomLocked,
+    Banned,
+}
+simple_result!(SpectateResult);
+
+/// Sent by a spectator that wants to become a player once the game is over.
+#[derive(Message)]
+#[rtype(JoinRoomResult)]
+pub struct SpectatorJoin {
+    pub id: IdType,
+}
+
+/// Sent by a player once it has acknowledged event_room_start, the relay packets wait until then.
+/// Returns the packets that were lost in the meantime.
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct RelayReady {
+    pub id: IdType,
+}
+
+/// Sent by the client that resumed the session of a member of the room.
+#[derive(Message)]
+#[rtype(result = "Option<RejoinResult>")]
+pub struct Rejoin {
+    pub id: IdType,
+    pub addr: Addr<ClientWs>,
+}
+
+pub struct RejoinResult {
+    pub players: Vec<PlayerObject>,
+    pub in_game: bool,
+    pub board: Option<BoardSnapshot>,
+    pub relay_lost: u64,// Relay packets missed while disconnected that the room no longer has
+}
+simple_result!(RejoinResult);
+
+
+struct RoomBot {
+    addr: Addr<BotActor>,
+    obj: PlayerObject,
+}
+
+struct RoomMember {
+    addr: Addr<ClientWs>,
+    obj: PlayerObject,
+    in_game: bool,
+    unsubscribed: HashSet<EventClass>,
+    privacy: PrivacySettings,
+    cosmetics_sent_at: Option<Instant>,
+    cosmetics_pending: bool,// A change is waiting for the end of the window to be broadcast
+    cosmetics_changes_in_game: u32,
+    network: Option<NetworkQuality>,
+    relay_cursor: u64,// Next packet of the relay queue to send
+    relay_ready: bool,// Can take the relay packets: the game start is acknowledged
+}
+
+impl RoomMember {
+    fn from_member(member: Member, is_host: bool) -> Self {
+        RoomMember {
+            obj: member.player_object(is_host),
+            addr: member.addr,
+            in_game: false,
+            unsubscribed: member.unsubscribed,
+            privacy: member.details.privacy,
+            cosmetics_sent_at: None,
+            cosmetics_pending: false,
+            cosmetics_changes_in_game: 0,
+            network: None,
+            relay_cursor: 0,
+            relay_ready: false,
+        }
+    }
+
+    /// Sends the relay packets that the member hasn't received yet (but its own ones), if it can take
+    /// them. Returns the packets that are lost.
+    fn catch_up(&mut self, queue: &RelayQueue) -> u64 {
+        if !self.relay_ready || !self.addr.connected() {
+            return 0;
+        }
+        let (packets, lost) = queue.read(self.relay_cursor);
+        for packet in packets.filter(|x| x.sender != self.obj.id.0) {
+            self.addr.do_send(SendRelayMexRaw { data: packet.data.clone() });
+        }
+        self.relay_cursor = queue.head();
+        lost
+    }
+
+    fn send_event(&self, event: &Arc<OutEvent>, room: &RoomOptions) {
+        let event = match privacy::redact_event(event, &self.privacy, room) {
+            Cow::Borrowed(_) => event.clone(),
+            Cow::Owned(x) => Arc::new(x),// Only in streamer mode
+        };
+        self.addr.do_send(Event(event));
+    }
+
+    /// Checks if the player is subscribed to the event's class (if it has any).
+    fn wants(&self, event: &OutEvent) -> bool {
+        match event.class() {
+            Some(class) => !self.unsubscribed.contains(&class),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomState {
+    Matchmaking,
+    Playing
+}
+
+pub struct RoomActor {
+    id: IdType,
+    code: String,// Short code of the room, see room_code.rs
+    lobby: Addr<LobbyActor>,
+    state: RoomState,
+    players: HashMap<IdType, RoomMember>,
+    bots: HashMap<IdType, RoomBot>,// They leave with the last player
+    spectators: HashMap<IdType, RoomMember>,
+    in_game_count: u32,
+    merge_proposal: Option<IdType>, // Room that the players have been invited to move to.
+    game: Option<GameState>,        // Only tracked with the authoritative engine enabled.
+    relay_filter: Option<RelayFilter>,// Without the engine, with the relayPrefilter feature
+    features: FeatureFlags,
+    read_only: bool,// During an incident, see lobby_actor::AdminSetIncident
+    options: RoomOptions,
+    locked: bool,// No one can join a locked room
+    settings: RoomSettings,
+    banned: HashSet<IdType>,
+    pinned: Option<String>,// Shown to every player that joins
+    game_started: Option<(Instant, Vec<PlayerObject>)>,// When the running game started and its players
+    game_records: GameRecords,// Of the running game, for the statistics of its players
+    loaded_game: bool,// The running game comes from a snapshot of the host, it doesn't count for the players
+    turn_clock: Option<TurnClock>,// The think times of a tournament match, see turn_times.rs
+    series: Option<Series>,// Of the series setting, kept after its end until the next game
+    spectator_delay: Option<SpectatorDelay>,// What the spectators receive later, see spectator_delay.rs
+    predicted_at: Option<usize>,// Tiles left at the last win_probability, see prediction.rs
+    vote: Option<(Vote, SpawnHandle)>,// The running vote, with the handle of its timeout
+    vote_cooldowns: HashMap<IdType, Instant>,// Until when the players whose vote failed can't start one
+
+    network_sent: Vec<PlayerNetworkQuality>,// Last connection qualities broadcast
+    start_countdown: Option<StartCountdown>,
+    turn_timer_handle: Option<SpawnHandle>,
+    turn_deadline: Option<u64>,// Of the turn timer, in server time (see clock.rs)
+    relay_bucket: Option<TokenBucket>,// Bytes relayed to the peers
+    relay_limited: HashSet<IdType>,// Senders already warned since the room went over its limit
+    relay: RelayQueue,
+    replay: Option<ReplayLog>,// Of the game in progress, with the authoritative engine
+    migrating: Option<HashMap<IdType, String>>,// Tickets of the players while the room moves to another instance
+    seats: Option<Seats>,// Players expected back after the room moved here
+    trace: Option<(Arc<RoomTrace>, SpawnHandle)>,// With the handle of its end
+}
+
+struct StartCountdown {
+    handle: SpawnHandle,
+    ends_at: Instant,
+    requested: bool,// Asked with room_start, not the auto-start
+}
+
+/// The players of each team, None in the rooms without teams.
+fn team_list(players: &[PlayerObject], teams: Option<u8>) -> Option<Vec<Team>> {
+    let teams = (0..teams?)
+        .map(|team| Team { team, players: players.iter().filter(|x| x.team == Some(team)).map(|x| x.id).collect() })
+        .collect();
+    Some(teams)
+}
+
+impl Actor for RoomActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(NETWORK_QUALITY_INTERVAL, |act, _| act.broadcast_network_quality());
+        if self.options.win_probability {
+            ctx.run_interval(PREDICTION_INTERVAL, |act, _| act.broadcast_prediction());
+        }
+        if self.spectator_delay.is_some() {
+            ctx.run_interval(SPECTATOR_FLUSH_INTERVAL, |act, _| act.flush_spectator_delay());
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Some((trace, _)) = self.trace.take() {
+            trace.note("Room closed");
+            trace.stop();
+        }
+    }
+}
+
+impl RoomActor {
+    pub fn new(id: IdType, code: String, lobby: Addr<LobbyActor>, host: Member, features: FeatureFlags, options: RoomOptions) -> Self {
+        let spectator_delay = options.spectator_delay_secs.filter(|x| *x > 0).map(SpectatorDelay::new);
+        let mut players = HashMap::new();
+        players.insert(host.id, RoomMember::from_member(host, true));
+        let settings = if options.tournament { tournament::match_settings() } else { RoomSettings::default() };
+
+        RoomActor {
+            id,
+            code,
+            lobby,
+            state: RoomState::Matchmaking,
+            players,
+            bots: HashMap::new(),
+            spectators: HashMap::new(),
+            in_game_count: 0,
+            merge_proposal: None,
+            game: None,
+            relay_filter: None,
+            features,
+            read_only: false,
+            options,
+            locked: false,
+            settings,
+            banned: HashSet::new(),
+            pinned: None,
+            game_started: None,
+            game_records: GameRecords::default(),
+            loaded_game: false,
+            turn_clock: None,
+            series: None,
+            spectator_delay,
+            predicted_at: None,
+            vote: None,
+            vote_cooldowns: HashMap::new(),
+            network_sent: Vec::new(),
+            start_countdown: None,
+            turn_timer_handle: None,
+            turn_deadline: None,
+            relay_bucket: rate_limit::room_bucket(),
+            relay_limited: HashSet::new(),
+            relay: RelayQueue::default(),
+            replay: None,
+            migrating: None,
+            seats: None,
+            trace: None,
+        }
+    }
+
+    fn cancel_start_countdown(&mut self, ctx: &mut Context<Self>) -> bool {
+        if let Some(countdown) = self.start_countdown.take() {
+            ctx.cancel_future(countdown.handle);
+            return true;
+        }
+        false
+    }
+
+    fn start_countdown(&mut self, ctx: &mut Context<Self>, msg: StartRoom, seconds: u32, requested: bool, except: Option<IdType>) {
+        self.cancel_start_countdown(ctx);
+
+        let duration = Duration::from_secs(seconds as u64);
+        self.start_countdown = Some(StartCountdown {
+            handle: ctx.notify_later(msg, duration),
+            ends_at: Instant::now() + duration,
+            requested,
+        });
+        self.broadcast_event(OutEvent::EventRoomStartCountdown { seconds: Some(seconds), canceled_by: None }, except);
+    }
+
+    /// Starts the auto-start countdown when the room reaches the players of its settings and stops it
+    /// when it doesn't anymore, `restart` restarts a running countdown (e.g. its duration changed).
+    /// A countdown asked with room_start is left alone, the room starts if it still can.
+    /// The player `except` isn't sent the countdown event (a joining player reads it in the response).
+    fn update_start_countdown(&mut self, ctx: &mut Context<Self>, restart: bool, except: Option<IdType>) {
+        if self.start_countdown.as_ref().map(|x| x.requested).unwrap_or(false) {
+            return;
+        }
+        let auto_start = match self.settings.auto_start {
+            Some(x) if self.state == RoomState::Matchmaking && self.seats() >= x.players => x,
+            _ => {
+                if self.cancel_start_countdown(ctx) {
+                    info!("Auto-start countdown canceled");
+                    self.broadcast_event(OutEvent::EventRoomStartCountdown { seconds: None, canceled_by: None }, except);
+                }
+                return;
+            },
+        };
+        if self.start_countdown.is_some() && !restart {
+            return;
+        }
+
+        info!(players = self.players.len(), seconds = auto_start.after_secs, "Auto-start countdown started");
+        let start = StartRoom {
+            by: None,
+            conn_type: RoomConnectionType::ServerBroadcast,
+            game: None,
+            countdown: false,
+        };
+        self.start_countdown(ctx, start, auto_start.after_secs, false, except);
+    }
+
+    /// Starts the timer of a new turn, only with the authoritative engine as the server must see the
+    /// moves to know when a turn ends.
+    fn restart_turn_timer(&mut self, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.turn_timer_handle.take() {
+            ctx.cancel_future(handle);
+        }
+        self.turn_deadline = None;
+        let secs = match self.settings.turn_timer_secs {
+            Some(x) if self.game.is_some() => x,
+            _ => return,
+        };
+
+        let handle = ctx.run_later(Duration::from_secs(secs as u64), |act, ctx| {
+            act.turn_timer_handle = None;
+            if let Some(clock) = &mut act.turn_clock {
+                clock.skip();
+            }
+            let game = match &mut act.game {
+                Some(x) => x,
+                None => return,
+            };
+            info!(room = act.id, "Turn skipped after the turn timer");
+            game.skip_turn();
+
+            let event = Arc::new(OutGameEvent::TurnTimeout { server_time: Some(clock::now_ms()) });
+            act.record_replay(None, &*event);
+            for player in act.players.values().filter(|x| x.in_game) {
+                player.addr.do_send(GameEvent(event.clone()));
+            }
+            act.send_spectators(event);
+            // The tile drawn has the deadline of the next turn.
+            act.restart_turn_timer(ctx);
+            act.broadcast_tile_drawn();
+        });
+        self.turn_timer_handle = Some(handle);
+        self.turn_deadline = Some(clock::deadline_ms(Duration::from_secs(secs as u64)));
+    }
+
+    /// The tile drawn by the server for the current turn.
+    fn tile_drawn_event(&self) -> Option<Arc<OutGameEvent>> {
+        let game = self.game.as_ref()?;
+        let (player, tile) = game.drawn()?;
+        Some(Arc::new(OutGameEvent::TileDrawn {
+            player: player.into(),
+            tile,
+            tiles_left: game.tiles_left(),
+            server_time: Some(clock::now_ms()),
+            deadline: self.turn_deadline,
+        }))
+    }
+
+    /// Sends an event of the game to the players in game (the ones that acknowledged the start get
+    /// the current state afterwards) and to the spectators.
+    fn broadcast_game_event(&mut self, event: Arc<OutGameEvent>) {
+        self.record_replay(None, &*event);
+        for player in self.players.values().filter(|x| x.in_game && x.relay_ready) {
+            player.addr.do_send(GameEvent(event.clone()));
+        }
+        self.send_spectators(event);
+    }
+
+    /// Sends an event of the game to the spectators, after the delay of the room if it has one.
+    fn send_spectators(&mut self, event: Arc<OutGameEvent>) {
+        match &mut self.spectator_delay {
+            Some(delay) => delay.push(Delayed::Event(event), Instant::now()),
+            None => {
+                for spectator in self.spectators.values() {
+                    spectator.addr.do_send(GameEvent(event.clone()));
+                }
+            },
+        }
+    }
+
+    /// Sends to the spectators what's been delayed long enough.
+    fn flush_spectator_delay(&mut self) {
+        let due = match &mut self.spectator_delay {
+            Some(delay) => delay.due(Instant::now()),
+            None => return,
+        };
+        for item in due.iter() {
+            for spectator in self.spectators.values() {
+                match item {
+                    Delayed::Relay(data) => spectator.addr.do_send(SendRelayMexRaw { data: data.clone() }),
+                    Delayed::Event(event) => spectator.addr.do_send(GameEvent(event.clone())),
+                    Delayed::Board(_) => {},
+                }
+            }
+        }
+    }
+
+    fn broadcast_tile_drawn(&mut self) {
+        self.start_turn_clock();
+        if let Some(event) = self.tile_drawn_event() {
+            self.broadcast_game_event(event);
+        }
+        self.play_bot_turn();
+    }
+
+    /// Sends the score of the series to a player that just ended its game.
+    fn send_series(&self, id: IdType) {
+        let (series, player) = match (&self.series, self.players.get(&id)) {
+            (Some(series), Some(player)) => (series, player),
+            _ => return,
+        };
+        let event = if series.is_over() {
+            OutEvent::EventSeriesEnded { series: series.view() }
+        } else {
+            OutEvent::EventSeriesUpdate { series: series.view() }
+        };
+        player.send_event(&Arc::new(event), &self.options);
+    }
+
+    /// Starts timing the turn of the tile drawn, in a tournament match.
+    fn start_turn_clock(&mut self) {
+        if let (Some(clock), Some((player, _))) = (&mut self.turn_clock, self.game.as_ref().and_then(|x| x.drawn())) {
+            clock.start(player, Instant::now());
+        }
+    }
+
+    /// The think times of the players of a tournament match.
+    fn time_report(&self) -> Option<Arc<OutGameEvent>> {
+        let clock = self.turn_clock.as_ref()?;
+        let players: Vec<IdType> = self.game_started.as_ref()?.1.iter().map(|x| x.id.0).collect();
+        Some(Arc::new(OutGameEvent::TimeReport { limit_secs: clock.limit_secs(), players: clock.report(&players) }))
+    }
+
+    /// Asks the bot to play if the tile drawn is its.
+    fn play_bot_turn(&self) {
+        let game = match &self.game {
+            Some(x) => x,
+            None => return,
+        };
+        if let Some(bot) = game.drawn().and_then(|(player, _)| self.bots.get(&player)) {
+            bot.addr.do_send(bot_actor::PlayTurn { game: game.clone() });
+        }
+    }
+
+    /// Sends the tile of the current turn to a member that just started following the game.
+    fn send_tile_drawn(&self, addr: &Addr<ClientWs>) {
+        if let Some(event) = self.tile_drawn_event() {
+            addr.do_send(GameEvent(event));
+        }
+    }
+
+    /// The scores computed by the server, with the features just scored. Every player in game is
+    /// listed, even without points.
+    fn score_event(&self, features: Vec<ScoredFeature>, is_final: bool) -> Option<Arc<OutGameEvent>> {
+        let game = self.game.as_ref()?;
+        let mut players: Vec<IdType> = self.players.values().filter(|x| x.in_game).map(|x| x.obj.id.0)
+            .chain(self.bots.keys().copied())
+            .collect();
+        players.extend(game.scores().keys().filter(|x| !self.players.contains_key(x) && !self.bots.contains_key(x)));
+        players.sort_unstable();
+        let scores = players.into_iter()
+            .map(|x| PlayerScore { player: x.into(), points: game.scores().get(&x).copied().unwrap_or(0) })
+            .collect();
+        // The teams of the start, a player who left still counts for its team.
+        let teams = self.game_started.as_ref().and_then(|(_, players)| team_list(players, self.settings.teams)).map(|teams| {
+            teams.into_iter()
+                .map(|x| TeamScore { team: x.team, points: x.players.iter().map(|p| game.scores().get(&p.0).copied().unwrap_or(0)).sum() })
+                .collect()
+        });
+        Some(Arc::new(OutGameEvent::ScoreUpdate { scores, features, is_final, teams }))
+    }
+
+    /// Sends the win probabilities of the players in game if the board changed since the last ones.
+    fn broadcast_prediction(&mut self) {
+        let game = match &self.game {
+            Some(x) if self.state == RoomState::Playing => x,
+            _ => return,
+        };
+        let tiles_left = game.tiles_left();
+        if self.predicted_at == Some(tiles_left) {
+            return;
+        }
+        let mut players: Vec<IdType> = self.players.values().filter(|x| x.in_game).map(|x| x.obj.id.0)
+            .chain(self.bots.keys().copied())
+            .collect();
+        players.sort_unstable();
+        let farms = self.settings.scoring != ScoringVariant::NoFarmers;
+        let players = match isolation::isolate(Subsystem::Predictions, || prediction::predict(game, &players, farms)) {
+            Some(x) => x,
+            None => return,
+        };
+        self.predicted_at = Some(tiles_left);
+        self.broadcast_game_event(Arc::new(OutGameEvent::WinProbability { tiles_left, players }));
+    }
+
+    /// Sends the features completed by the last move.
+    fn broadcast_scored(&mut self) {
+        let scored = match &mut self.game {
+            Some(game) => game.take_scored(),
+            None => return,
+        };
+        if scored.is_empty() {
+            return;
+        }
+        self.record_scored(&scored);
+        if let Some(event) = self.score_event(scored, false) {
+            self.broadcast_game_event(event);
+        }
+    }
+
+    /// Adds the features scored to the records of the game, the lobby unlocks the achievements they
+    /// reach (see achievements.rs).
+    fn record_scored(&mut self, scored: &[ScoredFeature]) {
+        if self.loaded_game {
+            return;
+        }
+        self.game_records.add(scored);
+        let mut reached = Vec::new();
+        for feature in scored.iter() {
+            let event = AchievementEvent::Scored(feature);
+            for achievement in achievements::evaluate(&event) {
+                reached.extend(feature.players.iter().filter(|x| self.players.contains_key(&x.0)).map(|x| (x.0, achievement)));
+            }
+        }
+        if !reached.is_empty() {
+            self.lobby.do_send(ReachAchievements { reached });
+        }
+    }
+
+    /// Adds a validated move or an event of the server to the replay of the game.
+    fn record_replay<T: ?Sized + Serialize>(&mut self, sender: Option<IdType>, event: &T) {
+        let replay = match &mut self.replay {
+            Some(x) => x,
+            None => return,
+        };
+        let recorded = isolation::isolate(Subsystem::Replays, || {
+            replay.record(sender, serde_json::to_value(event).expect("Error serializing replay event"));
+        });
+        if recorded.is_none() {
+            // A replay with holes would be misleading, the next game starts a new one.
+            self.replay = None;
+        }
+    }
+
+    /// Scores what's left at the end of the game, for everyone in game and the spectators.
+    fn score_final(&mut self) {
+        let farms = self.settings.scoring != ScoringVariant::NoFarmers;
+        let scored = self.game.as_mut().map(|x| x.score_end(farms)).unwrap_or_default();
+        self.record_scored(&scored);
+        if let Some(event) = self.score_event(scored, true) {
+            self.broadcast_game_event(event);
+        }
+        if let Some(event) = self.time_report() {
+            self.broadcast_game_event(event);
+        }
+    }
+
+    /// The room goes back to matchmaking once a player ended the game, a running vote is cancelled.
+    fn end_game(&mut self, ctx: &mut Context<Self>) {
+        self.state = RoomState::Matchmaking;
+        self.game = None;
+        self.relay_filter = None;
+        self.restart_turn_timer(ctx);// Without a game it only stops the timer
+        self.update_vote(ctx);
+        self.notify_lobby();
+    }
+
+    /// Adds the game that just ended to the history of its players, a loaded game doesn't change
+    /// their statistics nor the series.
+    fn record_match(&mut self) {
+        let (started_at, players) = match self.game_started.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let ended_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default();
+        metrics::game_ended(&self.settings, self.game.is_some(), started_at.elapsed());
+
+        let replay = self.replay.take().map(|x| x.finish(self.id, players.clone()));
+        let scores = self.game.as_ref().map(|x| x.scores());
+        let results = match self.loaded_game {
+            true => HashMap::new(),
+            false => self.game_records.results(players.iter().map(|x| x.id.0), scores),
+        };
+        if let (Some(series), Some(scores), false) = (&mut self.series, scores, self.loaded_game) {
+            series.record(scores);
+            if series.is_over() {
+                info!(room = self.id, winner = ?series.winner(), "Series over");
+                self.lobby.do_send(RecordSeries { players: series.players().to_vec(), winner: series.winner() });
+            }
+        }
+        let violations = self.turn_clock.take()
+            .map(|clock| players.iter().map(|x| (x.id.0, clock.violations(x.id.0))).collect())
+            .unwrap_or_default();
+        self.lobby.do_send(RecordMatch {
+            record: MatchRecord {
+                room_id: self.id.into(),
+                players,
+                ended_at,
+                duration_secs: started_at.elapsed().as_secs(),
+                tiles: self.game.as_ref().map(|x| x.tile_sequence()).unwrap_or_default(),
+                replay_id: None,
+            },
+            replay,
+            scores: scores.cloned().unwrap_or_default(),
+            results,
+            violations,
+        });
+    }
+
+    fn player_objects(&self) -> Vec<PlayerObject> {
+        self.players.values().map(|x| &x.obj).chain(self.bots.values().map(|x| &x.obj)).cloned().collect()
+    }
+
+    /// Players and bots, every one of them takes a seat.
+    fn seats(&self) -> usize {
+        self.players.len() + self.bots.len()
+    }
+
+    /// The team of a player entering the room: the one it had if it still exists, else the
+    /// smallest one. None in the rooms without teams.
+    fn team_for(&self, team: Option<u8>) -> Option<u8> {
+        let count = self.settings.teams?;
+        if team.map(|x| x < count).unwrap_or(false) {
+            return team;
+        }
+        let mut sizes = vec![0; count as usize];
+        let teams = self.players.values().map(|x| x.obj.team).chain(self.bots.values().map(|x| x.obj.team));
+        for team in teams.flatten().filter(|x| *x < count) {
+            sizes[team as usize] += 1;
+        }
+        (0..count).min_by_key(|x| sizes[*x as usize])
+    }
+
+    /// Moves the players and the bots out of the teams that don't exist anymore after a change of
+    /// the settings (all of them once the teams are disabled), and tells the room.
+    fn assign_teams(&mut self) {
+        let ids: Vec<IdType> = self.players.keys().chain(self.bots.keys()).copied().collect();
+        for id in ids {
+            let current = match self.players.get(&id) {
+                Some(x) => x.obj.team,
+                None => self.bots[&id].obj.team,
+            };
+            let team = self.team_for(current);
+            if team == current {
+                continue;
+            }
+            match self.players.get_mut(&id) {
+                Some(x) => x.obj.team = team,
+                None => self.bots.get_mut(&id).unwrap().obj.team = team,
+            }
+            self.broadcast_event(OutEvent::EventPlayerTeamChange { player: id.into(), team }, None);
+        }
+    }
+
+    /// Hands the running trace to a member that comes in, its connection writes its messages.
+    fn send_trace(&self, member: IdType, addr: &Addr<ClientWs>, ctx: &Context<Self>) {
+        if let Some((trace, _)) = &self.trace {
+            trace.note(&format!("Member {} in", member));
+            addr.do_send(client_ws::TraceRoom { room: ctx.address(), trace: trace.clone() });
+        }
+    }
+
+    fn end_trace(&mut self, ctx: &mut Context<Self>, note: &str) {
+        if let Some((trace, handle)) = self.trace.take() {
+            ctx.cancel_future(handle);
+            trace.note(note);
+            trace.stop();
+            info!(file = %trace.path().display(), "Room trace ended");
+        }
+    }
+
+    /// Tells the lobby about the new player count and state of the room.
+    fn notify_lobby(&self) {
+        self.lobby.do_send(RoomUpdate {
+            room_id: self.id,
+            player_count: self.seats(),
+            state: self.state,
+            locked: self.locked,
+            host: self.players.values().find(|x| x.obj.is_host).map(|x| x.obj.username.clone()).unwrap_or_default(),
+            settings: self.settings.clone(),
+            players: self.players.keys().copied().collect(),
+        });
+    }
+
+    /// Send event to all users in the room
+    fn broadcast_event(&self, event: OutEvent, skip_id: Option<IdType>) {
+        let event = Arc::new(event);
+        for (id, player) in self.players.iter() {
+            if Some(*id) == skip_id {
+                continue;
+            }
+            if player.in_game {
+                continue; // Don't send if player is still in the game.
+            }
+            if !player.wants(&event) {
+                continue;
+            }
+            player.send_event(&event, &self.options);
+        }
+    }
+
+    /// Sends the connection quality of every player, only if it changed since the last time.
+    fn broadcast_network_quality(&mut self) {
+        if self.state != RoomState::Matchmaking {
+            return;
+        }
+        let mut players: Vec<PlayerNetworkQuality> = self.players.values()
+            .filter_map(|x| Some(PlayerNetworkQuality { player: x.obj.id, quality: x.network? }))
+            .collect();
+        players.sort_by_key(|x| x.player.0);
+
+        if players != self.network_sent {
+            self.network_sent = players.clone();
+            self.broadcast_event(OutEvent::EventRoomNetworkQuality { players }, None);
+        }
+    }
+
+    fn add_player(&mut self, ctx: &mut Context<Self>, member_id: IdType, mut member: RoomMember) -> JoinRoomResult {
+        member.obj.team = self.team_for(member.obj.team);
+        let player = member.obj.clone();
+        self.send_trace(member_id, &member.addr, ctx);
+        self.players.insert(member_id, member);
+
+        self.broadcast_event(OutEvent::EventPlayerJoined { player }, None);
+
+        info!(player = member_id, "Room joined");
+
+        self.update_start_countdown(ctx, false, Some(member_id));
+        self.notify_lobby();
+
+        JoinRoomResult::Success {
+            room: ctx.address(),
+            players: self.player_objects(),
+            settings: self.settings.clone(),
+            pinned: self.pinned.clone(),
+            countdown: self.start_countdown.as_ref().map(|x| {
+                x.ends_at.saturating_duration_since(Instant::now()).as_secs_f64().ceil() as u32
+            }),
+        }
+    }
+
+    fn is_host(&self, id: IdType) -> bool {
+        self.players.get(&id).map(|x| x.obj.is_host).unwrap_or(false)
+    }
+
+    /// Removes a player (or a spectator) on the host's request.
+    fn kick(&mut self, ctx: &mut Context<Self>, host: IdType, target: IdType, ban: bool) -> bool {
+        if !self.is_host(host) || host == target || self.options.tournament || !self.remove_member(ctx, target, ban) {
+            return false;
+        }
+        info!(target, ban, "Player removed by the host");
+        true
+    }
+
+    /// Removes a player (or a spectator) after a kick, false if it's not in the room.
+    fn remove_member(&mut self, ctx: &mut Context<Self>, target: IdType, ban: bool) -> bool {
+        let member = match self.players.get(&target).or_else(|| self.spectators.get(&target)) {
+            Some(x) => x,
+            None => return false,
+        };
+        member.addr.do_send(Event::from(OutEvent::EventKicked { banned: ban }));
+
+        if ban {
+            self.banned.insert(target);
+        }
+        self.leave(ctx, target);
+        true
+    }
+
+    fn spectator_objects(&self) -> Vec<PlayerObject> {
+        self.spectators.values().map(|x| x.obj.clone()).collect()
+    }
+
+    /// Tells the players in game about the new spectator list.
+    fn notify_spectators_change(&self) {
+        let event = Arc::new(OutGameEvent::SpectatorsChange {
+            spectators: self.spectator_objects(),
+        });
+        for player in self.players.values() {
+            if player.in_game && !player.unsubscribed.contains(&EventClass::SpectatorCount) {
+                player.addr.do_send(GameEvent(event.clone()));
+            }
+        }
+    }
+
+    /// Broadcasts the current cosmetics of the player if a change is still waiting.
+    fn flush_cosmetics(&mut self, player_id: IdType) {
+        let player = match self.players.get_mut(&player_id) {
+            Some(x) if x.cosmetics_pending => x,
+            _ => return,
+        };
+        player.cosmetics_pending = false;
+        player.cosmetics_sent_at = Some(Instant::now());
+        let cosmetics = player.obj.cosmetics.clone();
+
+        self.broadcast_event(OutEvent::EventPlayerAvatarChange {
+            player: player_id.into(),
+            cosmetics,
+        }, Some(player_id));
+    }
+
+    fn leave(&mut self, ctx: &mut Context<Self>, player_id: IdType) {
+        if self.spectators.remove(&player_id).is_some() {
+            self.notify_spectators_change();
+            return;
+        }
+
+        let player = match self.players.remove(&player_id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        // Stops the countdown if the players count becomes lower than the auto-start one.
+        self.update_start_countdown(ctx, false, None);
+
+        let mut redrawn = false;
+        if player.in_game {
+            self.in_game_count -= 1;
+            // The turn of the player goes to the next one.
+            redrawn = self.game.as_mut().map(|x| x.remove_player(player_id)).unwrap_or(false);
+            if let Some(filter) = &mut self.relay_filter {
+                filter.remove_player(player_id);
+            }
+        }
+
+        let first_player = match self.players.keys().next() {
+            Some(x) => *x,
+            None => {
+                for spectator in self.spectators.values() {
+                    spectator.addr.do_send(GameEvent::from(OutGameEvent::RoomClosed {}));
+                }
+                self.lobby.do_send(RoomClosed { room_id: self.id });
+                self.bots.clear();
+                ctx.stop();
+                info!("Room deleted since all players quit");
+                return;
+            }
+        };
+
+        let new_host = if player.obj.is_host {
+            let p = self.players.get_mut(&first_player).expect("Invalid player");
+            p.obj.is_host = true;
+            Some(p.obj.id)
+        } else {
+            None
+        };
+
+        let event = Arc::new(OutEvent::EventPlayerLeft {
+            player: player_id.into(),
+            new_host,
+        });
+
+        let in_game_event = Arc::new(OutGameEvent::PlayerLeft {
+            player: player_id.into(),
+            new_host
+        });
+        self.record_replay(None, &*in_game_event);
+
+        for player in self.players.values() {
+            if player.in_game {
+                player.addr.do_send(GameEvent(in_game_event.clone()));
+            } else {
+                player.send_event(&event, &self.options);
+            }
+        }
+        self.send_spectators(in_game_event);
+        if redrawn {
+            self.broadcast_tile_drawn();
+        }
+        if player.in_game {
+            self.update_vote(ctx);
+        }
+        self.notify_lobby();
+    }
+}
+
+impl Handler<Join> for RoomActor {
+    type Result = JoinRoomResult;
+
+    fn handle(&mut self, msg: Join, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("join", room = self.id, player = msg.member.id).entered();
+        if self.state != RoomState::Matchmaking {
+            return JoinRoomResult::AlreadyPlaying;
+        }
+
+        if self.migrating.is_some() {
+            return JoinRoomResult::RoomLocked;
+        }
+
+        if self.seats() >= self.settings.max_players {
+            return JoinRoomResult::RoomIsFull;
+        }
+
+        // A player back after the room moved here takes its seat whatever the room became meanwhile.
+        let ticket = msg.ticket.filter(|x| self.seats.as_ref().map(|seats| seats.holds(x)).unwrap_or(false));
+        if ticket.is_none() {
+            // Only the players of the match are put in the room of a tournament.
+            if self.locked || (self.options.tournament && !msg.invited) {
+                return JoinRoomResult::RoomLocked;
+            }
+
+            if self.banned.contains(&msg.member.id) {
+                return JoinRoomResult::Banned;
+            }
+
+            if !msg.invited && self.options.password.is_some() && self.options.password != msg.password {
+                return JoinRoomResult::WrongPassword;
+            }
+        }
+
+        let member_id = msg.member.id;
+        let mut member = RoomMember::from_member(msg.member, false);
+        let seat = match (ticket, &mut self.seats) {
+            (Some(ticket), Some(seats)) => seats.claim(&ticket, member_id),
+            _ => false,
+        };
+        if seat {
+            member.obj.team = self.seats.as_ref().and_then(|x| x.team_of(member_id));
+        }
+        let result = self.add_player(ctx, member_id, member);
+        if seat {
+            self.start_migrated(ctx);
+        }
+        result
+    }
+}
+
+impl Handler<Leave> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("leave", room = self.id, player = msg.id).entered();
+        self.leave(ctx, msg.id);
+    }
+}
+
+impl Handler<EditCosmetics> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: EditCosmetics, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("edit_cosmetics", room = self.id, player = msg.id).entered();
+        let player = match self.players.get_mut(&msg.id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if player.obj.cosmetics == msg.obj {
+            return;
+        }
+        if self.state == RoomState::Playing {
+            if player.cosmetics_changes_in_game >= MAX_COSMETICS_CHANGES_IN_GAME {
+                return;
+            }
+            player.cosmetics_changes_in_game += 1;
+        }
+        player.obj.cosmetics = msg.obj;
+
+        if player.cosmetics_pending {
+            return;// Already scheduled, the latest cosmetics will be sent.
+        }
+        player.cosmetics_pending = true;
+
+        let elapsed = player.cosmetics_sent_at.map(|x| x.elapsed()).unwrap_or(COSMETICS_CHANGE_WINDOW);
+        if elapsed >= COSMETICS_CHANGE_WINDOW {
+            self.flush_cosmetics(msg.id);
+        } else {
+            let id = msg.id;
+            ctx.run_later(COSMETICS_CHANGE_WINDOW - elapsed, move |act, _| act.flush_cosmetics(id));
+        }
+    }
+}
+
+impl Handler<EditSubscriptions> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: EditSubscriptions, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("edit_subscriptions", room = self.id, player = msg.id).entered();
+        let player = match self.players.get_mut(&msg.id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        for class in msg.events {
+            if msg.subscribe {
+                player.unsubscribed.remove(&class);
+            } else {
+                player.unsubscribed.insert(class);
+            }
+        }
+    }
+}
+
+impl Handler<UpdateNetworkQuality> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateNetworkQuality, _: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("update_network_quality", room = self.id, player = msg.id).entered();
+        if let Some(player) = self.players.get_mut(&msg.id) {
+            player.network = msg.quality;
+        }
+    }
+}
+
+impl Handler<StartRoom> for RoomActor {
+    type Result = Result<(), Vec<StartFailure>>;
+
+    fn handle(&mut self, msg: StartRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("start_room", room = self.id, countdown = msg.countdown).entered();
+        let failures = start_checks::run(&StartingRoom {
+            state: self.state,
+            read_only: self.read_only || self.migrating.is_some(),
+            by_host: msg.by.map(|x| self.is_host(x)).unwrap_or(true),
+            tournament: self.options.tournament,
+            game: msg.game.as_ref(),
+            settings: &self.settings,
+            players: self.players.values().map(|x| StartingPlayer { obj: &x.obj, in_game: x.in_game })
+                .chain(self.bots.values().map(|x| StartingPlayer { obj: &x.obj, in_game: false }))
+                .collect(),
+        });
+        if !failures.is_empty() {
+            info!(reasons = ?failures.iter().map(|x| x.reason).collect::<Vec<_>>(), "Room start refused");
+            if self.cancel_start_countdown(ctx) {
+                self.broadcast_event(OutEvent::EventRoomStartCountdown { seconds: None, canceled_by: None }, None);
+            }
+            return Err(failures);
+        }
+
+        // A room_start during a countdown asked for one forces the start.
+        let requested = self.start_countdown.as_ref().map(|x| x.requested).unwrap_or(false);
+        if msg.countdown && !requested {
+            info!(seconds = START_COUNTDOWN, "Start countdown started");
+            let start = StartRoom { by: None, countdown: false, ..msg };
+            self.start_countdown(ctx, start, START_COUNTDOWN, true, None);
+            return Ok(());
+        }
+
+        info!("Room starting");
+
+        // Ensures that there wasn't any "lobby" countdown running.
+        self.cancel_start_countdown(ctx);
+
+        self.state = RoomState::Playing;
+        // The game of a room moved here, unless the host loads another one.
+        let seats = self.seats.take();
+        self.loaded_game = msg.game.is_some();
+        let game = msg.game.or_else(|| seats.and_then(Seats::into_game));
+        let board = game.as_ref().map(|x| x.snapshot());
+        if self.features.authoritative_engine {
+            let mut game = game.unwrap_or_default();
+            let players: Vec<IdType> = self.players.keys().chain(self.bots.keys()).copied().collect();
+            let players = match self.settings.series {
+                Some(best_of) => {
+                    if self.series.as_ref().map(|x| x.is_over() || x.best_of() != best_of).unwrap_or(true) {
+                        self.series = Some(Series::new(best_of));
+                    }
+                    self.series.as_mut().unwrap().turn_order(&players)
+                },
+                None => players,
+            };
+            game.start_draws(players, &mut rand::thread_rng());
+            if let Some(delay) = &mut self.spectator_delay {
+                delay.push(Delayed::Board(game.snapshot()), Instant::now());
+            }
+            self.game = Some(game);
+            self.replay = Some(ReplayLog::default());
+            // Every player receives the first draw once it acknowledges the start.
+            if let Some(event) = self.tile_drawn_event() {
+                self.record_replay(None, &*event);
+            }
+            self.play_bot_turn();
+        } else if self.features.relay_prefilter {
+            self.relay_filter = Some(RelayFilter::new(self.players.keys().copied().collect(), board.as_ref()));
+        }
+
+        let players = self.player_objects();
+        let event = Arc::new(OutEvent::EventRoomStart {
+            connection_type: msg.conn_type,
+            broadcast_id: format!("{}", self.id),
+            board,
+            teams: team_list(&players, self.settings.teams),
+        });
+
+        for player in self.players.values_mut() {
+            player.in_game = true;
+            player.cosmetics_changes_in_game = 0;
+            player.relay_cursor = self.relay.head();
+            player.relay_ready = false;
+            player.send_event(&event, &self.options);
+        }
+        self.in_game_count = self.players.len() as u32;
+        self.game_started = Some((Instant::now(), players));
+        self.game_records = GameRecords::default();
+        self.predicted_at = None;
+        let limit = self.settings.turn_timer_secs.unwrap_or(MOVE_TIME_LIMIT_SECS);
+        self.turn_clock = Some(TurnClock::new(limit)).filter(|_| self.options.tournament && self.game.is_some());
+        self.start_turn_clock();
+        metrics::game_started(&self.settings, self.game.is_some());
+        self.restart_turn_timer(ctx);
+        self.notify_lobby();
+        Ok(())
+    }
+}
+
+impl Handler<SendRelayMex> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendRelayMex, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("send_relay_mex", room = self.id, player = msg.sender_id).entered();
+        if msg.data.is_empty() {
+            return;
+        }
+
+        let player = match self.players.get(&msg.sender_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let (addr, share_samples) = (player.addr.clone(), player.privacy.share_relay_samples);
+
+        if let Some(bucket) = &mut self.relay_bucket {
+            let recipients = self.players.values().filter(|x| x.in_game).count().saturating_sub(1) + self.spectators.len();
+            if !bucket.try_take((msg.data.len() * recipients) as f64, Instant::now()) {
+                if self.relay_limited.insert(msg.sender_id) {
+                    warn!(room = self.id, player = msg.sender_id, "Room over its relay limit, dropping messages");
+                    addr.do_send(Event::from(OutEvent::RateLimited { scope: RateLimitScope::Room }));
+                }
+                return;
+            }
+            self.relay_limited.clear();
+        }
+
+        match self.relay_move(ctx, msg.sender_id, &msg.data) {
+            Ok(()) if share_samples => relay_sampling::sample(&msg.data),
+            Ok(()) => {},
+            Err(reason) => addr.do_send(GameEvent::from(OutGameEvent::MoveRejected { reason })),
+        }
+    }
+}
+
+impl Handler<BotMoves> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: BotMoves, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("bot_moves", room = self.id, bot = msg.id).entered();
+        if !self.bots.contains_key(&msg.id) || self.state != RoomState::Playing {
+            return;
+        }
+        for data in msg.moves.iter() {
+            // The turn may have been skipped in the meantime.
+            if let Err(reason) = self.relay_move(ctx, msg.id, data) {
+                info!(reason = %reason, "Bot move rejected");
+                break;
+            }
+        }
+    }
+}
+
+impl RoomActor {
+    /// Validates a move with the authoritative engine (or only prefilters it, see relay_filter) and
+    /// relays it to the players in game and to the spectators.
+    fn relay_move(&mut self, ctx: &mut Context<Self>, sender: IdType, data: &str) -> Result<(), Cow<'static, str>> {
+        if self.migrating.is_some() && self.game.is_some() {
+            return Err("The room is moving to another server".into());
+        }
+        let mut turn_ended = false;
+        let mut validated = false;
+        if let Some(game) = &mut self.game {
+            GameState::parse_move(data)
+                .map_err(Cow::from)
+                .and_then(|mv| match mv {
+                    Some(mv) => {
+                        // Placing a tile is the last mandatory move of a turn.
+                        turn_ended = matches!(mv, GameMove::PlaceTile { .. });
+                        validated = true;
+                        game.apply(sender, mv).map_err(Cow::from)
+                    },
+                    None => Ok(()),
+                })?;
+        } else if let Some(filter) = &mut self.relay_filter {
+            filter.check(sender, data)?;
+        }
+        if turn_ended {
+            if let Some(clock) = &mut self.turn_clock {
+                clock.end(sender, Instant::now());
+            }
+            self.restart_turn_timer(ctx);
+        }
+
+        let raw: Arc<str> = format!("{{\"sender\":\"{}\",{}", SerId(sender), &data[1..]).into();
+        self.relay.push(sender, raw.clone());
+        let delay = self.spectator_delay.as_mut();
+        let spectators = self.spectators.values_mut().filter(|_| delay.is_none());
+        for member in self.players.values_mut().filter(|x| x.in_game).chain(spectators) {
+            member.catch_up(&self.relay);
+        }
+        if let Some(delay) = &mut self.spectator_delay {
+            let now = Instant::now();
+            delay.push(Delayed::Relay(raw), now);
+            if let Some(game) = self.game.as_ref().filter(|_| validated) {
+                delay.push(Delayed::Board(game.snapshot()), now);
+            }
+        }
+        if validated {
+            if let Ok(mv) = serde_json::from_str::<serde_json::Value>(data) {
+                self.record_replay(Some(sender), &mv);
+            }
+        }
+        self.broadcast_scored();
+        if turn_ended {
+            self.broadcast_tile_drawn();
+        }
+        Ok(())
+    }
+}
+
+impl Handler<SendCursor> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendCursor, _: &mut Context<Self>) -> Self::Result {
+        let _span = debug_span!("send_cursor", room = self.id, player = msg.sender_id).entered();
+        let sender_id = msg.sender_id;
+        match self.players.get(&sender_id) {
+            Some(x) if x.in_game && !x.privacy.hide_presence => {},
+            _ => return,
+        }
+
+        let event = Arc::new(OutGameEvent::Cursor {
+            player: sender_id.into(),
+            position: msg.position,
+        });
+        if let Some(delay) = &mut self.spectator_delay {
+            delay.push(Delayed::Event(event.clone()), Instant::now());
+        }
+        let receivers = self.players.iter()
+            .filter(|(id, x)| **id != sender_id && x.in_game && !x.unsubscribed.contains(&EventClass::Presence))
+            .map(|(_, x)| x)
+            .chain(self.spectators.values().filter(|_| self.spectator_delay.is_none()));
+        for player in receivers {
+            // Never wait for a slow client, the next update will replace this one anyway.
+            let _ = player.addr.try_send(GameEvent(event.clone()));
+        }
+    }
+}
+
+impl Handler<SaveGame> for RoomActor {
+    type Result = Option<Vec<u8>>;
+
+    fn handle(&mut self, _: SaveGame, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("save_game", room = self.id).entered();
+        self.game.as_ref().map(snapshot::encode)
+    }
+}
+
+impl RoomActor {
+    /// Starts the game of a room moved here once every player is back, after a delay so that the
+    /// last one gets its room_join_response first.
+    fn start_migrated(&mut self, ctx: &mut Context<Self>) {
+        if self.seats.as_ref().map(Seats::ready).unwrap_or(false) {
+            let start = StartRoom { by: None, conn_type: RoomConnectionType::ServerBroadcast, game: None, countdown: false };
+            ctx.notify_later(start, MIGRATED_START_DELAY);
+        }
+    }
+}
+
+impl Handler<Migrate> for RoomActor {
+    type Result = Option<RoomData>;
+
+    fn handle(&mut self, msg: Migrate, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("migrate", room = self.id).entered();
+        if self.migrating.is_some() {
+            return None;
+        }
+        if self.cancel_start_countdown(ctx) {
+            self.broadcast_event(OutEvent::EventRoomStartCountdown { seconds: None, canceled_by: None }, None);
+        }
+        // The turn timer would change the game once it's sent.
+        if let Some(handle) = self.turn_timer_handle.take() {
+            ctx.cancel_future(handle);
+        }
+
+        let tickets: HashMap<IdType, String> = self.players.keys().map(|x| (*x, migration::new_ticket())).collect();
+        let data = RoomData {
+            room_id: self.id.into(),
+            code: self.code.clone(),
+            public: msg.public,
+            options: self.options.clone(),
+            settings: self.settings.clone(),
+            pinned: self.pinned.clone(),
+            seats: tickets.iter()
+                .map(|(id, ticket)| Seat { ticket: ticket.clone(), player_id: (*id).into(), team: self.players[id].obj.team })
+                .collect(),
+            game: self.game.as_ref().filter(|_| self.state == RoomState::Playing).map(MigratedGame::new),
+        };
+        info!(players = tickets.len(), with_game = data.game.is_some(), "Room frozen for its migration");
+        self.migrating = Some(tickets);
+        Some(data)
+    }
+}
+
+impl Handler<MigrationEnd> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: MigrationEnd, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("migration_end", room = self.id).entered();
+        let tickets = match self.migrating.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let url = match msg.url {
+            Some(x) => x,
+            None => {
+                info!("Migration refused, room unfrozen");
+                self.restart_turn_timer(ctx);
+                return;
+            },
+        };
+
+        for (id, player) in self.players.iter() {
+            let (url, room_id, ticket) = (url.clone(), self.id.into(), tickets.get(id).cloned().unwrap_or_default());
+            if player.in_game {
+                player.addr.do_send(GameEvent::from(OutGameEvent::RoomMigrated { url, room_id, ticket }));
+            } else {
+                player.addr.do_send(Event::from(OutEvent::EventRoomMigrated { url, room_id, ticket }));
+            }
+        }
+        let closed = Arc::new(OutGameEvent::RoomClosed {});
+        for spectator in self.spectators.values() {
+            spectator.addr.do_send(GameEvent(closed.clone()));
+        }
+        self.lobby.do_send(RoomClosed { room_id: self.id });
+        self.bots.clear();
+        ctx.stop();
+        info!(url = %url, "Room migrated");
+    }
+}
+
+impl Handler<RestoreMigrated> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RestoreMigrated, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("restore_migrated", room = self.id).entered();
+        self.settings = msg.settings;
+        self.pinned = msg.pinned;
+        // The host recreating the room is back in its team.
+        for (id, player) in self.players.iter_mut() {
+            player.obj.team = msg.seats.team_of(*id);
+        }
+        self.seats = Some(msg.seats);
+        self.assign_teams();
+        self.notify_lobby();
+        self.start_migrated(ctx);
+    }
+}
+
+impl Handler<GameEndRequest> for RoomActor {
+    type Result = Option<GameEndAck>;
+
+    fn handle(&mut self, msg: GameEndRequest, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("game_end_request", room = self.id, player = msg.id).entered();
+        if !self.players.get(&msg.id)?.in_game {
+            return None;
+        }
+
+        // The first player to end the game receives the final scores with the others.
+        if self.state == RoomState::Playing {
+            self.score_final();
+        }
+
+        let player = self.players.get_mut(&msg.id)?;
+        player.in_game = false;
+        self.in_game_count -= 1;
+        if self.state == RoomState::Playing {
+            self.send_spectators(Arc::new(OutGameEvent::GameEnded {}));
+            self.record_match();
+        }
+        self.send_series(msg.id);
+        self.end_game(ctx);
+
+        Some(GameEndAck {
+            players: self.player_objects(),
+            spectators: self.spectator_objects(),
+        })
+    }
+}
+
+impl RoomActor {
+    /// The players that vote on a proposal: the ones in game, but the player to kick.
+    fn voters(&self, proposal: VoteProposal) -> Vec<IdType> {
+        let kicked = match proposal {
+            VoteProposal::Kick { target } => Some(target.0),
+            _ => None,
+        };
+        self.players.iter().filter(|x| x.1.in_game && Some(*x.0) != kicked).map(|x| *x.0).collect()
+    }
+
+    fn send_in_game(&self, event: OutGameEvent) {
+        let event = Arc::new(event);
+        for player in self.players.values().filter(|x| x.in_game) {
+            player.addr.do_send(GameEvent(event.clone()));
+        }
+    }
+
+    /// Sends the count of the running vote to the players in game, or ends the vote once it's decided.
+    fn update_vote(&mut self, ctx: &mut Context<Self>) {
+        let vote = match &self.vote {
+            Some((vote, _)) => vote,
+            None => return,
+        };
+        let proposal = vote.proposal();
+        let voters = self.voters(proposal);
+        let cancelled = match proposal {
+            VoteProposal::Kick { target } => !self.players.get(&target.0).map(|x| x.in_game).unwrap_or(false),
+            _ => false,
+        };
+        let result = if cancelled || self.state != RoomState::Playing || !votes::enough_voters(proposal, voters.len()) {
+            Some(VoteResult::Cancelled)
+        } else {
+            vote.result(&voters)
+        };
+        match result {
+            Some(result) => self.end_vote(ctx, result),
+            None => self.send_in_game(OutGameEvent::VoteUpdate { vote: vote.view(&voters) }),
+        }
+    }
+
+    fn end_vote(&mut self, ctx: &mut Context<Self>, result: VoteResult) {
+        let (vote, handle) = match self.vote.take() {
+            Some(x) => x,
+            None => return,
+        };
+        ctx.cancel_future(handle);
+        let proposal = vote.proposal();
+        self.send_in_game(OutGameEvent::VoteEnded { vote: vote.view(&self.voters(proposal)), result });
+        info!(room = self.id, ?proposal, ?result, "Vote ended");
+
+        match result {
+            VoteResult::Passed => match proposal {
+                VoteProposal::Kick { target } => {
+                    self.remove_member(ctx, target.0, false);
+                },
+                VoteProposal::Abort => self.return_to_lobby(ctx, false),
+                VoteProposal::ReturnToLobby => self.return_to_lobby(ctx, true),
+            },
+            VoteResult::Failed => {
+                self.vote_cooldowns.insert(vote.started_by(), Instant::now() + VOTE_COOLDOWN);
+            },
+            VoteResult::Cancelled => {},
+        }
+    }
+
+    /// Ends the game for every player in game after a vote, they go back to the room as with an
+    /// end_game. Without `scored` the game is dropped: no final scores and nothing recorded.
+    fn return_to_lobby(&mut self, ctx: &mut Context<Self>, scored: bool) {
+        if self.state != RoomState::Playing {
+            return;
+        }
+        if scored {
+            self.score_final();
+        }
+        let ids = self.voters(VoteProposal::Abort);
+        for id in ids.iter() {
+            self.players.get_mut(id).unwrap().in_game = false;
+        }
+        self.in_game_count -= ids.len() as u32;
+        self.send_spectators(Arc::new(OutGameEvent::GameEnded {}));
+        if scored {
+            self.record_match();
+        } else {
+            self.game_started = None;
+            self.replay = None;
+            self.turn_clock = None;
+        }
+        self.end_game(ctx);
+
+        let (players, spectators) = (self.player_objects(), self.spectator_objects());
+        for id in ids {
+            let player = &self.players[&id];
+            player.addr.do_send(client_ws::ReturnToLobby { players: players.clone(), spectators: spectators.clone() });
+            self.send_series(id);
+        }
+    }
+}
+
+impl Handler<StartVote> for RoomActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: StartVote, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("start_vote", room = self.id, player = msg.id, proposal = ?msg.proposal).entered();
+        if self.options.tournament {
+            return Err("tournament_match");
+        }
+        let voters = self.voters(msg.proposal);
+        if self.state != RoomState::Playing || !voters.contains(&msg.id) {
+            return Err("not_in_game");
+        }
+        if self.vote.is_some() {
+            return Err("vote_in_progress");
+        }
+        if self.vote_cooldowns.get(&msg.id).map(|x| Instant::now() < *x).unwrap_or(false) {
+            return Err("vote_cooldown");
+        }
+        if let VoteProposal::Kick { target } = msg.proposal {
+            if !self.players.get(&target.0).map(|x| x.in_game).unwrap_or(false) {
+                return Err("invalid_target");
+            }
+        }
+        if !votes::enough_voters(msg.proposal, voters.len()) {
+            return Err("not_enough_voters");
+        }
+
+        let handle = ctx.run_later(VOTE_TIMEOUT, |act, ctx| act.end_vote(ctx, VoteResult::Failed));
+        self.vote = Some((Vote::new(msg.proposal, msg.id, clock::deadline_ms(VOTE_TIMEOUT)), handle));
+        info!("Vote started");
+        self.update_vote(ctx);
+        Ok(())
+    }
+}
+
+impl Handler<CastVote> for RoomActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: CastVote, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("cast_vote", room = self.id, player = msg.id, yes = msg.yes).entered();
+        let proposal = match &self.vote {
+            Some((vote, _)) => vote.proposal(),
+            None => return Err("no_vote"),
+        };
+        if !self.voters(proposal).contains(&msg.id) {
+            return Err("not_a_voter");
+        }
+        if let Some((vote, _)) = &mut self.vote {
+            vote.cast(msg.id, msg.yes);
+        }
+        self.update_vote(ctx);
+        Ok(())
+    }
+}
+
+impl Handler<ProposeMerge> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProposeMerge, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("propose_merge", room = self.id, target = ?msg.target).entered();
+        self.merge_proposal = msg.target;
+
+        if let Some(target) = msg.target {
+            self.broadcast_event(OutEvent::EventRoomMergeProposal {
+                room_id: Some(target.into()),
+                player_count: msg.player_count,
+            }, None);
+        }
+    }
+}
+
+impl Handler<GetMergeProposal> for RoomActor {
+    type Result = Option<IdType>;
+
+    fn handle(&mut self, _: GetMergeProposal, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_merge_proposal", room = self.id).entered();
+        self.merge_proposal
+    }
+}
+
+impl Handler<UpdateFeatures> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateFeatures, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("update_features", room = self.id).entered();
+        self.features = msg.0;
+    }
+}
+
+impl Handler<AddBot> for RoomActor {
+    type Result = Result<PlayerObject, &'static str>;
+
+    fn handle(&mut self, msg: AddBot, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("add_bot", room = self.id, player = msg.id).entered();
+        if !self.is_host(msg.id) {
+            return Err("not_host");
+        }
+        if self.options.tournament {
+            return Err("tournament_room");
+        }
+        if !self.features.authoritative_engine {
+            return Err("bots_unavailable");// They need the server to know the game
+        }
+        if self.state != RoomState::Matchmaking {
+            return Err("already_playing");
+        }
+        if self.seats() >= self.settings.max_players {
+            return Err("room_full");
+        }
+
+        let mut rng = rand::thread_rng();
+        let id = loop {
+            let id = rng.gen::<IdType>();
+            if !self.players.contains_key(&id) && !self.bots.contains_key(&id) {
+                break id;
+            }
+        };
+        let colors: HashSet<u64> = self.player_objects().iter().map(|x| x.cosmetics.color).collect();
+        let color = (1..).find(|x| !colors.contains(x)).unwrap_or_default();
+        let obj = PlayerObject {
+            id: id.into(),
+            username: format!("Bot {}", self.bots.len() + 1),
+            cosmetics: PlayerCosmetics { avatar: 0, color },
+            is_host: false,
+            team: self.team_for(None),
+        };
+        let addr = BotActor::new(id, ctx.address(), msg.difficulty).start();
+        self.bots.insert(id, RoomBot { addr, obj: obj.clone() });
+
+        self.broadcast_event(OutEvent::EventPlayerJoined { player: obj.clone() }, None);
+        info!(bot = id, difficulty = ?msg.difficulty, "Bot added");
+        self.update_start_countdown(ctx, false, None);
+        self.notify_lobby();
+        Ok(obj)
+    }
+}
+
+impl Handler<SetReadOnly> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetReadOnly, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("set_read_only", room = self.id, read_only = msg.0).entered();
+        self.read_only = msg.0;
+        if self.read_only && self.cancel_start_countdown(ctx) {
+            self.broadcast_event(OutEvent::EventRoomStartCountdown { seconds: None, canceled_by: None }, None);
+        }
+    }
+}
+
+impl Handler<Spectate> for RoomActor {
+    type Result = SpectateResult;
+
+    fn handle(&mut self, msg: Spectate, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("spectate", room = self.id, player = msg.member.id).entered();
+        if self.state != RoomState::Playing {
+            return SpectateResult::NotPlaying;
+        }
+
+        if self.locked {
+            return SpectateResult::RoomLocked;
+        }
+
+        if self.banned.contains(&msg.member.id) {
+            return SpectateResult::Banned;
+        }
+
+        if self.options.password.is_some() && self.options.password != msg.password {
+            return SpectateResult::WrongPassword;
+        }
+
+        let member_id = msg.member.id;
+        let mut spectator = RoomMember::from_member(msg.member, false);
+        spectator.relay_cursor = self.relay.head();
+        spectator.relay_ready = true;
+        match &self.spectator_delay {
+            Some(delay) => {
+                if let Some(event) = delay.tile_drawn() {
+                    spectator.addr.do_send(GameEvent(event.clone()));
+                }
+            },
+            None => self.send_tile_drawn(&spectator.addr),
+        }
+        self.send_trace(member_id, &spectator.addr, ctx);
+        self.spectators.insert(member_id, spectator);
+        self.notify_spectators_change();
+
+        info!(player = member_id, "Room watched");
+
+        SpectateResult::Success {
+            room: ctx.address(),
+            players: self.player_objects(),
+            spectators: self.spectator_objects(),
+            board: match &self.spectator_delay {
+                Some(delay) => delay.board().cloned(),
+                None => self.game.as_ref().map(|x| x.snapshot()),
+            },
+        }
+    }
+}
+
+impl Handler<SpectatorJoin> for RoomActor {
+    type Result = JoinRoomResult;
+
+    fn handle(&mut self, msg: SpectatorJoin, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("spectator_join", room = self.id, player = msg.id).entered();
+        if !self.spectators.contains_key(&msg.id) {
+            return JoinRoomResult::RoomNotFound;
+        }
+
+        if self.state != RoomState::Matchmaking {
+            return JoinRoomResult::AlreadyPlaying;
+        }
+
+        if self.seats() >= self.settings.max_players {
+            return JoinRoomResult::RoomIsFull;
+        }
+
+        let mut member = self.spectators.remove(&msg.id).unwrap();
+        member.obj.is_host = false;
+        self.notify_spectators_change();
+        self.add_player(ctx, msg.id, member)
+    }
+}
+
+impl Handler<Rejoin> for RoomActor {
+    type Result = Option<RejoinResult>;
+
+    fn handle(&mut self, msg: Rejoin, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("rejoin", room = self.id, player = msg.id).entered();
+        let player = self.players.get_mut(&msg.id)?;
+        player.addr = msg.addr.clone();
+        let in_game = player.in_game;
+        // The new client is playing right away, the packets relayed while away are sent after the
+        // resume response.
+        let mut relay_lost = 0;
+        if in_game {
+            player.relay_ready = true;
+            relay_lost = player.catch_up(&self.relay);
+            let addr = player.addr.clone();
+            self.send_tile_drawn(&addr);
+            if let Some(event) = self.score_event(vec![], false) {
+                addr.do_send(GameEvent(event));
+            }
+        }
+        self.send_trace(msg.id, &msg.addr, ctx);
+
+        if relay_lost > 0 {
+            warn!(relay_lost, "Room rejoined, some relay packets are lost");
+        } else {
+            info!("Room rejoined");
+        }
+
+        Some(RejoinResult {
+            players: self.player_objects(),
+            in_game,
+            board: if in_game { self.game.as_ref().map(|x| x.snapshot()) } else { None },
+            relay_lost,
+        })
+    }
+}
+
+impl Handler<RelayReady> for RoomActor {
+    type Result = u64;
+
+    fn handle(&mut self, msg: RelayReady, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("relay_ready", room = self.id, player = msg.id).entered();
+        match self.players.get_mut(&msg.id) {
+            Some(player) if player.in_game => {
+                player.relay_ready = true;
+                let lost = player.catch_up(&self.relay);
+                let addr = player.addr.clone();
+                self.send_tile_drawn(&addr);
+                lost
+            },
+            _ => 0,
+        }
+    }
+}
+
+impl Handler<PinMessage> for RoomActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: PinMessage, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("pin_message", room = self.id, player = msg.id).entered();
+        if !self.is_host(msg.id) {
+            return Err("not_host");
+        }
+        let message = msg.message.map(|x| x.trim().to_string()).filter(|x| !x.is_empty());
+        if let Some(message) = &message {
+            moderation::check_pinned_message(message)?;
+        }
+
+        // Logged so that the moderators can find out who pinned what.
+        info!(pinned = ?message, "Message pinned");
+        self.pinned = message;
+        self.broadcast_event(OutEvent::EventRoomPinnedMessage { message: self.pinned.clone() }, Some(msg.id));
+        Ok(())
+    }
+}
+
+impl Handler<UpdateRoomSettings> for RoomActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: UpdateRoomSettings, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("update_room_settings", room = self.id, player = msg.id).entered();
+        if !self.is_host(msg.id) {
+            return Err("not_host");
+        }
+        if self.options.tournament {
+            return Err("tournament_room");
+        }
+        if self.state != RoomState::Matchmaking {
+            return Err("already_playing");
+        }
+
+        let mut settings = msg.settings;
+        if let Some(language) = &settings.language {
+            settings.language = Some(normalize_language(language).ok_or("invalid_language")?);
+        }
+        if settings.max_players < 2 || settings.max_players > MAX_PLAYERS_PER_ROOM {
+            return Err("invalid_max_players");
+        }
+        if settings.max_players < self.seats() {
+            return Err("too_many_players");
+        }
+        if settings.turn_timer_secs.map(|x| !(10..=600).contains(&x)).unwrap_or(false) {
+            return Err("invalid_turn_timer");
+        }
+        if settings.expansions.iter().any(|x| !SUPPORTED_EXPANSIONS.contains(&x.as_str())) {
+            return Err("unsupported_expansion");
+        }
+        if let Some(auto_start) = settings.auto_start {
+            if auto_start.players < 2 || auto_start.players > settings.max_players || auto_start.after_secs > MAX_AUTO_START_SECS {
+                return Err("invalid_auto_start");
+            }
+        }
+        if settings.teams.map(|x| !(2..=MAX_TEAMS).contains(&x) || x as usize > settings.max_players).unwrap_or(false) {
+            return Err("invalid_teams");
+        }
+        if let Some(best_of) = settings.series {
+            if !SERIES_LENGTHS.contains(&best_of) || settings.teams.is_some() {
+                return Err("invalid_series");
+            }
+            if !self.features.authoritative_engine {
+                return Err("engine_required");
+            }
+        }
+
+        if self.settings != settings {
+            let auto_start_changed = self.settings.auto_start != settings.auto_start;
+            let teams_changed = self.settings.teams != settings.teams;
+            self.settings = settings;
+            self.series = None;
+            self.broadcast_event(OutEvent::EventRoomSettingsChange { settings: self.settings.clone() }, Some(msg.id));
+            if teams_changed {
+                self.assign_teams();
+            }
+            if auto_start_changed {
+                self.update_start_countdown(ctx, true, None);
+            }
+            self.notify_lobby();
+        }
+        Ok(())
+    }
+}
+
+impl Handler<ChangeTeam> for RoomActor {
+    type Result = Result<(), &'static str>;
+
+    fn handle(&mut self, msg: ChangeTeam, _ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("change_team", room = self.id, player = msg.id, team = msg.team).entered();
+        if self.state != RoomState::Matchmaking {
+            return Err("already_playing");
+        }
+        let count = self.settings.teams.ok_or("teams_disabled")?;
+        if msg.team >= count {
+            return Err("invalid_team");
+        }
+        let player = self.players.get_mut(&msg.id).ok_or("not_in_room")?;
+        if player.obj.team != Some(msg.team) {
+            player.obj.team = Some(msg.team);
+            self.broadcast_event(OutEvent::EventPlayerTeamChange { player: msg.id.into(), team: Some(msg.team) }, Some(msg.id));
+        }
+        Ok(())
+    }
+}
+
+impl Handler<CancelStart> for RoomActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: CancelStart, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("cancel_start", room = self.id, player = msg.id).entered();
+        if !self.players.contains_key(&msg.id) || !self.cancel_start_countdown(ctx) {
+            return false;
+        }
+
+        info!("Start countdown canceled by the player");
+        self.broadcast_event(OutEvent::EventRoomStartCountdown { seconds: None, canceled_by: Some(msg.id.into()) }, None);
+        true
+    }
+}
+
+impl Handler<KickPlayer> for RoomActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: KickPlayer, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("kick_player", room = self.id, player = msg.id, target = msg.target).entered();
+        self.kick(ctx, msg.id, msg.target, false)
+    }
+}
+
+impl Handler<BanPlayer> for RoomActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: BanPlayer, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("ban_player", room = self.id, player = msg.id, target = msg.target).entered();
+        self.kick(ctx, msg.id, msg.target, true)
+    }
+}
+
+impl Handler<TransferHost> for RoomActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: TransferHost, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("transfer_host", room = self.id, player = msg.id, target = msg.target).entered();
+        if !self.is_host(msg.id) || msg.id == msg.target || self.options.tournament || !self.players.contains_key(&msg.target) {
+            return false;
+        }
+        self.players.get_mut(&msg.id).unwrap().obj.is_host = false;
+        self.players.get_mut(&msg.target).unwrap().obj.is_host = true;
+        info!("Host transferred");
+
+        // Unlike the other room events the players in game receive it too.
+        let event = Arc::new(OutEvent::EventHostChanged { host: msg.target.into() });
+        for player in self.players.values() {
+            player.send_event(&event, &self.options);
+        }
+        self.notify_lobby();
+        true
+    }
+}
+
+impl Handler<SetLocked> for RoomActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: SetLocked, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("set_locked", room = self.id, player = msg.id, locked = msg.locked).entered();
+        match self.players.get(&msg.id) {
+            Some(x) if x.obj.is_host => {},
+            _ => return false,
+        }
+
+        if self.locked != msg.locked {
+            self.locked = msg.locked;
+            self.broadcast_event(OutEvent::EventRoomLockChange { locked: msg.locked }, Some(msg.id));
+            self.notify_lobby();
+        }
+        true
+    }
+}
+
+impl Handler<Close> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Close, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("close", room = self.id).entered();
+        let event = Arc::new(OutEvent::EventRoomClosed {});
+        let in_game_event = Arc::new(OutGameEvent::RoomClosed {});
+        for player in self.players.values() {
+            if player.in_game {
+                player.addr.do_send(GameEvent(in_game_event.clone()));
+            } else {
+                player.addr.do_send(Event(event.clone()));
+            }
+        }
+        for spectator in self.spectators.values() {
+            spectator.addr.do_send(GameEvent(in_game_event.clone()));
+        }
+        self.lobby.do_send(RoomClosed { room_id: self.id });
+        self.bots.clear();
+        ctx.stop();
+        info!("Room closed");
+    }
+}
+
+impl Handler<Trace> for RoomActor {
+    type Result = Option<PathBuf>;
+
+    fn handle(&mut self, msg: Trace, ctx: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("trace", room = self.id).entered();
+        self.end_trace(ctx, "Trace ended by an admin");
+        if msg.secs == 0 {
+            return None;
+        }
+
+        let duration = Duration::from_secs(msg.secs.min(MAX_TRACE_SECS));
+        let trace = match RoomTrace::create(&room_trace::trace_dir(), self.id, duration) {
+            Ok(x) => Arc::new(x),
+            Err(e) => {
+                warn!(error = %e, "Room trace file not created");
+                return None;
+            },
+        };
+        trace.note(&format!("Trace of room {} ({}) for {}s, {:?}", self.id, self.code, duration.as_secs(), self.state));
+        let handle = ctx.run_later(duration, |act, ctx| act.end_trace(ctx, "Trace expired"));
+        self.trace = Some((trace.clone(), handle));
+        for (id, member) in self.players.iter().chain(self.spectators.iter()) {
+            self.send_trace(*id, &member.addr, ctx);
+        }
+        info!(file = %trace.path().display(), secs = duration.as_secs(), "Room traced by an admin");
+        Some(trace.path().to_path_buf())
+    }
+}
+
+impl Handler<GetRoomCode> for RoomActor {
+    type Result = RoomCode;
+
+    fn handle(&mut self, _: GetRoomCode, _: &mut Context<Self>) -> Self::Result {
+        let _span = info_span!("get_room_code", room = self.id).entered();
+        RoomCode { id: self.id, code: self.code.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::admin::AdminConfig;
+    use crate::test_server::{self, Client, TestServer};
+
+    /// A room of the first player, joined by the others.
+    async fn room(server: &mut TestServer, names: &[&str], options: Value) -> (Value, Vec<Client>) {
+        let mut clients = Vec::new();
+        for name in names {
+            clients.push(server.login(name).await);
+        }
+        let res = clients[0].request(json!({ "type": "room_create", "options": options.clone() })).await;
+        let room = res["inviteId"].clone();
+        for client in clients[1..].iter_mut() {
+            let res = client.request(json!({ "type": "room_join", "inviteId": room, "password": options["password"] })).await;
+            assert_eq!(res["result"], "ok", "{}", res);
+        }
+        (room, clients)
+    }
+
+    #[actix_rt::test]
+    async fn passwords_are_checked() {
+        let mut server = test_server::start(AdminConfig::from_env());
+        let (room, _clients) = room(&mut server, &["alice"], json!({ "password": "secret" })).await;
+        let mut bob = server.login("bob").await;
+
+        for password in [Value::Null, json!("wrong")].iter() {
+            let res = bob.request(json!({ "type": "room_join", "inviteId": room, "password": password })).await;
+            assert_eq!(res["result"], "wrong_password");
+        }
+        let res = bob.request(json!({ "type": "room_join", "inviteId": room, "password": "secret" })).await;
+        assert_eq!(res["result"], "ok");
+    }
+
+    #[actix_rt::test]
+    async fn kicked_players_come_back_banned_ones_dont() {
+        let mut server = test_server::start(AdminConfig::from_env());
+        let (room, mut clients) = room(&mut server, &["alice", "bob"], json!({})).await;
+        let bob_id = clients[1].player_id.clone();
+
+        let res = clients[0].request(json!({ "type": "room_kick", "playerId": bob_id })).await;
+        assert_eq!(res["result"], "ok");
+        assert_eq!(clients[1].wait_for("event_kicked").await["banned"], false);
+        let res = clients[1].request(json!({ "type": "room_join", "inviteId": room })).await;
+        assert_eq!(res["result"], "ok");
+
+        let res = clients[0].request(json!({ "type": "room_ban", "playerId": bob_id })).await;
+        assert_eq!(res["result"], "ok");
+        assert_eq!(clients[1].wait_for("event_kicked").await["banned"], true);
+        let res = clients[1].request(json!({ "type": "room_join", "inviteId": room })).await;
+        assert_eq!(res["result"], "banned");
+    }
+
+    #[actix_rt::test]
+    async fn host_moves_when_the_host_leaves() {
+        let mut server = test_server::start(AdminConfig::from_env());
+        let (_, mut clients) = room(&mut server, &["alice", "bob", "carol"], json!({})).await;
+
+        let res = clients[0].request(json!({ "type": "room_leave" })).await;
+        assert_eq!(res["result"], "ok");
+        let left = clients[1].wait_for("event_player_left").await;
+        assert_eq!(left["player"], clients[0].player_id.as_str());
+        let host = clients.iter().position(|x| left["newHost"] == x.player_id.as_str()).unwrap();
+        assert_ne!(host, 0);
+
+        // Only the new host can lock the room.
+        let other = 3 - host;
+        let res = clients[other].request(json!({ "type": "room_lock", "locked": true })).await;
+        assert_eq!(res["result"], "not_host");
+        let res = clients[host].request(json!({ "type": "room_lock", "locked": true })).await;
+        assert_eq!(res["result"], "ok");
+    }
+
+    #[actix_rt::test]
+    async fn spectators_need_a_started_game_and_the_password() {
+        let mut server = test_server::start(AdminConfig::from_env());
+        let (room, mut clients) = room(&mut server, &["alice", "bob"], json!({ "password": "secret" })).await;
+        let mut carol = server.login("carol").await;
+        let mut dave = server.login("dave").await;
+
+        let res = carol.request(json!({ "type": "room_spectate", "inviteId": room, "password": "secret" })).await;
+        assert_eq!(res["result"], "not_playing");
+        let res = clients[0].request(json!({ "type": "room_start", "connectionType": "server_broadcast" })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+
+        let res = carol.request(json!({ "type": "room_spectate", "inviteId": room })).await;
+        assert_eq!(res["result"], "wrong_password");
+        let res = carol.request(json!({ "type": "room_spectate", "inviteId": room, "password": "secret" })).await;
+        assert_eq!(res["result"], "ok");
+        assert_eq!(res["spectators"][0]["id"], carol.player_id.as_str());
+
+        // A locked room refuses the new spectators too.
+        let res = clients[0].request(json!({ "type": "room_lock", "locked": true })).await;
+        assert_eq!(res["result"], "ok");
+        let res = dave.request(json!({ "type": "room_spectate", "inviteId": room, "password": "secret" })).await;
+        assert_eq!(res["result"], "room_locked");
+    }
+}
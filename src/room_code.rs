@@ -0,0 +1,54 @@
+//!
+//! Short room codes, easier to read aloud or type than the ids: every room gets ROOM_CODE_LEN
+//! characters from an alphabet without the ones that look alike (0 and O, 1, I and L). The lobby
+//! keeps the code of every room, room_join and room_spectate take either the id or the code, in
+//! any case and with dashes or spaces in between.
+//!
+
+use rand::Rng;
+
+pub const ROOM_CODE_LEN: usize = 6;
+const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+pub fn generate(rng: &mut impl Rng) -> String {
+    (0..ROOM_CODE_LEN)
+        .map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())] as char)
+        .collect()
+}
+
+/// A code starting with the prefix, see dev_rooms.rs.
+pub fn generate_with_prefix(rng: &mut impl Rng, prefix: &str) -> String {
+    prefix.chars().chain(generate(rng).chars().skip(prefix.len())).collect()
+}
+
+/// True if the text can start a code: characters of the alphabet, at most half of a code.
+pub fn is_prefix(text: &str) -> bool {
+    (1..=ROOM_CODE_LEN / 2).contains(&text.len()) && text.bytes().all(|x| ALPHABET.contains(&x))
+}
+
+/// The code typed by a player as the lobby keeps it, none if it can't be a code.
+pub fn normalize(input: &str) -> Option<String> {
+    let code: String = input.chars()
+        .filter(|x| !x.is_whitespace() && *x != '-')
+        .map(|x| x.to_ascii_uppercase())
+        .collect();
+    let valid = code.len() == ROOM_CODE_LEN && code.bytes().all(|x| ALPHABET.contains(&x));
+    Some(code).filter(|_| valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_normalized() {
+        let code = generate(&mut rand::thread_rng());
+        assert_eq!(normalize(&code.to_lowercase()), Some(code));
+        assert_eq!(normalize("abc-d2"), None);
+        assert_eq!(normalize("abc-234"), Some("ABC234".to_string()));
+        assert_eq!(normalize(" XQ7 KPM "), Some("XQ7KPM".to_string()));
+        // Characters that look alike aren't used, and the ids are longer.
+        assert_eq!(normalize("ABC10O"), None);
+        assert_eq!(normalize("AAAAAAAAACo="), None);
+    }
+}
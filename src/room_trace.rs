@@ -0,0 +1,113 @@
+//!
+//! Verbose tracing of a single room, to debug the player reports that can't be reproduced. An
+//! admin enables it on a room for a while (POST /admin/rooms/trace): every message received from
+//! and sent to its players and spectators is written with a timestamp to a file of its own,
+//! `room-<room id>-<start>.log` in ROOM_TRACE_DIR (the temporary directory by default). It ends by
+//! itself after the duration asked, at most MAX_TRACE_SECS.
+//!
+//! The room hands the trace to its members, their connections write the messages as they go
+//! through them (in the json format whatever the codec of the client). The payloads are written as
+//! they are, chat included: the files are for the operators only.
+//!
+
+use std::{env, fs::File, io::{self, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Mutex, PoisonError}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use crate::protocol::IdType;
+
+pub const MAX_TRACE_SECS: u64 = 3600;
+
+#[derive(Copy, Clone)]
+pub enum Direction {
+    In,// From the client
+    Out,
+}
+
+pub struct RoomTrace {
+    file: Mutex<File>,// Written by the connections of the members, on any thread
+    path: PathBuf,
+    until: Instant,
+    stopped: AtomicBool,
+}
+
+fn timestamp() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Where the files are written.
+pub fn trace_dir() -> PathBuf {
+    env::var_os("ROOM_TRACE_DIR").filter(|x| !x.is_empty()).map(PathBuf::from).unwrap_or_else(env::temp_dir)
+}
+
+impl RoomTrace {
+    /// Opens the file of a new trace of the room in `dir`.
+    pub fn create(dir: &Path, room: IdType, duration: Duration) -> io::Result<Self> {
+        let path = dir.join(format!("room-{}-{}.log", room, timestamp() as u64));
+        Ok(RoomTrace {
+            file: Mutex::new(File::create(&path)?),
+            path,
+            until: Instant::now() + duration,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn active(&self) -> bool {
+        !self.stopped.load(Ordering::Relaxed) && Instant::now() < self.until
+    }
+
+    /// Ends the trace before its time, the connections stop writing right away.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// A message of a member of the room.
+    pub fn record(&self, direction: Direction, player: IdType, data: &str) {
+        let direction = match direction {
+            Direction::In => "in ",
+            Direction::Out => "out",
+        };
+        self.write(&format!("{} {} {}", direction, player, data));
+    }
+
+    /// A line of the room itself: the start, the members that come in, the end.
+    pub fn note(&self, text: &str) {
+        self.write(&format!("--  {}", text));
+    }
+
+    fn write(&self, line: &str) {
+        // A line at a time: the lines of different connections don't mix.
+        let line = format!("{:.3} {}\n", timestamp(), line);
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn messages_are_written_until_the_end() {
+        let trace = RoomTrace::create(&env::temp_dir(), 7, Duration::from_secs(60)).unwrap();
+        assert!(trace.active());
+        trace.note("Trace started");
+        trace.record(Direction::In, 3, r#"{"id":1,"type":"room_start"}"#);
+        trace.record(Direction::Out, 4, r#"{"id":2,"type":"event_room_start"}"#);
+        trace.stop();
+        assert!(!trace.active());
+
+        let data = fs::read_to_string(trace.path()).unwrap();
+        fs::remove_file(trace.path()).unwrap();
+        let lines: Vec<&str> = data.lines().map(|x| x.split_once(' ').unwrap().1).collect();
+        assert_eq!(lines, [
+            "--  Trace started",
+            r#"in  3 {"id":1,"type":"room_start"}"#,
+            r#"out 4 {"id":2,"type":"event_room_start"}"#,
+        ]);
+        assert!(RoomTrace::create(&env::temp_dir().join("missing-trace-dir"), 7, Duration::from_secs(1)).is_err());
+    }
+}
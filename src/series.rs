@@ -0,0 +1,127 @@
+//!
+//! Match series: with the "series" setting (best of 3 or 5, authoritative engine only, without the
+//! teams) the games of a room add up. The best final score wins a game, a tie wins it for nobody.
+//! The first player to win a majority of the games wins the series, otherwise the one with the most
+//! wins once they've all been played (nobody if it's still a tie).
+//!
+//! The turn order of the first game is kept for the whole series and the starting player moves
+//! along it from one game to the next; players that join in the middle of the series come last.
+//! After every game the players receive the score of the series, then its winner once it's over and
+//! the lobby adds the series to their statistics (see player_stats.rs) and their ratings (see
+//! ratings.rs). A change of the settings starts a new series, the next game after the end of one
+//! too.
+//!
+
+use std::collections::HashMap;
+
+use crate::protocol::{IdType, SeriesView, SeriesWins};
+
+pub const SERIES_LENGTHS: &[u8] = &[3, 5];
+
+pub struct Series {
+    best_of: u8,
+    order: Vec<IdType>,// Turn order of the series, the players that joined it at the end
+    wins: HashMap<IdType, u32>,
+    games: u32,
+}
+
+impl Series {
+    pub fn new(best_of: u8) -> Self {
+        Series { best_of, order: Vec::new(), wins: HashMap::new(), games: 0 }
+    }
+
+    pub fn best_of(&self) -> u8 {
+        self.best_of
+    }
+
+    /// The turn order of the next game of the players present, starting with the next player.
+    pub fn turn_order(&mut self, players: &[IdType]) -> Vec<IdType> {
+        for player in players.iter() {
+            if !self.order.contains(player) {
+                self.order.push(*player);
+            }
+        }
+        let mut order: Vec<IdType> = self.order.iter().copied().filter(|x| players.contains(x)).collect();
+        if !order.is_empty() {
+            let start = self.games as usize % order.len();
+            order.rotate_left(start);
+        }
+        order
+    }
+
+    /// Adds a game that ended with these final scores.
+    pub fn record(&mut self, scores: &HashMap<IdType, u32>) {
+        self.games += 1;
+        let best = scores.values().max();
+        let mut winners = scores.iter().filter(|x| Some(x.1) == best).map(|x| *x.0);
+        if let Some(winner) = winners.next().filter(|_| winners.next().is_none()) {
+            *self.wins.entry(winner).or_default() += 1;
+        }
+    }
+
+    /// Once a player has a majority or every game was played.
+    pub fn is_over(&self) -> bool {
+        let majority = self.best_of as u32 / 2 + 1;
+        self.games >= self.best_of as u32 || self.wins.values().any(|x| *x >= majority)
+    }
+
+    /// The player with the most wins once the series is over, none for a tie.
+    pub fn winner(&self) -> Option<IdType> {
+        Some(()).filter(|_| self.is_over())?;
+        let best = self.wins.values().max()?;
+        let mut winners = self.wins.iter().filter(|x| x.1 == best).map(|x| *x.0);
+        winners.next().filter(|_| winners.next().is_none())
+    }
+
+    /// The players of the series, the ones without a win too.
+    pub fn players(&self) -> &[IdType] {
+        &self.order
+    }
+
+    pub fn view(&self) -> SeriesView {
+        SeriesView {
+            best_of: self.best_of,
+            games: self.games,
+            wins: self.order.iter()
+                .map(|x| SeriesWins { player: (*x).into(), wins: self.wins.get(x).copied().unwrap_or(0) })
+                .collect(),
+            winner: self.winner().map(Into::into),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores(list: &[(IdType, u32)]) -> HashMap<IdType, u32> {
+        list.iter().copied().collect()
+    }
+
+    #[test]
+    fn a_majority_wins_the_series() {
+        let mut series = Series::new(3);
+        assert_eq!(series.turn_order(&[1, 2]), [1, 2]);
+        series.record(&scores(&[(1, 30), (2, 20)]));
+        assert!(!series.is_over());
+        // The starting player moves along, a player joining comes last.
+        assert_eq!(series.turn_order(&[3, 2, 1]), [2, 3, 1]);
+
+        // A tie wins the game for nobody.
+        series.record(&scores(&[(1, 25), (2, 25), (3, 10)]));
+        assert_eq!(series.turn_order(&[1, 2, 3]), [3, 1, 2]);
+        assert_eq!(series.winner(), None);
+        series.record(&scores(&[(1, 40), (2, 25), (3, 10)]));
+        assert!(series.is_over());
+        assert_eq!(series.winner(), Some(1));
+        let view = series.view();
+        assert_eq!((view.games, view.wins.len(), view.wins[0].wins), (3, 3, 2));
+
+        let mut series = Series::new(3);
+        for _ in 0..3 {
+            series.record(&scores(&[(1, 10), (2, 10)]));
+        }
+        assert!(series.is_over());
+        assert_eq!(series.winner(), None);
+    }
+}
@@ -0,0 +1,56 @@
+//!
+//! Graceful shutdown: on SIGTERM the connected players are warned, no room is created anymore and
+//! the server stops once the last game ends or after SHUTDOWN_GRACE_SECS (300 by default).
+//! SIGINT (ctrl-c) still stops the server right away.
+//!
+
+use std::{env, time::{Duration, Instant}};
+
+use actix::prelude::*;
+use actix_rt::{signal, time};
+use actix_web::dev::Server;
+use tracing::{info, warn};
+
+use crate::lobby_actor::{self, LobbyActor};
+
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 300;
+/// How often the server checks whether the games are over.
+const GAMES_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn handle_signals(server: Server, lobby: Addr<LobbyActor>) {
+    let srv = server.clone();
+    actix_rt::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            info!("Interrupted, stopping now");
+            srv.stop(false).await;
+        }
+    });
+
+    #[cfg(unix)]
+    actix_rt::spawn(async move {
+        let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(error = %e, "Cannot listen for SIGTERM");
+                return;
+            },
+        };
+        terminate.recv().await;
+
+        let grace = env::var("SHUTDOWN_GRACE_SECS").ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+        info!(seconds = grace, "Stopping once the games end");
+        lobby.do_send(lobby_actor::Shutdown { seconds: grace });
+
+        let deadline = Instant::now() + Duration::from_secs(grace);
+        while Instant::now() < deadline {
+            match lobby.send(lobby_actor::CountPlayingRooms).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => time::delay_for(GAMES_POLL_INTERVAL).await,
+            }
+        }
+        info!("Stopping now");
+        server.stop(true).await;
+    });
+}
@@ -0,0 +1,100 @@
+//!
+//! Broadcast delay of the streamed games: in a room created with the "spectatorDelaySecs" option
+//! the spectators receive the relay traffic and the events of the game that many seconds after the
+//! players, so that a streamer can let anyone watch without giving the opponents its moves ahead of
+//! time. The room buffers what it would have sent to the spectators and hands it out every
+//! FLUSH_INTERVAL once it's old enough.
+//!
+//! A spectator that starts watching receives the board and the tile drawn as the spectators see
+//! them, then the buffered stream from there. The buffer keeps MAX_DELAYED items, a busier room
+//! loses the oldest ones. The closing of the room isn't delayed, what's left in the buffer is lost.
+//!
+
+use std::{collections::VecDeque, sync::Arc, time::{Duration, Instant}};
+
+use crate::game::BoardSnapshot;
+use crate::protocol::OutGameEvent;
+
+pub const MAX_SPECTATOR_DELAY_SECS: u32 = 300;
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_DELAYED: usize = 4096;
+
+pub enum Delayed {
+    Relay(Arc<str>),// A relay packet, with its sender
+    Event(Arc<OutGameEvent>),
+    Board(BoardSnapshot),// After a validated move, only for the spectators that start watching
+}
+
+pub struct SpectatorDelay {
+    delay: Duration,
+    queue: VecDeque<(Instant, Delayed)>,
+    board: Option<BoardSnapshot>,// As the spectators see it
+    tile_drawn: Option<Arc<OutGameEvent>>,
+}
+
+impl SpectatorDelay {
+    pub fn new(secs: u32) -> Self {
+        SpectatorDelay { delay: Duration::from_secs(secs as u64), queue: VecDeque::new(), board: None, tile_drawn: None }
+    }
+
+    pub fn push(&mut self, item: Delayed, now: Instant) {
+        if self.queue.len() >= MAX_DELAYED {
+            self.queue.pop_front();
+        }
+        self.queue.push_back((now, item));
+    }
+
+    /// The relay packets and the events old enough for the spectators. The boards are kept for the
+    /// spectators that start watching, they're never returned.
+    pub fn due(&mut self, now: Instant) -> Vec<Delayed> {
+        let mut due = Vec::new();
+        while let Some((at, _)) = self.queue.front() {
+            if now.duration_since(*at) < self.delay {
+                break;
+            }
+            match self.queue.pop_front().unwrap().1 {
+                Delayed::Board(board) => self.board = Some(board),
+                Delayed::Event(event) => {
+                    if let OutGameEvent::TileDrawn { .. } = &*event {
+                        self.tile_drawn = Some(event.clone());
+                    }
+                    due.push(Delayed::Event(event));
+                },
+                relay => due.push(relay),
+            }
+        }
+        due
+    }
+
+    pub fn board(&self) -> Option<&BoardSnapshot> {
+        self.board.as_ref()
+    }
+
+    pub fn tile_drawn(&self) -> Option<&Arc<OutGameEvent>> {
+        self.tile_drawn.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::GameState;
+
+    #[test]
+    fn items_wait_for_the_delay() {
+        let mut delay = SpectatorDelay::new(30);
+        let start = Instant::now();
+        delay.push(Delayed::Board(GameState::default().snapshot()), start);
+        delay.push(Delayed::Relay("{\"sender\":\"AAAAAAAAAAE=\"}".into()), start + Duration::from_secs(1));
+        delay.push(Delayed::Event(Arc::new(OutGameEvent::TurnTimeout { server_time: None })), start + Duration::from_secs(2));
+        assert!(delay.due(start + Duration::from_secs(29)).is_empty());
+        assert!(delay.board().is_none());
+
+        let due = delay.due(start + Duration::from_secs(31));
+        assert!(matches!(&due[..], [Delayed::Relay(_)]));
+        assert!(delay.board().is_some());
+        let due = delay.due(start + Duration::from_secs(40));
+        assert!(matches!(&due[..], [Delayed::Event(_)]));
+        assert!(delay.tile_drawn().is_none());
+    }
+}
@@ -0,0 +1,276 @@
+//!
+//! Preconditions of room_start. Every check looks at the room about to start and reports what blocks
+//! it, all of them run so that the host receives every failure at once and the client can show
+//! exactly what to fix. A new rule is a new function in CHECKS.
+//!
+
+use std::collections::HashMap;
+
+use crate::game::GameState;
+use crate::protocol::{IdType, PlayerObject, RoomSettings, SerId, StartFailure};
+use crate::room_actor::RoomState;
+
+const MIN_PLAYERS_TO_START: usize = 2;
+
+/// What the checks can see of the room.
+pub struct StartingRoom<'a> {
+    pub state: RoomState,
+    pub read_only: bool,// The lobby is read only during an incident
+    pub by_host: bool,// Asked by the host or by the server
+    pub tournament: bool,
+    pub game: Option<&'a GameState>,// Saved game to continue
+    pub settings: &'a RoomSettings,
+    pub players: Vec<StartingPlayer<'a>>,
+}
+
+pub struct StartingPlayer<'a> {
+    pub obj: &'a PlayerObject,
+    pub in_game: bool,// Still in the previous game
+}
+
+type Check = fn(&StartingRoom) -> Option<StartFailure>;
+
+const CHECKS: &[Check] = &[
+    host_only, not_playing, not_read_only, player_count, all_ready, unique_colors, balanced_teams, saved_game,
+];
+
+/// Every failure of the room, empty if it can start.
+pub fn run(room: &StartingRoom) -> Vec<StartFailure> {
+    CHECKS.iter().filter_map(|check| check(room)).collect()
+}
+
+fn failure(reason: &'static str, players: Vec<SerId>) -> Option<StartFailure> {
+    Some(StartFailure { reason, players, max_players: None })
+}
+
+fn host_only(room: &StartingRoom) -> Option<StartFailure> {
+    if !room.by_host {
+        return failure("not_host", vec![]);
+    }
+    None
+}
+
+fn not_playing(room: &StartingRoom) -> Option<StartFailure> {
+    if room.state != RoomState::Matchmaking {
+        return failure("already_playing", vec![]);
+    }
+    None
+}
+
+fn not_read_only(room: &StartingRoom) -> Option<StartFailure> {
+    if room.read_only {
+        return failure("server_read_only", vec![]);
+    }
+    None
+}
+
+fn player_count(room: &StartingRoom) -> Option<StartFailure> {
+    if room.players.len() < MIN_PLAYERS_TO_START {
+        return failure("not_enough_players", vec![]);
+    }
+    let limit = if room.players.len() > room.settings.max_players {
+        Some(("too_many_players", room.settings.max_players))
+    } else if room.players.len() > room.settings.ruleset_max_players() {
+        // A bigger group needs an expansion.
+        Some(("too_many_players_for_expansions", room.settings.ruleset_max_players()))
+    } else {
+        None
+    };
+    limit.map(|(reason, max)| StartFailure { reason, players: vec![], max_players: Some(max) })
+}
+
+/// The players still in the previous game (e.g. on its end screen) must come back first.
+fn all_ready(room: &StartingRoom) -> Option<StartFailure> {
+    let players: Vec<SerId> = room.players.iter()
+        .filter(|x| x.in_game)
+        .map(|x| x.obj.id)
+        .collect();
+    if players.is_empty() {
+        return None;
+    }
+    failure("players_not_ready", players)
+}
+
+/// The meeples of every player must be told apart on the board.
+fn unique_colors(room: &StartingRoom) -> Option<StartFailure> {
+    let mut by_color: HashMap<u64, Vec<SerId>> = HashMap::new();
+    for player in room.players.iter() {
+        by_color.entry(player.obj.cosmetics.color).or_default().push(player.obj.id);
+    }
+    let mut players: Vec<SerId> = by_color.into_values()
+        .filter(|x| x.len() > 1)
+        .flatten()
+        .collect();
+    if players.is_empty() {
+        return None;
+    }
+    players.sort_by_key(|x| x.0);
+    failure("duplicate_colors", players)
+}
+
+/// With teams every team needs as many players as the others, the players are the ones of the
+/// teams bigger than the smallest one.
+fn balanced_teams(room: &StartingRoom) -> Option<StartFailure> {
+    let count = room.settings.teams? as usize;
+    let mut teams: Vec<Vec<SerId>> = vec![Vec::new(); count];
+    for player in room.players.iter() {
+        if let Some(team) = teams.get_mut(player.obj.team.unwrap_or(u8::MAX) as usize) {
+            team.push(player.obj.id);
+        }
+    }
+    let smallest = teams.iter().map(Vec::len).min().unwrap_or_default();
+    if smallest > 0 && teams.iter().all(|x| x.len() == smallest) {
+        return None;
+    }
+    let mut players: Vec<SerId> = teams.into_iter().filter(|x| x.len() > smallest).flatten().collect();
+    players.sort_by_key(|x| x.0);
+    failure("unbalanced_teams", players)
+}
+
+/// A saved game can't decide a tournament match, and its meeples must belong to the players of the
+/// room: the players are the owners that aren't.
+fn saved_game(room: &StartingRoom) -> Option<StartFailure> {
+    let game = room.game?;
+    if room.tournament {
+        return failure("snapshot_not_allowed", vec![]);
+    }
+    let players: Vec<IdType> = room.players.iter().map(|x| x.obj.id.0).collect();
+    let unknown = game.unknown_players(&players);
+    if unknown.is_empty() {
+        return None;
+    }
+    failure("unknown_snapshot_players", unknown.into_iter().map(SerId).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{fixture::GameStateBuilder, MeeplePosition};
+    use crate::protocol::PlayerCosmetics;
+
+    fn player(id: usize, color: u64) -> PlayerObject {
+        PlayerObject {
+            id: SerId(id),
+            username: format!("p{}", id),
+            cosmetics: PlayerCosmetics { avatar: 0, color },
+            is_host: id == 1,
+            team: None,
+        }
+    }
+
+    fn reasons(failures: &[StartFailure]) -> Vec<&'static str> {
+        failures.iter().map(|x| x.reason).collect()
+    }
+
+    #[test]
+    fn every_failure_is_reported() {
+        let settings = RoomSettings { max_players: 2, ..RoomSettings::default() };
+        let objs = [player(1, 0xff0000), player(2, 0x00ff00), player(3, 0xff0000)];
+        let room = StartingRoom {
+            state: RoomState::Matchmaking,
+            read_only: false,
+            by_host: true,
+            tournament: false,
+            game: None,
+            settings: &settings,
+            players: objs.iter().enumerate().map(|(i, obj)| StartingPlayer { obj, in_game: i == 1 }).collect(),
+        };
+
+        let failures = run(&room);
+        assert_eq!(reasons(&failures), ["too_many_players", "players_not_ready", "duplicate_colors"]);
+        assert!(failures[1].players == [SerId(2)]);
+        assert!(failures[2].players == [SerId(1), SerId(3)]);
+    }
+
+    #[test]
+    fn ready_room() {
+        let settings = RoomSettings::default();
+        let objs = [player(1, 0xff0000), player(2, 0x00ff00)];
+        let room = StartingRoom {
+            state: RoomState::Matchmaking,
+            read_only: false,
+            by_host: true,
+            tournament: false,
+            game: None,
+            settings: &settings,
+            players: objs.iter().map(|obj| StartingPlayer { obj, in_game: false }).collect(),
+        };
+        assert!(run(&room).is_empty());
+
+        let room = StartingRoom { read_only: true, ..room };
+        assert_eq!(reasons(&run(&room)), ["server_read_only"]);
+    }
+
+    #[test]
+    fn expansions_raise_the_player_count() {
+        let objs: Vec<PlayerObject> = (1..=6).map(|i| player(i, i as u64)).collect();
+        let start = |expansions: Vec<String>| {
+            let settings = RoomSettings { expansions, ..RoomSettings::default() };
+            let room = StartingRoom {
+                state: RoomState::Matchmaking,
+            read_only: false,
+                by_host: true,
+                tournament: false,
+                game: None,
+                settings: &settings,
+                players: objs.iter().map(|obj| StartingPlayer { obj, in_game: false }).collect(),
+            };
+            run(&room)
+        };
+
+        let failures = start(vec![]);
+        assert_eq!(reasons(&failures), ["too_many_players_for_expansions"]);
+        assert_eq!(failures[0].max_players, Some(5));
+        assert!(start(vec!["inns_and_cathedrals".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn teams_must_be_balanced() {
+        let settings = RoomSettings { teams: Some(2), ..RoomSettings::default() };
+        let teams = [0, 1, 0, 0];
+        let objs: Vec<PlayerObject> = (1..=4).map(|i| PlayerObject { team: Some(teams[i - 1]), ..player(i, i as u64) }).collect();
+        let start = |count: usize| {
+            let room = StartingRoom {
+                state: RoomState::Matchmaking,
+                read_only: false,
+                by_host: true,
+                tournament: false,
+                game: None,
+                settings: &settings,
+                players: objs[..count].iter().map(|obj| StartingPlayer { obj, in_game: false }).collect(),
+            };
+            run(&room)
+        };
+
+        assert!(start(2).is_empty());
+        let failures = start(4);
+        assert_eq!(reasons(&failures), ["unbalanced_teams"]);
+        assert!(failures[0].players == [SerId(1), SerId(3), SerId(4)]);
+        // Two against one.
+        let failures = start(3);
+        assert!(failures[0].players == [SerId(1), SerId(3)]);
+    }
+
+    #[test]
+    fn saved_games() {
+        let settings = RoomSettings::default();
+        let objs = [player(1, 0xff0000), player(2, 0x00ff00)];
+        let game = GameStateBuilder::new().meeple((0, 0), 3, MeeplePosition::East).build();
+        let room = StartingRoom {
+            state: RoomState::Matchmaking,
+            read_only: false,
+            by_host: false,
+            tournament: false,
+            game: Some(&game),
+            settings: &settings,
+            players: objs.iter().map(|obj| StartingPlayer { obj, in_game: false }).collect(),
+        };
+
+        let failures = run(&room);
+        assert_eq!(reasons(&failures), ["not_host", "unknown_snapshot_players"]);
+        assert!(failures[1].players == [SerId(3)]);
+
+        let room = StartingRoom { by_host: true, tournament: true, ..room };
+        assert_eq!(reasons(&run(&room)), ["snapshot_not_allowed"]);
+    }
+}
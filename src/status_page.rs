@@ -0,0 +1,105 @@
+//!
+//! A tiny status page served on / to the requests that aren't a websocket upgrade, so that whoever
+//! hosts the server can check that it's alive with a browser: the players connected, the rooms, the
+//! version and the uptime. It's rendered here, no asset and no script.
+//!
+
+use std::{sync::OnceLock, time::{Duration, Instant}};
+
+use actix::prelude::*;
+use actix_web::{http::header, HttpRequest, HttpResponse};
+
+use crate::lobby_actor::{self, LobbyActor};
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+pub struct ServerStatus {
+    pub players: usize,// Connected, not the sessions waiting for a reconnection
+    pub rooms: usize,
+    pub playing_rooms: usize,
+    pub shutting_down: bool,
+    pub incident: Option<String>,// The message of the incident in progress, empty if it has none
+}
+
+/// Called when the server starts, the uptime is counted from here.
+pub fn init() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// True if the request asks for a websocket, the others get the status page.
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+    req.headers().get(header::UPGRADE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+pub async fn page(lobby: &Addr<LobbyActor>) -> HttpResponse {
+    let status = match lobby.send(lobby_actor::GetStatus).await {
+        Ok(x) => x,
+        Err(_) => return HttpResponse::ServiceUnavailable().finish(),
+    };
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(render(&status, STARTED.get().map(|x| x.elapsed()).unwrap_or_default()))
+}
+
+fn render(status: &ServerStatus, uptime: Duration) -> String {
+    let state = match &status.incident {
+        _ if status.shutting_down => "Shutting down".to_string(),
+        Some(x) if x.is_empty() => "Read only (incident)".to_string(),
+        Some(x) => format!("Read only (incident): {}", escape(x)),
+        None => "Online".to_string(),
+    };
+    let rows = [
+        ("State", state),
+        ("Version", env!("CARGO_PKG_VERSION").to_string()),
+        ("Uptime", format_uptime(uptime)),
+        ("Players", status.players.to_string()),
+        ("Rooms", format!("{} ({} playing)", status.rooms, status.playing_rooms)),
+    ];
+    let rows: String = rows.iter()
+        .map(|(name, value)| format!("<tr><th>{}</th><td>{}</td></tr>", name, value))
+        .collect();
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Carcassonne server</title>\
+        <style>body{{font-family:sans-serif;margin:2em}}th{{text-align:left;padding-right:1em}}</style>\
+        </head><body><h1>Carcassonne server</h1><table>{}</table></body></html>",
+        rows,
+    )
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    match days {
+        0 => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_is_rendered() {
+        let status = ServerStatus {
+            players: 3,
+            rooms: 2,
+            playing_rooms: 1,
+            shutting_down: false,
+            incident: Some("<b>db</b> down".to_string()),
+        };
+        let page = render(&status, Duration::from_secs(26 * 3600 + 5 * 60));
+        assert!(page.contains("<tr><th>Players</th><td>3</td></tr>"));
+        assert!(page.contains("2 (1 playing)"));
+        assert!(page.contains("1d 2h 5m"));
+        assert!(page.contains("&lt;b&gt;db&lt;/b&gt; down"));
+    }
+}
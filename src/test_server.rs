@@ -0,0 +1,122 @@
+//!
+//! The server of the handler tests: the routes of the server on a random port of the loopback,
+//! with a lobby of its own, and a websocket client speaking the json protocol like a player.
+//!
+
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix_web::{test, App};
+use actix_web_actors::ws;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde_json::{json, Value};
+
+use crate::admin::AdminConfig;
+use crate::handshake::HandshakeConfig;
+use crate::lobby_actor::LobbyActor;
+use crate::replication::ReplicationConfig;
+
+/// How long a client waits for a message before the test fails.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub trait Socket: Sink<ws::Message, Error = ws::ProtocolError> + Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin {}
+
+impl<T> Socket for T where T: Sink<ws::Message, Error = ws::ProtocolError> + Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin {}
+
+pub struct TestServer {
+    pub http: test::TestServer,
+    logins: u64,
+}
+
+pub fn start(admin: AdminConfig) -> TestServer {
+    let lobby = LobbyActor::default().start();
+    let http = test::start(move || {
+        App::new()
+            .data(lobby.clone())
+            .data(HandshakeConfig::from_env())
+            .data(admin.clone())
+            .data(ReplicationConfig::from_env())
+            .configure(crate::routes)
+    });
+    TestServer { http, logins: 0 }
+}
+
+impl TestServer {
+    /// A websocket not logged in yet, after the hello of the server.
+    pub async fn connect(&mut self) -> Client {
+        let socket = self.http.ws().await.expect("websocket refused");
+        let mut client = Client { socket: Box::new(socket), next_id: 0, player_id: String::new(), account_token: String::new() };
+        client.wait_for("hello").await;
+        client
+    }
+
+    /// A new player, logged in with a new account and a color of its own.
+    pub async fn login(&mut self, username: &str) -> Client {
+        self.logins += 1;
+        let mut client = self.connect().await;
+        let res = client.request(json!({ "type": "login", "details": { "username": username, "avatar": 1, "color": self.logins } })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        client.player_id = res["playerId"].as_str().unwrap().to_string();
+        client.account_token = res["accountToken"].as_str().unwrap().to_string();
+        client
+    }
+}
+
+pub struct Client {
+    socket: Box<dyn Socket>,
+    next_id: u64,
+    pub player_id: String,
+    pub account_token: String,
+}
+
+impl Client {
+    /// Sends a message with the next id.
+    pub async fn send(&mut self, mut message: Value) -> u64 {
+        self.next_id += 1;
+        message["id"] = self.next_id.into();
+        self.socket.send(ws::Message::Text(message.to_string())).await.expect("websocket closed");
+        self.next_id
+    }
+
+    /// The next message of the server, the pings aren't messages.
+    pub async fn recv(&mut self) -> Value {
+        loop {
+            let frame = tokio::time::timeout(TIMEOUT, self.socket.next()).await
+                .expect("no message from the server")
+                .expect("websocket closed")
+                .expect("invalid frame");
+            if let ws::Frame::Text(x) = frame {
+                return serde_json::from_slice(&x).unwrap();
+            }
+        }
+    }
+
+    /// Skips the messages until the server closes the websocket, with the description of its reason.
+    pub async fn closed(&mut self) -> Option<String> {
+        loop {
+            let frame = tokio::time::timeout(TIMEOUT, self.socket.next()).await.expect("websocket still open");
+            match frame {
+                Some(Ok(ws::Frame::Close(reason))) => return reason.and_then(|x| x.description),
+                Some(_) => {},
+                None => return None,
+            }
+        }
+    }
+
+    /// Skips the messages until one of this type.
+    pub async fn wait_for(&mut self, ptype: &str) -> Value {
+        loop {
+            let message = self.recv().await;
+            if message["type"] == ptype {
+                return message;
+            }
+        }
+    }
+
+    /// Sends a request and waits for its response.
+    pub async fn request(&mut self, message: Value) -> Value {
+        let ptype = format!("{}_response", message["type"].as_str().unwrap());
+        self.send(message).await;
+        self.wait_for(&ptype).await
+    }
+}
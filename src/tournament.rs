@@ -0,0 +1,250 @@
+//!
+//! Tournaments: single elimination brackets of 1v1 games. An organizer creates a tournament, the
+//! players register until the organizer starts it, then the lobby creates a private room for every
+//! match of the round and puts both players in it. The room starts by itself once they're both in,
+//! the final scores of its game (authoritative engine only) decide the winner, and once every match
+//! of the round has a winner the next round begins. A tie goes to the player with fewer time
+//! violations (see turn_times.rs), then to the better seed.
+//!
+//! The seeds are drawn when the tournament starts. The bracket has a power of two slots, the best
+//! seeds get a bye in the first round when the players don't fill it. A player that leaves the
+//! tournament or loses its session loses its match. When the room of a match closes before the end
+//! of its game the better seed goes on.
+//!
+
+use std::collections::HashMap;
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::protocol::{AutoStart, IdType, RoomSettings, TournamentMatchView, TournamentState, TournamentView};
+
+pub const MIN_TOURNAMENT_PLAYERS: usize = 2;
+pub const MAX_TOURNAMENT_PLAYERS: usize = 64;
+/// Auto-start countdown of the room of a match once both players are in.
+pub const MATCH_COUNTDOWN_SECS: u32 = 15;
+
+/// The settings of the room of a match, its host cannot change them.
+pub fn match_settings() -> RoomSettings {
+    RoomSettings {
+        max_players: 2,
+        auto_start: Some(AutoStart { players: 2, after_secs: MATCH_COUNTDOWN_SECS }),
+        ..RoomSettings::default()
+    }
+}
+
+/// The winner of a match from the end of its game: the best score, then the fewest time
+/// violations. None if it's still a tie, the better seed goes on.
+pub fn adjudicate(scores: &HashMap<IdType, u32>, violations: &HashMap<IdType, u32>) -> Option<IdType> {
+    let best = scores.values().max()?;
+    let tied: Vec<IdType> = scores.iter().filter(|x| x.1 == best).map(|x| *x.0).collect();
+    let violations = |player: &IdType| violations.get(player).copied().unwrap_or(0);
+    let fewest = tied.iter().map(violations).min()?;
+    let mut winners = tied.iter().filter(|x| violations(x) == fewest);
+    winners.next().copied().filter(|_| winners.next().is_none())
+}
+
+pub struct Match {
+    pub players: Vec<IdType>,// Better seed first, a single player has a bye
+    pub room: Option<IdType>,// Once created
+    pub winner: Option<IdType>,
+}
+
+pub struct Tournament {
+    pub id: IdType,
+    pub name: String,
+    pub organizer: IdType,
+    pub max_players: usize,
+    pub players: Vec<IdType>,// In the order of registration, then of the seeds
+    pub rounds: Vec<Vec<Match>>,
+    pub state: TournamentState,
+}
+
+impl Tournament {
+    pub fn new(id: IdType, name: String, organizer: IdType, max_players: usize) -> Self {
+        Tournament { id, name, organizer, max_players, players: Vec::new(), rounds: Vec::new(), state: TournamentState::Registering }
+    }
+
+    pub fn register(&mut self, player: IdType) -> Result<(), &'static str> {
+        if self.state != TournamentState::Registering {
+            return Err("already_started");
+        }
+        if self.players.contains(&player) {
+            return Err("already_registered");
+        }
+        if self.players.len() >= self.max_players {
+            return Err("tournament_full");
+        }
+        self.players.push(player);
+        Ok(())
+    }
+
+    /// Draws the seeds and the first round.
+    pub fn start(&mut self, rng: &mut impl Rng) -> Result<(), &'static str> {
+        if self.state != TournamentState::Registering {
+            return Err("already_started");
+        }
+        if self.players.len() < MIN_TOURNAMENT_PLAYERS {
+            return Err("not_enough_players");
+        }
+        self.players.shuffle(rng);
+        self.state = TournamentState::Running;
+
+        // The first seed meets the last one, the second the one before... the missing ones are byes.
+        // The slots are in the usual order of a bracket, the two best seeds can only meet in the final.
+        let mut slots = vec![0];
+        while slots.len() < self.players.len() {
+            let size = slots.len() * 2;
+            slots = slots.iter().flat_map(|x| [*x, size - 1 - x]).collect();
+        }
+        let first = slots.chunks(2)
+            .map(|x| {
+                let players: Vec<IdType> = x.iter().filter_map(|x| self.players.get(*x)).copied().collect();
+                let winner = Some(players[0]).filter(|_| players.len() == 1);
+                Match { players, room: None, winner }
+            })
+            .collect();
+        self.rounds.push(first);
+        self.advance();
+        Ok(())
+    }
+
+    /// The next match of the current round that needs a room.
+    pub fn to_play(&self) -> Option<usize> {
+        self.rounds.last()?.iter().position(|x| x.winner.is_none() && x.room.is_none())
+    }
+
+    fn seed(&self, player: IdType) -> usize {
+        self.players.iter().position(|x| *x == player).unwrap_or(usize::MAX)
+    }
+
+    /// The match of the current round that the room plays.
+    pub fn match_of_room(&self, room: IdType) -> Option<usize> {
+        self.rounds.last()?.iter().position(|x| x.room == Some(room) && x.winner.is_none())
+    }
+
+    /// The match of the current round that the player still plays.
+    fn match_of_player(&self, player: IdType) -> Option<usize> {
+        self.rounds.last()?.iter().position(|x| x.players.contains(&player) && x.winner.is_none())
+    }
+
+    /// Gives a match of the current round to a player (to the better seed without one), the next
+    /// round begins once it's the last one.
+    pub fn decide(&mut self, index: usize, winner: Option<IdType>) {
+        let best = match self.rounds.last().and_then(|x| x.get(index)) {
+            Some(game) => game.players.iter().copied().min_by_key(|x| self.seed(*x)),
+            None => return,
+        };
+        let game = &mut self.rounds.last_mut().unwrap()[index];
+        game.winner = winner.filter(|x| game.players.contains(x)).or(best);
+        self.advance();
+    }
+
+    /// A player leaving: removed before the start, loses its match after.
+    pub fn remove_player(&mut self, player: IdType) {
+        if self.state == TournamentState::Registering {
+            self.players.retain(|x| *x != player);
+            return;
+        }
+        if let Some(index) = self.match_of_player(player) {
+            let other = self.rounds.last().unwrap()[index].players.iter().copied().find(|x| *x != player);
+            self.decide(index, other);
+        }
+    }
+
+    /// Starts the next round once every match of the current one has a winner.
+    fn advance(&mut self) {
+        let round = self.rounds.last().unwrap();
+        if round.iter().any(|x| x.winner.is_none()) {
+            return;
+        }
+        if round.len() == 1 {
+            self.state = TournamentState::Finished;
+            return;
+        }
+        let winners: Vec<IdType> = round.iter().filter_map(|x| x.winner).collect();
+        let next = winners.chunks(2)
+            .map(|x| {
+                let mut players = x.to_vec();
+                players.sort_by_key(|x| self.seed(*x));
+                Match { players, room: None, winner: None }
+            })
+            .collect();
+        self.rounds.push(next);
+    }
+
+    pub fn winner(&self) -> Option<IdType> {
+        Some(self.state).filter(|x| *x == TournamentState::Finished)?;
+        self.rounds.last()?.first()?.winner
+    }
+
+    /// The organizer and the players, who receive the updates.
+    pub fn followers(&self) -> impl Iterator<Item = IdType> + '_ {
+        std::iter::once(self.organizer).chain(self.players.iter().copied().filter(move |x| *x != self.organizer))
+    }
+
+    pub fn view(&self) -> TournamentView {
+        TournamentView {
+            tournament_id: self.id.into(),
+            name: self.name.clone(),
+            organizer: self.organizer.into(),
+            max_players: self.max_players,
+            state: self.state,
+            players: self.players.iter().map(|x| (*x).into()).collect(),
+            rounds: self.rounds.iter()
+                .map(|round| round.iter()
+                    .map(|x| TournamentMatchView {
+                        players: x.players.iter().map(|x| (*x).into()).collect(),
+                        room_id: x.room.map(Into::into),
+                        winner: x.winner.map(Into::into),
+                    })
+                    .collect())
+                .collect(),
+            winner: self.winner().map(Into::into),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn winners_go_through_the_rounds() {
+        let mut tournament = Tournament::new(1, "Cup".to_string(), 10, 8);
+        for player in 1..=5 {
+            tournament.register(player).unwrap();
+        }
+        assert_eq!(tournament.register(3), Err("already_registered"));
+        tournament.start(&mut rand::thread_rng()).unwrap();
+        assert_eq!(tournament.register(6), Err("already_started"));
+
+        // 5 players in 8 slots: the 3 best seeds have a bye, the 4th plays the 5th.
+        let seeds = tournament.players.clone();
+        assert_eq!(tournament.to_play(), Some(1));
+        assert_eq!(tournament.rounds[0][1].players, [seeds[3], seeds[4]]);
+        assert_eq!(tournament.rounds[0].iter().filter(|x| x.winner.is_some()).count(), 3);
+
+        tournament.rounds[0][1].room = Some(100);
+        assert_eq!(tournament.to_play(), None);
+        assert_eq!(tournament.match_of_room(100), Some(1));
+        tournament.decide(1, Some(seeds[4]));
+        assert_eq!(tournament.rounds[1][0].players, [seeds[0], seeds[4]]);
+        assert_eq!(tournament.rounds[1][1].players, [seeds[1], seeds[2]]);
+
+        // A player leaving loses its match, a match without a winner goes to the better seed.
+        tournament.rounds[1][0].room = Some(101);
+        tournament.rounds[1][1].room = Some(102);
+        tournament.remove_player(seeds[0]);
+        assert_eq!(tournament.rounds.len(), 2);
+        tournament.decide(1, None);
+        assert_eq!(tournament.rounds[2][0].players, [seeds[1], seeds[4]]);
+        assert_eq!(tournament.winner(), None);
+        tournament.decide(0, Some(seeds[4]));
+        assert_eq!(tournament.state, TournamentState::Finished);
+        assert_eq!(tournament.winner(), Some(seeds[4]));
+
+        let scores: HashMap<IdType, u32> = vec![(1, 20), (2, 20), (3, 10)].into_iter().collect();
+        assert_eq!(adjudicate(&scores, &HashMap::new()), None);
+        assert_eq!(adjudicate(&scores, &vec![(1, 2), (2, 1), (3, 0)].into_iter().collect()), Some(2));
+    }
+}
@@ -0,0 +1,107 @@
+//!
+//! Think times of the tournament matches: the room times every turn, from the tile drawn to the tile
+//! placed by its player. A move over the limit (the turn timer of the room, MOVE_TIME_LIMIT_SECS
+//! without one) is a violation, like a turn skipped by the turn timer. The turn isn't skipped for it.
+//!
+//! When the game ends the players and the spectators receive a time_report (recorded in the replay
+//! with the other events, for the analysis after the game) and the lobby gets the violations with
+//! the result of the match: a tie on the scores goes to the player with fewer violations before the
+//! better seed (see tournament.rs).
+//!
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+use crate::protocol::{IdType, PlayerTimes};
+
+pub const MOVE_TIME_LIMIT_SECS: u32 = 90;
+
+#[derive(Default)]
+struct Times {
+    moves: Vec<Duration>,
+    skipped: u32,
+}
+
+pub struct TurnClock {
+    limit: Duration,
+    turn: Option<(IdType, Instant)>,// The player of the current turn, since when
+    players: BTreeMap<IdType, Times>,
+}
+
+impl TurnClock {
+    pub fn new(limit_secs: u32) -> Self {
+        TurnClock { limit: Duration::from_secs(limit_secs as u64), turn: None, players: BTreeMap::new() }
+    }
+
+    pub fn limit_secs(&self) -> u32 {
+        self.limit.as_secs() as u32
+    }
+
+    /// A tile was drawn for the player.
+    pub fn start(&mut self, player: IdType, now: Instant) {
+        self.turn = Some((player, now));
+    }
+
+    /// The player of the turn placed its tile.
+    pub fn end(&mut self, player: IdType, now: Instant) {
+        if let Some((_, started)) = self.turn.filter(|x| x.0 == player) {
+            self.turn = None;
+            self.players.entry(player).or_default().moves.push(now.duration_since(started));
+        }
+    }
+
+    /// The turn timer skipped the turn.
+    pub fn skip(&mut self) {
+        if let Some((player, _)) = self.turn.take() {
+            self.players.entry(player).or_default().skipped += 1;
+        }
+    }
+
+    pub fn violations(&self, player: IdType) -> u32 {
+        self.players.get(&player)
+            .map(|x| x.skipped + x.moves.iter().filter(|x| **x > self.limit).count() as u32)
+            .unwrap_or(0)
+    }
+
+    /// The times of the players of the game, in the order given.
+    pub fn report(&self, players: &[IdType]) -> Vec<PlayerTimes> {
+        players.iter()
+            .map(|player| {
+                let moves = self.players.get(player).map(|x| &x.moves[..]).unwrap_or_default();
+                let total: Duration = moves.iter().sum();
+                PlayerTimes {
+                    player: (*player).into(),
+                    moves: moves.len() as u32,
+                    average_ms: if moves.is_empty() { 0 } else { (total / moves.len() as u32).as_millis() as u64 },
+                    longest_ms: moves.iter().max().map(|x| x.as_millis() as u64).unwrap_or(0),
+                    violations: self.violations(*player),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_over_the_limit_are_violations() {
+        let mut clock = TurnClock::new(60);
+        let start = Instant::now();
+        clock.start(1, start);
+        clock.end(1, start + Duration::from_secs(10));
+        clock.start(2, start + Duration::from_secs(10));
+        // Not the player of the turn.
+        clock.end(1, start + Duration::from_secs(11));
+        clock.end(2, start + Duration::from_secs(80));
+        clock.start(1, start + Duration::from_secs(80));
+        clock.end(1, start + Duration::from_secs(110));
+        clock.start(2, start + Duration::from_secs(110));
+        clock.skip();
+
+        let report = clock.report(&[1, 2, 3]);
+        assert_eq!((report[0].moves, report[0].average_ms, report[0].longest_ms, report[0].violations), (2, 20_000, 30_000, 0));
+        assert_eq!((report[1].moves, report[1].longest_ms, report[1].violations), (1, 70_000, 2));
+        assert_eq!((report[2].moves, report[2].average_ms, report[2].violations), (0, 0, 0));
+    }
+}
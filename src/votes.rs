@@ -0,0 +1,134 @@
+//!
+//! In-game votes, next to the controls of the host: any player in game can ask the others to kick a
+//! player that stopped playing, to abort the game or to return to the lobby. The players in game
+//! have VOTE_TIMEOUT to vote (the one that started the vote says yes, the player to kick doesn't
+//! vote) and a majority of them passes it; it fails as soon as a majority can't be reached anymore,
+//! or at the timeout. The players receive the count after every vote, then the result.
+//!
+//! A kicked player leaves the room as with a kick of the host. An aborted game ends without a
+//! result: no statistics, no replay and no game of the series; a return to the lobby ends it as if
+//! every player had sent end_game, with the final scores. There's one vote at a time and a player
+//! whose vote failed waits VOTE_COOLDOWN before starting another one. Tournament matches have none.
+//!
+//! A kick needs MIN_KICK_VOTERS besides the player to kick, so that no player can remove another
+//! alone (in a game of two players); it's cancelled if players leave until there are fewer.
+//!
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::protocol::{IdType, VoteProposal, VoteResult, VoteView};
+
+pub const VOTE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const VOTE_COOLDOWN: Duration = Duration::from_secs(60);
+const MIN_KICK_VOTERS: usize = 2;
+
+/// Whether there are enough voters for the proposal.
+pub fn enough_voters(proposal: VoteProposal, voters: usize) -> bool {
+    match proposal {
+        VoteProposal::Kick { .. } => voters >= MIN_KICK_VOTERS,
+        _ => voters > 0,
+    }
+}
+
+pub struct Vote {
+    proposal: VoteProposal,
+    started_by: IdType,
+    ballots: HashMap<IdType, bool>,// The last one of each player counts
+    deadline: u64,// In server time, see clock.rs
+}
+
+impl Vote {
+    pub fn new(proposal: VoteProposal, started_by: IdType, deadline: u64) -> Self {
+        let mut ballots = HashMap::new();
+        ballots.insert(started_by, true);
+        Vote { proposal, started_by, ballots, deadline }
+    }
+
+    pub fn proposal(&self) -> VoteProposal {
+        self.proposal
+    }
+
+    pub fn started_by(&self) -> IdType {
+        self.started_by
+    }
+
+    pub fn cast(&mut self, voter: IdType, yes: bool) {
+        self.ballots.insert(voter, yes);
+    }
+
+    /// The yes and no votes of the voters given, the ballots of the players that left don't count.
+    fn count(&self, voters: &[IdType]) -> (u32, u32) {
+        let ballots = voters.iter().filter_map(|x| self.ballots.get(x));
+        ballots.fold((0, 0), |(yes, no), x| if *x { (yes + 1, no) } else { (yes, no + 1) })
+    }
+
+    /// The result once it's decided with these voters, the timeout aside.
+    pub fn result(&self, voters: &[IdType]) -> Option<VoteResult> {
+        let (yes, no) = self.count(voters);
+        let needed = needed(voters.len());
+        if yes >= needed {
+            Some(VoteResult::Passed)
+        } else if voters.len() as u32 - no < needed {
+            Some(VoteResult::Failed)
+        } else {
+            None
+        }
+    }
+
+    pub fn view(&self, voters: &[IdType]) -> VoteView {
+        let (yes, no) = self.count(voters);
+        VoteView {
+            proposal: self.proposal,
+            started_by: self.started_by.into(),
+            yes,
+            no,
+            needed: needed(voters.len()),
+            deadline: self.deadline,
+        }
+    }
+}
+
+/// A majority of the voters.
+fn needed(voters: usize) -> u32 {
+    voters as u32 / 2 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SerId;
+
+    #[test]
+    fn a_majority_decides_the_vote() {
+        let mut vote = Vote::new(VoteProposal::Abort, 1, 0);
+        let voters = [1, 2, 3, 4];
+        assert_eq!(vote.result(&voters), None);
+        vote.cast(2, true);
+        vote.cast(3, false);
+        assert_eq!(vote.result(&voters), None);
+        let view = vote.view(&voters);
+        assert_eq!((view.yes, view.no, view.needed), (2, 1, 3));
+        // A player changes its mind.
+        vote.cast(2, false);
+        assert_eq!(vote.result(&voters), Some(VoteResult::Failed));
+
+        // The ballot of a player that left doesn't count.
+        assert_eq!(vote.result(&[1, 4]), None);
+        vote.cast(4, true);
+        assert_eq!(vote.result(&[1, 4]), Some(VoteResult::Passed));
+    }
+
+    #[test]
+    fn a_kick_needs_two_voters() {
+        // In a game of two players the starter would be the only voter.
+        let kick = VoteProposal::Kick { target: SerId(2) };
+        assert!(!enough_voters(kick, 1));
+        assert!(enough_voters(kick, 2));
+        assert!(enough_voters(VoteProposal::Abort, 1));
+
+        let mut vote = Vote::new(kick, 1, 0);
+        assert_eq!(vote.result(&[1, 3]), None);
+        vote.cast(3, true);
+        assert_eq!(vote.result(&[1, 3]), Some(VoteResult::Passed));
+    }
+}